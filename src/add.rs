@@ -0,0 +1,70 @@
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::db;
+use crate::init;
+use crate::lock::Lock;
+use crate::report::Reporter;
+use crate::utils::{self, hash_files, normalize, read_paths};
+
+/// Add the files at `paths`, and the ones inside of the directories at `paths`, to the index
+/// without reading the rest of the data directory. Files already in the index are left as is.
+pub fn add(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    paths: &[impl AsRef<Utf8Path>],
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let paths = paths
+        .iter()
+        .map(|p| utils::relative_path(data_path, p.as_ref()))
+        .collect::<Result<Vec<Utf8PathBuf>>>()?;
+
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating insert transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "add").wrap_err("Failed recording operation")?;
+    let now = Instant::now();
+
+    let files = read_paths(data_path, config, &paths).wrap_err("Failed reading paths")?;
+    let mut new_files = vec![];
+    for p in files {
+        let relative = normalize(
+            p.strip_prefix(data_path)
+                .wrap_err_with(|| format!("Path \"{p}\" was not a base of \"{data_path}\""))?,
+        );
+        if db::hash(&transaction, &relative)
+            .wrap_err("Failed fetching hash from db")?
+            .is_some()
+        {
+            debug!("Skipping \"{relative}\", which is already in the index");
+            continue;
+        }
+        new_files.push(p);
+    }
+
+    let hashes = hash_files(&new_files, config, reporter);
+    for (p, h) in new_files.iter().zip(hashes) {
+        let h = h?;
+        let p = normalize(
+            p.strip_prefix(data_path)
+                .wrap_err_with(|| format!("Path \"{p}\" was not a base of \"{data_path}\""))?,
+        );
+        init::insert(&transaction, operation, data_path, config, reporter, &p, h)?;
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+
+    let elapsed = now.elapsed();
+    info!("Added {} files. Took {elapsed:.2?}", new_files.len());
+
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Copies of the database to go back to, made by `cstfs db backup` and before every operation that
+//! removes files or entries from the index.
+//!
+//! Refreshes only back it up when they remove many files, and `init --force` moves the database
+//! there instead of removing it. They are kept in `.cstfs/backups`, or next to the database when
+//! `db_path` puts it elsewhere, named after when they were made, and checked once made. Only the latest `backups` of `cstfs.toml` are kept. To go back to
+//! one, replace the database with it.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::utils;
+
+/// Directory where the copies of the database are kept, next to it when `config` puts it outside
+/// of the data directory, which may not be writable then
+#[must_use]
+pub fn dir(data_path: &Utf8Path, config: &Config) -> Utf8PathBuf {
+    if config.db_path.is_some() {
+        Utf8PathBuf::from(format!("{}.backups", db::path(data_path, config)))
+    } else {
+        utils::cstfs_dir(data_path).join("backups")
+    }
+}
+
+/// Whether `file_name` is the name of a copy of the database
+fn is_backup(file_name: &str) -> bool {
+    file_name.starts_with("cstfs-") && Utf8Path::new(file_name).extension() == Some("db")
+}
+
+/// Suffixes of the files sqlite keeps next to a database while it is open
+const SUFFIXES: [&str; 3] = ["", "-wal", "-shm"];
+
+/// Path of a new copy of the database in [`dir`], named after the current time
+fn new_path(data_path: &Utf8Path, config: &Config) -> Result<Utf8PathBuf> {
+    let dir = dir(data_path, config);
+    std::fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("Failed creating backup directory \"{dir}\""))?;
+    // Sorting by name sorts them by age
+    let path = dir.join(format!(
+        "cstfs-{}.db",
+        Utc::now().format("%Y%m%d-%H%M%S-%3f")
+    ));
+    if path.exists() {
+        bail!("\"{path}\" already exists");
+    }
+    Ok(path)
+}
+
+/// Remove the oldest copies of the database in [`dir`], so only `keep` are left
+fn rotate(data_path: &Utf8Path, config: &Config, keep: usize) -> Result<()> {
+    let mut backups = vec![];
+    for entry in dir(data_path, config)
+        .read_dir_utf8()
+        .wrap_err("Failed reading backup directory")?
+    {
+        let entry = entry.wrap_err("Failed reading backup directory entry")?;
+        if is_backup(entry.file_name()) {
+            backups.push(entry.path().to_path_buf());
+        }
+    }
+    backups.sort_unstable();
+    let old = backups.len().saturating_sub(keep);
+    for p in &backups[..old] {
+        for suffix in SUFFIXES {
+            utils::remove_file(Utf8Path::new(&format!("{p}{suffix}")))
+                .wrap_err_with(|| format!("Failed removing old backup \"{p}\""))?;
+        }
+    }
+    if old > 0 {
+        info!("Removed {old} old backups");
+    }
+    Ok(())
+}
+
+/// Copy the database into [`dir`], check the copy, and remove the oldest copies so only `keep` are
+/// left, returning the path of the copy
+fn make(data_path: &Utf8Path, config: &Config, keep: usize) -> Result<Utf8PathBuf> {
+    let path = new_path(data_path, config)?;
+    // Only named like a backup once checked, so a failed one is never taken for one
+    let partial = Utf8PathBuf::from(format!("{path}.partial"));
+    utils::remove_file(&partial).wrap_err("Failed removing previous partial backup")?;
+
+    let checked = (|| {
+        let copy = db::backup(data_path, config, &partial).wrap_err("Failed copying database")?;
+        let problems = db::integrity_check(&copy).wrap_err("Failed checking copy")?;
+        if !problems.is_empty() {
+            for p in &problems {
+                warn!("{p}");
+            }
+            bail!("Found {} problems in the copy", problems.len());
+        }
+        Ok(())
+    })();
+    if let Err(e) = checked {
+        for suffix in SUFFIXES {
+            utils::remove_file(Utf8Path::new(&format!("{partial}{suffix}")))
+                .wrap_err("Failed removing partial backup")?;
+        }
+        return Err(e);
+    }
+    std::fs::rename(&partial, &path).wrap_err_with(|| format!("Failed renaming to \"{path}\""))?;
+
+    rotate(data_path, config, keep)?;
+    Ok(path)
+}
+
+/// Copy the database into [`dir`], keeping the latest `backups` copies of `config`, and at least
+/// this one.
+///
+/// sqlite's backup API makes the copy, so it is consistent even while another command writes to
+/// the database. The copy is checked with sqlite's integrity check.
+pub fn backup(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    info!("Backing up the database of \"{data_path}\"");
+    let path = make(data_path, config, config.backups.max(1))?;
+    info!("Backed up the database to \"{path}\"");
+    Ok(())
+}
+
+/// Copy the database into [`dir`] before `command` changes it, unless `config` keeps no backups
+pub(crate) fn before(data_path: &Utf8Path, config: &Config, command: &str) -> Result<()> {
+    if config.backups == 0 {
+        return Ok(());
+    }
+    let path = make(data_path, config, config.backups)
+        .wrap_err_with(|| format!("Failed backing up the database before {command}"))?;
+    info!("Backed up the database to \"{path}\" before {command}");
+    Ok(())
+}
+
+/// Move the database into [`dir`] instead of removing it, before `init --force` makes a new one,
+/// unless `config` keeps no backups.
+///
+/// It is moved as it is, without opening it, as it may be replaced because it cannot be opened.
+pub(crate) fn set_aside(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let db_path = db::path(data_path, config);
+    if config.backups == 0 || !db_path.exists() {
+        return Ok(());
+    }
+    let path = new_path(data_path, config)?;
+    for suffix in SUFFIXES {
+        let (from, to) = (format!("{db_path}{suffix}"), format!("{path}{suffix}"));
+        if !Utf8Path::new(&from).exists() {
+            continue;
+        }
+        // The database may be on another filesystem than the data directory
+        if std::fs::rename(&from, &to).is_err() {
+            std::fs::copy(&from, &to)
+                .wrap_err_with(|| format!("Failed moving \"{from}\" to \"{to}\""))?;
+        }
+    }
+    info!("Moved the database to \"{path}\", in case it is needed back");
+    rotate(data_path, config, config.backups)
+}
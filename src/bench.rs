@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use indicatif::HumanBytes;
+use tracing::info;
+
+use crate::config::{Config, HashAlgorithm};
+use crate::db;
+use crate::report::Silent;
+use crate::utils::{hash_files, remove_file, walk};
+
+/// Rate of `amount` things done in `elapsed`, per second
+#[allow(clippy::cast_precision_loss)]
+fn per_second(amount: u64, elapsed: Duration) -> f64 {
+    amount as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Throughput of going through `bytes` bytes in `elapsed`, like `120.5 MiB/s`
+fn throughput(bytes: u64, elapsed: Duration) -> String {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let rate = per_second(bytes, elapsed) as u64;
+    format!("{}/s", HumanBytes(rate))
+}
+
+/// Hash every file in `sample` with `config`, returning how long it took
+fn time_hashing(sample: &[Utf8PathBuf], config: &Config) -> Result<Duration> {
+    let now = Instant::now();
+    for (p, h) in sample.iter().zip(hash_files(sample, config, &Silent)) {
+        h.wrap_err_with(|| format!("Failed hashing {p}"))?;
+    }
+    Ok(now.elapsed())
+}
+
+/// Insert `count` made up files into a scratch database next to the index, committing them in
+/// batches of `config.batch_size` like init does, returning how long it took
+fn time_inserts(data_path: &Utf8Path, config: &Config, count: usize) -> Result<Duration> {
+    let config = Config {
+        db_path: Some(Utf8PathBuf::from(".cstfs/bench.db")),
+        ..config.clone()
+    };
+    let paths = db::paths(data_path, &config);
+    for p in &paths {
+        remove_file(p).wrap_err("Failed removing scratch database")?;
+    }
+    let mut conn = db::open(data_path, &config).wrap_err("Failed to open scratch database")?;
+
+    let now = Instant::now();
+    let mut inserted = 0;
+    while inserted < count {
+        let batch = config.batch_size.get().min(count - inserted);
+        let transaction = conn
+            .transaction()
+            .wrap_err("Failed creating insert transaction")?;
+        for i in inserted..inserted + batch {
+            let path = Utf8PathBuf::from(format!("bench/{i}.jpg"));
+            db::insert_into(&transaction, &path, &format!("{i:016x}"))
+                .wrap_err("Failed inserting file")?;
+        }
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+        inserted += batch;
+    }
+    let elapsed = now.elapsed();
+
+    drop(conn);
+    for p in &paths {
+        remove_file(p).wrap_err("Failed removing scratch database")?;
+    }
+    Ok(elapsed)
+}
+
+/// Measure how fast the store at `data_path` can be walked, read, hashed and indexed, printing the
+/// results.
+///
+/// Hashing is measured with every algorithm and with different amounts of jobs, to help choosing
+/// `hash`, `jobs` and `batch-size`. The files hashed are the first ones found adding up to `sample` bytes. They are read once before
+/// hashing them, so unless they do not fit in memory the hashing is measured without the disk. The
+/// index is left untouched, `inserts` made up files are inserted into a scratch database instead.
+pub fn bench(data_path: &Utf8Path, config: &Config, sample: u64, inserts: usize) -> Result<()> {
+    info!("Benchmarking \"{data_path}\"");
+
+    let now = Instant::now();
+    let mut files = 0;
+    let mut sampled = vec![];
+    let mut sampled_bytes = 0;
+    for p in walk(data_path, config).wrap_err("Failed reading data directory contents")? {
+        let p = p?;
+        if p.file_name().is_some_and(db::is_db_file) {
+            continue;
+        }
+        files += 1;
+        if sampled_bytes < sample {
+            sampled_bytes += p.metadata().map_or(0, |m| m.len());
+            sampled.push(p);
+        }
+    }
+    let elapsed = now.elapsed();
+    println!(
+        "Walk: {files} files in {elapsed:.2?}, {:.0} files/s",
+        per_second(files, elapsed)
+    );
+    if sampled.is_empty() {
+        println!("No files to hash, add some to the data directory first");
+        return Ok(());
+    }
+
+    let now = Instant::now();
+    for p in &sampled {
+        let mut file = File::open(p).wrap_err_with(|| format!("Failed opening {p}"))?;
+        std::io::copy(&mut file, &mut std::io::sink())
+            .wrap_err_with(|| format!("Failed reading {p}"))?;
+    }
+    let elapsed = now.elapsed();
+    println!(
+        "Read: {} files, {} in {elapsed:.2?}, {} with one thread",
+        sampled.len(),
+        HumanBytes(sampled_bytes),
+        throughput(sampled_bytes, elapsed)
+    );
+
+    // Measuring the hashing alone, without the cache or the throttle
+    let config = Config {
+        xattr_cache: false,
+        throttle: None,
+        ..config.clone()
+    };
+    println!("Hashing by algorithm, with jobs = {}:", config.jobs());
+    for hash in HashAlgorithm::ALL {
+        let elapsed = time_hashing(
+            &sampled,
+            &Config {
+                hash,
+                ..config.clone()
+            },
+        )?;
+        println!(
+            "  {:<10} {}",
+            hash.name(),
+            throughput(sampled_bytes, elapsed)
+        );
+    }
+
+    println!("Hashing by jobs, with hash = {}:", config.hash.name());
+    let max_jobs = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .max(config.jobs());
+    let mut jobs = 1;
+    let mut fastest = (Duration::MAX, 1);
+    loop {
+        let elapsed = time_hashing(
+            &sampled,
+            &Config {
+                jobs: NonZeroUsize::new(jobs),
+                ..config.clone()
+            },
+        )?;
+        println!("  {jobs:<10} {}", throughput(sampled_bytes, elapsed));
+        fastest = fastest.min((elapsed, jobs));
+        if jobs == max_jobs {
+            break;
+        }
+        jobs = (jobs * 2).min(max_jobs);
+    }
+    println!("  Fastest with jobs = {}", fastest.1);
+
+    let elapsed = time_inserts(data_path, &config, inserts)?;
+    println!(
+        "Insert: {inserts} files in batches of {} in {elapsed:.2?}, {:.0} files/s",
+        config.batch_size,
+        per_second(inserts as u64, elapsed)
+    );
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+
+use crate::config::Config;
+use crate::db::{self, IndexedFile};
+use crate::info::resolve;
+use crate::utils::full_path;
+
+/// Full path of `file` if it is still there, or else of any other indexed copy of it, so a file
+/// can be found by its hash wherever it was moved to since being indexed
+pub(crate) fn locate(
+    conn: &rusqlite::Connection,
+    data_path: &Utf8Path,
+    file: &IndexedFile,
+) -> Result<Utf8PathBuf> {
+    let copies = db::files_with_hash(conn, &file.hash).wrap_err("Failed fetching copies")?;
+    let Some(full_path) = std::iter::once(file)
+        .chain(&copies)
+        .map(|f| full_path(data_path, Utf8Path::new(&f.path)))
+        .find(|p| p.is_file())
+    else {
+        bail!(
+            "No copy of {} is left, run `cstfs refresh` to find out where it went",
+            file.hash
+        );
+    };
+    Ok(full_path)
+}
+
+/// Write the contents of the indexed file `file`, given by its path or the start of its hash, to
+/// stdout. When the file is no longer at its indexed path, any other indexed copy of it is written
+/// instead.
+pub fn cat(data_path: &Utf8Path, config: &Config, file: &str) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let file = resolve(&conn, data_path, file)?;
+    let full_path = locate(&conn, data_path, &file)?;
+
+    let mut reader =
+        File::open(&full_path).wrap_err_with(|| format!("Failed opening \"{full_path}\""))?;
+    let mut stdout = std::io::stdout().lock();
+    match std::io::copy(&mut reader, &mut stdout).and_then(|_| stdout.flush()) {
+        // Whatever reads the output stopped early, like `head` does
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        res => res.wrap_err_with(|| format!("Failed writing \"{full_path}\" to stdout")),
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::num::{NonZeroU64, NonZeroUsize};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use serde::Deserialize;
+
+use crate::template;
+
+/// Name of the configuration file inside the data directory
+pub const FILE_NAME: &str = "cstfs.toml";
+/// Name of the registry of stores, in the configuration directory of the user
+pub const STORES_FILE_NAME: &str = "stores.toml";
+
+/// Algorithm used to hash the indexed files. Changing it on an existing store makes every file
+/// show up as changed on the next refresh.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    #[default]
+    Seahash,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [Self; 2] = [Self::Seahash, Self::Blake3];
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Seahash => "seahash",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    /// Check if `hash` looks like one made with this algorithm: lowercase hex of the right length
+    #[must_use]
+    pub fn is_valid_hash(self, hash: &str) -> bool {
+        let len = match self {
+            Self::Seahash => 16,
+            Self::Blake3 => 64,
+        };
+        hash.len() == len && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    }
+}
+
+/// How the kind of media of a file is found out
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Detection {
+    /// By its extension
+    #[default]
+    Extension,
+    /// By the magic bytes at the start of its contents, falling back to its extension when they
+    /// are not recognized
+    Content,
+}
+
+/// How files are read to hash them
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadMethod {
+    /// By mapping them into memory, falling back to reading them in chunks for the files that
+    /// cannot be mapped
+    #[default]
+    Mmap,
+    /// By reading them in chunks, for filesystems where mapping files misbehaves
+    Stream,
+}
+
+/// Kind of a media file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Audio,
+    Video,
+}
+
+/// What to do with a file that has the same contents as one already in the index
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicatePolicy {
+    /// Ask what to do with every duplicate
+    #[default]
+    Ask,
+    /// Remove the new file
+    RemoveNew,
+    /// Remove the file in the index and index the new one instead
+    RemoveOld,
+    /// Leave both files in place, without indexing the new one
+    Skip,
+}
+
+/// What to do with a copy of an indexed file, a new file with its contents while it is still in
+/// place
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyPolicy {
+    /// Deal with it like any other duplicate, as `on-duplicate` says
+    #[default]
+    Duplicate,
+    /// Leave it in place as an intentional copy, without asking. Only the original is indexed.
+    Keep,
+    /// Index it alongside the original as an intentional copy
+    Record,
+}
+
+/// What to do with an indexed file whose contents changed
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangePolicy {
+    /// Record its new hash in the index
+    #[default]
+    Update,
+    /// Leave the index as is and warn about it, since it may have been corrupted or overwritten,
+    /// so it keeps showing up as changed until it is looked into
+    Flag,
+    /// Ask whether to record its new hash for every changed file
+    Ask,
+}
+
+/// What to do with small files, like empty placeholders, which share their contents with many
+/// others and would otherwise be duplicates of each other
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmallFilePolicy {
+    /// Index them like any other file
+    #[default]
+    Index,
+    /// Do not index them at all
+    Ignore,
+    /// Index them, but leave their duplicates in place without asking, and without indexing them
+    Keep,
+}
+
+/// How images are previewed in the terminal when asking what to do with a duplicate
+#[derive(Debug, Clone, Copy, Default, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preview {
+    /// Not at all
+    #[default]
+    Off,
+    /// With the kitty or iTerm protocol, if the terminal is found out to support one of them
+    Auto,
+    /// With the kitty graphics protocol
+    Kitty,
+    /// With the iTerm inline images protocol, which some other terminals support too
+    Iterm,
+    /// As sixels, which have to be asked for since terminals supporting them cannot be told apart
+    Sixel,
+}
+
+/// Extensions of the files that are indexed, without the leading dot
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Extensions {
+    pub image: Vec<String>,
+    pub audio: Vec<String>,
+    pub video: Vec<String>,
+    /// Extensions of the sidecar files of photos, which are moved and removed together with the
+    /// photo they are named after instead of being indexed
+    pub sidecar: Vec<String>,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        let to_vec = |exts: &[&str]| exts.iter().map(ToString::to_string).collect();
+        Self {
+            image: to_vec(&[
+                "png", "jpg", "jpeg", "avif", "webp", "gif", "cr2", "nef", "arw", "dng", "raf",
+                "orf",
+            ]),
+            audio: to_vec(&["mp3", "opus", "flac"]),
+            video: to_vec(&["mkv", "mp4", "mov", "avi", "webm"]),
+            sidecar: to_vec(&["xmp", "pp3"]),
+        }
+    }
+}
+
+impl Extensions {
+    #[must_use]
+    pub fn is_image(&self, ext: &str) -> bool {
+        self.image.iter().any(|e| e == ext)
+    }
+
+    #[must_use]
+    pub fn is_audio(&self, ext: &str) -> bool {
+        self.audio.iter().any(|e| e == ext)
+    }
+
+    #[must_use]
+    pub fn is_video(&self, ext: &str) -> bool {
+        self.video.iter().any(|e| e == ext)
+    }
+
+    #[must_use]
+    pub fn is_sidecar(&self, ext: &str) -> bool {
+        self.sidecar.iter().any(|e| e == ext)
+    }
+
+    /// Kind of media files with extension `ext` are, if any
+    #[must_use]
+    pub fn kind(&self, ext: &str) -> Option<MediaKind> {
+        if self.is_image(ext) {
+            Some(MediaKind::Image)
+        } else if self.is_audio(ext) {
+            Some(MediaKind::Audio)
+        } else if self.is_video(ext) {
+            Some(MediaKind::Video)
+        } else {
+            None
+        }
+    }
+}
+
+/// Shell commands run in the data directory when a refresh changes the index.
+///
+/// Each is run once per refresh, with the kind and amount of changes in `CSTFS_*` environment
+/// variables and every change as a line of JSON on its stdin.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    /// Run with the new files
+    pub on_new: Option<String>,
+    /// Run with the files that are no longer in the data directory
+    pub on_removed: Option<String>,
+    /// Run with the duplicates and copies of other files
+    pub on_duplicate: Option<String>,
+    /// Run after every refresh, with the amount of changes of each kind it found
+    pub post_refresh: Option<String>,
+    /// Seconds a hook may run for before it is killed, so one that hangs does not hold up the
+    /// refresh
+    pub timeout_secs: u64,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_new: None,
+            on_removed: None,
+            on_duplicate: None,
+            post_refresh: None,
+            timeout_secs: 60,
+        }
+    }
+}
+
+/// Where the changes found by a refresh are sent, when it finds any
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Notify {
+    /// Url the changes are sent to as JSON in a POST request, which needs cstfs built with the
+    /// `webhook` feature. Only the first thousand changes are listed, the counts cover all of them.
+    pub webhook: Option<String>,
+    /// Whether a desktop notification is shown with the amount of changes of each kind
+    pub desktop: bool,
+}
+
+/// Settings of a store, read from `cstfs.toml` in its data directory, every one of them optional
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Config {
+    pub hash: HashAlgorithm,
+    pub read: ReadMethod,
+    /// Whether the hash of every file is cached in its `user.cstfs.hash` extended attribute, and
+    /// reused until the file is modified. The cache survives losing the database and is shared by
+    /// every store the file is in, and it spares hashing the files of renamed directories again. Windows
+    /// has no extended attributes, so there it does nothing.
+    pub xattr_cache: bool,
+    /// Amount of files hashed in parallel, all the available cores by default
+    pub jobs: Option<NonZeroUsize>,
+    /// Amount of directories read in parallel when walking the data directory, which is faster on
+    /// network shares and spinning disks, where reading a directory mostly waits on the disk
+    pub walk_jobs: NonZeroUsize,
+    /// Amount of files `init` indexes between commits, which is as many as an interrupted init
+    /// loses
+    pub batch_size: NonZeroUsize,
+    /// Megabytes per second files are read at when hashing them, between every hashing thread, to
+    /// keep hashing from taking up the whole disk. Without limit by default.
+    pub throttle: Option<NonZeroU64>,
+    /// How many times reading a file is tried again after an error that may go away, like the
+    /// timeouts of network filesystems, waiting twice as long every time
+    pub retries: u32,
+    /// Percentage of the size of every file its parity data takes, which is how much of it can be
+    /// repaired, see `cstfs parity generate`
+    pub parity_redundancy: u8,
+    /// How many copies of the database are kept in `.cstfs/backups`, the oldest ones being removed
+    /// as new ones are made. The database is copied there before every operation that removes files
+    /// or entries from the index, unless this is 0.
+    pub backups: usize,
+    /// How many files a refresh must remove from the index for the database to be backed up
+    /// before, as that many may be gone because a disk is not mounted
+    pub backup_removals: usize,
+    /// Whether cstfs runs with the lowest CPU and I/O priority, to not slow down the rest of the
+    /// machine while it hashes
+    pub nice: bool,
+    /// Globs of the paths, relative to the data directory, that are not indexed
+    pub ignore: Vec<String>,
+    /// Globs of the names of the files and directories that are not indexed, at any depth
+    pub exclude: Vec<String>,
+    /// How many directories deep below the data directory files are indexed, without limit by
+    /// default
+    pub max_depth: Option<usize>,
+    /// Whether hidden files and directories, whose names start with a dot or that have the hidden
+    /// attribute on Windows, are indexed
+    pub include_hidden: bool,
+    pub extensions: Extensions,
+    pub detect: Detection,
+    /// Whether symlinks pointing outside of the data directory are followed and indexed like
+    /// regular files and directories, or skipped
+    pub follow_symlinks: bool,
+    /// Whether symlinks to files inside of the data directory are indexed under their own path as
+    /// copies of the file they point to, or skipped as that file is indexed already
+    pub index_symlinks: bool,
+    /// Whether names only differing in case are different files, as on most Linux filesystems, or
+    /// the same one, as on exFAT, NTFS and APFS. Found out by trying it in the data directory when
+    /// not set.
+    pub case_sensitive: Option<bool>,
+    /// Whether every file is indexed, instead of only the ones with a media extension
+    pub all_files: bool,
+    pub on_duplicate: DuplicatePolicy,
+    pub on_copy: CopyPolicy,
+    pub on_change: ChangePolicy,
+    pub small_files: SmallFilePolicy,
+    /// Size in bytes up to which files are small, as in `small-files`. Only empty files by
+    /// default.
+    pub small_file_size: u64,
+    /// Directories, relative to the data directory, whose files are kept over their duplicates
+    /// elsewhere, the ones listed first over the ones after them. Refresh removes the other file
+    /// without asking, and `dedupe --keep` only picks among the files in the first of them.
+    pub preferred_dirs: Vec<Utf8PathBuf>,
+    pub preview: Preview,
+    /// Whether hashes are printed in full, instead of abbreviated to the shortest prefix telling
+    /// the indexed ones apart
+    pub full_hash: bool,
+    /// Command files are opened with, followed by their path, by `cstfs open` and to look at
+    /// duplicates before deciding what to do. The platform opener by default, as in
+    /// [`Config::viewer`].
+    pub viewer: Option<String>,
+    /// Whether removed files are moved to the trash, or deleted right away
+    pub use_trash: bool,
+    /// Template of the paths files are ingested or organized to, as described in [`crate::template`]
+    pub destination: String,
+    pub hooks: Hooks,
+    pub notify: Notify,
+    /// Where the database is kept instead of `cstfs.db` in the data directory, relative to it, for
+    /// data directories that cannot be written to. It may start with `~` for the home directory.
+    /// The lock and the backups are kept next to it.
+    pub db_path: Option<Utf8PathBuf>,
+    /// File holding the key the database is encrypted with, relative to the data directory. Only
+    /// with the `sqlcipher` feature.
+    pub keyfile: Option<Utf8PathBuf>,
+    /// Secret key made by `cstfs key generate` that dumps and exported manifests are signed with,
+    /// relative to the data directory. Only with the `sign` feature.
+    pub signing_key: Option<Utf8PathBuf>,
+    /// Key the database is encrypted with, taking precedence over `keyfile`. Never read from
+    /// cstfs.toml, which is usually kept next to the database, only given with `--passphrase`.
+    #[serde(skip)]
+    pub passphrase: Option<String>,
+    /// Whether the store is only read, with the database opened read-only and every command
+    /// changing the store refused. Always the case when the database cannot be written to.
+    pub read_only: bool,
+    /// Whether refresh skips reading the directories whose modification time did not change since
+    /// the last refresh, taking the files indexed in them to still be there. Files modified in
+    /// place do not change the time of their directory, so they are only found by a full refresh,
+    /// made every `full-refresh-days` or with `refresh --full`. Only for filesystems that keep the
+    /// times of directories up to date, which some network shares do not.
+    pub incremental: bool,
+    /// How many days after the last full refresh an incremental one reads every directory again
+    pub full_refresh_days: u32,
+    /// Whether init and refresh carry on past the files and directories they cannot read, failing
+    /// with the list of them once done, or stop at the first one
+    pub keep_going: bool,
+}
+
+#[must_use]
+pub fn path(data_path: &Utf8Path) -> Utf8PathBuf {
+    data_path.join(FILE_NAME)
+}
+
+/// Read the configuration of the store at `data_path`, using the defaults if it has none
+pub fn load(data_path: &Utf8Path) -> Result<Config> {
+    let path = path(data_path);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Failed reading \"{path}\"")),
+    };
+    toml::from_str(&contents).wrap_err_with(|| format!("Invalid configuration in \"{path}\""))
+}
+
+/// Path of the registry of stores, `cstfs/stores.toml` in the configuration directory of the user:
+/// `$XDG_CONFIG_HOME` or `~/.config`, or `%APPDATA%` on Windows
+pub fn stores_path() -> Result<Utf8PathBuf> {
+    let var = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let dir = if cfg!(windows) {
+        var("APPDATA")
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|home| format!("{home}/.config")))
+    };
+    let dir = dir.ok_or_else(|| eyre!("Could not find the configuration directory of the user"))?;
+    Ok(Utf8PathBuf::from(dir).join("cstfs").join(STORES_FILE_NAME))
+}
+
+/// Path of the registry of stores and the stores in it, by name
+fn registry() -> Result<(Utf8PathBuf, BTreeMap<String, String>)> {
+    let path = stores_path()?;
+    let contents = std::fs::read_to_string(&path)
+        .wrap_err_with(|| format!("Failed reading the registry of stores \"{path}\""))?;
+    let stores = toml::from_str(&contents)
+        .wrap_err_with(|| format!("Invalid registry of stores in \"{path}\""))?;
+    Ok((path, stores))
+}
+
+/// Names of the stores in the registry at [`stores_path`], sorted
+pub fn stores() -> Result<Vec<String>> {
+    Ok(registry()?.1.into_keys().collect())
+}
+
+/// Data directory of the store registered as `name` in the registry at [`stores_path`], which maps
+/// names to paths like `photos = "/mnt/nas/photos"`. Paths may start with `~` for the home
+/// directory.
+pub fn store(name: &str) -> Result<Utf8PathBuf> {
+    let (path, mut stores) = registry()?;
+    let dir = stores.remove(name).ok_or_else(|| {
+        let names: Vec<_> = stores.keys().map(String::as_str).collect();
+        eyre!(
+            "No store is named \"{name}\" in \"{path}\", it has {}",
+            if names.is_empty() {
+                "none".to_owned()
+            } else {
+                names.join(", ")
+            }
+        )
+    })?;
+    Ok(expand_home(&dir))
+}
+
+/// `path` with a leading `~` replaced by the home directory of the user
+#[must_use]
+pub fn expand_home(path: &str) -> Utf8PathBuf {
+    match (path.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{home}{rest}").into()
+        }
+        _ => path.into(),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hash: HashAlgorithm::default(),
+            read: ReadMethod::default(),
+            xattr_cache: false,
+            jobs: None,
+            walk_jobs: NonZeroUsize::MIN,
+            batch_size: NonZeroUsize::new(1000).unwrap_or(NonZeroUsize::MIN),
+            throttle: None,
+            retries: 3,
+            parity_redundancy: 10,
+            backups: 5,
+            backup_removals: 100,
+            nice: false,
+            ignore: vec![],
+            exclude: vec![],
+            max_depth: None,
+            include_hidden: true,
+            extensions: Extensions::default(),
+            detect: Detection::default(),
+            follow_symlinks: false,
+            index_symlinks: false,
+            case_sensitive: None,
+            all_files: false,
+            on_duplicate: DuplicatePolicy::default(),
+            on_copy: CopyPolicy::default(),
+            on_change: ChangePolicy::default(),
+            small_files: SmallFilePolicy::default(),
+            small_file_size: 0,
+            preferred_dirs: vec![],
+            preview: Preview::default(),
+            full_hash: false,
+            viewer: None,
+            use_trash: true,
+            destination: template::DEFAULT.to_owned(),
+            hooks: Hooks::default(),
+            notify: Notify::default(),
+            db_path: None,
+            keyfile: None,
+            signing_key: None,
+            passphrase: None,
+            read_only: false,
+            incremental: false,
+            full_refresh_days: 7,
+            keep_going: true,
+        }
+    }
+}
+
+impl Config {
+    /// Position in `preferred_dirs` of the first directory `path`, relative to the data directory,
+    /// is in, or the amount of them if it is in none, so the lower the more preferred it is
+    #[must_use]
+    pub fn preference(&self, path: &Utf8Path) -> usize {
+        self.preferred_dirs
+            .iter()
+            .position(|d| path.starts_with(d))
+            .unwrap_or(self.preferred_dirs.len())
+    }
+
+    /// Amount of files to hash in parallel
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+
+    /// Command files are opened with, arguments separated by whitespace: `viewer` if set, or
+    /// `xdg-open`, `open` on macOS and `explorer` on Windows
+    #[must_use]
+    pub fn viewer(&self) -> String {
+        self.viewer.clone().unwrap_or_else(|| {
+            let default = if cfg!(target_os = "macos") {
+                "open"
+            } else if cfg!(windows) {
+                "explorer"
+            } else {
+                "xdg-open"
+            };
+            default.to_owned()
+        })
+    }
+}
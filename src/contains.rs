@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::{debug, info};
+
+use crate::config::Config;
+use crate::db;
+use crate::report::Reporter;
+use crate::utils::{hash_files, recursive_directory_read};
+
+/// Check which of the files in `dir`, a directory outside of the store, are already in its index.
+///
+/// Files are compared by hash, only hashing the ones with the same size as an indexed file. If `only_missing` is set, only the paths of the files that are not
+/// in the index are printed.
+pub fn contains(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    dir: &Utf8Path,
+    only_missing: bool,
+) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let indexed: HashMap<String, String> = db::files(&conn)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|(path, hash)| (hash, path))
+        .collect();
+
+    let sizes = db::sizes(&conn).wrap_err("Failed fetching sizes from db")?;
+    let unknown_sizes = sizes.is_none();
+    if unknown_sizes {
+        debug!("Some indexed files have no size recorded, refresh to record them");
+    }
+    let sizes = sizes.unwrap_or_default();
+
+    let files = recursive_directory_read(dir, config)
+        .wrap_err_with(|| format!("Failed reading contents of \"{dir}\""))?;
+    // Files with a size no indexed file has cannot be in the index, so they are not hashed
+    let mut candidates = vec![];
+    for p in &files {
+        let size = p
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata of {p}"))?
+            .len();
+        candidates.push(unknown_sizes || sizes.contains(&size));
+    }
+    let to_hash: Vec<_> = files
+        .iter()
+        .zip(&candidates)
+        .filter(|(_, c)| **c)
+        .map(|(p, _)| p.clone())
+        .collect();
+    debug!("Hashing {} of {} files", to_hash.len(), files.len());
+    let mut hashes = hash_files(&to_hash, config, reporter).into_iter();
+
+    let mut present = 0;
+    for (p, candidate) in files.iter().zip(candidates) {
+        let h = if candidate {
+            hashes.next().transpose()?
+        } else {
+            None
+        };
+        let p = p.strip_prefix(dir).unwrap_or(p);
+        match (h.and_then(|h| indexed.get(&h)), only_missing) {
+            (Some(_), true) => present += 1,
+            (Some(indexed_path), false) => {
+                println!("Present: {p} (as {indexed_path})");
+                present += 1;
+            }
+            (None, true) => println!("{p}"),
+            (None, false) => println!("Missing: {p}"),
+        }
+    }
+    info!(
+        "{present} of {} files in \"{dir}\" are already in the store",
+        files.len()
+    );
+
+    Ok(())
+}
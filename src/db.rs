@@ -1,6 +1,14 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use camino::{Utf8Path, Utf8PathBuf};
 use color_eyre::eyre::eyre;
-use rusqlite::{Connection, Transaction};
+use rusqlite::backup::Backup;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::{Connection, ErrorCode, OpenFlags, Transaction};
+
+use crate::config::{self, Config, HashAlgorithm};
+use crate::utils::{self, normalize};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -23,17 +31,23 @@ pub enum Error {
         path_old: Utf8PathBuf,
     },
 
+    #[error("\"{0}\" is already in database")]
+    PathExists(Utf8PathBuf),
+
+    #[error("files are hashed with {recorded} in database, but {configured} is configured, set `hash = \"{recorded}\"` in cstfs.toml or pass `--hash {recorded}`")]
+    HashMismatch {
+        recorded: &'static str,
+        configured: &'static str,
+    },
+
     #[error("fetch failure:\n{0}")]
     QueryFailure(rusqlite::Error),
 
     #[error("update failure:\n{0}")]
     UpdateFailure(rusqlite::Error),
 
-    #[error("duplicate paths found on query: {0:?}")]
-    DuplicatePaths(Vec<Utf8PathBuf>),
-
-    #[error("cannot update path of hash {0} which does not exist")]
-    HashDoesNotExist(String),
+    #[error("{path}:{hash} is not in the database")]
+    FileDoesNotExist { path: Utf8PathBuf, hash: String },
 
     #[error("query affected {count} rows, expected {min_rows}..{max_rows}: {msg}")]
     TooManyRowsAffected {
@@ -51,13 +65,349 @@ pub enum Error {
         msg: String,
     },
 
+    #[error("a snapshot named \"{0}\" already exists")]
+    SnapshotExists(String),
+
+    #[error("there is no snapshot named \"{0}\"")]
+    SnapshotDoesNotExist(String),
+
+    #[error("no indexed file has a hash starting with \"{0}\"")]
+    HashPrefixNotFound(String),
+
+    #[error("more than one indexed file has a hash starting with \"{0}\"")]
+    AmbiguousHashPrefix(String),
+
+    #[error("there is no saved search named \"{0}\"")]
+    SearchDoesNotExist(String),
+
+    #[error("database could not be read, it is encrypted and its key is missing or wrong (see --passphrase and --keyfile), or it is not a database")]
+    WrongKey,
+
+    #[error("database must be upgraded to be read by this version of cstfs, which cannot be done while it is read-only")]
+    ReadOnlyMigration,
+
+    #[error("database could not be backed up:\n{0}")]
+    Backup(rusqlite::Error),
+
     #[error("unknown db error:\n{0}")]
     Unknown(#[from] color_eyre::Report),
 }
 
-pub fn open(data_path: &Utf8Path) -> Result<Connection, Error> {
-    let db_path = path(data_path);
-    let conn = Connection::open(db_path).map_err(Error::Open)?;
+/// Schema changes applied on top of the `files` table, in order. The amount of migrations already
+/// applied to a database is stored in its `user_version`, so they must never be reordered or
+/// modified, only appended to.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE remote_objects (
+        remote TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (remote, hash)
+    )",
+    "
+    CREATE TABLE operations (
+        id INTEGER NOT NULL PRIMARY KEY,
+        command TEXT NOT NULL,
+        started_at INTEGER NOT NULL
+    );
+    CREATE TABLE journal (
+        id INTEGER NOT NULL PRIMARY KEY,
+        operation INTEGER NOT NULL REFERENCES operations(id),
+        action TEXT NOT NULL,
+        path TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        prev_path TEXT,
+        trash_name TEXT
+    );
+    CREATE INDEX journal_operation ON journal(operation)",
+    "
+    ALTER TABLE journal ADD COLUMN prev_hash TEXT;
+    CREATE TABLE history (
+        id INTEGER NOT NULL PRIMARY KEY,
+        started_at INTEGER NOT NULL,
+        duration_ms INTEGER NOT NULL
+    );
+    CREATE TABLE history_diffs (
+        history INTEGER NOT NULL REFERENCES history(id),
+        kind TEXT NOT NULL,
+        path TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        orig_path TEXT,
+        prev_hash TEXT
+    );
+    CREATE INDEX history_diffs_history ON history_diffs(history)",
+    "
+    CREATE TABLE snapshots (
+        id INTEGER NOT NULL PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE snapshot_files (
+        snapshot INTEGER NOT NULL REFERENCES snapshots(id),
+        path TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (snapshot, hash)
+    )",
+    "
+    ALTER TABLE files ADD COLUMN size INTEGER;
+    CREATE INDEX files_size ON files(size)",
+    "
+    ALTER TABLE files ADD COLUMN last_verified INTEGER;
+    CREATE INDEX files_last_verified ON files(last_verified)",
+    "
+    ALTER TABLE files ADD COLUMN device INTEGER;
+    ALTER TABLE files ADD COLUMN inode INTEGER;
+    ALTER TABLE files ADD COLUMN mtime INTEGER;
+    CREATE INDEX files_inode ON files(device, inode)",
+    // The hash is no longer the primary key, so copies of a file can be indexed alongside it
+    "
+    CREATE TABLE files_by_path (
+        path TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        size INTEGER,
+        last_verified INTEGER,
+        device INTEGER,
+        inode INTEGER,
+        mtime INTEGER
+    );
+    INSERT INTO files_by_path(rowid, path, hash, size, last_verified, device, inode, mtime)
+    SELECT rowid, path, hash, size, last_verified, device, inode, mtime FROM files;
+    DROP TABLE files;
+    ALTER TABLE files_by_path RENAME TO files;
+    CREATE INDEX files_path ON files(path);
+    CREATE INDEX files_hash ON files(hash);
+    CREATE INDEX files_size ON files(size);
+    CREATE INDEX files_last_verified ON files(last_verified);
+    CREATE INDEX files_inode ON files(device, inode);
+    CREATE TABLE snapshot_files_by_path (
+        snapshot INTEGER NOT NULL REFERENCES snapshots(id),
+        path TEXT NOT NULL,
+        hash TEXT NOT NULL
+    );
+    INSERT INTO snapshot_files_by_path SELECT snapshot, path, hash FROM snapshot_files;
+    DROP TABLE snapshot_files;
+    ALTER TABLE snapshot_files_by_path RENAME TO snapshot_files;
+    CREATE INDEX snapshot_files_snapshot ON snapshot_files(snapshot)",
+    "
+    CREATE TABLE searches (
+        name TEXT NOT NULL PRIMARY KEY,
+        query TEXT NOT NULL
+    )",
+    // Paths are compared in NFC, so the ones indexed before must be in it too
+    "
+    UPDATE files SET path = nfc(path);
+    UPDATE snapshot_files SET path = nfc(path);
+    UPDATE journal SET path = nfc(path), prev_path = nfc(prev_path);
+    UPDATE history_diffs SET path = nfc(path), orig_path = nfc(orig_path)",
+    "
+    CREATE TABLE parity (
+        hash TEXT NOT NULL PRIMARY KEY,
+        created_at INTEGER NOT NULL
+    )",
+    "
+    CREATE TABLE pins (
+        pattern TEXT NOT NULL PRIMARY KEY,
+        created_at INTEGER NOT NULL
+    )",
+    "
+    CREATE TABLE dirs (
+        path TEXT NOT NULL PRIMARY KEY,
+        mtime INTEGER NOT NULL
+    );
+    ALTER TABLE history ADD COLUMN full INTEGER NOT NULL DEFAULT 1",
+    // What is known about existing stores is taken from their journal and history
+    "
+    CREATE TABLE meta (
+        key TEXT NOT NULL PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    INSERT INTO meta(key, value)
+    SELECT 'created_at', MIN(started_at) FROM operations WHERE command = 'init' HAVING COUNT(*) > 0;
+    INSERT INTO meta(key, value)
+    SELECT 'last_refresh', MAX(started_at) FROM history HAVING COUNT(*) > 0",
+    // Files are keyed by path, which the migration keying them by it did not enforce. A path
+    // indexed more than once is the same file, only the first row of it is kept.
+    "
+    DELETE FROM files WHERE rowid NOT IN (SELECT MIN(rowid) FROM files GROUP BY path);
+    DROP INDEX files_path;
+    CREATE UNIQUE INDEX files_path ON files(path)",
+];
+
+/// Version of the schema this version of cstfs migrates databases to
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+/// Version of cstfs, recorded in the databases it changes
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A mutation recorded in the journal, so it can be undone
+#[derive(Debug)]
+pub enum JournalAction {
+    /// The file at `path` was added to the index
+    Insert { path: Utf8PathBuf, hash: String },
+    /// The path of the file with hash `hash` was changed from `prev_path` to `path`
+    UpdatePath {
+        path: Utf8PathBuf,
+        prev_path: Utf8PathBuf,
+        hash: String,
+    },
+    /// The file at `path` was moved into the trash, where it is named `trash_name`
+    Trash {
+        path: Utf8PathBuf,
+        hash: String,
+        trash_name: String,
+    },
+    /// The file at `path` was removed from the index
+    Remove { path: Utf8PathBuf, hash: String },
+    /// The file at `path` was deleted from the disk for good, without going through the trash
+    Delete { path: Utf8PathBuf, hash: String },
+    /// The hash of the file at `path` was changed from `prev_hash` to `hash`
+    UpdateHash {
+        path: Utf8PathBuf,
+        hash: String,
+        prev_hash: String,
+    },
+    /// The file with hash `hash` was moved on disk from `prev_path` to `path`, and its path in the
+    /// index changed with it
+    Rename {
+        path: Utf8PathBuf,
+        prev_path: Utf8PathBuf,
+        hash: String,
+    },
+}
+
+/// What is recorded about a store in its `meta` table, each of them unknown for stores made before
+/// it was
+#[derive(Debug, Default)]
+pub struct Meta {
+    /// When the index was made, a unix timestamp
+    pub created_at: Option<i64>,
+    /// Name of the algorithm the files were hashed with by the last init or full refresh
+    pub hash: Option<String>,
+    /// Version of cstfs that last changed the index
+    pub version: Option<String>,
+    /// Last command that changed the index
+    pub last_operation: Option<String>,
+    /// When the last command that changed the index started, a unix timestamp
+    pub last_operation_at: Option<i64>,
+    /// When the last refresh started, a unix timestamp
+    pub last_refresh: Option<i64>,
+}
+
+/// Size of a file and what identifies it on disk, to find it again after it is moved without
+/// hashing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,
+    /// Device the file is on, on platforms that have one, with its bits stored as a signed integer
+    /// like sqlite does
+    pub device: Option<i64>,
+    /// Inode of the file, on platforms that have one, stored like `device`
+    pub inode: Option<i64>,
+    /// Modification time of the file, in nanoseconds since the unix epoch
+    pub mtime: Option<i64>,
+}
+
+/// A file in the index, with what the last refresh recorded about it
+#[derive(Debug)]
+pub struct IndexedFile {
+    pub path: String,
+    pub hash: String,
+    pub size: Option<u64>,
+    /// Modification time, in nanoseconds since the unix epoch
+    pub mtime: Option<i64>,
+    /// When it was last verified, as a unix timestamp, if it ever was
+    pub last_verified: Option<i64>,
+}
+
+impl IndexedFile {
+    /// Columns of the `files` table read by [`IndexedFile::from_row`], in order
+    const COLUMNS: &'static str = "path, hash, size, mtime, last_verified";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            path: row.get(0)?,
+            hash: row.get(1)?,
+            size: row.get(2)?,
+            mtime: row.get(3)?,
+            last_verified: row.get(4)?,
+        })
+    }
+}
+
+/// A change found by a refresh, as recorded in its history
+#[derive(Debug)]
+pub struct HistoryDiff {
+    /// What kind of change this is: new, duplicate, copied, changed, moved or removed
+    pub kind: String,
+    pub path: Utf8PathBuf,
+    pub hash: String,
+    /// Path the file was at before, for moves, or the file it has the contents of, for duplicates
+    /// and copies
+    pub orig_path: Option<Utf8PathBuf>,
+    /// Hash the file had before, for changes
+    pub prev_hash: Option<String>,
+}
+
+/// Name of the database file inside the data directory
+const FILE_NAME: &str = "cstfs.db";
+
+/// How long to wait for another cstfs process to release a lock on the database before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pages copied at a time when backing up the database, pausing between them to let other
+/// processes write to it
+const BACKUP_PAGES: i32 = 1000;
+
+/// How long backing up the database pauses between copying pages
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+/// Open the database of the store at `data_path`, at the path `config` gives for it, creating it
+/// and migrating it to the latest schema if needed
+pub fn open(data_path: &Utf8Path, config: &Config) -> Result<Connection, Error> {
+    let db_path = path(data_path, config);
+    let read_only = read_only(data_path, config);
+    if config.db_path.is_some() && !read_only {
+        if let Some(parent) = db_path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                Error::Unknown(eyre!("Failed creating directory \"{parent}\": {e}"))
+            })?;
+        }
+    }
+    let mut conn = if read_only {
+        open_read_only(&db_path)?
+    } else {
+        Connection::open(db_path).map_err(Error::Open)?
+    };
+    if let Some(key) = key(data_path, config)? {
+        unlock(&conn, &key)?;
+    }
+
+    // WAL lets readers run while a refresh writes, and with it NORMAL sync is still safe from
+    // corruption, only possibly losing the last transactions on a power loss
+    conn.busy_timeout(BUSY_TIMEOUT).map_err(Error::Open)?;
+    conn.execute_batch(if read_only {
+        "PRAGMA foreign_keys = ON"
+    } else {
+        "
+        PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = NORMAL;
+        PRAGMA foreign_keys = ON"
+    })
+    .map_err(|e| match e.sqlite_error_code() {
+        // Encrypted databases look like garbage without their key
+        Some(ErrorCode::NotADatabase) => Error::WrongKey,
+        _ => Error::Open(e),
+    })?;
+    add_functions(&conn).map_err(Error::Open)?;
+
+    if read_only {
+        let version: usize = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(Error::Open)?;
+        if version < MIGRATIONS.len() {
+            return Err(Error::ReadOnlyMigration);
+        }
+        return Ok(conn);
+    }
 
     conn.execute(
         "
@@ -69,40 +419,275 @@ pub fn open(data_path: &Utf8Path) -> Result<Connection, Error> {
     )
     .map_err(Error::Migration)?;
 
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(Error::Migration)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = conn.transaction().map_err(Error::Migration)?;
+        transaction
+            .execute_batch(migration)
+            .map_err(Error::Migration)?;
+        transaction
+            .pragma_update(None, "user_version", i + 1)
+            .map_err(Error::Migration)?;
+        transaction.commit().map_err(Error::Migration)?;
+    }
+
     Ok(conn)
 }
 
-pub fn path(data_path: &Utf8Path) -> Utf8PathBuf {
-    data_path.join("cstfs.db")
+/// Add the functions of cstfs the migrations use to `conn`: `nfc(path)` normalizes a path as
+/// [`normalize`] does
+fn add_functions(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "nfc",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let path: Option<String> = ctx.get(0)?;
+            Ok(path.map(|p| normalize(Utf8Path::new(&p)).into_string()))
+        },
+    )
+}
+
+/// Path of the database of the store at `data_path`, which is inside of it unless `config` says
+/// otherwise
+#[must_use]
+pub fn path(data_path: &Utf8Path, config: &Config) -> Utf8PathBuf {
+    config.db_path.as_ref().map_or_else(
+        || data_path.join(FILE_NAME),
+        |p| data_path.join(config::expand_home(p.as_str())),
+    )
+}
+
+/// Check if the store is only read, because `config` says so or because the directory its database
+/// is in cannot be written to, like on archival media
+#[must_use]
+pub fn read_only(data_path: &Utf8Path, config: &Config) -> bool {
+    if config.read_only {
+        return true;
+    }
+    let db_path = path(data_path, config);
+    db_path
+        .parent()
+        .filter(|d| !d.as_str().is_empty() && d.is_dir())
+        .is_some_and(|d| !utils::is_writable(d))
+}
+
+/// Open the database at `db_path` without ever writing to it. When the directory it is in cannot be
+/// written to either, sqlite is told the database cannot change, as otherwise it would need to
+/// make the files it keeps next to it to read it.
+fn open_read_only(db_path: &Utf8Path) -> Result<Connection, Error> {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let dir = db_path
+        .parent()
+        .filter(|d| !d.as_str().is_empty())
+        .unwrap_or_else(|| Utf8Path::new("."));
+    if utils::is_writable(dir) {
+        return Connection::open_with_flags(db_path, flags).map_err(Error::Open);
+    }
+    let mut path = db_path
+        .as_str()
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    if cfg!(windows) {
+        path = path.replace('\\', "/");
+        if db_path.is_absolute() {
+            path.insert(0, '/');
+        }
+    }
+    Connection::open_with_flags(
+        format!("file:{path}?immutable=1"),
+        flags | OpenFlags::SQLITE_OPEN_URI,
+    )
+    .map_err(Error::Open)
+}
+
+/// Key the database is encrypted with, the passphrase given or else the contents of the keyfile
+fn key(data_path: &Utf8Path, config: &Config) -> Result<Option<String>, Error> {
+    if let Some(passphrase) = &config.passphrase {
+        return Ok(Some(passphrase.clone()));
+    }
+    let Some(keyfile) = &config.keyfile else {
+        return Ok(None);
+    };
+    let keyfile = data_path.join(keyfile);
+    let key = std::fs::read_to_string(&keyfile)
+        .map_err(|e| Error::Unknown(eyre!("Failed reading keyfile \"{keyfile}\": {e}")))?;
+    Ok(Some(key.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+/// Unlock the encrypted database with `key` to read and write it, which has to be done before
+/// anything else is done with it
+#[cfg(feature = "sqlcipher")]
+fn unlock(conn: &Connection, key: &str) -> Result<(), Error> {
+    conn.pragma_update(None, "key", key).map_err(Error::Open)?;
+    // A wrong key is only noticed once the database is read
+    conn.query_row("SELECT count(*) FROM sqlite_master", (), |_| Ok(()))
+        .map_err(|_| Error::WrongKey)
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn unlock(_: &Connection, _: &str) -> Result<(), Error> {
+    Err(Error::Unknown(eyre!(
+        "A database key was given, but cstfs was built without the `sqlcipher` feature to use it"
+    )))
+}
+
+/// Copy the database into a new database at `dest` with the backup API of sqlite, which makes a
+/// consistent copy even while other processes write to it.
+///
+/// The copy is encrypted with the same key as the database, and is returned open to check it.
+pub fn backup(data_path: &Utf8Path, config: &Config, dest: &Utf8Path) -> Result<Connection, Error> {
+    let conn = open(data_path, config)?;
+    let mut copy = Connection::open(dest).map_err(Error::Open)?;
+    if let Some(key) = key(data_path, config)? {
+        unlock(&copy, &key)?;
+    }
+    Backup::new(&conn, &mut copy)
+        .and_then(|b| b.run_to_completion(BACKUP_PAGES, BACKUP_PAUSE, None))
+        .map_err(Error::Backup)?;
+    Ok(copy)
+}
+
+/// Paths of the database and of the files sqlite keeps next to it while it is open
+#[must_use]
+pub fn paths(data_path: &Utf8Path, config: &Config) -> [Utf8PathBuf; 3] {
+    let path = path(data_path, config);
+    let wal = Utf8PathBuf::from(format!("{path}-wal"));
+    let shm = Utf8PathBuf::from(format!("{path}-shm"));
+    [path, wal, shm]
+}
+
+/// Check if `file_name` is the name of the database or of one of the files sqlite keeps next to it
+#[must_use]
+pub fn is_db_file(file_name: &str) -> bool {
+    file_name
+        .strip_prefix(FILE_NAME)
+        .is_some_and(|suffix| matches!(suffix, "" | "-wal" | "-shm" | "-journal"))
+}
+
+/// Fetch the path and hash of every file in the index
+pub fn files(conn: &Connection) -> Result<Vec<(String, String)>, Error> {
+    let mut query = conn
+        .prepare("SELECT path, hash FROM files")
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch every hash in the index once, sorted
+pub fn hashes(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut query = conn
+        .prepare("SELECT DISTINCT hash FROM files ORDER BY hash")
+        .map_err(Error::QueryFailure)?;
+    let hashes = query
+        .query_map([], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(hashes)
+}
+
+/// Fetch the version of the schema of the database, which is newer than [`SCHEMA_VERSION`] if it
+/// was last opened by a newer version of cstfs
+pub fn schema_version(conn: &Connection) -> Result<usize, Error> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(Error::QueryFailure)
+}
+
+/// Fetch the path and hash of every file in the index whose path is shared with another file,
+/// ordered by path
+pub fn duplicate_paths(conn: &Connection) -> Result<Vec<(String, String)>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT path, hash FROM files
+             WHERE path IN (SELECT path FROM files GROUP BY path HAVING COUNT(*) > 1)
+             ORDER BY path, hash",
+        )
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch the hash of the file at `path`, if it is in the index
+pub fn hash(conn: &Connection, path: &Utf8Path) -> Result<Option<String>, Error> {
+    let res = conn.query_row(
+        "SELECT hash FROM files WHERE path = ?1",
+        [path.as_str()],
+        |row| row.get(0),
+    );
+    match res {
+        Ok(hash) => Ok(Some(hash)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::QueryFailure(e)),
+    }
 }
 
+/// Add the file at `path` with hash `hash` to the index, failing with
+/// [`Error::DuplicateInsertion`] if a file with the same hash is already in it, or with
+/// [`Error::PathExists`] if `path` is.
+///
+/// The statements are cached in the connection, so inserting many files in a transaction does not
+/// prepare them again for each one.
 pub fn insert_into(
     transaction: &Transaction<'_>,
     path: &Utf8Path,
     hash: &str,
 ) -> Result<(), Error> {
-    let select_result: Result<String, rusqlite::Error> = transaction.query_row(
-        "SELECT path FROM files as f WHERE f.hash = ?1",
-        [hash],
-        |row| row.get(0),
-    );
-
-    match select_result {
-        Ok(path_old) => {
-            return Err(Error::DuplicateInsertion {
-                path_old: Utf8PathBuf::from(path_old),
-                path_new: path.to_path_buf(),
-            })
-        }
-        Err(rusqlite::Error::QueryReturnedNoRows) => {}
-        Err(e) => return Err(Error::QueryFailure(e)),
+    let rows = transaction
+        .prepare_cached(
+            "INSERT INTO files(path, hash)
+             SELECT ?1, ?2 WHERE NOT EXISTS (SELECT 1 FROM files WHERE hash = ?2)
+             ON CONFLICT(path) DO NOTHING",
+        )
+        .and_then(|mut insert| insert.execute([path.as_str(), hash]))
+        .map_err(|e| Error::InsertionFailure {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+            source: e,
+        })?;
+    if rows == 1 {
+        return Ok(());
     }
+    // Nothing was inserted, which is only looked into then
+    let path_old: Option<String> = transaction
+        .prepare_cached("SELECT path FROM files WHERE hash = ?1 LIMIT 1")
+        .and_then(|mut query| query.query_row([hash], |row| row.get(0)))
+        .map(Some)
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        })
+        .map_err(Error::QueryFailure)?;
+    Err(path_old.map_or_else(
+        || Error::PathExists(path.to_path_buf()),
+        |path_old| Error::DuplicateInsertion {
+            path_old: Utf8PathBuf::from(path_old),
+            path_new: path.to_path_buf(),
+        },
+    ))
+}
 
+/// Add the file at `path` with hash `hash` to the index, even if other files with the same hash
+/// are in it already, as a copy of them
+pub fn insert_copy(
+    transaction: &Transaction<'_>,
+    path: &Utf8Path,
+    hash: &str,
+) -> Result<(), Error> {
     let rows = transaction
-        .execute(
-            "INSERT INTO files(path, hash) VALUES (?1, ?2)",
-            [path.as_str(), hash],
-        )
+        .prepare_cached("INSERT INTO files(path, hash) VALUES (?1, ?2)")
+        .and_then(|mut insert| insert.execute([path.as_str(), hash]))
         .map_err(|e| Error::InsertionFailure {
             path: path.to_path_buf(),
             hash: hash.to_owned(),
@@ -117,57 +702,1160 @@ pub fn insert_into(
     Ok(())
 }
 
+/// Add `file` to the index with everything known about it, as [`listing`] returns it, even if other
+/// files with the same hash are in it already
+pub fn restore_file(transaction: &Transaction<'_>, file: &IndexedFile) -> Result<(), Error> {
+    transaction
+        .prepare_cached(
+            "INSERT INTO files(path, hash, size, mtime, last_verified) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .and_then(|mut insert| {
+            insert.execute((
+                &file.path,
+                &file.hash,
+                file.size,
+                file.mtime,
+                file.last_verified,
+            ))
+        })
+        .map_err(|e| Error::InsertionFailure {
+            path: Utf8PathBuf::from(&file.path),
+            hash: file.hash.clone(),
+            source: e,
+        })?;
+    Ok(())
+}
+
+/// Change the path of the file at `prev_path` with hash `hash` to `path`
 pub fn update_path(
     transaction: &Transaction<'_>,
+    prev_path: &Utf8Path,
     path: &Utf8Path,
     hash: &str,
 ) -> Result<(), Error> {
-    let mut query = transaction
-        .prepare("SELECT path FROM files as f where f.hash = ?1")
-        .map_err(Error::QueryFailure)?;
-    let matched_paths: Vec<String> = query
-        .query_map([hash], |row| row.get(0))
-        .map_err(Error::QueryFailure)?
-        .collect::<Result<_, _>>()
-        .map_err(Error::QueryFailure)?;
-    match matched_paths.len() {
-        0 => return Err(Error::HashDoesNotExist(hash.to_owned())),
-        1 => {}
-        2.. => {
-            return Err(Error::DuplicatePaths(
-                matched_paths.iter().map(Utf8PathBuf::from).collect(),
-            ))
-        }
-    };
-
     let rows = transaction
         .execute(
             "UPDATE files
              SET path = ?1
-             WHERE hash = ?2",
-            [path.as_str(), hash],
+             WHERE path = ?2 AND hash = ?3",
+            [path.as_str(), prev_path.as_str(), hash],
         )
         .map_err(Error::UpdateFailure)?;
 
     match rows {
-        0 => {
-            return Err(Error::TooFewRowsAffected {
-                count: rows,
-                min_rows: 1,
-                max_rows: 1,
-                msg: "updating a hash path should update a single row".to_owned(),
-            })
-        }
-        1 => {}
-        2.. => {
-            return Err(Error::TooManyRowsAffected {
-                count: rows,
-                min_rows: 1,
-                max_rows: 1,
-                msg: "updating a hash path should update a single row".to_owned(),
+        0 => Err(Error::FileDoesNotExist {
+            path: prev_path.to_path_buf(),
+            hash: hash.to_owned(),
+        }),
+        1 => Ok(()),
+        2.. => Err(Error::TooManyRowsAffected {
+            count: rows,
+            min_rows: 1,
+            max_rows: 1,
+            msg: "updating the path of a file should update a single row".to_owned(),
+        }),
+    }
+}
+
+/// Remove the file at `path` with hash `hash` from the index
+pub fn remove(transaction: &Transaction<'_>, path: &Utf8Path, hash: &str) -> Result<(), Error> {
+    let rows = transaction
+        .execute(
+            "DELETE FROM files WHERE path = ?1 AND hash = ?2",
+            [path.as_str(), hash],
+        )
+        .map_err(Error::UpdateFailure)?;
+    if rows == 0 {
+        return Err(Error::FileDoesNotExist {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Change the hash of the file at `path` to `hash`, returning its previous hash. Its [`Stat`] is
+/// unknown until it is set again, and it counts as never verified.
+///
+/// If another file already has `hash`, the file is kept as a copy of it when `copies` is set, and
+/// [`Error::DuplicateInsertion`] is returned otherwise.
+pub fn update_hash(
+    transaction: &Transaction<'_>,
+    path: &Utf8Path,
+    hash: &str,
+    copies: bool,
+) -> Result<String, Error> {
+    let select_result: Result<String, rusqlite::Error> = if copies {
+        Err(rusqlite::Error::QueryReturnedNoRows)
+    } else {
+        transaction.query_row(
+            "SELECT path FROM files as f WHERE f.hash = ?1 AND f.path != ?2 LIMIT 1",
+            [hash, path.as_str()],
+            |row| row.get(0),
+        )
+    };
+    match select_result {
+        Ok(path_old) => {
+            return Err(Error::DuplicateInsertion {
+                path_old: Utf8PathBuf::from(path_old),
+                path_new: path.to_path_buf(),
             })
         }
-    };
+        Err(rusqlite::Error::QueryReturnedNoRows) => {}
+        Err(e) => return Err(Error::QueryFailure(e)),
+    }
+
+    let prev_hash: String = transaction
+        .query_row(
+            "SELECT hash FROM files as f WHERE f.path = ?1",
+            [path.as_str()],
+            |row| row.get(0),
+        )
+        .map_err(Error::QueryFailure)?;
+    let rows = transaction
+        .execute(
+            "UPDATE files
+             SET hash = ?1, size = NULL, device = NULL, inode = NULL, mtime = NULL,
+                 last_verified = NULL
+             WHERE path = ?2",
+            [hash, path.as_str()],
+        )
+        .map_err(Error::UpdateFailure)?;
+    if rows != 1 {
+        return Err(Error::TooManyRowsAffected {
+            count: rows,
+            min_rows: 1,
+            max_rows: 1,
+            msg: "updating the hash of a path should update a single row".to_owned(),
+        });
+    }
+
+    Ok(prev_hash)
+}
 
+/// Record the size of the file at `path` and what identifies it on disk
+pub fn set_stat(transaction: &Transaction<'_>, path: &Utf8Path, stat: &Stat) -> Result<(), Error> {
+    transaction
+        .prepare_cached(
+            "UPDATE files SET size = ?1, device = ?2, inode = ?3, mtime = ?4 WHERE path = ?5",
+        )
+        .and_then(|mut update| {
+            update.execute((
+                stat.size,
+                stat.device,
+                stat.inode,
+                stat.mtime,
+                path.as_str(),
+            ))
+        })
+        .map_err(Error::UpdateFailure)?;
     Ok(())
 }
+
+/// Fetch the path and hash of every file in the index whose [`Stat`] is not known
+pub fn unstatted_files(conn: &Connection) -> Result<Vec<(String, String)>, Error> {
+    let mut query = conn
+        .prepare("SELECT path, hash FROM files WHERE size IS NULL OR mtime IS NULL")
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch the path, hash and size, if known, of every file in the index. Hardlinks of a file that
+/// was already fetched have a size of 0, so the space they share is only counted once.
+pub fn sized_files(conn: &Connection) -> Result<Vec<(String, String, Option<u64>)>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT path, hash, CASE
+                 WHEN inode IS NOT NULL AND rowid > (
+                     SELECT MIN(rowid) FROM files AS f
+                     WHERE f.device = files.device AND f.inode = files.inode
+                 ) THEN 0
+                 ELSE size
+             END FROM files ORDER BY rowid",
+        )
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch every file in the index, with its size and modification time if they are known
+pub fn listing(conn: &Connection) -> Result<Vec<IndexedFile>, Error> {
+    let mut query = conn
+        .prepare(&format!("SELECT {} FROM files", IndexedFile::COLUMNS))
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], IndexedFile::from_row)
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch the indexed file at `path`, if there is one
+pub fn indexed_file(conn: &Connection, path: &Utf8Path) -> Result<Option<IndexedFile>, Error> {
+    let res = conn.query_row(
+        &format!("SELECT {} FROM files WHERE path = ?1", IndexedFile::COLUMNS),
+        [path.as_str()],
+        IndexedFile::from_row,
+    );
+    match res {
+        Ok(file) => Ok(Some(file)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::QueryFailure(e)),
+    }
+}
+
+/// Fetch every indexed file with hash `hash`, which is more than one if copies of it are indexed
+pub fn files_with_hash(conn: &Connection, hash: &str) -> Result<Vec<IndexedFile>, Error> {
+    let mut query = conn
+        .prepare(&format!(
+            "SELECT {} FROM files WHERE hash = ?1 ORDER BY rowid",
+            IndexedFile::COLUMNS
+        ))
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([hash], IndexedFile::from_row)
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Find the hash of an indexed file that starts with `prefix`, failing if there are none or more
+/// than one of them
+pub fn resolve_hash(conn: &Connection, prefix: &str) -> Result<String, Error> {
+    if prefix.is_empty() || !prefix.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::HashPrefixNotFound(prefix.to_owned()));
+    }
+    // Two are enough to tell it is ambiguous
+    let mut query = conn
+        .prepare("SELECT DISTINCT hash FROM files WHERE hash GLOB ?1 || '*' LIMIT 2")
+        .map_err(Error::QueryFailure)?;
+    let mut hashes: Vec<String> = query
+        .query_map([prefix.to_ascii_lowercase()], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    match hashes.len() {
+        0 => Err(Error::HashPrefixNotFound(prefix.to_owned())),
+        1 => Ok(hashes.remove(0)),
+        _ => Err(Error::AmbiguousHashPrefix(prefix.to_owned())),
+    }
+}
+
+/// Fetch the path, hash and [`Stat`] of every file in the index whose inode is known
+pub fn inodes(conn: &Connection) -> Result<Vec<(String, String, Stat)>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT path, hash, size, device, inode, mtime FROM files
+             WHERE size IS NOT NULL AND inode IS NOT NULL",
+        )
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([], |row| {
+            let stat = Stat {
+                size: row.get(2)?,
+                device: row.get(3)?,
+                inode: row.get(4)?,
+                mtime: row.get(5)?,
+            };
+            Ok((row.get(0)?, row.get(1)?, stat))
+        })
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch the sizes of the files in the index, or `None` if the size of any of them is not known
+pub fn sizes(conn: &Connection) -> Result<Option<HashSet<u64>>, Error> {
+    let mut query = conn
+        .prepare("SELECT DISTINCT size FROM files")
+        .map_err(Error::QueryFailure)?;
+    let sizes: Vec<Option<u64>> = query
+        .query_map([], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(sizes.into_iter().collect())
+}
+
+/// Fetch the path, hash and size, if known, of every file in the index that was last verified
+/// before `before` (a unix timestamp) or never, the least recently verified first
+pub fn unverified_files(
+    conn: &Connection,
+    before: i64,
+) -> Result<Vec<(String, String, Option<u64>)>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT path, hash, size FROM files
+             WHERE last_verified IS NULL OR last_verified < ?1
+             ORDER BY last_verified, path",
+        )
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([before], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Fetch how many files in the index were never verified, and when the least recently verified of
+/// the others was (a unix timestamp), if there are any
+pub fn verification(conn: &Connection) -> Result<(usize, Option<i64>), Error> {
+    conn.query_row(
+        "SELECT COUNT(*) - COUNT(last_verified), MIN(last_verified) FROM files",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .map_err(Error::QueryFailure)
+}
+
+/// Record that the file at `path` was found to still have hash `hash` at `at` (a unix timestamp).
+/// Nothing changes if the index has another hash for it by now.
+pub fn set_verified(
+    transaction: &Transaction<'_>,
+    path: &Utf8Path,
+    hash: &str,
+    at: i64,
+) -> Result<(), Error> {
+    transaction
+        .prepare_cached("UPDATE files SET last_verified = ?1 WHERE path = ?2 AND hash = ?3")
+        .and_then(|mut update| update.execute((at, path.as_str(), hash)))
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Start a new operation in the journal for `command`, returning its id.
+///
+/// Operations other than refreshes forget the [`dir_times`], as the index may no longer have the
+/// files in the directories they were recorded for.
+pub fn begin_operation(transaction: &Transaction<'_>, command: &str) -> Result<i64, Error> {
+    transaction
+        .execute(
+            "INSERT INTO operations(command, started_at) VALUES (?1, ?2)",
+            (command, chrono::Utc::now().timestamp()),
+        )
+        .map_err(Error::UpdateFailure)?;
+    let id = transaction.last_insert_rowid();
+    set_meta(transaction, "version", VERSION)?;
+    set_meta(transaction, "last_operation", command)?;
+    set_meta(
+        transaction,
+        "last_operation_at",
+        &chrono::Utc::now().timestamp().to_string(),
+    )?;
+    if command != "refresh" {
+        transaction
+            .execute("DELETE FROM dirs", [])
+            .map_err(Error::UpdateFailure)?;
+    }
+    Ok(id)
+}
+
+/// Record `action` in the journal as part of `operation`
+pub fn record(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    action: &JournalAction,
+) -> Result<(), Error> {
+    let (kind, path, hash) = match action {
+        JournalAction::Insert { path, hash } => ("insert", path, hash),
+        JournalAction::UpdatePath { path, hash, .. } => ("update_path", path, hash),
+        JournalAction::Trash { path, hash, .. } => ("trash", path, hash),
+        JournalAction::Remove { path, hash } => ("remove", path, hash),
+        JournalAction::Delete { path, hash } => ("delete", path, hash),
+        JournalAction::UpdateHash { path, hash, .. } => ("update_hash", path, hash),
+        JournalAction::Rename { path, hash, .. } => ("rename", path, hash),
+    };
+    let prev_path = match action {
+        JournalAction::UpdatePath { prev_path, .. } | JournalAction::Rename { prev_path, .. } => {
+            Some(prev_path.as_str())
+        }
+        _ => None,
+    };
+    let trash_name = match action {
+        JournalAction::Trash { trash_name, .. } => Some(trash_name.as_str()),
+        _ => None,
+    };
+    let prev_hash = match action {
+        JournalAction::UpdateHash { prev_hash, .. } => Some(prev_hash.as_str()),
+        _ => None,
+    };
+    transaction
+        .prepare_cached(
+            "INSERT INTO journal(operation, action, path, hash, prev_path, trash_name, prev_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .and_then(|mut insert| {
+            insert.execute((
+                operation,
+                kind,
+                path.as_str(),
+                hash,
+                prev_path,
+                trash_name,
+                prev_hash,
+            ))
+        })
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch the id, command and start time of the last operation in the journal
+pub fn last_operation(conn: &Connection) -> Result<Option<(i64, String, i64)>, Error> {
+    let res = conn.query_row(
+        "SELECT id, command, started_at FROM operations ORDER BY id DESC LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    );
+    match res {
+        Ok(op) => Ok(Some(op)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::QueryFailure(e)),
+    }
+}
+
+/// Fetch the actions recorded as part of `operation`, latest first
+pub fn operation_actions(conn: &Connection, operation: i64) -> Result<Vec<JournalAction>, Error> {
+    type JournalRow = (
+        String,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+        Option<String>,
+    );
+
+    let mut query = conn
+        .prepare(
+            "SELECT action, path, hash, prev_path, trash_name, prev_hash FROM journal
+             WHERE operation = ?1 ORDER BY id DESC",
+        )
+        .map_err(Error::QueryFailure)?;
+    let rows: Vec<JournalRow> = query
+        .query_map([operation], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+
+    rows.into_iter()
+        .map(|(kind, path, hash, prev_path, trash_name, prev_hash)| {
+            let path = Utf8PathBuf::from(path);
+            match (kind.as_str(), prev_path, trash_name, prev_hash) {
+                ("insert", ..) => Ok(JournalAction::Insert { path, hash }),
+                ("update_path", Some(prev_path), ..) => Ok(JournalAction::UpdatePath {
+                    path,
+                    prev_path: prev_path.into(),
+                    hash,
+                }),
+                ("trash", _, Some(trash_name), _) => Ok(JournalAction::Trash {
+                    path,
+                    hash,
+                    trash_name,
+                }),
+                ("remove", ..) => Ok(JournalAction::Remove { path, hash }),
+                ("delete", ..) => Ok(JournalAction::Delete { path, hash }),
+                ("update_hash", _, _, Some(prev_hash)) => Ok(JournalAction::UpdateHash {
+                    path,
+                    hash,
+                    prev_hash,
+                }),
+                ("rename", Some(prev_path), ..) => Ok(JournalAction::Rename {
+                    path,
+                    prev_path: prev_path.into(),
+                    hash,
+                }),
+                _ => Err(Error::Unknown(eyre!(
+                    "Invalid journal entry: action={kind}, path={path}, hash={hash}"
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Remove `operation` and all of its actions from the journal, once it is undone
+///
+/// The [`dir_times`] are forgotten like in [`begin_operation`].
+pub fn delete_operation(transaction: &Transaction<'_>, operation: i64) -> Result<(), Error> {
+    transaction
+        .execute("DELETE FROM journal WHERE operation = ?1", [operation])
+        .map_err(Error::UpdateFailure)?;
+    transaction
+        .execute("DELETE FROM operations WHERE id = ?1", [operation])
+        .map_err(Error::UpdateFailure)?;
+    transaction
+        .execute("DELETE FROM dirs", [])
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch the hashes known to be stored in `remote`
+#[cfg(feature = "s3")]
+pub fn remote_hashes(conn: &Connection, remote: &str) -> Result<Vec<String>, Error> {
+    let mut query = conn
+        .prepare("SELECT hash FROM remote_objects WHERE remote = ?1")
+        .map_err(Error::QueryFailure)?;
+    let hashes = query
+        .query_map([remote], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(hashes)
+}
+
+/// Record that the file with hash `hash` is stored in `remote`
+#[cfg(feature = "s3")]
+pub fn insert_remote_object(
+    transaction: &Transaction<'_>,
+    remote: &str,
+    hash: &str,
+) -> Result<(), Error> {
+    transaction
+        .execute(
+            "INSERT OR IGNORE INTO remote_objects(remote, hash) VALUES (?1, ?2)",
+            [remote, hash],
+        )
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch the hashes of the files parity data was made for
+pub fn parity_hashes(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut query = conn
+        .prepare("SELECT hash FROM parity")
+        .map_err(Error::QueryFailure)?;
+    let hashes = query
+        .query_map([], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(hashes)
+}
+
+/// Record that parity data was made for the file with hash `hash` at `created_at`, a unix
+/// timestamp
+pub fn insert_parity(conn: &Connection, hash: &str, created_at: i64) -> Result<(), Error> {
+    conn.execute(
+        "INSERT OR REPLACE INTO parity(hash, created_at) VALUES (?1, ?2)",
+        (hash, created_at),
+    )
+    .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Forget the parity data of the file with hash `hash`
+pub fn remove_parity(conn: &Connection, hash: &str) -> Result<(), Error> {
+    conn.execute("DELETE FROM parity WHERE hash = ?1", [hash])
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch the patterns of the pinned paths, in the order they were pinned
+pub fn pins(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut query = conn
+        .prepare("SELECT pattern FROM pins ORDER BY created_at, rowid")
+        .map_err(Error::QueryFailure)?;
+    let pins = query
+        .query_map([], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(pins)
+}
+
+/// Pin the paths matching `pattern` at `created_at`, a unix timestamp, returning whether it was
+/// not pinned already
+pub fn insert_pin(conn: &Connection, pattern: &str, created_at: i64) -> Result<bool, Error> {
+    let rows = conn
+        .execute(
+            "INSERT OR IGNORE INTO pins(pattern, created_at) VALUES (?1, ?2)",
+            (pattern, created_at),
+        )
+        .map_err(Error::UpdateFailure)?;
+    Ok(rows > 0)
+}
+
+/// Unpin the paths matching `pattern`, returning whether it was pinned
+pub fn remove_pin(conn: &Connection, pattern: &str) -> Result<bool, Error> {
+    let rows = conn
+        .execute("DELETE FROM pins WHERE pattern = ?1", [pattern])
+        .map_err(Error::UpdateFailure)?;
+    Ok(rows > 0)
+}
+
+/// Record a refresh that started at `started_at` (a unix timestamp) and took `duration_ms`, and
+/// the changes it found, returning its id in the history
+///
+/// `full` says whether it read every directory of the data directory. It is the last refresh in
+/// the store metadata.
+pub fn record_history(
+    transaction: &Transaction<'_>,
+    started_at: i64,
+    duration_ms: i64,
+    full: bool,
+    diffs: &[HistoryDiff],
+) -> Result<i64, Error> {
+    transaction
+        .execute(
+            "INSERT INTO history(started_at, duration_ms, full) VALUES (?1, ?2, ?3)",
+            (started_at, duration_ms, full),
+        )
+        .map_err(Error::UpdateFailure)?;
+    let id = transaction.last_insert_rowid();
+    set_meta(transaction, "last_refresh", &started_at.to_string())?;
+
+    let mut insert = transaction
+        .prepare(
+            "INSERT INTO history_diffs(history, kind, path, hash, orig_path, prev_hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )
+        .map_err(Error::UpdateFailure)?;
+    for d in diffs {
+        insert
+            .execute((
+                id,
+                &d.kind,
+                d.path.as_str(),
+                &d.hash,
+                d.orig_path.as_deref().map(Utf8Path::as_str),
+                &d.prev_hash,
+            ))
+            .map_err(Error::UpdateFailure)?;
+    }
+
+    Ok(id)
+}
+
+/// Set `key` to `value` in the `meta` table
+fn set_meta(transaction: &Transaction<'_>, key: &str, value: &str) -> Result<(), Error> {
+    transaction
+        .execute(
+            "INSERT OR REPLACE INTO meta(key, value) VALUES (?1, ?2)",
+            [key, value],
+        )
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch what is recorded about the store in the `meta` table
+pub fn meta(conn: &Connection) -> Result<Meta, Error> {
+    let mut query = conn
+        .prepare("SELECT key, value FROM meta")
+        .map_err(Error::QueryFailure)?;
+    let rows = query
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<Vec<(String, String)>, _>>()
+        .map_err(Error::QueryFailure)?;
+    let mut meta = Meta::default();
+    for (key, value) in rows {
+        let time = || value.parse().ok();
+        match key.as_str() {
+            "created_at" => meta.created_at = time(),
+            "last_operation_at" => meta.last_operation_at = time(),
+            "last_refresh" => meta.last_refresh = time(),
+            "hash" => meta.hash = Some(value),
+            "version" => meta.version = Some(value),
+            "last_operation" => meta.last_operation = Some(value),
+            _ => {}
+        }
+    }
+    Ok(meta)
+}
+
+/// Record that the index was made at `created_at`, a unix timestamp, by this version of cstfs,
+/// hashing the files with the algorithm named `hash`
+pub fn record_creation(
+    transaction: &Transaction<'_>,
+    created_at: i64,
+    hash: &str,
+) -> Result<(), Error> {
+    set_meta(transaction, "created_at", &created_at.to_string())?;
+    set_meta(transaction, "version", VERSION)?;
+    set_meta(transaction, "hash", hash)
+}
+
+/// Find out which algorithm the indexed files are hashed with. `None` if the index is empty.
+///
+/// It is the one recorded in the `meta` table, or for stores made before it was, the one that makes
+/// hashes like those of the indexed files.
+pub fn hash_algorithm(conn: &Connection) -> Result<Option<HashAlgorithm>, Error> {
+    let res = conn.query_row("SELECT hash FROM files LIMIT 1", [], |row| {
+        row.get::<_, String>(0)
+    });
+    let hash = match res {
+        Ok(hash) => hash,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(Error::QueryFailure(e)),
+    };
+    let recorded = meta(conn)?.hash;
+    Ok(HashAlgorithm::ALL.into_iter().find(|a| {
+        recorded
+            .as_deref()
+            .map_or_else(|| a.is_valid_hash(&hash), |name| a.name() == name)
+    }))
+}
+
+/// Make sure the indexed files are hashed with `configured`, as comparing them to hashes made with
+/// another algorithm would make every file look changed
+pub fn check_hash(conn: &Connection, configured: HashAlgorithm) -> Result<(), Error> {
+    match hash_algorithm(conn)? {
+        Some(recorded) if recorded != configured => Err(Error::HashMismatch {
+            recorded: recorded.name(),
+            configured: configured.name(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Record that every indexed file is hashed with the algorithm named `hash`
+pub fn record_hash(transaction: &Transaction<'_>, hash: &str) -> Result<(), Error> {
+    set_meta(transaction, "hash", hash)
+}
+
+/// Fetch the start time of the last refresh that read every directory of the data directory
+pub fn last_full_refresh(conn: &Connection) -> Result<Option<i64>, Error> {
+    conn.query_row(
+        "SELECT MAX(started_at) FROM history WHERE full",
+        [],
+        |row| row.get(0),
+    )
+    .map_err(Error::QueryFailure)
+}
+
+/// Fetch the path, relative to the data directory, and modification time of every directory
+/// recorded by the last refreshes
+pub fn dir_times(conn: &Connection) -> Result<Vec<(String, i64)>, Error> {
+    let mut query = conn
+        .prepare("SELECT path, mtime FROM dirs")
+        .map_err(Error::QueryFailure)?;
+    let dirs = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(dirs)
+}
+
+/// Replace the modification times recorded for the directories at or below the paths in `scope`,
+/// or for every one if it is empty, with `times`
+pub fn record_dir_times(
+    transaction: &Transaction<'_>,
+    scope: &[Utf8PathBuf],
+    times: &[(Utf8PathBuf, i64)],
+) -> Result<(), Error> {
+    if scope.is_empty() {
+        transaction
+            .execute("DELETE FROM dirs", [])
+            .map_err(Error::UpdateFailure)?;
+    }
+    for path in scope {
+        transaction
+            .execute(
+                "DELETE FROM dirs WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/'",
+                [path.as_str()],
+            )
+            .map_err(Error::UpdateFailure)?;
+    }
+    let mut insert = transaction
+        .prepare_cached("INSERT OR REPLACE INTO dirs(path, mtime) VALUES (?1, ?2)")
+        .map_err(Error::UpdateFailure)?;
+    for (path, mtime) in times {
+        insert
+            .execute((path.as_str(), mtime))
+            .map_err(Error::UpdateFailure)?;
+    }
+    Ok(())
+}
+
+/// Fetch the id, start time and duration of every refresh in the history, latest first
+pub fn history(conn: &Connection) -> Result<Vec<(i64, i64, i64)>, Error> {
+    let mut query = conn
+        .prepare("SELECT id, started_at, duration_ms FROM history ORDER BY id DESC")
+        .map_err(Error::QueryFailure)?;
+    let entries = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(entries)
+}
+
+/// Fetch the changes found by the refresh with id `history`
+pub fn history_diffs(conn: &Connection, history: i64) -> Result<Vec<HistoryDiff>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT kind, path, hash, orig_path, prev_hash FROM history_diffs
+             WHERE history = ?1 ORDER BY rowid",
+        )
+        .map_err(Error::QueryFailure)?;
+    let diffs = query
+        .query_map([history], |row| {
+            Ok(HistoryDiff {
+                kind: row.get(0)?,
+                path: Utf8PathBuf::from(row.get::<_, String>(1)?),
+                hash: row.get(2)?,
+                orig_path: row.get::<_, Option<String>>(3)?.map(Utf8PathBuf::from),
+                prev_hash: row.get(4)?,
+            })
+        })
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(diffs)
+}
+
+/// Record the current contents of the index as a snapshot named `name`
+pub fn create_snapshot(transaction: &Transaction<'_>, name: &str) -> Result<(), Error> {
+    let exists: bool = transaction
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM snapshots WHERE name = ?1)",
+            [name],
+            |row| row.get(0),
+        )
+        .map_err(Error::QueryFailure)?;
+    if exists {
+        return Err(Error::SnapshotExists(name.to_owned()));
+    }
+
+    transaction
+        .execute(
+            "INSERT INTO snapshots(name, created_at) VALUES (?1, ?2)",
+            (name, chrono::Utc::now().timestamp()),
+        )
+        .map_err(Error::UpdateFailure)?;
+    transaction
+        .execute(
+            "INSERT INTO snapshot_files(snapshot, path, hash)
+             SELECT ?1, path, hash FROM files",
+            [transaction.last_insert_rowid()],
+        )
+        .map_err(Error::UpdateFailure)?;
+    Ok(())
+}
+
+/// Fetch the name, creation time and amount of files of every snapshot, oldest first
+pub fn snapshots(conn: &Connection) -> Result<Vec<(String, i64, i64)>, Error> {
+    let mut query = conn
+        .prepare(
+            "SELECT s.name, s.created_at, COUNT(f.hash) FROM snapshots AS s
+             LEFT JOIN snapshot_files AS f ON f.snapshot = s.id
+             GROUP BY s.id ORDER BY s.id",
+        )
+        .map_err(Error::QueryFailure)?;
+    let snapshots = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(snapshots)
+}
+
+/// Fetch the path and hash of every file in the snapshot named `name`
+pub fn snapshot_files(conn: &Connection, name: &str) -> Result<Vec<(String, String)>, Error> {
+    let id: i64 = match conn.query_row("SELECT id FROM snapshots WHERE name = ?1", [name], |row| {
+        row.get(0)
+    }) {
+        Ok(id) => id,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(Error::SnapshotDoesNotExist(name.to_owned()))
+        }
+        Err(e) => return Err(Error::QueryFailure(e)),
+    };
+
+    let mut query = conn
+        .prepare("SELECT path, hash FROM snapshot_files WHERE snapshot = ?1")
+        .map_err(Error::QueryFailure)?;
+    let files = query
+        .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(files)
+}
+
+/// Record a snapshot named `name` created at `created_at` (a unix timestamp) of `files`, pairs of
+/// paths and hashes
+pub fn restore_snapshot(
+    transaction: &Transaction<'_>,
+    name: &str,
+    created_at: i64,
+    files: &[(String, String)],
+) -> Result<(), Error> {
+    transaction
+        .execute(
+            "INSERT INTO snapshots(name, created_at) VALUES (?1, ?2)",
+            (name, created_at),
+        )
+        .map_err(|e| match e {
+            rusqlite::Error::SqliteFailure(f, _)
+                if f.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Error::SnapshotExists(name.to_owned())
+            }
+            e => Error::UpdateFailure(e),
+        })?;
+    let id = transaction.last_insert_rowid();
+    let mut insert = transaction
+        .prepare_cached("INSERT INTO snapshot_files(snapshot, path, hash) VALUES (?1, ?2, ?3)")
+        .map_err(Error::UpdateFailure)?;
+    for (path, hash) in files {
+        insert
+            .execute((id, path, hash))
+            .map_err(Error::UpdateFailure)?;
+    }
+    Ok(())
+}
+
+/// Save the search query `query` as `name`, replacing the one saved as it before, if any. Returns
+/// whether there was one.
+pub fn save_search(conn: &Connection, name: &str, query: &str) -> Result<bool, Error> {
+    let exists: bool = conn
+        .query_row(
+            "SELECT EXISTS(SELECT 1 FROM searches WHERE name = ?1)",
+            [name],
+            |row| row.get(0),
+        )
+        .map_err(Error::QueryFailure)?;
+    conn.execute(
+        "INSERT INTO searches(name, query) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET query = excluded.query",
+        [name, query],
+    )
+    .map_err(Error::UpdateFailure)?;
+    Ok(exists)
+}
+
+/// Fetch the query of the search saved as `name`
+pub fn search(conn: &Connection, name: &str) -> Result<String, Error> {
+    match conn.query_row(
+        "SELECT query FROM searches WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    ) {
+        Ok(query) => Ok(query),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(Error::SearchDoesNotExist(name.to_owned()))
+        }
+        Err(e) => Err(Error::QueryFailure(e)),
+    }
+}
+
+/// Fetch the name and query of every saved search, by name
+pub fn searches(conn: &Connection) -> Result<Vec<(String, String)>, Error> {
+    let mut query = conn
+        .prepare("SELECT name, query FROM searches ORDER BY name")
+        .map_err(Error::QueryFailure)?;
+    let searches = query
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(searches)
+}
+
+/// Remove the search saved as `name`
+pub fn remove_search(conn: &Connection, name: &str) -> Result<(), Error> {
+    let rows = conn
+        .execute("DELETE FROM searches WHERE name = ?1", [name])
+        .map_err(Error::UpdateFailure)?;
+    if rows == 0 {
+        return Err(Error::SearchDoesNotExist(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Run sqlite's integrity check on the database, returning the problems it found
+pub fn integrity_check(conn: &Connection) -> Result<Vec<String>, Error> {
+    let mut query = conn
+        .prepare("PRAGMA integrity_check")
+        .map_err(Error::QueryFailure)?;
+    let problems: Vec<String> = query
+        .query_map([], |row| row.get(0))
+        .map_err(Error::QueryFailure)?
+        .collect::<Result<_, _>>()
+        .map_err(Error::QueryFailure)?;
+    Ok(match problems.as_slice() {
+        [ok] if ok == "ok" => vec![],
+        _ => problems,
+    })
+}
+
+/// Refresh the statistics sqlite uses to plan queries
+pub fn analyze(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("ANALYZE").map_err(Error::UpdateFailure)
+}
+
+/// Rebuild the database file, releasing the space left unused by deleted rows
+pub fn vacuum(conn: &Connection) -> Result<(), Error> {
+    conn.execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE)")
+        .map_err(Error::UpdateFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    /// Make the database of a store at `data_path` as the migrations up to `version` left it, with
+    /// `sql` run on it afterwards
+    fn make_db(data_path: &Utf8Path, version: usize, sql: &str) -> rusqlite::Result<()> {
+        let conn = Connection::open(data_path.join(FILE_NAME))?;
+        add_functions(&conn)?;
+        conn.execute_batch(
+            "CREATE TABLE files (
+                path TEXT NOT NULL,
+                hash TEXT NOT NULL PRIMARY KEY
+            )",
+        )?;
+        for migration in &MIGRATIONS[..version] {
+            conn.execute_batch(migration)?;
+        }
+        conn.pragma_update(None, "user_version", version)?;
+        conn.execute_batch(sql)
+    }
+
+    #[test]
+    fn migrates_index_of_the_first_schema() -> color_eyre::Result<()> {
+        let data_path = test_dir("migrate-first");
+        let nfd = "e\u{301}.jpg";
+        make_db(
+            &data_path,
+            0,
+            &format!("INSERT INTO files VALUES ('a.jpg', '1'), ('{nfd}', '2')"),
+        )?;
+
+        let conn = open(&data_path, &Config::default())?;
+        assert_eq!(schema_version(&conn)?, SCHEMA_VERSION);
+        let mut files = files(&conn)?;
+        files.sort_unstable();
+        assert_eq!(
+            files,
+            [
+                ("a.jpg".to_owned(), "1".to_owned()),
+                ("\u{e9}.jpg".to_owned(), "2".to_owned()),
+            ]
+        );
+
+        // Copies can be indexed alongside the file, but a path only once
+        let transaction = conn.unchecked_transaction()?;
+        insert_copy(&transaction, Utf8Path::new("b.jpg"), "1")?;
+        assert!(insert_copy(&transaction, Utf8Path::new("a.jpg"), "3").is_err());
+        assert!(matches!(
+            insert_into(&transaction, Utf8Path::new("c.jpg"), "1"),
+            Err(Error::DuplicateInsertion { .. })
+        ));
+        transaction.commit()?;
+        assert_eq!(files_with_hash(&conn, "1")?.len(), 2);
+
+        drop(conn);
+        std::fs::remove_dir_all(&data_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_snapshots_and_history_through_migrations() -> color_eyre::Result<()> {
+        let data_path = test_dir("migrate-snapshots");
+        make_db(
+            &data_path,
+            4,
+            "
+            INSERT INTO files VALUES ('a.jpg', '1');
+            INSERT INTO snapshots VALUES (1, 'old', 10);
+            INSERT INTO snapshot_files VALUES (1, 'a.jpg', '1'), (1, 'b.jpg', '2');
+            INSERT INTO operations VALUES (1, 'init', 5);
+            INSERT INTO history VALUES (1, 20, 3)",
+        )?;
+
+        let conn = open(&data_path, &Config::default())?;
+        let mut snapshot = snapshot_files(&conn, "old")?;
+        snapshot.sort_unstable();
+        assert_eq!(
+            snapshot,
+            [
+                ("a.jpg".to_owned(), "1".to_owned()),
+                ("b.jpg".to_owned(), "2".to_owned()),
+            ]
+        );
+        let meta = meta(&conn)?;
+        assert_eq!(meta.created_at, Some(5));
+        assert_eq!(meta.last_refresh, Some(20));
+
+        drop(conn);
+        std::fs::remove_dir_all(&data_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_first_row_of_paths_indexed_twice() -> color_eyre::Result<()> {
+        let data_path = test_dir("migrate-paths");
+        // The migration keying files by path, before it was enforced
+        make_db(
+            &data_path,
+            MIGRATIONS.len() - 1,
+            "INSERT INTO files(path, hash) VALUES ('a.jpg', '1'), ('a.jpg', '2'), ('b.jpg', '1')",
+        )?;
+
+        let conn = open(&data_path, &Config::default())?;
+        let mut files = files(&conn)?;
+        files.sort_unstable();
+        assert_eq!(
+            files,
+            [
+                ("a.jpg".to_owned(), "1".to_owned()),
+                ("b.jpg".to_owned(), "1".to_owned()),
+            ]
+        );
+
+        drop(conn);
+        std::fs::remove_dir_all(&data_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn updates_hash_to_the_one_of_another_file_only_as_a_copy() -> color_eyre::Result<()> {
+        let data_path = test_dir("update-hash");
+        let conn = open(&data_path, &Config::default())?;
+        let transaction = conn.unchecked_transaction()?;
+        insert_into(&transaction, Utf8Path::new("a.jpg"), "1")?;
+        insert_into(&transaction, Utf8Path::new("b.jpg"), "2")?;
+
+        assert!(matches!(
+            update_hash(&transaction, Utf8Path::new("b.jpg"), "1", false),
+            Err(Error::DuplicateInsertion { .. })
+        ));
+        assert_eq!(
+            update_hash(&transaction, Utf8Path::new("b.jpg"), "1", true)?,
+            "2"
+        );
+        assert_eq!(
+            hash(&transaction, Utf8Path::new("b.jpg"))?.as_deref(),
+            Some("1")
+        );
+        transaction.commit()?;
+
+        drop(conn);
+        std::fs::remove_dir_all(&data_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,562 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use console::style;
+use indicatif::HumanBytes;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::backup;
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::pin::Pins;
+use crate::refresh::{copy_diffs, DiffType};
+use crate::remote::shell_quote;
+use crate::remove::Removal;
+use crate::report::Reporter;
+use crate::utils::{canonicalize, full_path, relative_path, Abbrev};
+
+/// Which file of a group of duplicates is kept when they are resolved without asking
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Keep {
+    /// The file modified the longest ago
+    Oldest,
+    /// The file modified most recently
+    Newest,
+    /// The file with the shortest path
+    ShortestPath,
+    /// The file in the directory with the most entries, to gather files where most of them are
+    LargestDir,
+}
+
+impl Keep {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Oldest => "oldest",
+            Self::Newest => "newest",
+            Self::ShortestPath => "one with the shortest path",
+            Self::LargestDir => "one in the largest directory",
+        }
+    }
+}
+
+/// Order in which groups of duplicates are listed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sort {
+    /// The groups whose duplicates take the most space first
+    #[default]
+    Wasted,
+    /// The groups with the most files first
+    Count,
+    /// By hash
+    Hash,
+}
+
+/// How groups of duplicates are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Every group with its hash, the size of its files and how much space they waste
+    #[default]
+    Text,
+    /// Like fdupes does: the full path of every file on its own line, with a blank line after
+    /// every group
+    Fdupes,
+    /// Like the JSON output of rmlint, which rmlint can read back with `--replay`
+    RmlintJson,
+    /// A shell script removing every duplicate, or replacing it with a hard link to the indexed
+    /// file when given `-l`, to read over before running it
+    Sh,
+}
+
+/// A file with the same contents as the others in its group, with what tells it apart from them
+pub struct File {
+    /// Path of the file, relative to the data directory
+    pub path: Utf8PathBuf,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// Width and height of the file, if it is an image
+    pub dimensions: Option<(u32, u32)>,
+    /// Whether the file is pinned, so it is never removed
+    pub pinned: bool,
+}
+
+impl File {
+    fn read(data_path: &Utf8Path, path: Utf8PathBuf, pins: &Pins) -> Result<Self> {
+        let full_path = full_path(data_path, &path);
+        let metadata = full_path
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata of {full_path}"))?;
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::from),
+            dimensions: image::image_dimensions(&full_path).ok(),
+            pinned: pins.contains(&path),
+            path,
+        })
+    }
+}
+
+/// An indexed file together with the files in the data directory that duplicate it
+pub struct Group {
+    pub hash: String,
+    /// The indexed file first, then its duplicates, which are not indexed
+    pub files: Vec<File>,
+}
+
+impl Group {
+    /// Bytes taken by the duplicates of the indexed file
+    #[must_use]
+    pub fn wasted(&self) -> u64 {
+        self.files[1..].iter().map(|f| f.size).sum()
+    }
+}
+
+/// Find the files in the data directory that are duplicates of an indexed file, grouped by their
+/// contents. The progress of hashing them is sent to `reporter`.
+fn groups(data_path: &Utf8Path, config: &Config, reporter: &dyn Reporter) -> Result<Vec<Group>> {
+    let pins = {
+        let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+        Pins::load(&conn)?
+    };
+    let mut by_hash: BTreeMap<String, (Utf8PathBuf, Vec<Utf8PathBuf>)> = BTreeMap::new();
+    for diff in copy_diffs(data_path, config, reporter)? {
+        if let DiffType::Copied { orig_path } = diff.ty {
+            // Removing a symlink indexed as a copy would not free any space
+            if full_path(data_path, &diff.path).is_symlink() {
+                continue;
+            }
+            by_hash
+                .entry(diff.hash)
+                .or_insert_with(|| (orig_path, vec![]))
+                .1
+                .push(diff.path);
+        }
+    }
+
+    by_hash
+        .into_iter()
+        .map(|(hash, (indexed, duplicates))| {
+            let files = std::iter::once(indexed)
+                .chain(duplicates)
+                .map(|p| File::read(data_path, p, &pins))
+                .collect::<Result<_>>()?;
+            Ok(Group { hash, files })
+        })
+        .collect()
+}
+
+/// Print every group of files in the data directory with the same contents, sorted as `sort`
+/// says and in `format`, without changing anything
+pub fn list(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    sort: Sort,
+    format: Format,
+) -> Result<()> {
+    let mut groups = groups(data_path, config, reporter).wrap_err("Failed finding duplicates")?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let abbrev = Abbrev::new(&conn, config)?;
+    match sort {
+        Sort::Wasted => groups.sort_by_key(|g| Reverse(g.wasted())),
+        Sort::Count => groups.sort_by_key(|g| Reverse(g.files.len())),
+        // They are found by hash already
+        Sort::Hash => {}
+    }
+
+    match format {
+        Format::Text => print_text(&groups, &abbrev),
+        Format::Fdupes => print_fdupes(data_path, &groups)?,
+        Format::RmlintJson => print_rmlint(data_path, config, &groups)?,
+        Format::Sh => print_sh(data_path, &groups)?,
+    }
+    Ok(())
+}
+
+fn print_text(groups: &[Group], abbrev: &Abbrev) {
+    for group in groups {
+        println!(
+            "{}: {} files, {} wasted",
+            style(abbrev.apply(&group.hash)).yellow().bold(),
+            group.files.len(),
+            HumanBytes(group.wasted())
+        );
+        for (i, file) in group.files.iter().enumerate() {
+            let indexed = if i == 0 {
+                style(" (indexed)").dim()
+            } else {
+                style("")
+            };
+            let pinned = if file.pinned {
+                style(" (pinned)").dim()
+            } else {
+                style("")
+            };
+            println!(
+                "  {:>10}  \"{}\"{indexed}{pinned}",
+                HumanBytes(file.size).to_string(),
+                file.path
+            );
+        }
+    }
+    let wasted: u64 = groups.iter().map(Group::wasted).sum();
+    println!(
+        "{} groups of duplicates, wasting {}",
+        groups.len(),
+        HumanBytes(wasted)
+    );
+}
+
+fn print_fdupes(data_path: &Utf8Path, groups: &[Group]) -> Result<()> {
+    let data_path = canonicalize(data_path).wrap_err("Failed resolving data directory")?;
+    for group in groups {
+        for file in &group.files {
+            println!("{}", full_path(&data_path, &file.path));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn print_rmlint(data_path: &Utf8Path, config: &Config, groups: &[Group]) -> Result<()> {
+    let data_path = canonicalize(data_path).wrap_err("Failed resolving data directory")?;
+    let mut entries = vec![json!({
+        "description": "rmlint json-dump of lint files",
+        "cwd": data_path,
+        "args": std::env::args().collect::<Vec<_>>().join(" "),
+        "version": env!("CARGO_PKG_VERSION"),
+        "checksum_type": config.hash.name(),
+    })];
+    for group in groups {
+        for (i, file) in group.files.iter().enumerate() {
+            let path = full_path(&data_path, &file.path);
+            // In seconds, like rmlint has them
+            #[allow(clippy::cast_precision_loss)]
+            let mtime = file.modified.map(|t| t.timestamp_micros() as f64 / 1e6);
+            entries.push(json!({
+                "id": entries.len(),
+                "type": "duplicate_file",
+                "checksum": group.hash,
+                "path": path,
+                "size": file.size,
+                "depth": path.components().count() - 1,
+                "is_original": i == 0 || file.pinned,
+                "mtime": mtime,
+            }));
+        }
+    }
+    entries.push(json!({
+        "aborted": false,
+        "duplicates": groups.iter().map(|g| g.files.len() - 1).sum::<usize>(),
+        "duplicate_sets": groups.len(),
+        "total_files": groups.iter().map(|g| g.files.len()).sum::<usize>(),
+        "total_lint_size": groups.iter().map(Group::wasted).sum::<u64>(),
+    }));
+    let json = serde_json::to_string_pretty(&entries).wrap_err("Failed writing duplicates")?;
+    println!("{json}");
+    Ok(())
+}
+
+/// Commands of the script printed by [`print_sh`] before the ones dealing with every duplicate
+const SH_PREAMBLE: &str = r#"#!/bin/sh
+# Duplicates of indexed files found by cstfs. Read this over before running it: every duplicate
+# is removed, and replaced with a hard link to the indexed file with the same contents if given -l.
+set -eu
+
+link=false
+if [ "${1:-}" = -l ]; then
+    link=true
+fi
+
+dedupe() {
+    rm -f -- "$2"
+    if "$link"; then
+        ln -- "$1" "$2"
+    fi
+}
+
+# Pinned duplicates are kept, they are only listed
+pinned() {
+    :
+}
+"#;
+
+fn print_sh(data_path: &Utf8Path, groups: &[Group]) -> Result<()> {
+    let data_path = canonicalize(data_path).wrap_err("Failed resolving data directory")?;
+    println!("{SH_PREAMBLE}");
+    for group in groups {
+        let indexed = shell_quote(full_path(&data_path, &group.files[0].path).as_str());
+        println!("# {}", group.hash);
+        for file in &group.files[1..] {
+            let duplicate = full_path(&data_path, &file.path);
+            if file.pinned {
+                // Not in a comment, as a quoted path can span lines
+                println!("pinned {}", shell_quote(duplicate.as_str()));
+                continue;
+            }
+            println!("dedupe {indexed} {}", shell_quote(duplicate.as_str()));
+        }
+    }
+    Ok(())
+}
+
+/// Choose the file to keep in every group of `groups` as `keep` says, printing which one it is.
+///
+/// Only pinned files are chosen from in groups that have any, and of them only the ones in the
+/// most preferred directory of `config`. Ties are broken in favor of the indexed file, then of the
+/// first duplicate found.
+pub fn choose(
+    data_path: &Utf8Path,
+    config: &Config,
+    groups: &[Group],
+    keep: Keep,
+) -> Result<Vec<Option<usize>>> {
+    let mut dir_sizes: HashMap<Utf8PathBuf, usize> = HashMap::new();
+    let mut dir_size = |path: &Utf8Path| -> Result<Reverse<usize>> {
+        let dir = path
+            .parent()
+            .map_or_else(|| data_path.to_path_buf(), |p| data_path.join(p));
+        if let Some(size) = dir_sizes.get(&dir) {
+            return Ok(Reverse(*size));
+        }
+        let size = dir
+            .read_dir_utf8()
+            .wrap_err_with(|| format!("Failed reading directory contents of {dir}"))?
+            .count();
+        dir_sizes.insert(dir, size);
+        Ok(Reverse(size))
+    };
+
+    let mut kept = Vec::with_capacity(groups.len());
+    for group in groups {
+        let pinned = group.files.iter().any(|f| f.pinned);
+        let candidates: Vec<_> = group
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.pinned || !pinned)
+            .collect();
+        let preferred = candidates
+            .iter()
+            .map(|(_, f)| config.preference(&f.path))
+            .min()
+            .unwrap_or_default();
+        let files = candidates
+            .into_iter()
+            .filter(|(_, f)| config.preference(&f.path) == preferred);
+        let i = match keep {
+            // Files whose modification time is unknown are only kept if every one of them is
+            Keep::Oldest => files.min_by_key(|(_, f)| (f.modified.is_none(), f.modified)),
+            Keep::Newest => files.min_by_key(|(_, f)| (f.modified.is_none(), Reverse(f.modified))),
+            Keep::ShortestPath => files.min_by_key(|(_, f)| f.path.as_str().len()),
+            Keep::LargestDir => {
+                let sizes = group
+                    .files
+                    .iter()
+                    .map(|f| dir_size(&f.path))
+                    .collect::<Result<Vec<_>>>()?;
+                files.min_by_key(|(i, _)| sizes[*i])
+            }
+        }
+        .map_or(0, |(i, _)| i);
+
+        println!(
+            "Keeping \"{}\", the {} out of the {} files with hash {}",
+            group.files[i].path,
+            keep.name(),
+            group.files.len(),
+            group.hash
+        );
+        kept.push(Some(i));
+    }
+    Ok(kept)
+}
+
+/// Read which files the output of rmlint or fdupes at `path` keeps and which it removes, as
+/// pairs of their paths relative to the data directory and whether they are kept. Files outside
+/// of the data directory are left out.
+///
+/// rmlint's JSON output keeps its originals. In the output of fdupes, groups of paths separated by
+/// blank lines, the first file of every group is kept, and relative paths are taken as relative to
+/// the data directory.
+fn decisions(data_path: &Utf8Path, path: &Utf8Path) -> Result<HashMap<Utf8PathBuf, bool>> {
+    let contents =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+    let files: Vec<(Utf8PathBuf, bool)> = if contents.trim_start().starts_with('[') {
+        let entries: Vec<serde_json::Value> = serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("\"{path}\" is not JSON output of rmlint"))?;
+        entries
+            .iter()
+            .filter(|e| e["type"] == "duplicate_file")
+            .filter_map(|e| Some((e["path"].as_str()?.into(), e["is_original"] == true)))
+            .collect()
+    } else {
+        let mut first = true;
+        let mut files = vec![];
+        for line in contents.lines() {
+            if line.is_empty() {
+                first = true;
+            } else {
+                files.push((line.into(), first));
+                first = false;
+            }
+        }
+        files
+    };
+    Ok(files
+        .into_iter()
+        .filter_map(|(p, kept)| Some((relative_path(data_path, &p).ok()?, kept)))
+        .collect())
+}
+
+/// Choose the file to keep in every group of `groups` as the output of rmlint or fdupes at `path`
+/// says, printing which one it is.
+///
+/// Groups are only changed when the output has a decision for every one of their files, and keeps
+/// exactly one of them, so no file it does not say to remove is removed.
+pub fn import(
+    data_path: &Utf8Path,
+    groups: &[Group],
+    path: &Utf8Path,
+) -> Result<Vec<Option<usize>>> {
+    let decisions = decisions(data_path, path)?;
+    info!(
+        "Read decisions about {} files from \"{path}\"",
+        decisions.len()
+    );
+    Ok(groups
+        .iter()
+        .map(|group| {
+            let kept: Option<Vec<usize>> = group
+                .files
+                .iter()
+                .enumerate()
+                .map(|(i, f)| decisions.get(&f.path).map(|kept| (i, *kept)))
+                .filter(|d| d.map_or(true, |(_, kept)| kept))
+                .map(|d| d.map(|(i, _)| i))
+                .collect();
+            match kept.as_deref() {
+                Some(&[i]) => {
+                    println!(
+                        "Keeping \"{}\" out of the {} files with hash {}",
+                        group.files[i].path,
+                        group.files.len(),
+                        group.hash
+                    );
+                    Some(i)
+                }
+                Some(kept) if !kept.is_empty() => {
+                    warn!(
+                        "\"{path}\" keeps {} files with hash {}, leaving them as they are",
+                        kept.len(),
+                        group.hash
+                    );
+                    None
+                }
+                _ => {
+                    warn!(
+                        "\"{path}\" does not say what to do with every file with hash {}, leaving them as they are",
+                        group.hash
+                    );
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// Go through the files in the data directory that are duplicates of an indexed file, letting
+/// `choose` pick which file of every group is kept, and removing the rest.
+///
+/// `choose` returns, for every group, the index in [`Group::files`] of the file to keep, or `None`
+/// to leave the group as it is. When a duplicate is kept, it is indexed in place of the indexed
+/// file. Pinned files are never removed, and one is kept instead of the file chosen if it is not
+/// pinned. Removed files are moved to the trash if `config` uses it, and back if the index cannot be
+/// changed, while the ones deleted for good are only deleted once it is.
+pub fn dedupe(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    choose: impl FnOnce(&[Group]) -> Result<Vec<Option<usize>>>,
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let groups = groups(data_path, config, reporter).wrap_err("Failed finding duplicates")?;
+    if groups.is_empty() {
+        info!("No duplicates found in \"{data_path}\"");
+        return Ok(());
+    }
+    let kept = choose(&groups)?;
+    if kept.iter().any(Option::is_some) {
+        backup::before(data_path, config, "dedupe")?;
+    }
+
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating dedupe transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "dedupe").wrap_err("Failed recording operation")?;
+    let now = Instant::now();
+    let mut removed = 0;
+    let mut removal = Removal::new(operation, data_path, config.use_trash);
+    let remove_duplicates = || -> Result<()> {
+        for (group, keep) in groups.iter().zip(kept) {
+            let Some(keep) = keep else {
+                continue;
+            };
+            let indexed = &group.files[0];
+            let mut kept = group
+                .files
+                .get(keep)
+                .ok_or_else(|| eyre!("Group of {} has no file {keep}", group.hash))?;
+            if let Some(pinned) = group.files.iter().find(|f| f.pinned && !kept.pinned) {
+                info!(
+                    "Keeping \"{}\" instead of \"{}\", as it is pinned",
+                    pinned.path, kept.path
+                );
+                kept = pinned;
+            }
+            let (kept, indexed_pinned, indexed) = (&kept.path, indexed.pinned, &indexed.path);
+            if kept != indexed && !indexed_pinned {
+                // The duplicate kept is indexed in place of the indexed file
+                removal.delete(&transaction, config, indexed, &group.hash)?;
+                db::update_path(&transaction, indexed, kept, &group.hash)
+                    .wrap_err_with(|| format!("Could not update path {kept} at {}", group.hash))?;
+                let action = JournalAction::UpdatePath {
+                    path: kept.clone(),
+                    prev_path: indexed.clone(),
+                    hash: group.hash.clone(),
+                };
+                db::record(&transaction, operation, &action)
+                    .wrap_err("Failed recording path update")?;
+                info!("Updated index with {kept}");
+                removed += 1;
+            }
+            for file in &group.files[1..] {
+                if file.path != *kept && !file.pinned {
+                    removal.delete(&transaction, config, &file.path, &group.hash)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(())
+    };
+    let res = remove_duplicates().and_then(|()| {
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")
+    });
+    removal.finish(res)?;
+
+    let elapsed = now.elapsed();
+    info!("Removed {removed} duplicates. Took {elapsed:.2?}");
+    Ok(())
+}
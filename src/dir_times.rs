@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::utils::normalize;
+
+/// Modification times of the directories of the data directory, recorded by a walk to skip reading
+/// the ones that did not change on the next refresh.
+///
+/// A directory's modification time changes when files are added to it, removed from it or renamed
+/// in it, so the files indexed in one that did not change are still the ones in it. Files modified
+/// in place do not change it, and are only found when every directory is read again.
+#[derive(Default)]
+pub struct DirTimes {
+    /// Times recorded before, by path relative to the data directory
+    known: HashMap<Utf8PathBuf, i64>,
+    /// Directories recorded right below each recorded one
+    children: HashMap<Utf8PathBuf, Vec<Utf8PathBuf>>,
+    /// Times of the directories read or skipped by the walk
+    seen: Mutex<Vec<(Utf8PathBuf, i64)>>,
+    /// Directories the walk skipped, as they did not change
+    unchanged: Mutex<HashSet<Utf8PathBuf>>,
+}
+
+impl DirTimes {
+    /// Skip the directories whose time is still the one in `known`, pairs of paths relative to the
+    /// data directory and times as recorded in the index. With none, every directory is read.
+    pub fn new(known: Vec<(String, i64)>) -> Self {
+        let known: HashMap<Utf8PathBuf, i64> =
+            known.into_iter().map(|(p, t)| (p.into(), t)).collect();
+        let mut children: HashMap<Utf8PathBuf, Vec<Utf8PathBuf>> = HashMap::new();
+        for path in known.keys() {
+            if let Some(parent) = path.parent() {
+                children
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .push(path.clone());
+            }
+        }
+        Self {
+            known,
+            children,
+            ..Self::default()
+        }
+    }
+
+    /// Whether any directory was recorded before, so some may be skipped
+    pub fn has_known(&self) -> bool {
+        !self.known.is_empty()
+    }
+
+    /// Directories below the directory at `path`, relative to the data directory, if it did not
+    /// change since it was recorded and does not have to be read. Otherwise `None`, and it must be
+    /// [`read`](Self::read).
+    pub fn unchanged(&self, path: &Utf8Path, full_path: &Utf8Path) -> Option<Vec<Utf8PathBuf>> {
+        let path = normalize(path);
+        let mtime = mtime(full_path)?;
+        if self.known.get(&path) != Some(&mtime) {
+            return None;
+        }
+        let children = self.children.get(&path).cloned().unwrap_or_default();
+        self.seen
+            .lock()
+            .expect("Walking thread panicked")
+            .push((path.clone(), mtime));
+        self.unchanged
+            .lock()
+            .expect("Walking thread panicked")
+            .insert(path);
+        Some(children)
+    }
+
+    /// Record the time the directory at `path`, relative to the data directory, had when it started
+    /// being read, `mtime` as given by [`mtime`]
+    pub fn read(&self, path: &Utf8Path, mtime: Option<i64>) {
+        if let Some(mtime) = mtime {
+            self.seen
+                .lock()
+                .expect("Walking thread panicked")
+                .push((normalize(path), mtime));
+        }
+    }
+
+    /// Whether the indexed file at `path`, relative to the data directory, is in a directory that
+    /// was skipped, and so is still there
+    pub fn covers(&self, path: &Utf8Path) -> bool {
+        let parent = path.parent().unwrap_or(path);
+        self.unchanged
+            .lock()
+            .expect("Walking thread panicked")
+            .contains(parent)
+    }
+
+    /// Times of every directory read or skipped, to record them for the next refresh
+    pub fn take(&self) -> Vec<(Utf8PathBuf, i64)> {
+        std::mem::take(&mut self.seen.lock().expect("Walking thread panicked"))
+    }
+}
+
+/// Modification time of the file or directory at `path`, in nanoseconds since the unix epoch like
+/// the ones of the indexed files
+pub fn mtime(path: &Utf8Path) -> Option<i64> {
+    let modified = path.metadata().ok()?.modified().ok()?;
+    i64::try_from(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
@@ -0,0 +1,201 @@
+//! Dumps of the index, as JSON files that any later version of cstfs can restore on any machine.
+//!
+//! They do not depend on how the database is laid out. They have the indexed files with what is
+//! known about them, the snapshots and the saved searches. What only makes sense on the machine the
+//! dump was made on, like inodes, the journal and the history of refreshes, is left out.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::{self, IndexedFile};
+use crate::lock::Lock;
+use crate::sign;
+
+/// What dumps say they are, to not restore some other JSON file
+const FORMAT: &str = "cstfs-dump";
+
+/// Version of the dumps made, increased when a change to them cannot be read by older versions
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Dump {
+    format: String,
+    version: u32,
+    /// Name of the algorithm the hashes were made with
+    hash: String,
+    files: Vec<DumpedFile>,
+    snapshots: Vec<DumpedSnapshot>,
+    searches: Vec<DumpedSearch>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DumpedFile {
+    path: String,
+    hash: String,
+    size: Option<u64>,
+    /// Modification time, in nanoseconds since the unix epoch
+    mtime: Option<i64>,
+    /// When it was last verified, as a unix timestamp
+    last_verified: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DumpedSnapshot {
+    name: String,
+    /// When it was created, as a unix timestamp
+    created_at: i64,
+    /// Paths and hashes of the files in it
+    files: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct DumpedSearch {
+    name: String,
+    query: String,
+}
+
+/// Write a dump of the index to `output`, or to stdout without it. Dumps to a file are signed if
+/// `config` has a signing key.
+pub fn dump(data_path: &Utf8Path, config: &Config, output: Option<&Utf8Path>) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::listing(&conn)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|f| DumpedFile {
+            path: f.path,
+            hash: f.hash,
+            size: f.size,
+            mtime: f.mtime,
+            last_verified: f.last_verified,
+        })
+        .collect();
+    let mut snapshots = vec![];
+    for (name, created_at, _) in db::snapshots(&conn).wrap_err("Failed fetching snapshots")? {
+        let files = db::snapshot_files(&conn, &name).wrap_err("Failed fetching snapshot")?;
+        snapshots.push(DumpedSnapshot {
+            name,
+            created_at,
+            files,
+        });
+    }
+    let searches = db::searches(&conn)
+        .wrap_err("Failed fetching saved searches")?
+        .into_iter()
+        .map(|(name, query)| DumpedSearch { name, query })
+        .collect();
+    let dump = Dump {
+        format: FORMAT.to_owned(),
+        version: VERSION,
+        hash: config.hash.name().to_owned(),
+        files,
+        snapshots,
+        searches,
+    };
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(output) => Box::new(
+            File::create(output).wrap_err_with(|| format!("Failed creating \"{output}\""))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut writer = BufWriter::new(&mut writer);
+    serde_json::to_writer_pretty(&mut writer, &dump).wrap_err("Failed writing dump")?;
+    writeln!(writer)
+        .and_then(|()| writer.flush())
+        .wrap_err("Failed writing dump")?;
+    drop(writer);
+    if let Some(output) = output {
+        info!("Dumped {} files to \"{output}\"", dump.files.len());
+        sign::sign(data_path, config, output).wrap_err("Failed signing dump")?;
+    } else if config.signing_key.is_some() {
+        warn!("Not signing the dump written to stdout, give a file to write it to with --output to sign it");
+    }
+    Ok(())
+}
+
+/// Make a new index for the store at `data_path` out of the dump at `input`. The store must have
+/// no database yet, so nothing is overwritten.
+///
+/// With `public_key`, the dump must have a signature made with its secret key.
+pub fn restore(
+    data_path: &Utf8Path,
+    config: &Config,
+    input: &Utf8Path,
+    public_key: Option<&Utf8Path>,
+) -> Result<()> {
+    if let Some(public_key) = public_key {
+        sign::verify(input, public_key).wrap_err("Failed checking the signature of the dump")?;
+    }
+    let file = File::open(input).wrap_err_with(|| format!("Failed opening \"{input}\""))?;
+    let dump: Dump = serde_json::from_reader(BufReader::new(file))
+        .wrap_err_with(|| format!("\"{input}\" is not a dump of a cstfs index"))?;
+    if dump.format != FORMAT {
+        bail!("\"{input}\" is not a dump of a cstfs index");
+    }
+    if dump.version > VERSION {
+        bail!(
+            "\"{input}\" has version {} of the dump format, this cstfs only reads up to {VERSION}",
+            dump.version
+        );
+    }
+    if dump.hash != config.hash.name() {
+        bail!(
+            "\"{input}\" has hashes made with {} but this store uses {}, set `hash` in cstfs.toml",
+            dump.hash,
+            config.hash.name()
+        );
+    }
+
+    let db_path = db::path(data_path, config);
+    if db_path
+        .try_exists()
+        .wrap_err("Could not check database existence")?
+    {
+        bail!("\"{db_path}\" already exists, move it away to restore the dump in its place");
+    }
+    let _lock = Lock::acquire(data_path, config)?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating restore transaction")?;
+    let files = dump.files.len();
+    for f in dump.files.into_iter().map(|f| IndexedFile {
+        path: f.path,
+        hash: f.hash,
+        size: f.size,
+        mtime: f.mtime,
+        last_verified: f.last_verified,
+    }) {
+        db::restore_file(&transaction, &f).wrap_err("Failed restoring file")?;
+    }
+    for s in &dump.snapshots {
+        db::restore_snapshot(&transaction, &s.name, s.created_at, &s.files)
+            .wrap_err_with(|| format!("Failed restoring snapshot {}", s.name))?;
+    }
+    for s in &dump.searches {
+        db::save_search(&transaction, &s.name, &s.query)
+            .wrap_err_with(|| format!("Failed restoring saved search {}", s.name))?;
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+    info!(
+        "Restored {files} files, {} snapshots and {} saved searches from \"{input}\"",
+        dump.snapshots.len(),
+        dump.searches.len()
+    );
+    Ok(())
+}
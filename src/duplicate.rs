@@ -0,0 +1,161 @@
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::Transaction;
+use tracing::info;
+
+use crate::config::{Config, DuplicatePolicy, SmallFilePolicy};
+use crate::db::{self, JournalAction};
+use crate::pin::Pins;
+use crate::remove::delete;
+use crate::report::{Reporter, Resolution};
+use crate::utils;
+
+/// Deal with `path_new`, which is not in the index yet but has the same hash as `path_old`, as
+/// the duplicate policy in `config` says, asking `reporter` if needed, and recording every change
+/// in the journal as part of `operation`. Unless the policy is to skip them, the file in the most
+/// preferred directory is kept without asking. Pinned files are never the one removed.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_duplicate(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    path_old: &Utf8Path,
+    path_new: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    // Checked first, as a symlink has the inode of the file it points to
+    let (full_old, full_new) = (
+        utils::full_path(data_path, path_old),
+        utils::full_path(data_path, path_new),
+    );
+    if utils::is_symlink_copy(config, &full_old, &full_new) {
+        return record_symlink(
+            transaction,
+            operation,
+            data_path,
+            config,
+            path_old,
+            path_new,
+            hash,
+        );
+    }
+    // Removing either would not free any space
+    if utils::is_hardlink(&full_old, &full_new) {
+        info!("Skipped {path_new}, hardlink of {path_old}");
+        return Ok(());
+    }
+    if matches!(config.small_files, SmallFilePolicy::Keep)
+        && utils::stat(&full_new)?.size <= config.small_file_size
+    {
+        info!("Kept {path_new}, small duplicate of {path_old}");
+        return Ok(());
+    }
+    let pins = Pins::load(transaction)?;
+    let pinned = (pins.contains(path_old), pins.contains(path_new));
+    if pinned == (true, true) {
+        info!("Kept {path_new}, pinned duplicate of pinned {path_old}");
+        return Ok(());
+    }
+    let resolution = match config.on_duplicate {
+        DuplicatePolicy::Skip => Resolution::Skip,
+        // The file in the preferred directory is kept without asking
+        _ if config.preference(path_old) < config.preference(path_new) => Resolution::RemoveNew,
+        _ if config.preference(path_new) < config.preference(path_old) => Resolution::RemoveOld,
+        DuplicatePolicy::Ask => reporter.duplicate(path_old, path_new)?,
+        DuplicatePolicy::RemoveNew => Resolution::RemoveNew,
+        DuplicatePolicy::RemoveOld => Resolution::RemoveOld,
+    };
+    // Whichever file was chosen, the pinned one is the one kept
+    let resolution = match (resolution, pinned) {
+        (Resolution::Skip, _) => Resolution::Skip,
+        (_, (true, _)) => Resolution::RemoveNew,
+        (_, (_, true)) => Resolution::RemoveOld,
+        (r, _) => r,
+    };
+    resolve(
+        transaction,
+        operation,
+        data_path,
+        config,
+        resolution,
+        path_old,
+        path_new,
+        hash,
+    )
+}
+
+/// Index `path_new` as a copy of the indexed `path_old`, one of them being a symlink to the other,
+/// recording it in the journal as part of `operation`
+fn record_symlink(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    path_old: &Utf8Path,
+    path_new: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    db::insert_copy(transaction, path_new, hash)
+        .wrap_err_with(|| format!("Failed inserting {path_new} into the index"))?;
+    let full_path = utils::full_path(data_path, path_new);
+    let stat = utils::retry(config, &full_path, || utils::stat(&full_path))?;
+    db::set_stat(transaction, path_new, &stat).wrap_err("Failed recording size")?;
+    let action = JournalAction::Insert {
+        path: path_new.to_path_buf(),
+        hash: hash.to_owned(),
+    };
+    db::record(transaction, operation, &action).wrap_err("Failed recording insertion")?;
+    info!("Indexed {path_new} as a copy of {path_old}, through a symlink");
+    Ok(())
+}
+
+/// Deal with `path_new`, a duplicate of the indexed `path_old`, as `resolution` says
+#[allow(clippy::too_many_arguments)]
+pub fn resolve(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    resolution: Resolution,
+    path_old: &Utf8Path,
+    path_new: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    match resolution {
+        Resolution::RemoveNew => {
+            delete(
+                transaction,
+                operation,
+                data_path,
+                config,
+                config.use_trash,
+                path_new,
+                hash,
+            )?;
+        }
+        Resolution::RemoveOld => {
+            delete(
+                transaction,
+                operation,
+                data_path,
+                config,
+                config.use_trash,
+                path_old,
+                hash,
+            )?;
+            db::update_path(transaction, path_old, path_new, hash)
+                .wrap_err_with(|| format!("Could not update path {path_new} at {hash}"))?;
+            let action = JournalAction::UpdatePath {
+                path: path_new.to_path_buf(),
+                prev_path: path_old.to_path_buf(),
+                hash: hash.to_owned(),
+            };
+            db::record(transaction, operation, &action).wrap_err("Failed recording path update")?;
+            info!("Updated index with {path_new}");
+        }
+        Resolution::Skip => info!("Skipped {path_new}, duplicate of {path_old}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::io::Write;
+use std::sync::Mutex;
+
+use camino::Utf8Path;
+use color_eyre::{Report, Result};
+use cstfs::{db::HistoryDiff, Diff, Reporter, Resolution};
+use serde_json::json;
+
+use crate::terminal::Terminal;
+
+/// Event of a command failing with `error`, the last one it emits
+pub fn error(error: &Report) -> serde_json::Value {
+    let causes: Vec<String> = error.chain().map(ToString::to_string).collect();
+    json!({"event": "error", "message": error.to_string(), "causes": causes})
+}
+
+/// Progress of the files being hashed
+#[derive(Default)]
+struct Hashing {
+    files: usize,
+    done: usize,
+    bytes: u64,
+    hashed: u64,
+}
+
+/// Reports events as lines of JSON on stderr, for programs wrapping cstfs to show its progress.
+/// Duplicates and changes are still asked about on the terminal.
+pub struct Json<'a> {
+    terminal: &'a Terminal,
+    hashing: Mutex<Hashing>,
+}
+
+impl<'a> Json<'a> {
+    pub fn new(terminal: &'a Terminal) -> Self {
+        Self {
+            terminal,
+            hashing: Mutex::new(Hashing::default()),
+        }
+    }
+
+    fn emit(event: &serde_json::Value) {
+        // Progress is best effort, it is not worth stopping the work if stderr is gone
+        let _ = writeln!(std::io::stderr().lock(), "{event}");
+    }
+}
+
+impl Reporter for Json<'_> {
+    fn hashing_started(&self, files: usize, bytes: u64) {
+        *self.hashing.lock().expect("Reporter panicked") = Hashing {
+            files,
+            bytes,
+            ..Hashing::default()
+        };
+        Self::emit(&json!({"event": "hashing_started", "files": files, "bytes": bytes}));
+    }
+
+    fn file_found(&self, path: &Utf8Path, bytes: u64) {
+        let mut hashing = self.hashing.lock().expect("Reporter panicked");
+        hashing.files += 1;
+        hashing.bytes += bytes;
+        drop(hashing);
+        Self::emit(&json!({"event": "file_found", "path": path, "bytes": bytes}));
+    }
+
+    fn file_started(&self, path: &Utf8Path) {
+        Self::emit(&json!({"event": "file_started", "path": path}));
+    }
+
+    fn file_hashed(&self, path: &Utf8Path, bytes: u64) {
+        let mut hashing = self.hashing.lock().expect("Reporter panicked");
+        hashing.done += 1;
+        hashing.hashed += bytes;
+        #[allow(clippy::cast_precision_loss)]
+        let percent = if hashing.bytes == 0 {
+            100.0
+        } else {
+            hashing.hashed as f64 * 100.0 / hashing.bytes as f64
+        };
+        let event = json!({
+            "event": "file_hashed",
+            "path": path,
+            "bytes": bytes,
+            "files_done": hashing.done,
+            "files": hashing.files,
+            "percent": percent,
+        });
+        drop(hashing);
+        Self::emit(&event);
+    }
+
+    fn hashing_finished(&self) {
+        Self::emit(&json!({"event": "hashing_finished"}));
+    }
+
+    fn diff_found(&self, diff: &Diff) {
+        let d = HistoryDiff::from(diff);
+        Self::emit(&json!({
+            "event": "diff_found",
+            "kind": d.kind,
+            "path": d.path,
+            "hash": d.hash,
+            "orig_path": d.orig_path,
+            "prev_hash": d.prev_hash,
+        }));
+    }
+
+    fn duplicate(&self, path_old: &Utf8Path, path_new: &Utf8Path) -> Result<Resolution> {
+        self.terminal.duplicate(path_old, path_new)
+    }
+
+    fn changed(&self, path: &Utf8Path) -> Result<bool> {
+        self.terminal.changed(path)
+    }
+}
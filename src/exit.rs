@@ -0,0 +1,58 @@
+//! Exit codes of `cstfs`, kept the same across versions so scripts and cron jobs can tell how a
+//! command went without reading its output.
+
+use color_eyre::Report;
+
+use crate::lock::Locked;
+use crate::utils;
+
+/// How a command ended, as the code it exits with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Code {
+    /// Done, and nothing was found out of place
+    Clean = 0,
+    /// Done, and changes were found: by `refresh` in the data directory, or by `snapshot diff`
+    /// between two snapshots
+    Changes = 1,
+    /// Done, but problems were found with the files or the index, by `verify`, `fsck` or `lint`,
+    /// or some files could not be read and were skipped
+    Failures = 2,
+    /// The command line is not valid
+    Usage = 3,
+    /// The command failed
+    Error = 4,
+    /// Another cstfs process is changing the store
+    Locked = 5,
+    /// Interrupted by Ctrl-C, like other programs are
+    Interrupted = 130,
+}
+
+/// Error of a command that ran to the end but found problems, like corrupted files
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct Failures(pub String);
+
+impl Code {
+    /// Code of a command that failed with `error`
+    #[must_use]
+    pub fn of(error: &Report) -> Self {
+        if utils::interrupted() {
+            return Self::Interrupted;
+        }
+        for e in error.chain() {
+            if e.is::<Failures>() {
+                return Self::Failures;
+            }
+            if e.is::<Locked>() {
+                return Self::Locked;
+            }
+        }
+        Self::Error
+    }
+}
+
+impl From<Code> for std::process::ExitCode {
+    fn from(code: Code) -> Self {
+        Self::from(code as u8)
+    }
+}
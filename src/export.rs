@@ -0,0 +1,410 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Local;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::{Config, HashAlgorithm, MediaKind};
+use crate::db;
+use crate::sign;
+use crate::utils::hash_file;
+use crate::{thumbs, utils};
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 2em; background: #111; color: #ddd; }
+a { color: #8cf; text-decoration: none; }
+nav { margin-bottom: 1em; }
+ul.albums { list-style: none; padding: 0; }
+ul.albums li { margin: 0.25em 0; }
+div.grid { display: flex; flex-wrap: wrap; gap: 1em; }
+figure { margin: 0; width: 256px; }
+figure img { max-width: 256px; max-height: 256px; display: block; margin: auto; }
+figcaption { font-size: 0.8em; overflow-wrap: anywhere; text-align: center; }
+";
+
+/// Escape `s` so it can be placed in html text or a quoted attribute
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Percent encode every component of `path` so it can be used as a relative url
+fn encode_url_path(path: &Utf8Path) -> String {
+    let mut out = String::new();
+    for (i, component) in path.components().enumerate() {
+        if i > 0 {
+            out.push('/');
+        }
+        for b in component.as_str().bytes() {
+            if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+                out.push(b as char);
+            } else {
+                let _ = write!(out, "%{b:02X}");
+            }
+        }
+    }
+    out
+}
+
+/// A file shown in the gallery
+struct Entry<'a> {
+    path: &'a Utf8Path,
+    hash: &'a str,
+    kind: Option<MediaKind>,
+}
+
+/// Contents of a single album (directory) of the gallery
+#[derive(Default)]
+struct Album<'a> {
+    albums: BTreeSet<Utf8PathBuf>,
+    files: Vec<Entry<'a>>,
+}
+
+/// Path, relative to the output directory, of the page for the album at `dir`
+fn album_page(dir: &Utf8Path) -> Utf8PathBuf {
+    if dir.as_str().is_empty() {
+        Utf8PathBuf::from("index.html")
+    } else {
+        Utf8Path::new("albums").join(dir).join("index.html")
+    }
+}
+
+fn render_album(dir: &Utf8Path, album: &Album<'_>) -> String {
+    let page = album_page(dir);
+    let root = "../".repeat(page.components().count() - 1);
+    let title = if dir.as_str().is_empty() {
+        "cstfs gallery".to_owned()
+    } else {
+        escape_html(dir.as_str())
+    };
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<nav><a href=\"{root}index.html\">Home</a>"
+    );
+    let mut ancestor = Utf8PathBuf::new();
+    for component in dir.components() {
+        ancestor.push(component);
+        let _ = write!(
+            html,
+            " / <a href=\"{root}{}\">{}</a>",
+            encode_url_path(&album_page(&ancestor)),
+            escape_html(component.as_str())
+        );
+    }
+    html.push_str("</nav>\n");
+
+    if !album.albums.is_empty() {
+        html.push_str("<ul class=\"albums\">\n");
+        for sub in &album.albums {
+            let _ = writeln!(
+                html,
+                "<li><a href=\"{root}{}\">{}/</a></li>",
+                encode_url_path(&album_page(sub)),
+                escape_html(sub.file_name().unwrap_or_default())
+            );
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("<div class=\"grid\">\n");
+    for Entry { path, hash, kind } in &album.files {
+        let name = escape_html(path.file_name().unwrap_or_default());
+        let href = format!("{root}files/{}", encode_url_path(path));
+        let preview = match kind {
+            Some(MediaKind::Image | MediaKind::Video) => {
+                format!("<img src=\"{root}thumbs/{hash}.jpg\" alt=\"{name}\" loading=\"lazy\">")
+            }
+            Some(MediaKind::Audio) => {
+                format!("<audio controls preload=\"none\" src=\"{href}\"></audio>")
+            }
+            None => String::new(),
+        };
+        let _ = writeln!(
+            html,
+            "<figure><a href=\"{href}\">{preview}</a><figcaption>{name}</figcaption></figure>"
+        );
+    }
+    html.push_str("</div>\n</body>\n</html>\n");
+
+    html
+}
+
+/// Hard link `from` to `to`, copying it over if that is not possible (e.g. on different
+/// filesystems)
+fn link_or_copy(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    crate::utils::remove_file(to).wrap_err("Failed removing previous file")?;
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to).wrap_err("Failed copying file")?;
+    }
+    Ok(())
+}
+
+/// Line of a checksum file for the file at `path` with hash `hash`. Like coreutils does, paths with
+/// a backslash or a line break in them are escaped, marking the line with a backslash at its start.
+fn checksum_line(hash: &str, path: &str) -> String {
+    if path.contains(['\\', '\n', '\r']) {
+        let escaped = path
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r");
+        format!("\\{hash}  {escaped}\n")
+    } else {
+        format!("{hash}  {path}\n")
+    }
+}
+
+/// Tool whose checksum files `export --checksums` writes, to check the files with it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChecksumFormat {
+    /// `sha256sum -c`, hashing every file again with sha256
+    #[default]
+    Sha256sum,
+    /// `b3sum -c`, with the hashes in the index if the store is hashed with blake3, or else hashing
+    /// every file again with it
+    B3sum,
+}
+
+/// Write the hash and path of every indexed file to `output`, like the tool of `format` does, so
+/// the files can be checked with it from the data directory without cstfs.
+///
+/// It is signed if `config` has a signing key.
+pub fn checksums(
+    data_path: &Utf8Path,
+    config: &Config,
+    output: &Utf8Path,
+    format: ChecksumFormat,
+) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let indexed_with = db::hash_algorithm(&conn)
+        .wrap_err("Failed fetching hash algorithm")?
+        .unwrap_or(config.hash);
+    let mut files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    files.sort_unstable();
+
+    info!("Exporting the checksums of \"{data_path}\" to \"{output}\"");
+    let now = Instant::now();
+    let blake3 = Config {
+        hash: HashAlgorithm::Blake3,
+        ..config.clone()
+    };
+    let mut contents = String::new();
+    for (path, hash) in &files {
+        let full_path = || utils::full_path(data_path, Utf8Path::new(path));
+        let hash = match format {
+            ChecksumFormat::B3sum if indexed_with == HashAlgorithm::Blake3 => hash.clone(),
+            ChecksumFormat::B3sum => hash_file(&full_path(), &blake3)?,
+            ChecksumFormat::Sha256sum => sha256_file(&full_path())?,
+        };
+        contents.push_str(&checksum_line(&hash, path));
+    }
+    std::fs::write(output, contents).wrap_err_with(|| format!("Failed writing \"{output}\""))?;
+    let elapsed = now.elapsed();
+    info!(
+        "Exported the checksums of {} files to \"{output}\". Took {elapsed:.2?}",
+        files.len()
+    );
+    sign::sign(data_path, config, output).wrap_err("Failed signing checksums")
+}
+
+/// Declaration every bag starts with, of the version of the bagit spec it follows
+const BAGIT_DECLARATION: &str = "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n";
+
+/// Lowercase hex of the sha256 of the file at `path`
+fn sha256_file(path: &Utf8Path) -> Result<String> {
+    let mut file = File::open(path).wrap_err_with(|| format!("Failed opening \"{path}\""))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// `path` as it is written in the manifests of a bag, with line breaks and percent signs
+/// percent encoded
+fn bagit_path(path: &str) -> String {
+    path.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Make a bag of the indexed files at `out_dir` as the bagit spec lays them out, for archives that
+/// take deposits like that. `out_dir` must not exist or be empty.
+///
+/// The files are linked (or copied) under `data/` at their paths in the data directory, and listed
+/// with their sha256 in `manifest-sha256.txt`. As the index has no sha256 of them, they are hashed
+/// once more while exporting them.
+pub fn bagit(data_path: &Utf8Path, config: &Config, out_dir: &Utf8Path) -> Result<()> {
+    if out_dir.exists()
+        && out_dir
+            .read_dir_utf8()
+            .wrap_err_with(|| format!("Failed reading \"{out_dir}\""))?
+            .next()
+            .is_some()
+    {
+        bail!("\"{out_dir}\" is not empty, make the bag in a new directory");
+    }
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let mut files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    files.sort_unstable();
+
+    info!("Making a bag of \"{data_path}\" at \"{out_dir}\"");
+    let now = Instant::now();
+    let mut manifest = String::new();
+    let mut bytes = 0;
+    for (path, _) in &files {
+        let from = utils::full_path(data_path, Utf8Path::new(path));
+        let to = out_dir.join("data").join(path);
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+        }
+        link_or_copy(&from, &to).wrap_err_with(|| format!("Failed exporting file {path}"))?;
+        bytes += to
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata of \"{to}\""))?
+            .len();
+        let _ = writeln!(manifest, "{}  data/{}", sha256_file(&to)?, bagit_path(path));
+    }
+
+    let info = format!(
+        "Bagging-Date: {}\nBag-Software-Agent: cstfs {}\nPayload-Oxum: {bytes}.{}\n",
+        Local::now().format("%Y-%m-%d"),
+        env!("CARGO_PKG_VERSION"),
+        files.len()
+    );
+    let mut tag_manifest = String::new();
+    for (name, contents) in [
+        ("bagit.txt", BAGIT_DECLARATION),
+        ("bag-info.txt", &info),
+        ("manifest-sha256.txt", &manifest),
+    ] {
+        let path = out_dir.join(name);
+        std::fs::write(&path, contents).wrap_err_with(|| format!("Failed writing \"{path}\""))?;
+        let _ = writeln!(
+            tag_manifest,
+            "{}  {name}",
+            hex::encode(Sha256::digest(contents))
+        );
+    }
+    let path = out_dir.join("tagmanifest-sha256.txt");
+    std::fs::write(&path, tag_manifest).wrap_err_with(|| format!("Failed writing \"{path}\""))?;
+    // It has the hash of the manifest, so signing it signs every file
+    sign::sign(data_path, config, &path).wrap_err("Failed signing bag")?;
+
+    let elapsed = now.elapsed();
+    info!(
+        "Done making a bag of {} files at \"{out_dir}\". Took {elapsed:.2?}",
+        files.len()
+    );
+    Ok(())
+}
+
+/// Render a static html gallery of the index at `out_dir`, with one album per directory. The
+/// indexed files are linked (or copied) under `files/`, and their thumbnails under `thumbs/`.
+pub fn gallery(data_path: &Utf8Path, config: &Config, out_dir: &Utf8Path) -> Result<()> {
+    thumbs::generate(data_path, config, thumbs::DEFAULT_SIZE, false)
+        .wrap_err("Failed generating thumbnails")?;
+
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let mut files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    files.sort_unstable();
+
+    info!("Exporting gallery to \"{out_dir}\"");
+    let now = Instant::now();
+
+    let mut albums: BTreeMap<Utf8PathBuf, Album<'_>> = BTreeMap::new();
+    albums.insert(Utf8PathBuf::new(), Album::default());
+    for (path, hash) in &files {
+        let path = Utf8Path::new(path);
+        let dir = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+        let kind = utils::media_kind(&utils::full_path(data_path, path), config)
+            .wrap_err_with(|| format!("Failed finding out the type of {path}"))?;
+        albums
+            .entry(dir.to_path_buf())
+            .or_default()
+            .files
+            .push(Entry { path, hash, kind });
+        // Make sure every ancestor knows about its subalbum, even if it has no files itself
+        let mut child = dir;
+        while let Some(parent) = child.parent() {
+            albums
+                .entry(parent.to_path_buf())
+                .or_default()
+                .albums
+                .insert(child.to_path_buf());
+            child = parent;
+        }
+    }
+
+    let thumbs_out = out_dir.join("thumbs");
+    std::fs::create_dir_all(&thumbs_out)
+        .wrap_err_with(|| format!("Failed creating directory \"{thumbs_out}\""))?;
+    for (path, hash) in &files {
+        let from = utils::full_path(data_path, Utf8Path::new(path));
+        let to = out_dir.join("files").join(path);
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+        }
+        link_or_copy(&from, &to).wrap_err_with(|| format!("Failed exporting file {path}"))?;
+
+        let thumb = thumbs::path(data_path, hash);
+        if thumb.exists() {
+            link_or_copy(&thumb, &thumbs_out.join(format!("{hash}.jpg")))
+                .wrap_err_with(|| format!("Failed exporting thumbnail of {path}"))?;
+        }
+    }
+
+    for (dir, album) in &albums {
+        let page = out_dir.join(album_page(dir));
+        if let Some(parent) = page.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+        }
+        std::fs::write(&page, render_album(dir, album))
+            .wrap_err_with(|| format!("Failed writing page \"{page}\""))?;
+    }
+
+    let elapsed = now.elapsed();
+    info!(
+        "Done exporting {} files in {} albums to \"{out_dir}\". Took {elapsed:.2?}",
+        files.len(),
+        albums.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::checksum_line;
+
+    #[test]
+    fn checksum_line_is_hash_and_path() {
+        assert_eq!(checksum_line("abc", "dir/a.jpg"), "abc  dir/a.jpg\n");
+    }
+
+    #[test]
+    fn checksum_line_escapes_backslashes_and_line_breaks() {
+        assert_eq!(checksum_line("abc", "a\\b.jpg"), "\\abc  a\\\\b.jpg\n");
+        assert_eq!(checksum_line("abc", "a\nb\r.jpg"), "\\abc  a\\nb\\r.jpg\n");
+    }
+}
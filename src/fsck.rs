@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use rusqlite::Transaction;
+use tracing::{info, warn};
+
+use crate::backup;
+use crate::config::{Config, CopyPolicy, HashAlgorithm};
+use crate::db::{self, JournalAction};
+use crate::exit::Failures;
+use crate::init;
+use crate::lock::Lock;
+use crate::report::Reporter;
+use crate::utils::{self, hash_file, hash_files, normalize, recursive_directory_read};
+
+/// Class of inconsistency between the index and the data directory that `fsck` can repair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Problem {
+    /// Indexed files missing from the data directory, which are removed from the index
+    Missing,
+    /// Files in the data directory that are not indexed, which are added to the index
+    Unindexed,
+    /// Paths indexed more than once, of which only the row matching the contents of the file is
+    /// kept
+    DuplicatePath,
+    /// Hashes that are not valid for any algorithm, which are recomputed
+    MalformedHash,
+    /// Hashes made with another algorithm than the configured one, which are recomputed
+    HashAlgorithm,
+}
+
+/// Problems found so far, and how many of them were repaired
+#[derive(Default)]
+struct Findings {
+    found: usize,
+    repaired: usize,
+}
+
+/// State of a check of the index
+struct Check<'a> {
+    transaction: &'a Transaction<'a>,
+    operation: i64,
+    data_path: &'a Utf8Path,
+    config: &'a Config,
+    reporter: &'a dyn Reporter,
+    repair: &'a [Problem],
+    findings: Findings,
+}
+
+impl Check<'_> {
+    fn repairs(&self, problem: Problem) -> bool {
+        self.repair.contains(&problem)
+    }
+
+    /// Remove the file at `path` with hash `hash` from the index, without touching the disk
+    fn remove(&self, path: &Utf8Path, hash: &str) -> Result<()> {
+        db::remove(self.transaction, path, hash)
+            .wrap_err_with(|| format!("Failed removing {path} from the index"))?;
+        let action = JournalAction::Remove {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+        };
+        db::record(self.transaction, self.operation, &action).wrap_err("Failed recording removal")
+    }
+
+    fn schema(&mut self) -> Result<()> {
+        let version = db::schema_version(self.transaction).wrap_err("Failed fetching schema")?;
+        if version > db::SCHEMA_VERSION {
+            warn!(
+                "Database schema is at version {version}, newer than the {} this version of cstfs knows, which cannot be repaired",
+                db::SCHEMA_VERSION
+            );
+            self.findings.found += 1;
+        }
+        Ok(())
+    }
+
+    /// Check for paths that are indexed more than once, returning the ones left as they are
+    fn duplicate_paths(&mut self) -> Result<HashSet<Utf8PathBuf>> {
+        let rows = db::duplicate_paths(self.transaction)
+            .wrap_err("Failed fetching duplicate paths from db")?;
+        let mut by_path: HashMap<Utf8PathBuf, Vec<String>> = HashMap::new();
+        for (path, hash) in rows {
+            by_path.entry(path.into()).or_default().push(hash);
+        }
+
+        let mut left = HashSet::new();
+        for (path, hashes) in by_path {
+            warn!("\"{path}\" is indexed {} times", hashes.len());
+            self.findings.found += 1;
+            if !self.repairs(Problem::DuplicatePath) {
+                left.insert(path);
+                continue;
+            }
+
+            let full_path = utils::full_path(self.data_path, &path);
+            let current = if full_path.is_file() {
+                Some(
+                    hash_file(&full_path, self.config)
+                        .wrap_err_with(|| format!("Could not hash file {path}"))?,
+                )
+            } else {
+                None
+            };
+            // Keep the row matching the file if there is one, or the first one otherwise, which
+            // the next refresh then finds out about
+            let keep = current
+                .filter(|h| hashes.contains(h))
+                .unwrap_or_else(|| hashes[0].clone());
+            for hash in hashes.iter().filter(|h| **h != keep) {
+                self.remove(&path, hash)?;
+            }
+            info!("Kept a single row for \"{path}\"");
+            self.findings.repaired += 1;
+        }
+        Ok(left)
+    }
+
+    /// Check for indexed files missing from the data directory, returning the ones left in the
+    /// index
+    fn missing(&mut self) -> Result<Vec<(Utf8PathBuf, String)>> {
+        let files = db::files(self.transaction).wrap_err("Failed fetching files from db")?;
+        let mut present = vec![];
+        for (path, hash) in files {
+            let path = Utf8PathBuf::from(path);
+            if utils::full_path(self.data_path, &path)
+                .try_exists()
+                .wrap_err_with(|| format!("Could not check existence of \"{path}\""))?
+            {
+                present.push((path, hash));
+                continue;
+            }
+
+            warn!("Indexed file \"{path}\" is missing");
+            self.findings.found += 1;
+            if self.repairs(Problem::Missing) {
+                self.remove(&path, &hash)?;
+                info!("Removed \"{path}\" from the index");
+                self.findings.repaired += 1;
+            }
+        }
+        Ok(present)
+    }
+
+    /// Check that the hashes of `files` were made with the configured algorithm
+    fn hashes(
+        &mut self,
+        files: &[(Utf8PathBuf, String)],
+        skip: &HashSet<Utf8PathBuf>,
+    ) -> Result<()> {
+        let mut other_algorithms: HashMap<&str, usize> = HashMap::new();
+        let mut rehash = vec![];
+        for (path, hash) in files {
+            if skip.contains(path) || self.config.hash.is_valid_hash(hash) {
+                continue;
+            }
+            let algorithm = HashAlgorithm::ALL
+                .into_iter()
+                .find(|a| a.is_valid_hash(hash));
+            if let Some(a) = algorithm {
+                *other_algorithms.entry(a.name()).or_default() += 1;
+            } else {
+                warn!("\"{path}\" has malformed hash \"{hash}\"");
+            }
+            let problem = algorithm.map_or(Problem::MalformedHash, |_| Problem::HashAlgorithm);
+            self.findings.found += 1;
+            if self.repairs(problem) {
+                rehash.push(path);
+            }
+        }
+        for (name, count) in other_algorithms {
+            warn!(
+                "{count} files were hashed with {name}, but {} is configured",
+                self.config.hash.name()
+            );
+        }
+        if rehash.is_empty() {
+            return Ok(());
+        }
+
+        info!("Rehashing {} files", rehash.len());
+        let full_paths: Vec<_> = rehash
+            .iter()
+            .map(|p| utils::full_path(self.data_path, p))
+            .collect();
+        let hashes = hash_files(&full_paths, self.config, self.reporter);
+        for (path, hash) in rehash.into_iter().zip(hashes) {
+            let hash = hash?;
+            let copies = matches!(self.config.on_copy, CopyPolicy::Record);
+            match db::update_hash(self.transaction, path, &hash, copies) {
+                Ok(prev_hash) => {
+                    let action = JournalAction::UpdateHash {
+                        path: path.clone(),
+                        hash,
+                        prev_hash,
+                    };
+                    db::record(self.transaction, self.operation, &action)
+                        .wrap_err("Failed recording hash update")?;
+                    self.findings.repaired += 1;
+                }
+                Err(db::Error::DuplicateInsertion { path_old, .. }) => {
+                    warn!("Not rehashing \"{path}\", which is a duplicate of \"{path_old}\"");
+                }
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| format!("Failed updating hash of {path}"))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check for files in the data directory that are not indexed
+    fn unindexed(&mut self) -> Result<()> {
+        let indexed: HashSet<Utf8PathBuf> = db::files(self.transaction)
+            .wrap_err("Failed fetching files from db")?
+            .into_iter()
+            .map(|(path, _)| path.into())
+            .collect();
+        let mut unindexed = vec![];
+        for p in recursive_directory_read(self.data_path, self.config)
+            .wrap_err("Failed reading data directory contents")?
+        {
+            let relative = p.strip_prefix(self.data_path).wrap_err_with(|| {
+                format!("Path \"{p}\" was not a base of \"{}\"", self.data_path)
+            })?;
+            if indexed.contains(&normalize(relative)) {
+                continue;
+            }
+            warn!("File \"{relative}\" is not indexed");
+            self.findings.found += 1;
+            unindexed.push(p);
+        }
+        if !self.repairs(Problem::Unindexed) || unindexed.is_empty() {
+            return Ok(());
+        }
+
+        let hashes = hash_files(&unindexed, self.config, self.reporter);
+        for (p, h) in unindexed.iter().zip(hashes) {
+            let p = p.strip_prefix(self.data_path).wrap_err_with(|| {
+                format!("Path \"{p}\" was not a base of \"{}\"", self.data_path)
+            })?;
+            let p = &normalize(p);
+            init::insert(
+                self.transaction,
+                self.operation,
+                self.data_path,
+                self.config,
+                self.reporter,
+                p,
+                h?,
+            )?;
+            self.findings.repaired += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Check that the index is consistent with itself and with the data directory, repairing the
+/// classes of problems in `repair`.
+///
+/// Fails with [`Failures`] if any problem is left unrepaired.
+pub fn fsck(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    repair: &[Problem],
+) -> Result<()> {
+    // Only checking does not change the store, so it can run alongside other commands
+    let _lock = (!repair.is_empty())
+        .then(|| Lock::acquire(data_path, config))
+        .transpose()?;
+    if !repair.is_empty() {
+        backup::before(data_path, config, "fsck")?;
+    }
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating fsck transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "fsck").wrap_err("Failed recording operation")?;
+
+    info!("Checking \"{data_path}\"");
+    let now = Instant::now();
+    let mut check = Check {
+        transaction: &transaction,
+        operation,
+        data_path,
+        config,
+        reporter,
+        repair,
+        findings: Findings::default(),
+    };
+    check.schema()?;
+    let duplicated = check.duplicate_paths()?;
+    let present = check.missing()?;
+    check.hashes(&present, &duplicated)?;
+    check.unindexed()?;
+    let Findings { found, repaired } = check.findings;
+
+    if repaired > 0 {
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+    }
+    let elapsed = now.elapsed();
+    info!("Found {found} problems, repaired {repaired}. Took {elapsed:.2?}");
+    if found > repaired {
+        bail!(Failures(format!(
+            "{} problems were left unrepaired",
+            found - repaired
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+
+use crate::config::Config;
+use crate::utils::hash_file;
+
+/// Print the hash of every file in `paths`, which don't have to be in the store, as it would be
+/// stored in its index
+pub fn hash(config: &Config, paths: &[impl AsRef<Utf8Path>]) -> Result<()> {
+    for p in paths {
+        let p = p.as_ref();
+        let h = hash_file(p, config).wrap_err_with(|| format!("Could not hash file {p}"))?;
+        println!("{h}  {p}");
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::collections::BTreeMap;
+
+use camino::Utf8Path;
+use chrono::{Local, TimeZone};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+
+use crate::config::Config;
+use crate::db::{self, HistoryDiff};
+use crate::style;
+use crate::utils::Abbrev;
+
+/// Format the unix timestamp `t` as a local date and time
+pub(crate) fn format_timestamp(t: i64) -> String {
+    Local.timestamp_opt(t, 0).single().map_or_else(
+        || t.to_string(),
+        |t| t.format("%Y-%m-%d %H:%M:%S").to_string(),
+    )
+}
+
+/// Print the change `d`, with its hashes abbreviated as `abbrev` says
+fn print_diff(d: &HistoryDiff, abbrev: &Abbrev) {
+    let HistoryDiff {
+        kind,
+        path,
+        hash,
+        orig_path,
+        prev_hash,
+    } = d;
+    match (kind.as_str(), orig_path, prev_hash) {
+        ("moved", Some(orig_path), _) => {
+            println!("{} {orig_path} -> {path}", style::label("Moved:"));
+        }
+        ("duplicate", Some(orig_path), _) => {
+            println!("{} {path} of {orig_path}", style::label("Duplicate:"));
+        }
+        ("copied", Some(orig_path), _) => {
+            println!("{} {orig_path} -> {path}", style::label("Copied:"));
+        }
+        ("changed", _, Some(prev_hash)) => println!(
+            "{} {path} ({} -> {})",
+            style::label("Changed:"),
+            abbrev.apply(prev_hash),
+            abbrev.apply(hash)
+        ),
+        ("new", ..) => println!("{} {path}", style::label("New:")),
+        ("removed", ..) => println!("{} {path}", style::label("Removed:")),
+        _ => println!("{kind}: {path}"),
+    }
+}
+
+/// Print every refresh in the history with a summary of the changes it found, or every change
+/// found by the refresh with id `id`
+pub fn history(data_path: &Utf8Path, config: &Config, id: Option<i64>) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let entries = db::history(&conn).wrap_err("Failed fetching history")?;
+
+    if let Some(id) = id {
+        let Some((_, started_at, duration_ms)) = entries.iter().find(|(i, ..)| *i == id) else {
+            bail!("There is no refresh with id {id} in the history");
+        };
+        println!(
+            "Refresh #{id} at {} (took {duration_ms}ms)",
+            format_timestamp(*started_at)
+        );
+        let diffs = db::history_diffs(&conn, id).wrap_err("Failed fetching history diffs")?;
+        if diffs.is_empty() {
+            println!("No changes");
+        }
+        let abbrev = Abbrev::new(&conn, config)?;
+        for d in &diffs {
+            print_diff(d, &abbrev);
+        }
+        return Ok(());
+    }
+
+    if entries.is_empty() {
+        println!("No refreshes in the history");
+    }
+    for (id, started_at, duration_ms) in entries {
+        let diffs = db::history_diffs(&conn, id).wrap_err("Failed fetching history diffs")?;
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for d in &diffs {
+            *counts.entry(d.kind.as_str()).or_default() += 1;
+        }
+        let summary = if counts.is_empty() {
+            "no changes".to_owned()
+        } else {
+            counts
+                .iter()
+                .map(|(kind, count)| format!("{count} {kind}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        println!(
+            "#{id}  {}  ({duration_ms}ms)  {summary}",
+            format_timestamp(started_at)
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::refresh::{Diff, DiffType};
+
+/// Command that runs `command` in the shell
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    }
+}
+
+/// How often a running hook is checked on, to kill it once it runs out of time
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run the hook `name`, which is `command`, in the data directory, with `env` set and `events`
+/// written to its stdin one per line. It is killed if it runs for longer than `config` lets it.
+fn run(
+    data_path: &Utf8Path,
+    config: &Config,
+    name: &str,
+    command: &str,
+    env: &[(&str, &str)],
+    events: Vec<serde_json::Value>,
+) -> Result<()> {
+    debug!("Running hook {name}: {command}");
+    let mut child = shell(command)
+        .current_dir(data_path)
+        .env("CSTFS_DATA_DIR", data_path)
+        .envs(env.iter().copied())
+        .stdin(Stdio::piped())
+        .spawn()
+        .wrap_err("Failed starting hook")?;
+    // Written from another thread, so a hook that does not read it all still runs out of time
+    let writer = child.stdin.take().map(|stdin| {
+        std::thread::spawn(move || {
+            let mut stdin = BufWriter::new(stdin);
+            for event in events {
+                writeln!(stdin, "{event}")?;
+            }
+            stdin.flush()
+        })
+    });
+
+    let timeout = Duration::from_secs(config.hooks.timeout_secs);
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().wrap_err("Failed waiting for hook")? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            // Killing it closes its stdin, which stops the writer
+            child.kill().wrap_err("Failed killing hook")?;
+            child.wait().wrap_err("Failed waiting for hook")?;
+            bail!("Killed it after running for {timeout:?}");
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    if let Some(writer) = writer {
+        match writer.join().expect("Hook writer panicked") {
+            // Hooks that only read the environment may exit without reading their stdin
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {}
+            res => res.wrap_err("Failed writing events to hook")?,
+        }
+    }
+    if !status.success() {
+        warn!("Hook {name} failed: {status}");
+    }
+    Ok(())
+}
+
+/// Run the hook configured for every kind of change once with the ones of `diffs`, once a refresh
+/// applied them
+pub fn diffs(data_path: &Utf8Path, config: &Config, diffs: &[Diff]) {
+    let hooks = [
+        ("on-new", &config.hooks.on_new),
+        ("on-removed", &config.hooks.on_removed),
+        ("on-duplicate", &config.hooks.on_duplicate),
+    ];
+    for (name, command) in hooks {
+        let Some(command) = command else {
+            continue;
+        };
+        let events: Vec<_> = diffs
+            .iter()
+            .filter(|d| hook(&d.ty) == Some(name))
+            .map(|d| {
+                let orig_path = match &d.ty {
+                    DiffType::Duplicate { orig_path } | DiffType::Copied { orig_path } => {
+                        Some(orig_path.as_str())
+                    }
+                    _ => None,
+                };
+                json!({
+                    "event": d.ty.name(),
+                    "path": d.path,
+                    "hash": d.hash,
+                    "orig_path": orig_path,
+                })
+            })
+            .collect();
+        if events.is_empty() {
+            continue;
+        }
+        let count = events.len().to_string();
+        let env = [("CSTFS_EVENT", name), ("CSTFS_CHANGES", count.as_str())];
+        if let Err(e) = run(data_path, config, name, command, &env, events) {
+            warn!("Hook {name} failed: {e:#}");
+        }
+    }
+}
+
+/// Name of the hook run for changes of type `ty`, if there is one
+const fn hook(ty: &DiffType) -> Option<&'static str> {
+    match ty {
+        DiffType::New => Some("on-new"),
+        DiffType::Removed => Some("on-removed"),
+        DiffType::Duplicate { .. } | DiffType::Copied { .. } => Some("on-duplicate"),
+        DiffType::Changed { .. } | DiffType::Moved { .. } => None,
+    }
+}
+
+/// Run the hook configured for after a refresh, which applied `diffs` and took `elapsed`
+pub fn post_refresh(data_path: &Utf8Path, config: &Config, diffs: &[Diff], elapsed: Duration) {
+    let Some(command) = &config.hooks.post_refresh else {
+        return;
+    };
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for diff in diffs {
+        *counts.entry(diff.ty.name()).or_default() += 1;
+    }
+    let duration_ms = elapsed.as_millis().to_string();
+    let changes = diffs.len().to_string();
+    let event = json!({
+        "event": "refresh",
+        "changes": diffs.len(),
+        "counts": counts,
+        "duration_ms": elapsed.as_millis(),
+    });
+    let env = [
+        ("CSTFS_EVENT", "refresh"),
+        ("CSTFS_CHANGES", changes.as_str()),
+        ("CSTFS_DURATION_MS", duration_ms.as_str()),
+    ];
+    if let Err(e) = run(
+        data_path,
+        config,
+        "post-refresh",
+        command,
+        &env,
+        vec![event],
+    ) {
+        warn!("Hook post-refresh failed: {e:#}");
+    }
+}
@@ -0,0 +1,69 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::Connection;
+
+use crate::config::{self, Config};
+use crate::db;
+use crate::refresh::{self, Diff};
+use crate::report::Reporter;
+
+/// Handle to the index of a data directory, with the configuration of its store
+pub struct Index {
+    data_path: Utf8PathBuf,
+    config: Config,
+    conn: Connection,
+}
+
+impl Index {
+    /// Open the index of the data directory at `data_path`, with the configuration in its
+    /// cstfs.toml. The database is created if it does not exist yet.
+    pub fn open(data_path: &Utf8Path) -> Result<Self> {
+        let config = config::load(data_path).wrap_err("Failed loading configuration")?;
+        Self::with_config(data_path, config)
+    }
+
+    /// Open the index of the data directory at `data_path`, with `config` instead of the one in
+    /// its cstfs.toml
+    pub fn with_config(data_path: &Utf8Path, config: Config) -> Result<Self> {
+        let conn = db::open(data_path, &config).wrap_err("Failed to open db")?;
+        Ok(Self {
+            data_path: data_path.to_path_buf(),
+            config,
+            conn,
+        })
+    }
+
+    pub fn data_path(&self) -> &Utf8Path {
+        &self.data_path
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Connection to the database, to use with the functions in [`db`]
+    pub const fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Mutable connection to the database, to open transactions on it
+    pub const fn connection_mut(&mut self) -> &mut Connection {
+        &mut self.conn
+    }
+
+    /// Path, relative to the data directory, and hash of every file in the index
+    pub fn files(&self) -> Result<Vec<(String, String)>> {
+        db::files(&self.conn).wrap_err("Failed fetching files from db")
+    }
+
+    /// Changes between the files in the data directory and the index, without applying them
+    pub fn diffs(&self, reporter: &dyn Reporter) -> Result<Vec<Diff>> {
+        refresh::generate_diffs(&self.data_path, &self.config, reporter)
+    }
+
+    /// Apply the changes in the data directory to the index, like `cstfs refresh`, returning how
+    /// many there were
+    pub fn refresh(&self, reporter: &dyn Reporter) -> Result<usize> {
+        refresh::refresh(&self.data_path, &self.config, reporter, &[], false)
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use indicatif::HumanBytes;
+
+use crate::config::{Config, MediaKind};
+use crate::db::{self, IndexedFile};
+use crate::history::format_timestamp;
+use crate::utils::{full_path, media_kind, relative_path};
+
+/// EXIF fields shown, with the label they are shown with
+const EXIF_FIELDS: [(exif::Tag, &str); 8] = [
+    (exif::Tag::Make, "Camera make"),
+    (exif::Tag::Model, "Camera model"),
+    (exif::Tag::LensModel, "Lens"),
+    (exif::Tag::DateTimeOriginal, "Taken at"),
+    (exif::Tag::ExposureTime, "Exposure"),
+    (exif::Tag::FNumber, "Aperture"),
+    (exif::Tag::PhotographicSensitivity, "ISO"),
+    (exif::Tag::FocalLength, "Focal length"),
+];
+
+/// Print a field of the file, aligned with the others
+fn field(label: &str, value: impl std::fmt::Display) {
+    println!("{:<14} {value}", format!("{label}:"));
+}
+
+/// Find the indexed file `file` refers to, either by its path or by its hash or the start of it. Among copies of the same hash, the oldest one in
+/// the index is returned.
+pub(crate) fn resolve(
+    conn: &rusqlite::Connection,
+    data_path: &Utf8Path,
+    file: &str,
+) -> Result<IndexedFile> {
+    if let Ok(relative) = relative_path(data_path, Utf8Path::new(file)) {
+        if let Some(indexed) = db::indexed_file(conn, &relative).wrap_err("Failed fetching file")? {
+            return Ok(indexed);
+        }
+    }
+    let hash = db::resolve_hash(conn, file)
+        .wrap_err_with(|| format!("\"{file}\" is not the path of an indexed file nor its hash"))?;
+    db::files_with_hash(conn, &hash)
+        .wrap_err("Failed fetching file")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| color_eyre::eyre::eyre!("No indexed file has hash {hash}"))
+}
+
+/// Print the details of the file on disk at `full_path`: its type, dimensions and EXIF metadata
+fn print_contents(full_path: &Utf8Path, config: &Config) -> Result<()> {
+    let kind = match media_kind(full_path, config)? {
+        Some(MediaKind::Image) => "image",
+        Some(MediaKind::Audio) => "audio",
+        Some(MediaKind::Video) => "video",
+        None => "other",
+    };
+    match infer::get_from_path(full_path).wrap_err("Failed reading file header")? {
+        Some(t) => field("Type", format!("{kind} ({})", t.mime_type())),
+        None => field("Type", kind),
+    }
+    if let Ok((width, height)) = image::image_dimensions(full_path) {
+        field("Dimensions", format!("{width}x{height}"));
+    }
+
+    let file = File::open(full_path).wrap_err("Failed opening file")?;
+    let Ok(exif) = exif::Reader::new().read_from_container(&mut BufReader::new(file)) else {
+        return Ok(());
+    };
+    for (tag, label) in EXIF_FIELDS {
+        if let Some(f) = exif.get_field(tag, exif::In::PRIMARY) {
+            field(label, f.display_value().with_unit(&exif));
+        }
+    }
+    Ok(())
+}
+
+/// Print everything known about the indexed file `file`, given by its path or the start of its
+/// hash: what the index has about it, its copies and duplicates, and what its contents say about it
+pub fn info(data_path: &Utf8Path, config: &Config, file: &str) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let file = resolve(&conn, data_path, file)?;
+
+    field("Path", &file.path);
+    field("Hash", &file.hash);
+    let copies = db::files_with_hash(&conn, &file.hash).wrap_err("Failed fetching copies")?;
+    for copy in copies.iter().filter(|c| c.path != file.path) {
+        field("Indexed copy", &copy.path);
+    }
+    // Duplicates are not indexed, they are only known from the refresh that found them
+    if let Some((id, ..)) = db::history(&conn)
+        .wrap_err("Failed fetching history")?
+        .first()
+    {
+        let diffs = db::history_diffs(&conn, *id).wrap_err("Failed fetching history diffs")?;
+        for d in diffs
+            .iter()
+            .filter(|d| d.hash == file.hash && matches!(d.kind.as_str(), "duplicate" | "copied"))
+        {
+            field("Duplicate", &d.path);
+        }
+    }
+    match file.size {
+        Some(size) => field("Size", format!("{} ({size} bytes)", HumanBytes(size))),
+        None => field("Size", "not known yet"),
+    }
+    if let Some(mtime) = file.mtime {
+        field(
+            "Modified",
+            format_timestamp(mtime.div_euclid(1_000_000_000)),
+        );
+    }
+    field(
+        "Last verified",
+        file.last_verified
+            .map_or_else(|| "never".to_owned(), format_timestamp),
+    );
+
+    let full_path = full_path(data_path, Utf8Path::new(&file.path));
+    if full_path.exists() {
+        print_contents(&full_path, config)
+            .wrap_err_with(|| format!("Failed reading \"{full_path}\""))?;
+    } else {
+        println!("It is no longer at its path, run `cstfs refresh` to find out where it went");
+    }
+    Ok(())
+}
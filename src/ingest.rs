@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::report::Reporter;
+use crate::template::{self, Fields};
+use crate::utils::{self, hash_file, hash_files, recursive_directory_read};
+
+/// Copy the file at `from` to `to`, which must not exist yet, checking that the copy hashes to
+/// `hash`
+fn copy_file(from: &Utf8Path, to: &Utf8Path, config: &Config, hash: &str) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+    }
+    if let Err(e) = std::fs::copy(from, to) {
+        // Not left half copied
+        let _ = utils::remove_file(to);
+        return Err(e).wrap_err_with(|| format!("Failed copying \"{from}\" to \"{to}\""));
+    }
+
+    let copied_hash =
+        hash_file(to, config).wrap_err_with(|| format!("Could not hash copied file {to}"))?;
+    if copied_hash != hash {
+        utils::remove_file(to).wrap_err_with(|| format!("Failed removing corrupt copy {to}"))?;
+        bail!("Copy of \"{from}\" has hash {copied_hash}, expected {hash}");
+    }
+    Ok(())
+}
+
+/// Copy the files in `src_dir` whose contents are not in the index yet into the data directory,
+/// at the path `config.destination` gives for them, and add them to the index.
+///
+/// Files with the same contents as an indexed one, or as another file in `src_dir`, are left out.
+/// If `dry_run` is set, only print what would be copied.
+pub fn ingest(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    src_dir: &Utf8Path,
+    dry_run: bool,
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    template::check(&config.destination).wrap_err("Invalid destination template")?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating ingest transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "ingest").wrap_err("Failed recording operation")?;
+    let mut known: HashSet<String> = db::files(&transaction)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|(_, hash)| hash)
+        .collect();
+
+    info!("Ingesting \"{src_dir}\" into \"{data_path}\"");
+    let now = Instant::now();
+    let files = recursive_directory_read(src_dir, config)
+        .wrap_err_with(|| format!("Failed reading contents of \"{src_dir}\""))?;
+    let hashes = hash_files(&files, config, reporter);
+
+    let (mut copied, mut present) = (0, 0);
+    // Full paths of the copies made, removed again if the index cannot be changed
+    let mut new_files = vec![];
+    let ingest_all = || -> Result<()> {
+        for (p, h) in files.iter().zip(hashes) {
+            let h = h?;
+            let relative = p.strip_prefix(src_dir).unwrap_or(p);
+            if known.contains(&h) {
+                debug!("Skipping \"{relative}\", which is already in the store");
+                present += 1;
+                continue;
+            }
+
+            let dst = template::render(&config.destination, &Fields::new(src_dir, p, &h))?;
+            let full_dst = data_path.join(&dst);
+            if full_dst
+                .try_exists()
+                .wrap_err_with(|| format!("Could not check existence of \"{full_dst}\""))?
+            {
+                warn!("Skipping \"{relative}\", a different file already exists at \"{dst}\"");
+                continue;
+            }
+            if dry_run {
+                info!("Would copy \"{relative}\" to \"{dst}\"");
+                known.insert(h);
+                copied += 1;
+                continue;
+            }
+
+            info!("Copying \"{relative}\" to \"{dst}\"");
+            copy_file(p, &full_dst, config, &h)?;
+            new_files.push(full_dst.clone());
+            db::insert_into(&transaction, &dst, &h)
+                .wrap_err_with(|| format!("Failed inserting {dst} into db"))?;
+            let stat = utils::stat(&full_dst)?;
+            db::set_stat(&transaction, &dst, &stat).wrap_err("Failed recording size")?;
+            let action = JournalAction::Insert {
+                path: dst,
+                hash: h.clone(),
+            };
+            db::record(&transaction, operation, &action).wrap_err("Failed recording insertion")?;
+            known.insert(h);
+            copied += 1;
+        }
+        Ok(())
+    };
+    let res = ingest_all().and_then(|()| {
+        if dry_run {
+            return Ok(());
+        }
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")
+    });
+    if let Err(e) = res {
+        // The files in the source directory are left as they are, ingesting them again is enough
+        for p in &new_files {
+            if let Err(e) = utils::remove_file(p) {
+                warn!("Could not remove the copy \"{p}\": {e}");
+            }
+        }
+        return Err(e);
+    }
+
+    let elapsed = now.elapsed();
+    let verb = if dry_run { "Would copy" } else { "Copied" };
+    info!("{verb} {copied} files ({present} were already in the store). Took {elapsed:.2?}");
+
+    Ok(())
+}
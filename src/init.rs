@@ -1,125 +1,241 @@
-use std::io::Write;
+use std::collections::HashSet;
 use std::time::Instant;
 
-use camino::Utf8Path;
-use color_eyre::{eyre::WrapErr, Result};
-use crossterm::{
-    cursor::{MoveToColumn, MoveUp},
-    QueueableCommand,
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
 };
-use rusqlite::Transaction;
+use rusqlite::{Connection, Transaction};
+use tracing::{debug, info};
 
-use crate::db;
-use crate::utils::{self, hash_file, recursive_directory_read};
+use crate::backup;
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::duplicate::handle_duplicate;
+use crate::lock::Lock;
+use crate::report::Reporter;
+use crate::skipped::Skipped;
+use crate::utils::{self, hash_stream, normalize, remove_file, walk};
 
-pub fn init(data_path: &Utf8Path) -> Result<()> {
-    let mut conn = db::open(data_path).wrap_err("Failed to open db")?;
-
-    let transaction = conn
-        .transaction()
-        .wrap_err("Failed creating insert transaction")?;
-    println!("Starting database generation at \"{data_path}\"");
-    let now = Instant::now();
-    let directory_contents =
-        recursive_directory_read(data_path).wrap_err("Failed reading data directory contents")?;
-    let total = directory_contents.len();
-    for (i, p) in directory_contents
-        .iter()
-        .enumerate()
-        .map(|(i, p)| (i + 1, p))
-    {
-        let mut stdout = std::io::stdout();
-        stdout
-            .queue(MoveToColumn(0))
-            .wrap_err("Failed to move cursor to beginning of line")?;
-        if i > 1 {
-            stdout
-                .queue(MoveUp(1))
-                .wrap_err("Failed to move cursor up")?;
+/// Make a new index of the data directory, replacing the existing one if `force` is set.
+///
+/// Files are indexed in batches of `config.batch_size`, each committed on its own. If `resume` is
+/// set, an interrupted init is continued, skipping the files already indexed. The database is
+/// removed if this fails before committing anything, to not leave an empty index behind.
+pub fn init(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    force: bool,
+    resume: bool,
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let db_exists = db::path(data_path, config)
+        .try_exists()
+        .wrap_err("Could not check database existence")?;
+    if db_exists && !force && !resume {
+        bail!("Cannot initialize a database that already exists, use --resume to continue an interrupted init");
+    }
+    if force {
+        info!("Regenerating database");
+        backup::set_aside(data_path, config).wrap_err("Failed backing up database")?;
+        for p in db::paths(data_path, config) {
+            remove_file(&p).wrap_err("Failed removing database to reinitialize")?;
+        }
+    }
+    let mut committed = 0;
+    match generate(data_path, config, reporter, &mut committed) {
+        Ok(()) => Ok(()),
+        Err(e) if committed > 0 || (resume && db_exists) => {
+            let stopped = if utils::interrupted() {
+                "being interrupted"
+            } else {
+                "failing"
+            };
+            info!(
+                "Indexed {committed} files before {stopped}, run `cstfs init --resume` to continue"
+            );
+            Err(e)
+        }
+        e @ Err(_) => {
+            for p in db::paths(data_path, config) {
+                remove_file(&p).wrap_err("Failed to remove db file after failed init")?;
+            }
+            e
         }
-        println!("Adding file {i}/{total}...");
-        stdout.flush().wrap_err("Failed flushing")?;
+    }
+}
+
+/// Files of an init that were hashed but not committed yet
+struct Batch<'a> {
+    data_path: &'a Utf8Path,
+    config: &'a Config,
+    reporter: &'a dyn Reporter,
+    operation: Option<i64>,
+    /// Paths, relative to the data directory, and hashes of the files
+    files: Vec<(Utf8PathBuf, String)>,
+    /// Amount of files committed so far
+    committed: usize,
+}
 
-        let h = hash_file(p).wrap_err_with(|| format!("Could not hash file {p}"))?;
-        let p = p
-            .strip_prefix(data_path)
-            .wrap_err_with(|| format!("Path \"{p}\" was not a base of \"{data_path}\""))?;
-        match db::insert_into(&transaction, p, &h) {
-            Ok(()) => {}
-            Err(db::Error::DuplicateInsertion { path_old, path_new }) => {
-                handle_duplicate(&transaction, data_path, &path_old, &path_new, &h)
-                    .wrap_err_with(|| format!("Could not handle duplicate file {p}"))?;
+impl Batch<'_> {
+    /// Insert the files in the batch into the index and commit them
+    fn commit(&mut self, conn: &mut Connection) -> Result<()> {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        let transaction = conn
+            .transaction()
+            .wrap_err("Failed creating insert transaction")?;
+        let operation = match self.operation {
+            Some(op) => op,
+            None => {
+                db::begin_operation(&transaction, "init").wrap_err("Failed recording operation")?
             }
-            e @ Err(_) => e.wrap_err("Failed inserting into database")?,
+        };
+        let count = self.files.len();
+        for (p, h) in self.files.drain(..) {
+            insert(
+                &transaction,
+                operation,
+                self.data_path,
+                self.config,
+                self.reporter,
+                &p,
+                h,
+            )?;
         }
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+        self.operation = Some(operation);
+        self.committed += count;
+        Ok(())
+    }
+}
+
+/// Index every file in the data directory that is not indexed yet, hashing them while the data
+/// directory is walked and committing them in batches. `committed` is set to the amount of files
+/// committed.
+fn generate(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    committed: &mut usize,
+) -> Result<()> {
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let indexed: HashSet<Utf8PathBuf> = db::files(&conn)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|(path, _)| path.into())
+        .collect();
+
+    info!("Starting database generation at \"{data_path}\"");
+    let now = Instant::now();
+    if indexed.is_empty() {
+        let transaction = conn
+            .transaction()
+            .wrap_err("Failed creating metadata transaction")?;
+        db::record_creation(&transaction, Utc::now().timestamp(), config.hash.name())
+            .wrap_err("Failed recording store metadata")?;
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+    } else {
+        info!("Resuming, {} files are already indexed", indexed.len());
     }
-    transaction
-        .commit()
-        .wrap_err("Could not commit transaction")?;
+    let skipped = Skipped::default();
+    let paths = skipped
+        .walk(
+            config,
+            walk(data_path, config).wrap_err("Failed reading data directory contents")?,
+        )
+        .filter(|p| {
+            // Errors are passed on, to be reported when hashing
+            let Ok(Ok(relative)) = p.as_ref().map(|p| p.strip_prefix(data_path)) else {
+                return true;
+            };
+            !indexed.contains(&normalize(relative))
+        });
+
+    let mut batch = Batch {
+        data_path,
+        config,
+        reporter,
+        operation: None,
+        files: vec![],
+        committed: 0,
+    };
+    let res = hash_stream(paths, config, reporter, |p, h| {
+        let Some(h) = skipped.hash(config, &p, h)? else {
+            return Ok(());
+        };
+        let p = normalize(
+            p.strip_prefix(data_path)
+                .wrap_err_with(|| format!("Path \"{p}\" was not a base of \"{data_path}\""))?,
+        );
+        batch.files.push((p, h));
+        if batch.files.len() >= config.batch_size.get() {
+            batch.commit(&mut conn)?;
+            debug!("Indexed {} files", batch.committed);
+        }
+        Ok(())
+    });
+    // The files hashed before an interruption are kept, so init can be resumed after them
+    let res = match res {
+        Ok(()) => batch.commit(&mut conn),
+        Err(e) if utils::interrupted() => batch.commit(&mut conn).and(Err(e)),
+        e @ Err(_) => e,
+    };
+    *committed = batch.committed;
+    res?;
+
     let elapsed = now.elapsed();
-    println!("Done generating database at \"{data_path}\". Took {elapsed:.2?}");
+    info!(
+        "Done generating database at \"{data_path}\", added {committed} files. Took {elapsed:.2?}"
+    );
 
-    Ok(())
+    skipped.finish()
 }
 
-fn handle_duplicate(
+/// Add the file at `path` with hash `hash` to the index, recording it in the journal as part of
+/// `operation`. If a file with the same hash is already in it, it is dealt with as a duplicate.
+pub fn insert(
     transaction: &Transaction<'_>,
+    operation: i64,
     data_path: &Utf8Path,
-    path_old: &Utf8Path,
-    path_new: &Utf8Path,
-    hash: &str,
+    config: &Config,
+    reporter: &dyn Reporter,
+    path: &Utf8Path,
+    hash: String,
 ) -> Result<()> {
-    const VALID_COMMANDS: &str = "Y/n/s/o/?";
-    let flush = || -> Result<()> { std::io::stdout().flush().wrap_err("Failed flushing stdout") };
-
-    print!("Found path \"{path_new}\", duplicate of \"{path_old}\", would you like to remove it? ({VALID_COMMANDS}): ");
-    flush()?;
-
-    let stdin = std::io::stdin();
-    loop {
-        let mut input = String::new();
-        stdin
-            .read_line(&mut input)
-            .wrap_err("Failed reading line from stdin")?;
-        println!();
-        flush()?;
-        match input.trim().to_lowercase().as_str() {
-            "" | "y" => {
-                let full_path = data_path.join(path_new);
-                utils::remove_file(&full_path)
-                    .wrap_err_with(|| format!("Could not remove path {path_new}"))?;
-                println!("Removed file {path_new}");
-                println!();
-                flush()?;
-                break;
-            }
-            "n" => {
-                println!("Quitting...");
-                std::process::exit(1);
-            }
-            "s" => todo!("Adding a file to the ignore list is not implemented"),
-            "o" => {
-                let full_path = data_path.join(path_old);
-                utils::remove_file(&full_path)
-                    .wrap_err_with(|| format!("Could not remove path {path_old}"))?;
-                println!("Removed file {path_old}");
-                db::update_path(transaction, path_new, hash)
-                    .wrap_err_with(|| format!("Could not update path {path_new} at {hash}"))?;
-                println!("Updated index with {path_new}");
-                println!();
-                flush()?;
-                break;
-            }
-            "?" => {
-                println!("y(Yes)  - Remove the new file");
-                println!("n(No)   - Do not remove the file and quit the program");
-                println!("s(Skip) - Skip the file and add it to the ignorelist");
-                println!("o(Old)  - Remove the old file and keep the new one");
-                println!("?(Help) - Print this message");
-            }
-            _ => println!("Invalid command, valid ones are ({VALID_COMMANDS})"),
+    match db::insert_into(transaction, path, &hash) {
+        Ok(()) => {
+            let full_path = utils::full_path(data_path, path);
+            let stat = utils::retry(config, &full_path, || utils::stat(&full_path))?;
+            db::set_stat(transaction, path, &stat).wrap_err("Failed recording size")?;
+            let action = JournalAction::Insert {
+                path: path.to_path_buf(),
+                hash,
+            };
+            db::record(transaction, operation, &action).wrap_err("Failed recording insertion")?;
+        }
+        Err(db::Error::DuplicateInsertion { path_old, path_new }) => {
+            handle_duplicate(
+                transaction,
+                operation,
+                data_path,
+                config,
+                reporter,
+                &path_old,
+                &path_new,
+                &hash,
+            )
+            .wrap_err_with(|| format!("Could not handle duplicate file {path}"))?;
         }
-        flush()?;
+        e @ Err(_) => e.wrap_err("Failed inserting into database")?,
     }
     Ok(())
 }
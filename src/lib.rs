@@ -0,0 +1,84 @@
+//! Content addressed index of a directory of media files, kept in a sqlite database inside of it.
+//!
+//! [`Index`] is the entry point for programs embedding cstfs: it opens the index of a data
+//! directory with its configuration, and finds out how the files in it changed since the last
+//! refresh as a list of [`Diff`]s, sending the progress of the work to a [`Reporter`]. The modules
+//! named after the subcommands of the `cstfs` binary implement them, and [`db`] gives direct
+//! access to the database.
+
+#![deny(
+    clippy::enum_glob_use,
+    clippy::pedantic,
+    clippy::nursery,
+    clippy::unwrap_used
+)]
+// Every fallible function returns an eyre report or a `db::Error` with the context of what failed
+#![allow(clippy::missing_errors_doc)]
+
+pub mod config;
+pub mod db;
+mod dir_times;
+mod duplicate;
+mod hooks;
+mod index;
+mod lock;
+mod notify;
+pub mod report;
+mod sidecar;
+mod skipped;
+mod utils;
+
+pub mod add;
+pub mod backup;
+pub mod bench;
+pub mod cat;
+pub mod contains;
+pub mod dedupe;
+pub mod dump;
+pub mod exit;
+pub mod export;
+pub mod fsck;
+pub mod hash;
+pub mod history;
+pub mod info;
+pub mod ingest;
+pub mod init;
+pub mod lint;
+pub mod list;
+pub mod maintain;
+pub mod merge;
+pub mod open;
+pub mod organize;
+pub mod parity;
+pub mod pin;
+pub mod playlist;
+pub mod prune;
+pub mod random;
+pub mod refresh;
+pub mod remote;
+pub mod remove;
+pub mod rename;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod search;
+pub mod sign;
+pub mod snapshot;
+pub mod split;
+pub mod stats;
+pub mod status;
+pub mod style;
+pub mod sync;
+pub mod template;
+pub mod thumbs;
+pub mod trash;
+pub mod undo;
+pub mod verify;
+
+pub use config::Config;
+pub use index::Index;
+pub use refresh::{dir_moves, generate_diffs, Diff, DiffType, DirMove};
+pub use report::{Reporter, Resolution};
+pub use utils::{
+    full_path, hash_file, hash_files, hash_stream, interrupt, interrupted, media_kind, read_paths,
+    recursive_directory_read, walk, Walk,
+};
@@ -0,0 +1,216 @@
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::exit::Failures;
+use crate::lock::Lock;
+use crate::sidecar;
+use crate::utils::full_path;
+
+/// Extensions of files whose contents are detected as having the first extension, which match them
+/// as well. TIFF takes in the RAW formats built on it.
+const ALIASES: &[(&str, &[&str])] = &[
+    ("jpg", &["jpeg", "jpe", "jfif"]),
+    (
+        "tif",
+        &[
+            "tiff", "nef", "nrw", "arw", "srf", "sr2", "dng", "pef", "3fr", "erf", "kdc", "mos",
+        ],
+    ),
+    ("heif", &["heic", "hif"]),
+    ("mp4", &["m4v", "m4a", "m4b"]),
+    ("m4v", &["mp4"]),
+    ("mov", &["qt"]),
+    ("mkv", &["mka", "mk3d"]),
+    ("mpg", &["mpeg", "mpe", "m2p", "vob"]),
+    ("wmv", &["asf", "wma"]),
+    ("ogg", &["oga", "ogv", "ogx"]),
+    ("aiff", &["aif", "aifc"]),
+    ("wav", &["wave"]),
+    ("midi", &["mid"]),
+];
+
+/// An indexed file whose extension does not match its contents
+struct Mismatch {
+    /// Path of the file, relative to the data directory
+    path: Utf8PathBuf,
+    hash: String,
+    /// Extension its contents have
+    detected: &'static str,
+}
+
+/// Check if a file with extension `ext` may have contents detected as having extension `detected`
+fn matches(ext: &str, detected: &str) -> bool {
+    let ext = ext.to_lowercase();
+    ext == detected
+        || ALIASES
+            .iter()
+            .any(|(d, aliases)| *d == detected && aliases.contains(&ext.as_str()))
+}
+
+/// Type of the contents of the indexed file at `path`, if its extension does not match them. Files
+/// without an extension or whose contents are not recognized are not checked, nor the ones whose
+/// extension and contents are both not media, whose formats are often built on others.
+fn mismatch(data_path: &Utf8Path, config: &Config, path: &Utf8Path) -> Result<Option<infer::Type>> {
+    let Some(ext) = path.extension() else {
+        return Ok(None);
+    };
+    let full_path = full_path(data_path, path);
+    let Some(t) = infer::get_from_path(&full_path)
+        .wrap_err_with(|| format!("Failed reading file header of \"{path}\""))?
+    else {
+        return Ok(None);
+    };
+    let is_media = matches!(
+        t.matcher_type(),
+        infer::MatcherType::Image | infer::MatcherType::Audio | infer::MatcherType::Video
+    );
+    if matches(ext, t.extension())
+        || (!is_media && config.extensions.kind(&ext.to_lowercase()).is_none())
+    {
+        return Ok(None);
+    }
+    Ok(Some(t))
+}
+
+/// Check if a file renamed to have extension `ext` would still be indexed, which is not the case
+/// for contents that are not media
+fn renameable(config: &Config, ext: &str) -> bool {
+    config.all_files || config.extensions.kind(ext).is_some()
+}
+
+/// Find the indexed files whose extension does not match their contents, printing them
+fn find(data_path: &Utf8Path, config: &Config, conn: &Connection) -> Result<Vec<Mismatch>> {
+    let mut files = db::files(conn).wrap_err("Failed fetching files from db")?;
+    files.sort_unstable();
+    let mut mismatches = vec![];
+    for (path, hash) in files {
+        let path = Utf8Path::new(&path);
+        if !full_path(data_path, path).is_file() {
+            warn!("Not checking \"{path}\", which is no longer there");
+            continue;
+        }
+        if let Some(t) = mismatch(data_path, config, path)? {
+            println!(
+                "{path}: its contents are {} (.{})",
+                t.mime_type(),
+                t.extension()
+            );
+            mismatches.push(Mismatch {
+                path: path.to_path_buf(),
+                hash,
+                detected: t.extension(),
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Rename the files in `renamed` back to their previous paths, so they are still where the index
+/// says they are when renaming them is not committed
+fn put_back(data_path: &Utf8Path, renamed: &[(Utf8PathBuf, Utf8PathBuf)]) -> Result<()> {
+    for (from, to) in renamed {
+        std::fs::rename(data_path.join(to), full_path(data_path, from))
+            .wrap_err_with(|| format!("Failed renaming \"{to}\" back to \"{from}\""))?;
+    }
+    Ok(())
+}
+
+/// List the indexed files whose extension does not match their contents, as found out from the
+/// magic bytes at their start, like a PNG named `.jpg`.
+///
+/// If `rename` is set, they are renamed to the extension of their contents along with their
+/// sidecars, updating their paths in the index. Fails with [`Failures`] if any is left as it was.
+pub fn lint(data_path: &Utf8Path, config: &Config, rename: bool) -> Result<()> {
+    let _lock = rename
+        .then(|| Lock::acquire(data_path, config))
+        .transpose()?;
+    let now = Instant::now();
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let mismatches = find(data_path, config, &conn)?;
+    if !rename {
+        info!(
+            "Found {} files whose extension does not match their contents. Took {:.2?}",
+            mismatches.len(),
+            now.elapsed()
+        );
+        if !mismatches.is_empty() {
+            bail!(Failures(format!(
+                "{} files have an extension that does not match their contents, run `cstfs lint --rename` to rename them",
+                mismatches.len()
+            )));
+        }
+        return Ok(());
+    }
+
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating rename transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "lint").wrap_err("Failed recording operation")?;
+    let mut renamed = vec![];
+    let mut left = 0;
+    for mismatch in mismatches {
+        let Mismatch {
+            path,
+            hash,
+            detected,
+        } = mismatch;
+        let to = path.with_extension(detected);
+        if !renameable(config, detected) {
+            warn!("Not renaming \"{path}\" to \"{to}\", it would no longer be indexed");
+            left += 1;
+            continue;
+        }
+        if data_path
+            .join(&to)
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{to}\""))?
+        {
+            warn!("Not renaming \"{path}\", \"{to}\" already exists");
+            left += 1;
+            continue;
+        }
+        db::update_path(&transaction, &path, &to, &hash)
+            .wrap_err_with(|| format!("Failed updating path of {path}"))?;
+        let action = JournalAction::Rename {
+            path: to.clone(),
+            prev_path: path.clone(),
+            hash,
+        };
+        db::record(&transaction, operation, &action).wrap_err("Failed recording rename")?;
+        if let Err(e) = std::fs::rename(full_path(data_path, &path), data_path.join(&to)) {
+            put_back(data_path, &renamed)?;
+            return Err(e).wrap_err_with(|| format!("Failed renaming \"{path}\" to \"{to}\""));
+        }
+        renamed.push((path, to));
+    }
+    if let Err(e) = transaction.commit() {
+        put_back(data_path, &renamed)?;
+        return Err(e).wrap_err("Could not commit transaction");
+    }
+    for (from, to) in &renamed {
+        info!("Moved: {from} -> {to}");
+        sidecar::move_along(data_path, config, from, to)?;
+    }
+
+    info!(
+        "Renamed {} files to the extension of their contents. Took {:.2?}",
+        renamed.len(),
+        now.elapsed()
+    );
+    if left > 0 {
+        bail!(Failures(format!(
+            "{left} files whose extension does not match their contents could not be renamed"
+        )));
+    }
+    Ok(())
+}
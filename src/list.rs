@@ -0,0 +1,160 @@
+use std::cmp::Reverse;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, Utc};
+use color_eyre::{eyre::WrapErr, Result};
+use console::style;
+use indicatif::HumanBytes;
+
+use crate::config::Config;
+use crate::db::{self, IndexedFile};
+use crate::utils::{relative_path, Abbrev};
+
+/// Order in which the files are listed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sort {
+    /// By path
+    #[default]
+    Path,
+    /// The largest files first
+    Size,
+    /// The files modified most recently first
+    Modified,
+    /// By hash
+    Hash,
+}
+
+/// Which of the indexed files are listed, every one of them by default
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Extensions the files have one of, without the dot and in any case. Any when empty.
+    pub extensions: Vec<String>,
+    /// Size in bytes the files have at least
+    pub min_size: Option<u64>,
+    /// Size in bytes the files have at most
+    pub max_size: Option<u64>,
+    /// Directory the files are in, at any depth, relative to the current directory or absolute
+    pub under: Option<Utf8PathBuf>,
+    /// Time after which the files were last modified
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    /// Whether `file`, which is in `under` if given, and modified since `since` in nanoseconds
+    /// since the unix epoch if given, is let through
+    fn matches(&self, file: &IndexedFile, under: Option<&Utf8Path>, since: Option<i64>) -> bool {
+        let path = Utf8Path::new(&file.path);
+        if !self.extensions.is_empty() {
+            let Some(ext) = path.extension() else {
+                return false;
+            };
+            let trimmed = |e: &String| e.trim_start_matches('.').eq_ignore_ascii_case(ext);
+            if !self.extensions.iter().any(trimmed) {
+                return false;
+            }
+        }
+        if let Some(dir) = under {
+            if !path.starts_with(dir) {
+                return false;
+            }
+        }
+        if let Some(min) = self.min_size {
+            if !matches!(file.size, Some(s) if s >= min) {
+                return false;
+            }
+        }
+        if let Some(max) = self.max_size {
+            if !matches!(file.size, Some(s) if s <= max) {
+                return false;
+            }
+        }
+        if let Some(since) = since {
+            if !matches!(file.mtime, Some(m) if m >= since) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parse a point in time given as a local date like `2023-06-01`, a date and time in RFC 3339, or
+/// how long ago it was, like `30d`
+pub fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return date
+            .and_time(NaiveTime::MIN)
+            .and_local_timezone(Local)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc))
+            .ok_or_else(|| format!("{s} does not exist in the local timezone"));
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let ago = humantime::parse_duration(s)
+        .map_err(|_| "expected a date like 2023-06-01 or a duration like 30d".to_owned())?;
+    chrono::Duration::from_std(ago)
+        .ok()
+        .and_then(|ago| Utc::now().checked_sub_signed(ago))
+        .ok_or_else(|| format!("{s} is too long ago"))
+}
+
+/// Print the hash, size and path of every indexed file that `filter` lets through, sorted as
+/// `sort` says, or the other way around if `reverse` is set.
+///
+/// If `null` is set, only their paths are printed, each followed by a NUL byte. The index is read
+/// alone, so the sizes and modification times are the ones the last refresh recorded. Files whose
+/// size or modification time is not known yet are left out when filtering by them.
+pub fn list(
+    data_path: &Utf8Path,
+    config: &Config,
+    filter: &Filter,
+    sort: Sort,
+    reverse: bool,
+    null: bool,
+) -> Result<()> {
+    let under = filter
+        .under
+        .as_deref()
+        .map(|p| relative_path(data_path, p))
+        .transpose()?;
+    let since = filter
+        .since
+        .map(|t| t.timestamp_nanos_opt().unwrap_or(i64::MIN));
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let mut files = db::listing(&conn).wrap_err("Failed fetching files from db")?;
+    files.retain(|f| filter.matches(f, under.as_deref(), since));
+
+    match sort {
+        Sort::Path => files.sort_unstable_by(|a, b| a.path.cmp(&b.path)),
+        Sort::Size => files
+            .sort_unstable_by(|a, b| (Reverse(a.size), &a.path).cmp(&(Reverse(b.size), &b.path))),
+        Sort::Modified => files
+            .sort_unstable_by(|a, b| (Reverse(a.mtime), &a.path).cmp(&(Reverse(b.mtime), &b.path))),
+        Sort::Hash => files.sort_unstable_by(|a, b| (&a.hash, &a.path).cmp(&(&b.hash, &b.path))),
+    }
+    if reverse {
+        files.reverse();
+    }
+
+    if null {
+        for f in &files {
+            print!("{}\0", f.path);
+        }
+    } else {
+        print(&files, &Abbrev::new(&conn, config)?);
+    }
+    Ok(())
+}
+
+/// Print the hash, abbreviated as `abbrev` says, size and path of every file in `files`, one per
+/// line
+pub(crate) fn print(files: &[IndexedFile], abbrev: &Abbrev) {
+    for f in files {
+        let size = f
+            .size
+            .map_or_else(|| "?".to_owned(), |s| HumanBytes(s).to_string());
+        let hash = style(abbrev.apply(&f.hash)).yellow();
+        println!("{hash}  {size:>10}  {}", f.path);
+    }
+}
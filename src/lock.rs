@@ -0,0 +1,81 @@
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, Write};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use fs2::FileExt;
+
+use crate::config::Config;
+use crate::db;
+use crate::utils;
+
+/// Error of a command that could not take the lock on a store, held by another process
+#[derive(thiserror::Error, Debug)]
+#[error("Another cstfs process{holder} is changing \"{data_path}\", try again when it is done")]
+pub struct Locked {
+    data_path: Utf8PathBuf,
+    /// Who holds the lock, like ` (pid 1234)`, if known
+    holder: String,
+}
+
+/// Advisory lock on a store, held by the commands that change it so they do not run at the same
+/// time. Commands that only read the store do not take it. It is released when dropped.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock on the store at `data_path`, failing right away if another process holds it.
+    ///
+    /// The lock file is kept next to the database when `config` puts it outside of the data
+    /// directory, which may not be writable then.
+    pub fn acquire(data_path: &Utf8Path, config: &Config) -> Result<Self> {
+        if db::read_only(data_path, config) {
+            bail!("\"{data_path}\" is read-only, given --read-only or because its database cannot be written to, so it cannot be changed");
+        }
+        let path = if config.db_path.is_some() {
+            Utf8PathBuf::from(format!("{}.lock", db::path(data_path, config)))
+        } else {
+            utils::cstfs_dir(data_path).join("lock")
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Failed creating directory \"{dir}\""))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("Failed opening lock file \"{path}\""))?;
+
+        if let Err(e) = file.try_lock_exclusive() {
+            if e.kind() != ErrorKind::WouldBlock
+                && e.raw_os_error() != fs2::lock_contended_error().raw_os_error()
+            {
+                return Err(e).wrap_err_with(|| format!("Failed locking \"{path}\""));
+            }
+            // Windows does not let other processes read a locked file, so the pid may be unknown
+            let mut pid = String::new();
+            let holder = match file.read_to_string(&mut pid) {
+                Ok(_) if !pid.trim().is_empty() => format!(" (pid {})", pid.trim()),
+                _ => String::new(),
+            };
+            bail!(Locked {
+                data_path: data_path.to_path_buf(),
+                holder,
+            });
+        }
+
+        // The pid is only there to tell the user who holds the lock
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| write!(file, "{}", std::process::id()))
+            .wrap_err_with(|| format!("Failed writing lock file \"{path}\""))?;
+        Ok(Self { _file: file })
+    }
+}
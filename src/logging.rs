@@ -0,0 +1,122 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use console::style;
+use cstfs::style::with_label;
+use serde_json::json;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    Layer,
+};
+
+/// Formats events for the terminal as bare messages, like the plain output cstfs always had, only
+/// marking warnings and errors, and lining up and coloring the labels of changes
+struct Terminal;
+
+impl<S, N> FormatEvent<S, N> for Terminal
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "{} ", style("error:").red().bold().for_stderr())?,
+            Level::WARN => write!(
+                writer,
+                "{} ",
+                style("warning:").yellow().bold().for_stderr()
+            )?,
+            _ => {}
+        }
+        let mut message = String::new();
+        ctx.field_format()
+            .format_fields(Writer::new(&mut message), event)?;
+        writeln!(writer, "{}", with_label(&message))
+    }
+}
+
+/// Formats events as lines of JSON, so they can go on stderr along with the progress events of
+/// `--progress json` without breaking them up
+struct Json;
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = String::new();
+        ctx.field_format()
+            .format_fields(Writer::new(&mut message), event)?;
+        let level = event.metadata().level().as_str().to_lowercase();
+        let event = json!({"event": "log", "level": level, "message": message});
+        writeln!(writer, "{event}")
+    }
+}
+
+/// Set up the output of log events: to stderr at the level given by `verbosity` (0 shows info,
+/// each step up or down shows one more or one less level), as lines of JSON if `json` is set, and
+/// to the end of `log_file` if given, with timestamps and at least at the info level, so unattended
+/// runs leave a record
+pub fn init(verbosity: i8, json: bool, log_file: Option<&Utf8Path>) -> Result<()> {
+    let level = match verbosity {
+        ..=-3 => LevelFilter::OFF,
+        -2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        2.. => LevelFilter::TRACE,
+    };
+    let terminal = (!json).then(|| {
+        tracing_subscriber::fmt::layer()
+            .event_format(Terminal)
+            .with_writer(std::io::stderr)
+            .with_filter(level)
+    });
+    let json = json.then(|| {
+        tracing_subscriber::fmt::layer()
+            .event_format(Json)
+            .with_ansi(false)
+            .with_writer(std::io::stderr)
+            .with_filter(level)
+    });
+
+    let file = log_file
+        .map(|path| {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Failed opening log file \"{path}\""))?;
+            Ok::<_, color_eyre::Report>(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(Mutex::new(file))
+                    .with_filter(level.max(LevelFilter::INFO)),
+            )
+        })
+        .transpose()?;
+
+    let subscriber = tracing_subscriber::registry()
+        .with(terminal)
+        .with(json)
+        .with(file);
+    tracing::subscriber::set_global_default(subscriber).wrap_err("Failed setting up logging")
+}
@@ -5,77 +5,1286 @@
     clippy::unwrap_used
 )]
 
-use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
-use color_eyre::{
-    eyre::{bail, WrapErr},
-    Result,
+use std::io::Write;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use clap::builder::PossibleValuesParser;
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, Subcommand, ValueHint};
+use color_eyre::config::{HookBuilder, Theme};
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::warn;
+
+#[cfg(feature = "s3")]
+use cstfs::s3;
+use cstfs::{
+    add, backup, bench, cat, config, contains, dedupe, dump, exit, export, fsck, hash, history,
+    info, ingest, init, lint, list, maintain, merge, open, organize, parity, pin, playlist, prune,
+    random, refresh, remote, remove, rename, search, sign, snapshot, split, stats, status, style,
+    sync, thumbs, trash, undo, verify, Reporter,
 };
 
-mod db;
-mod utils;
+mod events;
+mod logging;
+mod preview;
+mod priority;
+mod progress;
+mod terminal;
+#[cfg(feature = "tui")]
+mod tui;
 
-mod init;
-mod refresh;
+/// Meaning of the exit codes, as in [`exit::Code`]
+const EXIT_CODES: &str = "\
+Exit codes:
+  0    Done, and nothing was found out of place
+  1    Done, and changes were found by `refresh` or `diff`
+  2    Done, but `verify`, `fsck` or `lint` found problems, or files could not be read
+  3    The command line is not valid
+  4    The command failed
+  5    Another cstfs process is changing the store
+  130  Interrupted";
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_long_help = EXIT_CODES)]
 struct Cli {
-    /// Data store directory (where the pix are)
-    #[arg(short, default_value_t = Utf8PathBuf::from("."))]
-    data_dir: Utf8PathBuf,
+    /// Data store directory (where the pix are). `CSTFS_DATA_DIR` if not given, or else the
+    /// current directory
+    #[arg(short, long, value_hint = ValueHint::DirPath)]
+    data_dir: Option<Utf8PathBuf>,
+
+    /// Name of the store to use instead of the data directory, as registered in the
+    /// `cstfs/stores.toml` of the configuration directory of the user, like `photos = "/mnt/photos"`
+    #[arg(short, long, conflicts_with = "data_dir")]
+    store: Option<String>,
+
+    /// Show more output, repeat to show even more
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Show less output, repeat to show even less
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Also append the output to this file, with timestamps
+    #[arg(long, global = true)]
+    log_file: Option<Utf8PathBuf>,
+
+    /// How progress is shown
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: progress::Progress,
+
+    /// When the output is colored
+    #[arg(long, global = true, value_enum, default_value_t)]
+    color: style::ColorChoice,
+
+    #[command(flatten)]
+    config: ConfigArgs,
 
     #[command(subcommand)]
     command: Command,
 }
 
+// Options overriding the ones in the cstfs.toml of the store. Not a doc comment, since clap would
+// use it as the about of the whole command
+#[derive(clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
+struct ConfigArgs {
+    /// Hash algorithm, overriding `hash` in cstfs.toml
+    #[arg(long, global = true)]
+    hash: Option<config::HashAlgorithm>,
+
+    /// Amount of files hashed in parallel, overriding `jobs` in cstfs.toml
+    #[arg(short, long, global = true)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Amount of directories read in parallel, overriding `walk-jobs` in cstfs.toml
+    #[arg(long, global = true)]
+    walk_jobs: Option<NonZeroUsize>,
+
+    /// Amount of files init indexes between commits, overriding `batch-size` in cstfs.toml
+    #[arg(long, global = true)]
+    batch_size: Option<NonZeroUsize>,
+
+    /// Megabytes per second files are read at when hashing them, overriding `throttle` in
+    /// cstfs.toml
+    #[arg(long, global = true, value_name = "MB/s")]
+    throttle: Option<NonZeroU64>,
+
+    /// How many times reading a file is tried again after an error that may go away, overriding
+    /// `retries` in cstfs.toml
+    #[arg(long, global = true)]
+    retries: Option<u32>,
+
+    /// Run with the lowest CPU and I/O priority, like `nice` in cstfs.toml
+    #[arg(long, global = true)]
+    nice: bool,
+
+    /// Glob of paths, relative to the data directory, to not index, added to `ignore` in
+    /// cstfs.toml. Can be given multiple times
+    #[arg(long, global = true)]
+    ignore: Vec<String>,
+
+    /// Glob of the names of files and directories to not index, at any depth, added to `exclude`
+    /// in cstfs.toml. Can be given multiple times
+    #[arg(long, global = true)]
+    exclude: Vec<String>,
+
+    /// How many directories deep below the data directory to index files, overriding `max-depth`
+    /// in cstfs.toml
+    #[arg(long, global = true)]
+    max_depth: Option<usize>,
+
+    /// How the type of files is found out, overriding `detect` in cstfs.toml
+    #[arg(long, global = true)]
+    detect: Option<config::Detection>,
+
+    /// Index hidden files and directories, like `include-hidden` in cstfs.toml
+    #[arg(long, global = true, overrides_with = "exclude_hidden")]
+    include_hidden: bool,
+
+    /// Skip hidden files and directories, overriding `include-hidden` in cstfs.toml
+    #[arg(long, global = true)]
+    exclude_hidden: bool,
+
+    /// Follow symlinks that point outside of the data directory, like `follow-symlinks` in
+    /// cstfs.toml
+    #[arg(long, global = true, overrides_with = "no_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Skip every symlink, overriding `follow-symlinks` in cstfs.toml
+    #[arg(long, global = true)]
+    no_follow_symlinks: bool,
+
+    /// Index symlinks to files inside of the data directory as copies of their target, like
+    /// `index-symlinks` in cstfs.toml
+    #[arg(long, global = true)]
+    index_symlinks: bool,
+
+    /// Index every file instead of only media files, like `all-files` in cstfs.toml
+    #[arg(long, global = true)]
+    all_files: bool,
+
+    /// Cache hashes in the extended attributes of the files, like `xattr-cache` in cstfs.toml
+    #[arg(long, global = true)]
+    xattr_cache: bool,
+
+    /// What to do with duplicate files, overriding `on-duplicate` in cstfs.toml
+    #[arg(long, global = true)]
+    on_duplicate: Option<config::DuplicatePolicy>,
+
+    /// What to do with copies of indexed files, overriding `on-copy` in cstfs.toml
+    #[arg(long, global = true)]
+    on_copy: Option<config::CopyPolicy>,
+
+    /// What to do with indexed files whose contents changed, overriding `on-change` in cstfs.toml
+    #[arg(long, global = true)]
+    on_change: Option<config::ChangePolicy>,
+
+    /// What to do with small files, overriding `small-files` in cstfs.toml
+    #[arg(long, global = true)]
+    small_files: Option<config::SmallFilePolicy>,
+
+    /// Size up to which files are small, like `4KB`, overriding `small-file-size` in cstfs.toml
+    #[arg(long, global = true, value_parser = |s: &str| parse_size::parse_size(s))]
+    small_file_size: Option<u64>,
+
+    /// How images are previewed when asking about duplicates, overriding `preview` in cstfs.toml
+    #[arg(long, global = true)]
+    preview: Option<config::Preview>,
+
+    /// Print hashes in full instead of abbreviated, like `full-hash` in cstfs.toml
+    #[arg(long, global = true)]
+    full_hash: bool,
+
+    /// Delete removed files right away instead of moving them to the trash
+    #[arg(long, global = true)]
+    no_trash: bool,
+
+    /// Template of the paths files are ingested or organized to, overriding `destination` in
+    /// cstfs.toml. For example `{yyyy}/{mm}/{filename}`
+    #[arg(long, global = true)]
+    destination: Option<String>,
+
+    /// Where the database is kept, overriding `db-path` in cstfs.toml. For example
+    /// `~/.local/share/cstfs/photos.db`, for a data directory that cannot be written to
+    #[arg(long, global = true)]
+    db_path: Option<Utf8PathBuf>,
+
+    /// Key the database is encrypted with. Only when built with the `sqlcipher` feature
+    #[arg(long, global = true, env = "CSTFS_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// File holding the key the database is encrypted with, overriding `keyfile` in cstfs.toml.
+    /// Only when built with the `sqlcipher` feature
+    #[arg(long, global = true, conflicts_with = "passphrase")]
+    keyfile: Option<Utf8PathBuf>,
+
+    /// Only read the store, opening its database read-only and refusing every command that
+    /// changes it, like `read-only` in cstfs.toml. For stores on archival media
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Carry on past the files that cannot be read, listing them once done, like `keep-going` in
+    /// cstfs.toml
+    #[arg(long, global = true, overrides_with = "fail_fast")]
+    keep_going: bool,
+
+    /// Stop at the first file that cannot be read, overriding `keep-going` in cstfs.toml
+    #[arg(long, global = true)]
+    fail_fast: bool,
+}
+
 #[derive(Subcommand)]
 enum Command {
     /// Make an empty database in the directory
     Init {
         /// If true, will delete the existing database and make a new empty one
-        #[arg(short, long)]
+        #[arg(short, long, conflicts_with = "resume")]
         force: bool,
+        /// Continue an interrupted init, skipping the files it already indexed
+        #[arg(long)]
+        resume: bool,
     },
     /// Check the directory contents and compare against the database index,
     /// merging the new results
-    Refresh,
+    Refresh {
+        /// Only look at these files and directories of the data directory. Indexed files elsewhere
+        /// are left as they are, unless they were moved into them
+        paths: Vec<Utf8PathBuf>,
+        /// Read every directory, even with `incremental` set in cstfs.toml, to find the files
+        /// modified in place in directories that did not change otherwise
+        #[arg(long)]
+        full: bool,
+    },
+    /// Remove the indexed files that are missing from the data directory from the index, without
+    /// looking for any other change
+    Prune,
+    /// Move a file, updating its path in the index at the same time
+    #[command(visible_aliases = ["move", "rename"])]
+    Mv {
+        /// Path of the file
+        from: Utf8PathBuf,
+        /// Path to move the file to, inside of the data directory. If it is a directory, the file
+        /// is moved inside of it
+        to: Utf8PathBuf,
+    },
+    /// Remove files from the disk and from the index at the same time, moving them to the trash
+    Rm {
+        /// Paths of the files
+        #[arg(required = true)]
+        paths: Vec<Utf8PathBuf>,
+        /// Delete the files permanently instead of moving them to the trash
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Add files to the index without reading the whole data directory
+    Add {
+        /// Paths of the files, or directories of the data directory to add every file inside of
+        #[arg(required = true)]
+        paths: Vec<Utf8PathBuf>,
+    },
+    /// Print the hash of files, as it is stored in the index, to check if they are in the store
+    Hash {
+        /// Paths of the files, which don't have to be inside of the data directory
+        #[arg(required = true)]
+        paths: Vec<Utf8PathBuf>,
+    },
+    /// Check which of the files in a directory outside of the store are already in it, comparing
+    /// them by hash
+    Contains {
+        /// Directory to check, like a freshly copied camera card
+        dir: Utf8PathBuf,
+        /// Only print the paths of the files that are not in the store
+        #[arg(long)]
+        missing: bool,
+    },
+    /// Copy the files in a directory outside of the store whose contents are not in it yet into the
+    /// data directory, at the path given by the destination template, and index them
+    Ingest {
+        /// Directory to copy files from, like a camera card
+        src_dir: Utf8PathBuf,
+        /// Only print what would be copied
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Move the indexed files to the paths given by the destination template, updating the index
+    /// at the same time
+    Organize {
+        /// Only print what would be moved
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Copy the files missing from either this store or another one into the other, comparing
+    /// their indexes by hash
+    Sync {
+        /// Data directory of the other store, or `[user@]host:path` for a store reached over ssh
+        other_dir: String,
+        /// Only copy files from this store into the other one
+        #[arg(long, conflicts_with = "pull")]
+        push: bool,
+        /// Only copy files from the other store into this one
+        #[arg(long)]
+        pull: bool,
+        /// Only print what would be copied
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Path to the cstfs executable on the remote host
+        #[arg(long, default_value = "cstfs")]
+        remote_cstfs: String,
+    },
+    /// Copy the files of another store into this one and index them, leaving out the duplicates
+    Merge {
+        /// Data directory of the other store
+        other_dir: Utf8PathBuf,
+        /// Directory inside of this store the files are copied to, the name of the other data
+        /// directory by default
+        #[arg(long)]
+        prefix: Option<Utf8PathBuf>,
+        /// Only print what would be merged
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Make a new store out of the indexed files in a directory of this one
+    Split {
+        /// Directory of this store the files are in
+        subdir: Utf8PathBuf,
+        /// Data directory of the new store, outside of this one
+        dest: Utf8PathBuf,
+        /// Remove the files from this store once they are in the new one
+        #[arg(long = "move")]
+        move_files: bool,
+    },
+    /// Serve the store over stdin and stdout, used by `sync` to reach stores over ssh
+    #[command(hide = true)]
+    Serve,
+    /// Upload the indexed files that are not yet in an S3 compatible bucket, keyed by their hash.
+    /// Credentials are read from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    #[cfg(feature = "s3")]
+    PushRemote {
+        #[command(flatten)]
+        bucket: BucketArgs,
+    },
+    /// Download the indexed files missing from the data directory from an S3 compatible bucket
+    /// they were pushed to. Credentials are read from `AWS_ACCESS_KEY_ID` and
+    /// `AWS_SECRET_ACCESS_KEY`.
+    #[cfg(feature = "s3")]
+    FetchRemote {
+        #[command(flatten)]
+        bucket: BucketArgs,
+    },
+    /// List the previous refreshes and a summary of the changes they applied, like git log
+    History {
+        /// Id of a refresh, to show every change it applied
+        id: Option<i64>,
+    },
+    /// Record the current state of the index under a name, or list the snapshots if no name is given
+    Snapshot {
+        /// Name of the new snapshot
+        name: Option<String>,
+    },
+    /// Show the files added, removed, moved and changed between two snapshots
+    Diff {
+        /// Snapshot to compare from
+        from: String,
+        /// Snapshot to compare to, the current index if not given
+        to: Option<String>,
+    },
+    /// Roll back the last operation that changed the index, restoring the files it trashed
+    Undo,
+    /// Pin the paths matching globs, so they are never removed as duplicates, and are kept instead
+    /// of their duplicates. Lists the pinned patterns, relative to the data directory, without any
+    Pin { patterns: Vec<String> },
+    /// Unpin patterns pinned with `pin`
+    Unpin {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// Export the index in another format
+    #[command(group(ArgGroup::new("output").required(true)))]
+    Export {
+        /// Render a static html gallery, with one album per directory, into DIR
+        #[arg(long, value_name = "DIR", group = "output")]
+        gallery: Option<Utf8PathBuf>,
+        /// Write the hash and path of every file into FILE, to check them with the tool given by
+        /// `--format` from the data directory
+        #[arg(long, value_name = "FILE", group = "output")]
+        checksums: Option<Utf8PathBuf>,
+        /// Tool the checksums are written for, sha256sum by default
+        #[arg(long, value_enum, conflicts_with_all = ["gallery", "bagit"])]
+        format: Option<export::ChecksumFormat>,
+        /// Make a bag of the files in DIR as the bagit spec lays them out, listing them with their
+        /// sha256. DIR must be new or empty
+        #[arg(long, value_name = "DIR", group = "output")]
+        bagit: Option<Utf8PathBuf>,
+    },
+    /// Manage the files removed by cstfs, which are kept in a trash until it is emptied
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommand,
+    },
+    /// List the files in the data directory that duplicate an indexed file, grouped by their
+    /// contents, without removing any
+    Duplicates {
+        /// Order of the groups
+        #[arg(long, value_enum, default_value_t)]
+        sort: dedupe::Sort,
+        /// How the groups are printed, to pass them on to other tools
+        #[arg(long, value_enum, default_value_t)]
+        format: dedupe::Format,
+    },
+    /// Show everything known about an indexed file
+    Info {
+        /// Path of the file, or its hash or the start of it
+        file: String,
+    },
+    /// Write the contents of an indexed file to stdout, from wherever a copy of it is
+    Cat {
+        /// Hash of the file or the start of it, or its path
+        file: String,
+    },
+    /// Open an indexed file with the viewer, the platform opener by default
+    Open {
+        /// Hash of the file or the start of it, its path, or a search query only it matches
+        target: String,
+    },
+    /// Print the path of an indexed file picked at random, or open it
+    Random {
+        /// Search query the picked files match, like in `search query`
+        query: Option<String>,
+        /// Amount of different files to pick
+        #[arg(short = 'n', long, default_value_t = 1)]
+        count: usize,
+        /// Open the picked files with the viewer instead of printing their paths
+        #[arg(long)]
+        open: bool,
+    },
+    /// Make an M3U playlist of the indexed audio files, with paths relative to the data directory
+    Playlist {
+        /// Search query the files in the playlist match, like in `search query`
+        query: Option<String>,
+        /// File the playlist is written to, instead of stdout
+        #[arg(short, long)]
+        output: Option<Utf8PathBuf>,
+        /// Point the entries of a playlist made before to where its files are now
+        #[arg(long, value_name = "PLAYLIST", conflicts_with_all = ["query", "output"])]
+        update: Option<Utf8PathBuf>,
+    },
+    /// List the indexed files, with their hash and size, filtered and sorted as asked
+    List {
+        /// Only list the files with this extension. Can be given multiple times
+        #[arg(long = "ext", value_name = "EXT")]
+        extensions: Vec<String>,
+        /// Only list the files at least this large, like `10MB`
+        #[arg(long, value_parser = |s: &str| parse_size::parse_size(s))]
+        min_size: Option<u64>,
+        /// Only list the files at most this large, like `10MB`
+        #[arg(long, value_parser = |s: &str| parse_size::parse_size(s))]
+        max_size: Option<u64>,
+        /// Only list the files inside of this directory
+        #[arg(long)]
+        under: Option<Utf8PathBuf>,
+        /// Only list the files modified since this date, like `2023-06-01`, or in this long, like
+        /// `30d`
+        #[arg(long, value_parser = list::parse_since)]
+        since: Option<DateTime<Utc>>,
+        /// Order of the files
+        #[arg(long, value_enum, default_value_t)]
+        sort: list::Sort,
+        /// List the files in the opposite order
+        #[arg(long)]
+        reverse: bool,
+        /// Only print the paths, each followed by a NUL byte instead of a newline, for `xargs -0`
+        #[arg(short = '0', long)]
+        null: bool,
+    },
+    /// Find the indexed files matching a query, and save queries to run them again later
+    Search {
+        #[command(subcommand)]
+        command: SearchCommand,
+    },
+    /// Summarize the index: how many files it has and how much space they take, by media type and
+    /// extension, its largest files and what the last refresh found
+    Stats {
+        /// How many of the largest files to list
+        #[arg(long, default_value_t = 10)]
+        largest: usize,
+        /// Show how much space the files in every directory take instead, like `du`
+        #[arg(long, conflicts_with = "largest")]
+        by_dir: bool,
+        /// Only show the directories at most this many levels deep with `--by-dir`
+        #[arg(long, requires = "by_dir")]
+        depth: Option<usize>,
+        /// Print metrics in the Prometheus text format instead, for the textfile collector of the
+        /// node exporter
+        #[arg(long, conflicts_with_all = ["largest", "by_dir"])]
+        prometheus: bool,
+    },
+    /// List the indexed files whose extension does not match their contents, like a PNG named
+    /// `.jpg`, as found out from the magic bytes at their start
+    Lint {
+        /// Rename them to the extension of their contents, updating the index
+        #[arg(long)]
+        rename: bool,
+    },
+    /// Show what is recorded about the store: when it was made, last refreshed and last changed,
+    /// which algorithm its files are hashed with and which version of cstfs last changed it
+    Status {
+        /// Print it as a JSON object instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Measure how fast the store can be walked, hashed with every algorithm and amount of jobs,
+    /// and indexed, to help choosing `jobs`, `hash` and `batch-size` in cstfs.toml
+    Bench {
+        /// How much of the files to hash, like `1GB`
+        #[arg(long, default_value = "256MB", value_parser = |s: &str| parse_size::parse_size(s))]
+        sample: u64,
+        /// How many made up files to insert into a scratch database
+        #[arg(long, default_value_t = 10000)]
+        inserts: usize,
+    },
+    /// Print the script completing the arguments of cstfs in `shell`, to be sourced by it. The
+    /// names of the stores registered when it is printed are completed too
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Go through the files in the data directory that duplicate an indexed file, choosing which
+    /// file of every group to keep and removing the others
+    Dedupe {
+        /// Keep the file this picks in every group without asking
+        #[arg(long, value_enum)]
+        keep: Option<dedupe::Keep>,
+        /// Choose in a full screen interface instead of answering a prompt for every group
+        #[cfg(feature = "tui")]
+        #[arg(long, conflicts_with_all = ["keep", "from"])]
+        tui: bool,
+        /// Keep and remove the files that this output of rmlint as JSON or of fdupes does, where
+        /// the first file of every group is kept
+        #[arg(long, conflicts_with = "keep", value_name = "FILE")]
+        from: Option<Utf8PathBuf>,
+    },
+    /// Check that the index is consistent with itself and with the data directory
+    Fsck {
+        /// Classes of problems to repair, every one of them is only reported otherwise
+        #[arg(long, value_enum, value_delimiter = ',')]
+        repair: Vec<fsck::Problem>,
+    },
+    /// Check that the contents of the indexed files still match their hashes, the least recently
+    /// verified first
+    Verify {
+        /// Only verify the files that were not verified in this long, like `30d`
+        #[arg(long, value_parser = humantime::parse_duration)]
+        older_than: Option<Duration>,
+        /// Stop after verifying files that take this much space, like `100GB`
+        #[arg(long, value_parser = |s: &str| parse_size::parse_size(s))]
+        budget: Option<u64>,
+        /// Only verify a random sample of this percentage of the files, like `1%`
+        #[arg(long, value_parser = parse_percentage)]
+        sample: Option<f64>,
+        /// Only verify a random sample of this many files
+        #[arg(long, conflicts_with = "sample")]
+        sample_count: Option<usize>,
+    },
+    /// Make keys to sign dumps and exported manifests with, and check their signatures
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage the database itself
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Manage the parity data files are repaired with, made with par2
+    Parity {
+        #[command(subcommand)]
+        command: ParityCommand,
+    },
+    /// Repair corrupted files with their parity data, every file that has it by default
+    Repair {
+        /// Indexed files to repair if they are corrupted
+        paths: Vec<Utf8PathBuf>,
+    },
+    /// Manage the thumbnail cache
+    Thumbs {
+        #[command(subcommand)]
+        command: ThumbsCommand,
+    },
+}
+
+#[cfg(feature = "s3")]
+#[derive(clap::Args)]
+struct BucketArgs {
+    /// Url of the S3 compatible service
+    #[arg(long)]
+    endpoint: String,
+    /// Name of the bucket
+    #[arg(long)]
+    bucket: String,
+    /// Region of the bucket
+    #[arg(long, default_value = "us-east-1")]
+    region: String,
+    /// Prefix prepended to the hash to make the key of every object
+    #[arg(long, default_value = "")]
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl From<BucketArgs> for s3::Bucket {
+    fn from(args: BucketArgs) -> Self {
+        Self {
+            endpoint: args.endpoint,
+            name: args.bucket,
+            region: args.region,
+            prefix: args.prefix,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum TrashCommand {
+    /// List the files in the trash
+    List,
+    /// Move files in the trash back to where they were
+    Restore {
+        /// Names of the files in the trash, as shown by `trash list`
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Permanently remove every file in the trash
+    Empty,
+}
+
+/// Search queries are made of terms like `ext:jpg`, `under:photos`, `min-size:10MB`,
+/// `max-size:1GB`, `since:2023-06-01` and `year:2023`, combined with `AND`, `OR` and `NOT`, and
+/// grouped with parentheses. Terms next to each other must all match.
+#[derive(Subcommand)]
+enum SearchCommand {
+    /// List the indexed files matching a query
+    Query {
+        /// Query, like `ext:jpg AND year:2023`
+        query: String,
+    },
+    /// Save a query to run it later by its name, replacing the one with the same name
+    Save { name: String, query: String },
+    /// List the files matching a saved query
+    Run { name: String },
+    /// List the saved queries
+    List,
+    /// Remove a saved query
+    Rm { name: String },
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Check the integrity of the database, refresh its query statistics and compact it
+    Maintain,
+    /// Copy the database into .cstfs/backups and check the copy, keeping the latest `backups` of
+    /// cstfs.toml
+    Backup,
+    /// Write the index to a JSON file that any version of cstfs can restore
+    Dump {
+        /// File the dump is written to, instead of stdout
+        #[arg(short, long)]
+        output: Option<Utf8PathBuf>,
+    },
+    /// Make the index of a store without one out of a dump
+    Restore {
+        /// Dump made by `db dump`
+        input: Utf8PathBuf,
+        /// Public key the dump must be signed with, made by `key generate`, to check it was not
+        /// changed since it was made
+        #[arg(long)]
+        public_key: Option<Utf8PathBuf>,
+    },
+}
 
-    let cli = Cli::parse();
-    let data_path = &cli.data_dir;
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Make a new key pair, writing the secret key to PATH and the public key to PATH.pub. Set
+    /// `signing-key` in cstfs.toml to the secret key to sign with it
+    Generate { path: Utf8PathBuf },
+    /// Check the signature of a file signed with a key, kept next to it in FILE.minisig
+    Verify {
+        file: Utf8PathBuf,
+        /// Public key of the key the file was signed with
+        #[arg(long)]
+        public_key: Utf8PathBuf,
+    },
+}
 
-    let db_path = db::path(data_path);
-    let db_exists = db_path
-        .try_exists()
-        .wrap_err("Could not check database existence")?;
+#[derive(Subcommand)]
+enum ParityCommand {
+    /// Make parity data for every indexed file without it, removing the parity data of hashes
+    /// no longer in the index
+    Generate,
+}
 
+#[derive(Subcommand)]
+enum ThumbsCommand {
+    /// Generate thumbnails for every indexed image and video, removing the ones whose hash is no
+    /// longer in the index
+    Generate {
+        /// Size of the box thumbnails are scaled down to fit in
+        #[arg(short, long, default_value_t = thumbs::DEFAULT_SIZE)]
+        size: u32,
+        /// If true, will regenerate thumbnails that are already cached
+        #[arg(short, long)]
+        force: bool,
+    },
+}
+
+impl ConfigArgs {
+    /// Configuration of the store at `data_path`, with the options given on the command line
+    /// taking precedence over the ones in its cstfs.toml
+    // One override for every option
+    #[allow(clippy::too_many_lines)]
+    fn load(&self, data_path: &Utf8Path) -> Result<config::Config> {
+        let mut config = config::load(data_path)?;
+        if let Some(hash) = self.hash {
+            config.hash = hash;
+        }
+        if let Some(jobs) = self.jobs {
+            config.jobs = Some(jobs);
+        }
+        if let Some(walk_jobs) = self.walk_jobs {
+            config.walk_jobs = walk_jobs;
+        }
+        if let Some(batch_size) = self.batch_size {
+            config.batch_size = batch_size;
+        }
+        if let Some(throttle) = self.throttle {
+            config.throttle = Some(throttle);
+        }
+        if let Some(retries) = self.retries {
+            config.retries = retries;
+        }
+        if self.nice {
+            config.nice = true;
+        }
+        config.ignore.extend(self.ignore.iter().cloned());
+        config.exclude.extend(self.exclude.iter().cloned());
+        if let Some(max_depth) = self.max_depth {
+            config.max_depth = Some(max_depth);
+        }
+        if let Some(detect) = self.detect {
+            config.detect = detect;
+        }
+        if self.include_hidden {
+            config.include_hidden = true;
+        }
+        if self.exclude_hidden {
+            config.include_hidden = false;
+        }
+        if self.follow_symlinks {
+            config.follow_symlinks = true;
+        }
+        if self.no_follow_symlinks {
+            config.follow_symlinks = false;
+        }
+        if self.index_symlinks {
+            config.index_symlinks = true;
+        }
+        if self.all_files {
+            config.all_files = true;
+        }
+        if self.xattr_cache {
+            config.xattr_cache = true;
+        }
+        if let Some(on_duplicate) = self.on_duplicate {
+            config.on_duplicate = on_duplicate;
+        }
+        if let Some(on_copy) = self.on_copy {
+            config.on_copy = on_copy;
+        }
+        if let Some(on_change) = self.on_change {
+            config.on_change = on_change;
+        }
+        if let Some(small_files) = self.small_files {
+            config.small_files = small_files;
+        }
+        if let Some(small_file_size) = self.small_file_size {
+            config.small_file_size = small_file_size;
+        }
+        if let Some(preview) = self.preview {
+            config.preview = preview;
+        }
+        if self.full_hash {
+            config.full_hash = true;
+        }
+        if self.no_trash {
+            config.use_trash = false;
+        }
+        if let Some(destination) = &self.destination {
+            config.destination.clone_from(destination);
+        }
+        if let Some(db_path) = &self.db_path {
+            // Unlike in cstfs.toml, a relative path given here is relative to where cstfs runs
+            let cwd = std::env::current_dir().wrap_err("Failed reading current directory")?;
+            let cwd = Utf8PathBuf::try_from(cwd).wrap_err("Current directory is not UTF-8")?;
+            config.db_path = Some(cwd.join(config::expand_home(db_path.as_str())));
+        }
+        if let Some(passphrase) = &self.passphrase {
+            config.passphrase = Some(passphrase.clone());
+        }
+        if let Some(keyfile) = &self.keyfile {
+            let cwd = std::env::current_dir().wrap_err("Failed reading current directory")?;
+            let cwd = Utf8PathBuf::try_from(cwd).wrap_err("Current directory is not UTF-8")?;
+            config.keyfile = Some(cwd.join(keyfile));
+        }
+        if self.read_only {
+            config.read_only = true;
+        }
+        if self.keep_going {
+            config.keep_going = true;
+        }
+        if self.fail_fast {
+            config.keep_going = false;
+        }
+        Ok(config)
+    }
+}
+
+/// Write the completion script of `shell` to stdout, completing the names of the stores in the
+/// registry after `--store`
+fn completions(shell: clap_complete::Shell) -> Result<()> {
+    let mut command = Cli::command();
+    let stores = config::stores().unwrap_or_default();
+    if !stores.is_empty() {
+        command = command.mut_arg("store", |arg| {
+            arg.value_parser(PossibleValuesParser::new(stores))
+        });
+    }
+    // Made in memory first, as it panics when it fails writing, like when piped to `head`
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut command, "cstfs", &mut script);
+    match std::io::stdout().lock().write_all(&script) {
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+            Err(e).wrap_err("Failed writing completion script")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse a percentage like `1%` or `0.5`, the percent sign being optional, as a fraction
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let percentage: f64 = s
+        .strip_suffix('%')
+        .unwrap_or(s)
+        .trim()
+        .parse()
+        .map_err(|e| format!("{e}"))?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err("must be between 0% and 100%".to_owned());
+    }
+    Ok(percentage / 100.0)
+}
+
+/// Let Ctrl-C stop the work gracefully through [`cstfs::interrupt`], instead of killing cstfs in
+/// the middle of it. Pressing it again exits right away.
+fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if cstfs::interrupted() {
+            std::process::exit(130);
+        }
+        warn!("Interrupted, stopping once the files being hashed are done. Press Ctrl-C again to exit right away");
+        cstfs::interrupt();
+    })
+    .wrap_err("Failed setting up Ctrl-C handler")
+}
+
+fn main() -> ExitCode {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // Help and the version are printed like errors, but are not
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                exit::Code::Usage
+            } else {
+                exit::Code::Clean
+            }
+            .into();
+        }
+    };
+    style::set_color(cli.color);
+    let theme = if console::colors_enabled_stderr() {
+        Theme::dark()
+    } else {
+        Theme::new()
+    };
+    if let Err(e) = HookBuilder::default().theme(theme).install() {
+        eprintln!("Error: {e:?}");
+        return exit::Code::Error.into();
+    }
+    let json = cli.progress == progress::Progress::Json;
+    match run(cli) {
+        Ok(code) => code.into(),
+        Err(e) => {
+            if json {
+                eprintln!("{}", events::error(&e));
+            } else {
+                eprintln!("Error: {e:?}");
+            }
+            exit::Code::of(&e).into()
+        }
+    }
+}
+
+// A single match dispatching every subcommand
+#[allow(clippy::too_many_lines)]
+fn run(cli: Cli) -> Result<exit::Code> {
+    // A store named on the command line is picked over the data directory in the environment
+    let data_path = &match (&cli.store, &cli.data_dir) {
+        (Some(name), _) => config::store(name).wrap_err("Failed finding store")?,
+        (None, Some(data_dir)) => data_dir.clone(),
+        (None, None) => std::env::var("CSTFS_DATA_DIR")
+            .ok()
+            .filter(|d| !d.is_empty())
+            .map_or_else(|| Utf8PathBuf::from("."), Utf8PathBuf::from),
+    };
+
+    let verbosity =
+        i8::try_from(cli.verbose).unwrap_or(i8::MAX) - i8::try_from(cli.quiet).unwrap_or(i8::MAX);
+    let json = cli.progress == progress::Progress::Json;
+    logging::init(verbosity, json, cli.log_file.as_deref())
+        .wrap_err("Failed setting up logging")?;
+
+    let config = &cli
+        .config
+        .load(data_path)
+        .wrap_err("Failed loading configuration")?;
+    if config.nice {
+        priority::lower().wrap_err("Failed lowering priority")?;
+    }
+    let terminal = &terminal::Terminal::new(data_path, config);
+    let json;
+    let reporter: &dyn Reporter = match cli.progress {
+        progress::Progress::Bar => terminal,
+        progress::Progress::Json => {
+            json = events::Json::new(terminal);
+            &json
+        }
+    };
+
+    let mut code = exit::Code::Clean;
     match cli.command {
-        Command::Init { force } => {
-            if db_exists && !force {
-                bail!("Cannot initialize a database that already exists");
+        Command::Init { force, resume } => {
+            handle_interrupts()?;
+            init::init(data_path, config, reporter, force, resume)
+                .wrap_err("Failed initializing db")?;
+        }
+        Command::Refresh { paths, full } => {
+            handle_interrupts()?;
+            let changes = refresh::refresh(data_path, config, reporter, &paths, full)
+                .wrap_err("Failed refreshing db contents")?;
+            if changes > 0 {
+                code = exit::Code::Changes;
+            }
+        }
+        Command::Prune => {
+            prune::prune(data_path, config).wrap_err("Failed pruning index")?;
+        }
+        Command::Add { paths } => {
+            add::add(data_path, config, reporter, &paths).wrap_err("Failed adding files")?;
+        }
+        Command::Contains { dir, missing } => {
+            contains::contains(data_path, config, reporter, &dir, missing)
+                .wrap_err("Failed checking directory")?;
+        }
+        Command::Ingest { src_dir, dry_run } => {
+            ingest::ingest(data_path, config, reporter, &src_dir, dry_run)
+                .wrap_err("Failed ingesting files")?;
+        }
+        Command::Organize { dry_run } => {
+            organize::organize(data_path, config, dry_run).wrap_err("Failed organizing files")?;
+        }
+        Command::Hash { paths } => hash::hash(config, &paths).wrap_err("Failed hashing files")?,
+        Command::Mv { from, to } => {
+            rename::rename(data_path, config, &from, &to).wrap_err("Failed moving file")?;
+        }
+        Command::Rm { paths, force } => {
+            remove::remove(data_path, config, &paths, force).wrap_err("Failed removing files")?;
+        }
+        Command::Sync {
+            other_dir,
+            push,
+            pull,
+            dry_run,
+            remote_cstfs,
+        } => {
+            let direction = match (push, pull) {
+                (true, _) => sync::Direction::Push,
+                (_, true) => sync::Direction::Pull,
+                _ => sync::Direction::Both,
+            };
+            sync::sync(
+                data_path,
+                config,
+                &other_dir,
+                &remote_cstfs,
+                direction,
+                dry_run,
+            )
+            .wrap_err("Failed syncing stores")?;
+        }
+        Command::Merge {
+            other_dir,
+            prefix,
+            dry_run,
+        } => {
+            merge::merge(data_path, config, &other_dir, prefix.as_deref(), dry_run)
+                .wrap_err("Failed merging stores")?;
+        }
+        Command::Split {
+            subdir,
+            dest,
+            move_files,
+        } => {
+            split::split(data_path, config, &subdir, &dest, move_files)
+                .wrap_err("Failed splitting store")?;
+        }
+        Command::Serve => {
+            remote::serve(data_path, config.clone()).wrap_err("Failed serving store")?;
+        }
+        #[cfg(feature = "s3")]
+        Command::PushRemote { bucket } => {
+            s3::push(data_path, config, &bucket.into()).wrap_err("Failed pushing to remote")?;
+        }
+        #[cfg(feature = "s3")]
+        Command::FetchRemote { bucket } => {
+            s3::fetch(data_path, config, &bucket.into()).wrap_err("Failed fetching from remote")?;
+        }
+        Command::History { id } => {
+            history::history(data_path, config, id).wrap_err("Failed showing history")?;
+        }
+        Command::Snapshot { name: Some(name) } => {
+            snapshot::create(data_path, config, &name).wrap_err("Failed creating snapshot")?;
+        }
+        Command::Snapshot { name: None } => {
+            snapshot::list(data_path, config).wrap_err("Failed listing snapshots")?;
+        }
+        Command::Diff { from, to } => {
+            let differences = snapshot::diff(data_path, config, &from, to.as_deref())
+                .wrap_err("Failed diffing snapshots")?;
+            if differences > 0 {
+                code = exit::Code::Changes;
+            }
+        }
+        Command::Undo => undo::undo(data_path, config).wrap_err("Failed undoing last operation")?,
+        Command::Pin { patterns } if patterns.is_empty() => {
+            pin::list(data_path, config).wrap_err("Failed listing pins")?;
+        }
+        Command::Pin { patterns } => {
+            pin::pin(data_path, config, &patterns).wrap_err("Failed pinning paths")?;
+        }
+        Command::Unpin { patterns } => {
+            pin::unpin(data_path, config, &patterns).wrap_err("Failed unpinning paths")?;
+        }
+        Command::Export {
+            gallery,
+            checksums,
+            format,
+            bagit,
+        } => {
+            if let Some(out_dir) = gallery {
+                export::gallery(data_path, config, &out_dir)
+                    .wrap_err("Failed exporting gallery")?;
+            }
+            if let Some(output) = checksums {
+                export::checksums(data_path, config, &output, format.unwrap_or_default())
+                    .wrap_err("Failed exporting checksums")?;
+            }
+            if let Some(out_dir) = bagit {
+                export::bagit(data_path, config, &out_dir).wrap_err("Failed making bag")?;
             }
-            if force {
-                println!("Regenerating database");
-                crate::utils::remove_file(&db_path)
-                    .wrap_err("Failed removing database to reinitialize")?;
+        }
+        Command::Trash { command } => match command {
+            TrashCommand::List => trash::list(data_path).wrap_err("Failed listing trash")?,
+            TrashCommand::Restore { names } => {
+                trash::restore(data_path, config, &names)
+                    .wrap_err("Failed restoring from trash")?;
             }
-            match init::init(data_path).wrap_err("Failed initializing db") {
-                Ok(()) => {}
-                e @ Err(_) => {
-                    crate::utils::remove_file(&db_path)
-                        .wrap_err("Failed to remove db file after failed init")?;
-                    e?;
-                }
+            TrashCommand::Empty => {
+                trash::empty(data_path, config).wrap_err("Failed emptying trash")?;
             }
+        },
+        Command::Duplicates { sort, format } => {
+            dedupe::list(data_path, config, reporter, sort, format)
+                .wrap_err("Failed listing duplicates")?;
         }
-        Command::Refresh => {
-            refresh::refresh(data_path).wrap_err("Failed refreshing db contents")?;
+        Command::Info { file } => {
+            info::info(data_path, config, &file).wrap_err("Failed showing file")?;
         }
-    };
+        Command::Cat { file } => {
+            cat::cat(data_path, config, &file).wrap_err("Failed writing file")?;
+        }
+        Command::Open { target } => {
+            open::open(data_path, config, &target).wrap_err("Failed opening file")?;
+        }
+        Command::Random { query, count, open } => {
+            random::random(data_path, config, query.as_deref(), count, open)
+                .wrap_err("Failed picking a random file")?;
+        }
+        Command::Playlist {
+            query,
+            output,
+            update,
+        } => {
+            if let Some(playlist) = update {
+                playlist::update(data_path, config, &playlist)
+                    .wrap_err("Failed updating playlist")?;
+            } else {
+                playlist::create(data_path, config, query.as_deref(), output.as_deref())
+                    .wrap_err("Failed making playlist")?;
+            }
+        }
+        Command::List {
+            extensions,
+            min_size,
+            max_size,
+            under,
+            since,
+            sort,
+            reverse,
+            null,
+        } => {
+            let filter = list::Filter {
+                extensions,
+                min_size,
+                max_size,
+                under,
+                since,
+            };
+            list::list(data_path, config, &filter, sort, reverse, null)
+                .wrap_err("Failed listing files")?;
+        }
+        Command::Search { command } => match command {
+            SearchCommand::Query { query } => {
+                search::query(data_path, config, &query).wrap_err("Failed searching files")?;
+            }
+            SearchCommand::Save { name, query } => {
+                search::save(data_path, config, &name, &query).wrap_err("Failed saving search")?;
+            }
+            SearchCommand::Run { name } => {
+                search::run(data_path, config, &name).wrap_err("Failed searching files")?;
+            }
+            SearchCommand::List => {
+                search::list(data_path, config).wrap_err("Failed listing saved searches")?;
+            }
+            SearchCommand::Rm { name } => {
+                search::remove(data_path, config, &name).wrap_err("Failed removing search")?;
+            }
+        },
+        Command::Stats {
+            by_dir: true,
+            depth,
+            ..
+        } => {
+            stats::by_dir(data_path, config, depth).wrap_err("Failed showing disk usage")?;
+        }
+        Command::Stats {
+            prometheus: true, ..
+        } => {
+            stats::prometheus(data_path, config).wrap_err("Failed showing metrics")?;
+        }
+        Command::Stats { largest, .. } => {
+            stats::stats(data_path, config, largest).wrap_err("Failed showing stats")?;
+        }
+        Command::Lint { rename } => {
+            lint::lint(data_path, config, rename).wrap_err("Failed linting files")?;
+        }
+        Command::Status { json } => {
+            status::status(data_path, config, json).wrap_err("Failed showing status")?;
+        }
+        Command::Completions { shell } => completions(shell)?,
+        Command::Bench { sample, inserts } => {
+            bench::bench(data_path, config, sample, inserts).wrap_err("Failed benchmarking")?;
+        }
+        Command::Dedupe {
+            from: Some(from), ..
+        } => {
+            dedupe::dedupe(data_path, config, reporter, |groups| {
+                dedupe::import(data_path, groups, &from)
+            })
+            .wrap_err("Failed removing duplicates")?;
+        }
+        Command::Dedupe {
+            keep: Some(keep), ..
+        } => {
+            dedupe::dedupe(data_path, config, reporter, |groups| {
+                dedupe::choose(data_path, config, groups, keep)
+            })
+            .wrap_err("Failed removing duplicates")?;
+        }
+        #[cfg(feature = "tui")]
+        Command::Dedupe { tui: true, .. } => {
+            dedupe::dedupe(data_path, config, reporter, tui::choose_kept)
+                .wrap_err("Failed removing duplicates")?;
+        }
+        Command::Dedupe { .. } => {
+            dedupe::dedupe(data_path, config, reporter, terminal::choose_kept)
+                .wrap_err("Failed removing duplicates")?;
+        }
+        Command::Fsck { repair } => {
+            fsck::fsck(data_path, config, reporter, &repair).wrap_err("Failed checking index")?;
+        }
+        Command::Verify {
+            older_than,
+            budget,
+            sample,
+            sample_count,
+        } => {
+            let sample = sample
+                .map(verify::Sample::Fraction)
+                .or_else(|| sample_count.map(verify::Sample::Count));
+            let selection = verify::Selection {
+                older_than,
+                sample,
+                budget,
+            };
+            verify::verify(data_path, config, reporter, selection)
+                .wrap_err("Failed verifying files")?;
+        }
+        Command::Db {
+            command: DbCommand::Maintain,
+        } => maintain::maintain(data_path, config).wrap_err("Failed maintaining database")?,
+        Command::Db {
+            command: DbCommand::Backup,
+        } => backup::backup(data_path, config).wrap_err("Failed backing up database")?,
+        Command::Db {
+            command: DbCommand::Dump { output },
+        } => dump::dump(data_path, config, output.as_deref()).wrap_err("Failed dumping index")?,
+        Command::Db {
+            command: DbCommand::Restore { input, public_key },
+        } => dump::restore(data_path, config, &input, public_key.as_deref())
+            .wrap_err("Failed restoring index")?,
+        Command::Key {
+            command: KeyCommand::Generate { path },
+        } => sign::generate(&path).wrap_err("Failed making key")?,
+        Command::Key {
+            command: KeyCommand::Verify { file, public_key },
+        } => {
+            sign::verify(&file, &public_key).wrap_err("Failed checking signature")?;
+        }
+        Command::Parity {
+            command: ParityCommand::Generate,
+        } => parity::generate(data_path, config).wrap_err("Failed generating parity data")?,
+        Command::Repair { paths } => {
+            parity::repair(data_path, config, reporter, &paths)
+                .wrap_err("Failed repairing files")?;
+        }
+        Command::Thumbs {
+            command: ThumbsCommand::Generate { size, force },
+        } => {
+            thumbs::generate(data_path, config, size, force)
+                .wrap_err("Failed generating thumbnails")?;
+        }
+    }
 
-    Ok(())
+    Ok(code)
 }
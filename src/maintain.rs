@@ -0,0 +1,53 @@
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::lock::Lock;
+
+/// Total size of the database and the files sqlite keeps next to it
+fn db_size(data_path: &Utf8Path, config: &Config) -> Result<u64> {
+    let mut size = 0;
+    for p in db::paths(data_path, config) {
+        match p.metadata() {
+            Ok(m) => size += m.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).wrap_err_with(|| format!("Failed reading metadata of {p}")),
+        }
+    }
+    Ok(size)
+}
+
+/// Check the integrity of the database, then refresh its query statistics and compact it
+pub fn maintain(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+
+    info!("Checking database integrity");
+    let problems = db::integrity_check(&conn).wrap_err("Failed checking integrity")?;
+    if !problems.is_empty() {
+        for p in &problems {
+            warn!("{p}");
+        }
+        bail!(
+            "Found {} problems in the database, not compacting it",
+            problems.len()
+        );
+    }
+    info!("No problems found");
+
+    info!("Analyzing database");
+    db::analyze(&conn).wrap_err("Failed analyzing database")?;
+
+    let before = db_size(data_path, config)?;
+    info!("Compacting database");
+    db::vacuum(&conn).wrap_err("Failed compacting database")?;
+    let after = db_size(data_path, config)?;
+    info!("Done, database went from {before} to {after} bytes");
+
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::config::{self, Config};
+use crate::sync::{LocalStore, Store};
+use crate::utils::{canonicalize, check_relative, clean_relative, full_path};
+
+/// Whether the files at `a` and `b` have the same contents, read byte by byte
+fn same_contents(a: &Utf8Path, b: &Utf8Path) -> Result<bool> {
+    let open = |p: &Utf8Path| -> Result<(u64, BufReader<File>)> {
+        let file = File::open(p).wrap_err_with(|| format!("Failed opening \"{p}\""))?;
+        let size = file
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata for \"{p}\""))?
+            .len();
+        Ok((size, BufReader::new(file)))
+    };
+    let ((size_a, a), (size_b, b)) = (open(a)?, open(b)?);
+    if size_a != size_b {
+        return Ok(false);
+    }
+    for (x, y) in a.bytes().zip(b.bytes()) {
+        if x.wrap_err("Failed reading file")? != y.wrap_err("Failed reading file")? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Fold the index of the store at `other` into this one, copying its files into `prefix` inside
+/// of the data directory, the name of the other data directory by default.
+///
+/// Files whose hash is already indexed here are reported as duplicates and left out, after
+/// checking their contents are the same. A different file with the same hash as one here cannot be
+/// indexed alongside it, so it is left out too, with a warning. If `dry_run` is set, only print
+/// what would be merged.
+pub fn merge(
+    data_path: &Utf8Path,
+    config: &Config,
+    other: &Utf8Path,
+    prefix: Option<&Utf8Path>,
+    dry_run: bool,
+) -> Result<()> {
+    let other_config = config::load(other).wrap_err("Failed loading other store config")?;
+    if other_config.hash.name() != config.hash.name() {
+        bail!(
+            "\"{other}\" is indexed with {} and this store with {}, rehash one of them first",
+            other_config.hash.name(),
+            config.hash.name()
+        );
+    }
+    let prefix = match prefix {
+        Some(prefix) => clean_relative(prefix)?,
+        None => canonicalize(other)
+            .ok()
+            .and_then(|p| p.file_name().map(Utf8PathBuf::from))
+            .ok_or_else(|| eyre!("Could not name a directory after \"{other}\", give a prefix"))?,
+    };
+
+    let mut local = LocalStore::open(data_path, config.clone())
+        .wrap_err("Failed opening local store")?
+        .recorded_as("merge");
+    let mut other_store =
+        LocalStore::open(other, other_config).wrap_err("Failed opening other store")?;
+
+    info!("Merging \"{other}\" into \"{data_path}\" under \"{prefix}\"");
+    let now = Instant::now();
+    // Files merged are added to it, so the duplicates among them are left out too
+    let mut indexed: HashMap<String, Utf8PathBuf> = local
+        .files()?
+        .into_iter()
+        .map(|(path, hash)| (hash, path.into()))
+        .collect();
+
+    let (mut merged, mut duplicates, mut skipped) = (0, 0, 0);
+    for (path, hash) in other_store.files()? {
+        let path = Utf8Path::new(&path);
+        if let Err(e) = check_relative(path) {
+            warn!("Skipping a file of \"{other}\": {e}");
+            skipped += 1;
+            continue;
+        }
+        if let Some(local_path) = indexed.get(&hash) {
+            let (a, b) = (full_path(data_path, local_path), full_path(other, path));
+            if same_contents(&a, &b)
+                .wrap_err_with(|| format!("Failed comparing \"{b}\" with \"{a}\""))?
+            {
+                info!("Not merging \"{path}\", duplicate of \"{local_path}\"");
+                duplicates += 1;
+            } else {
+                warn!("Skipping \"{b}\", it has the same hash {hash} as \"{a}\" but different contents");
+                skipped += 1;
+            }
+            continue;
+        }
+        let dst = prefix.join(path);
+        if local.exists(&dst)? {
+            warn!("Skipping \"{path}\", a different file already exists at \"{dst}\"");
+            skipped += 1;
+            continue;
+        }
+        if dry_run {
+            info!("Would merge \"{path}\" into \"{dst}\"");
+            merged += 1;
+            continue;
+        }
+
+        let (size, mut contents) = other_store
+            .open(path)
+            .wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+        local
+            .put(&dst, &hash, size, &mut contents)
+            .wrap_err_with(|| format!("Failed copying \"{path}\""))?;
+        indexed.insert(hash, dst);
+        merged += 1;
+    }
+
+    let elapsed = now.elapsed();
+    let verb = if dry_run { "Would merge" } else { "Merged" };
+    info!("{verb} {merged} files from \"{other}\". Took {elapsed:.2?}");
+    info!("Left out {duplicates} duplicates, and {skipped} files that could not be merged");
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+use crate::db::HistoryDiff;
+use crate::refresh::Diff;
+
+/// Most changes sent to the webhook at once, so a refresh of a whole library does not make a
+/// request too large for it. The counts still cover every change.
+const MAX_CHANGES: usize = 1000;
+
+/// Send the changes a refresh of `data_path` found, `diffs`, to the webhook and as a desktop
+/// notification, as `config` says. Nothing is sent if there are none.
+pub fn refresh(data_path: &Utf8Path, config: &Config, diffs: &[Diff]) {
+    if diffs.is_empty() {
+        return;
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for diff in diffs {
+        *counts.entry(diff.ty.name()).or_default() += 1;
+    }
+
+    if let Some(url) = &config.notify.webhook {
+        let changes: Vec<_> = diffs
+            .iter()
+            .take(MAX_CHANGES)
+            .map(|d| {
+                let d = HistoryDiff::from(d);
+                json!({
+                    "kind": d.kind,
+                    "path": d.path,
+                    "hash": d.hash,
+                    "orig_path": d.orig_path,
+                    "prev_hash": d.prev_hash,
+                })
+            })
+            .collect();
+        let event = json!({
+            "event": "refresh",
+            "data_dir": data_path,
+            "counts": counts,
+            "changes": changes,
+            "omitted": diffs.len().saturating_sub(MAX_CHANGES),
+        });
+        if let Err(e) = post(url, &event) {
+            warn!("Failed sending changes to webhook: {e:#}");
+        }
+    }
+
+    if config.notify.desktop {
+        let summary: Vec<_> = counts.iter().map(|(k, n)| format!("{n} {k}")).collect();
+        let body = format!("{}: {}", data_path, summary.join(", "));
+        if let Err(e) = desktop("cstfs refresh", &body) {
+            warn!("Failed showing desktop notification: {e:#}");
+        }
+    }
+}
+
+/// POST `event` as JSON to `url`
+#[cfg(feature = "webhook")]
+fn post(url: &str, event: &serde_json::Value) -> Result<()> {
+    use color_eyre::eyre::bail;
+
+    /// How long the whole request may take, so an unreachable webhook does not hold up the refresh
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+    debug!("Sending changes to {url}");
+    let agent = ureq::Agent::config_builder()
+        .http_status_as_error(false)
+        .timeout_global(Some(TIMEOUT))
+        .build()
+        .new_agent();
+    let mut res = agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(event.to_string())
+        .wrap_err("POST request failed")?;
+    if !res.status().is_success() {
+        let body = res.body_mut().read_to_string().unwrap_or_default();
+        bail!("POST request failed with status {}: {body}", res.status());
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "webhook"))]
+fn post(url: &str, _event: &serde_json::Value) -> Result<()> {
+    color_eyre::eyre::bail!(
+        "Cannot send changes to {url}, cstfs was built without the `webhook` feature"
+    )
+}
+
+/// Show a desktop notification titled `title`, with the notifier of the platform
+fn desktop(title: &str, body: &str) -> Result<()> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        // Passed as arguments, as AppleScript strings are not quoted like Rust ones
+        let mut cmd = Command::new("osascript");
+        cmd.args([
+            "-e",
+            "on run argv",
+            "-e",
+            "display notification (item 2 of argv) with title (item 1 of argv)",
+            "-e",
+            "end run",
+            title,
+            body,
+        ]);
+        cmd
+    } else {
+        let mut cmd = Command::new("notify-send");
+        cmd.arg(title).arg(body);
+        cmd
+    };
+    debug!("Showing desktop notification: {body}");
+    let status = cmd.status().wrap_err("Failed running notifier")?;
+    if !status.success() {
+        color_eyre::eyre::bail!("Notifier failed: {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+use std::process::Command;
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::info;
+
+use crate::cat::locate;
+use crate::config::Config;
+use crate::db;
+use crate::info::resolve;
+use crate::list;
+use crate::search;
+use crate::utils::Abbrev;
+
+/// Open the indexed file `target` refers to with the viewer, `xdg-open` or the opener of the
+/// platform by default.
+///
+/// `target` is the path of the file, the start of its hash, or a search query like
+/// `ext:jpg year:2019` that only one indexed file matches. When several do they are printed
+/// instead, to pick one of them by its hash.
+pub fn open(data_path: &Utf8Path, config: &Config, target: &str) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let file = match resolve(&conn, data_path, target) {
+        Ok(file) => file,
+        Err(e) => {
+            let mut files = search::matching(&conn, target).map_err(|_| e)?;
+            match files.len() {
+                0 => bail!("No indexed file matches \"{target}\""),
+                1 => files.swap_remove(0),
+                n => {
+                    list::print(&files, &Abbrev::new(&conn, config)?);
+                    bail!("{n} indexed files match \"{target}\", open one of them by its hash");
+                }
+            }
+        }
+    };
+    launch(config, &locate(&conn, data_path, &file)?)
+}
+
+/// Open the file at `full_path` with the viewer, waiting for it to exit
+pub(crate) fn launch(config: &Config, full_path: &Utf8Path) -> Result<()> {
+    let viewer = config.viewer();
+    let mut args = viewer.split_whitespace();
+    let program = args.next().ok_or_else(|| eyre!("No viewer configured"))?;
+    info!("Opening \"{full_path}\"");
+    let status = Command::new(program)
+        .args(args)
+        .arg(full_path)
+        .status()
+        .wrap_err_with(|| format!("Failed running viewer {viewer}"))?;
+    if !status.success() {
+        bail!("Viewer {viewer} failed opening \"{full_path}\": {status}");
+    }
+    Ok(())
+}
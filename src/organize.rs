@@ -0,0 +1,117 @@
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::Transaction;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::rename;
+use crate::template::{self, Fields};
+use crate::utils;
+
+/// Move every indexed file to the path `config.destination` gives for it, returning the moves done
+/// so far even if one of them fails
+fn move_files(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    dry_run: bool,
+    moved: &mut Vec<(Utf8PathBuf, Utf8PathBuf)>,
+) -> Result<()> {
+    let mut files = db::files(transaction).wrap_err("Failed fetching files from db")?;
+    files.sort_unstable();
+    for (path, hash) in files {
+        let path = Utf8PathBuf::from(path);
+        let full_path = utils::full_path(data_path, &path);
+        let dst = template::render(
+            &config.destination,
+            &Fields::new(data_path, &full_path, &hash),
+        )?;
+        if dst == path {
+            continue;
+        }
+        if data_path
+            .join(&dst)
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{dst}\""))?
+        {
+            warn!("Leaving \"{path}\" in place, a different file already exists at \"{dst}\"");
+            continue;
+        }
+        if dry_run {
+            info!("Would move: {path} -> {dst}");
+            moved.push((path, dst));
+            continue;
+        }
+
+        db::update_path(transaction, &path, &dst, &hash)
+            .wrap_err_with(|| format!("Failed updating path of {path}"))?;
+        let action = JournalAction::Rename {
+            path: dst.clone(),
+            prev_path: path.clone(),
+            hash,
+        };
+        db::record(transaction, operation, &action).wrap_err("Failed recording move")?;
+        rename::move_file(data_path, config, &path, &dst)
+            .wrap_err_with(|| format!("Failed moving \"{path}\" to \"{dst}\""))?;
+        info!("Moved: {path} -> {dst}");
+        moved.push((path, dst));
+    }
+    Ok(())
+}
+
+/// Move the indexed files to the paths the destination template in `config` gives for them,
+/// updating the index in the same transaction.
+///
+/// If anything fails, the files already moved are put back. If `dry_run` is set, only print what
+/// would be moved.
+pub fn organize(data_path: &Utf8Path, config: &Config, dry_run: bool) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    template::check(&config.destination).wrap_err("Invalid destination template")?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating organize transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "organize").wrap_err("Failed recording operation")?;
+
+    info!("Organizing \"{data_path}\" as \"{}\"", config.destination);
+    let now = Instant::now();
+    let mut moved = vec![];
+    let res = move_files(
+        &transaction,
+        operation,
+        data_path,
+        config,
+        dry_run,
+        &mut moved,
+    )
+    .and_then(|()| {
+        if dry_run {
+            return Ok(());
+        }
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")
+    });
+    if let Err(e) = res {
+        // Put the files back, so they are still where the index says they are
+        if !dry_run {
+            for (path, dst) in moved.iter().rev() {
+                rename::move_file(data_path, config, dst, path)
+                    .wrap_err_with(|| format!("Failed moving \"{dst}\" back to \"{path}\""))?;
+            }
+        }
+        return Err(e);
+    }
+
+    let elapsed = now.elapsed();
+    let verb = if dry_run { "Would move" } else { "Moved" };
+    info!("{verb} {} files. Took {elapsed:.2?}", moved.len());
+
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! Parity data of the indexed files, to repair them when `verify` finds them corrupted.
+//!
+//! It is made and used by par2, which must be in the `PATH`. Every file gets parity data of its
+//! own, keyed by its hash like the thumbnails are, so it still applies after the file is moved.
+//! Files are given to par2 named after their hash, from a staging directory, as par2 remembers
+//! the names of the files it protects.
+
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::exit::Failures;
+use crate::lock::Lock;
+use crate::report::Reporter;
+use crate::utils::{self, full_path, hash_file, hash_files, relative_path};
+
+/// Directory where parity data is kept
+#[must_use]
+pub fn dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    utils::cstfs_dir(data_path).join("parity")
+}
+
+/// Path of the index file of the parity data of the file with hash `hash`. par2 keeps the
+/// recovery blocks in files next to it, named like it with the range of blocks they have.
+#[must_use]
+pub fn path(data_path: &Utf8Path, hash: &str) -> Utf8PathBuf {
+    dir(data_path).join(format!("{hash}.par2"))
+}
+
+/// Directory files are given to par2 from, named after their hash
+fn staging_dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    dir(data_path).join("staging")
+}
+
+/// Run par2 with `args`, failing with what it printed if it fails
+fn par2(args: &[&str]) -> Result<()> {
+    let output = Command::new("par2")
+        .args(args)
+        .output()
+        .wrap_err("Failed running par2, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "par2 exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Make parity data for the file at `full_path` with hash `hash`, able to repair `redundancy`
+/// percent of it
+fn create(data_path: &Utf8Path, full_path: &Utf8Path, hash: &str, redundancy: u8) -> Result<()> {
+    let staging = staging_dir(data_path);
+    let staged = staging.join(hash);
+    utils::remove_file(&staged).wrap_err("Failed removing previous staged file")?;
+    if std::fs::hard_link(full_path, &staged).is_err() {
+        std::fs::copy(full_path, &staged).wrap_err("Failed staging file")?;
+    }
+    let res = par2(&[
+        "create",
+        "-q",
+        "-q",
+        &format!("-r{redundancy}"),
+        "-n1",
+        &format!("-B{staging}"),
+        path(data_path, hash).as_str(),
+        staged.as_str(),
+    ]);
+    utils::remove_file(&staged).wrap_err("Failed removing staged file")?;
+    res
+}
+
+/// Remove the parity data of every hash that is not in `hashes`, returning how many were removed
+fn remove_stale(data_path: &Utf8Path, hashes: &HashSet<&str>) -> Result<usize> {
+    let mut removed = HashSet::new();
+    for entry in dir(data_path)
+        .read_dir_utf8()
+        .wrap_err("Failed reading parity directory")?
+    {
+        let entry = entry.wrap_err("Failed reading parity directory entry")?;
+        let p = entry.path();
+        // Like `<hash>.par2` and `<hash>.vol00+10.par2`
+        let Some((hash, _)) = entry.file_name().split_once('.') else {
+            continue;
+        };
+        if hashes.contains(hash) {
+            continue;
+        }
+        utils::remove_file(p).wrap_err_with(|| format!("Failed removing parity data {p}"))?;
+        removed.insert(hash.to_owned());
+    }
+    Ok(removed.len())
+}
+
+/// Make parity data for every indexed file that has none yet, and remove the parity data of hashes
+/// no longer in the index
+pub fn generate(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    let protected: HashSet<String> = db::parity_hashes(&conn)
+        .wrap_err("Failed fetching parity data from db")?
+        .into_iter()
+        .collect();
+
+    let staging = staging_dir(data_path);
+    std::fs::create_dir_all(&staging)
+        .wrap_err_with(|| format!("Failed creating parity directory \"{staging}\""))?;
+
+    info!("Generating parity data at \"{}\"", dir(data_path));
+    let now = Instant::now();
+    let (mut generated, mut failed) = (0, 0);
+    let mut done = HashSet::new();
+    for (p, hash) in &files {
+        if !done.insert(hash.as_str()) {
+            continue;
+        }
+        if protected.contains(hash) && path(data_path, hash).exists() {
+            continue;
+        }
+        if utils::interrupted() {
+            break;
+        }
+        let p = full_path(data_path, Utf8Path::new(p));
+        match create(data_path, &p, hash, config.parity_redundancy) {
+            Ok(()) => {
+                // Recorded right away, so an interrupted run does not lose what it made
+                db::insert_parity(&conn, hash, Utc::now().timestamp())
+                    .wrap_err("Failed recording parity data")?;
+                generated += 1;
+            }
+            Err(e) => {
+                warn!("Could not generate parity data for \"{p}\": {e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    let hashes: HashSet<&str> = files.iter().map(|(_, h)| h.as_str()).collect();
+    let removed = remove_stale(data_path, &hashes).wrap_err("Failed removing stale parity data")?;
+    for hash in protected.iter().filter(|h| !hashes.contains(h.as_str())) {
+        db::remove_parity(&conn, hash).wrap_err("Failed removing parity data from db")?;
+    }
+
+    let elapsed = now.elapsed();
+    info!(
+        "Generated parity data for {generated} files ({failed} failed), removed {removed} stale. Took {elapsed:.2?}"
+    );
+    if utils::interrupted() {
+        bail!("Interrupted, run `cstfs parity generate` again to carry on");
+    }
+    Ok(())
+}
+
+/// Whether the file with hash `hash` has parity data to repair it with
+#[must_use]
+pub fn exists(data_path: &Utf8Path, hash: &str) -> bool {
+    path(data_path, hash).exists()
+}
+
+/// Repair the file at `full_path`, whose contents should have hash `hash`, with its parity data
+fn repair_file(
+    data_path: &Utf8Path,
+    config: &Config,
+    full_path: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    let staging = staging_dir(data_path);
+    std::fs::create_dir_all(&staging)
+        .wrap_err_with(|| format!("Failed creating parity directory \"{staging}\""))?;
+    // A copy, as par2 replaces the file it repairs. A link left behind by an interrupted run is
+    // removed first, to not write through it.
+    let staged = staging.join(hash);
+    utils::remove_file(&staged).wrap_err("Failed removing previous staged file")?;
+    std::fs::copy(full_path, &staged).wrap_err("Failed staging file")?;
+    let res = par2(&[
+        "repair",
+        "-q",
+        "-q",
+        &format!("-B{staging}"),
+        path(data_path, hash).as_str(),
+    ])
+    .and_then(|()| {
+        let repaired = hash_file(&staged, config)?;
+        if repaired != hash {
+            bail!("par2 could not bring it back, its hash is still {repaired}");
+        }
+        std::fs::copy(&staged, full_path).wrap_err("Failed writing repaired file")?;
+        Ok(())
+    });
+    // With the damaged copy par2 keeps as a backup, named like it with a number added
+    for entry in staging
+        .read_dir_utf8()
+        .wrap_err("Failed reading parity directory")?
+    {
+        let entry = entry.wrap_err("Failed reading parity directory entry")?;
+        if entry.file_name().starts_with(hash) {
+            utils::remove_file(entry.path()).wrap_err("Failed removing staged file")?;
+        }
+    }
+    res
+}
+
+/// Repair the indexed files at `paths` that are corrupted, or every indexed file with parity data
+/// that is if `paths` is empty.
+///
+/// They are hashed to find out, sending the progress to `reporter`. Fails with [`Failures`] if any of them could not be repaired.
+pub fn repair(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    paths: &[Utf8PathBuf],
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let indexed: HashMap<Utf8PathBuf, String> = db::files(&conn)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|(p, h)| (p.into(), h))
+        .collect();
+    let files: Vec<(Utf8PathBuf, String)> = if paths.is_empty() {
+        indexed
+            .into_iter()
+            .filter(|(_, h)| exists(data_path, h))
+            .collect()
+    } else {
+        paths
+            .iter()
+            .map(|p| {
+                let p = relative_path(data_path, p)?;
+                let hash = indexed
+                    .get(&p)
+                    .ok_or_else(|| eyre!("\"{p}\" is not indexed"))?
+                    .clone();
+                Ok((p, hash))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    info!("Checking {} files in \"{data_path}\"", files.len());
+    let now = Instant::now();
+    let full_paths: Vec<_> = files.iter().map(|(p, _)| full_path(data_path, p)).collect();
+    // Cached hashes would hide the corruption
+    let uncached = Config {
+        xattr_cache: false,
+        ..config.clone()
+    };
+    let hashes = hash_files(&full_paths, &uncached, reporter);
+
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating repair transaction")?;
+    let (mut repaired, mut failed) = (0, 0);
+    for (((path, hash), full_path), current) in files.iter().zip(&full_paths).zip(hashes) {
+        match current {
+            Ok(current) if current == *hash => continue,
+            Ok(_) if !exists(data_path, hash) => {
+                warn!("\"{path}\" is corrupted, but has no parity data to repair it with");
+                failed += 1;
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Could not repair \"{path}\": {e:#}");
+                failed += 1;
+                continue;
+            }
+        }
+        match repair_file(data_path, &uncached, full_path, hash) {
+            Ok(()) => {
+                info!("Repaired \"{path}\"");
+                let stat = utils::stat(full_path)?;
+                db::set_stat(&transaction, path, &stat).wrap_err("Failed recording size")?;
+                db::set_verified(&transaction, path, hash, Utc::now().timestamp())
+                    .wrap_err("Failed recording verification")?;
+                repaired += 1;
+            }
+            Err(e) => {
+                warn!("Could not repair \"{path}\": {e:#}");
+                failed += 1;
+            }
+        }
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+
+    let elapsed = now.elapsed();
+    info!("Repaired {repaired} files, {failed} could not be. Took {elapsed:.2?}");
+    if failed > 0 {
+        bail!(Failures(format!("{failed} files could not be repaired")));
+    }
+    Ok(())
+}
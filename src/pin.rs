@@ -0,0 +1,95 @@
+//! Pinned paths, which are never removed as duplicates.
+//!
+//! Pins are globs of paths relative to the data directory, kept in the database. A pinned directory
+//! pins every file inside of it. When a pinned file has duplicates, it is the one kept: dedupe
+//! keeps it whichever file it was told to keep, and refresh removes the other file instead, so
+//! originals always win over the copies exported from them.
+
+use camino::Utf8Path;
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use globset::{Glob, GlobSet};
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::utils::{self, relative_path};
+
+/// Patterns pinning paths, to check whether a path is pinned
+pub(crate) struct Pins(GlobSet);
+
+impl Pins {
+    /// Read the pinned patterns from the database
+    pub(crate) fn load(conn: &Connection) -> Result<Self> {
+        let patterns = db::pins(conn).wrap_err("Failed fetching pins from db")?;
+        Ok(Self(
+            utils::glob_set(&patterns).wrap_err("Invalid pinned patterns")?,
+        ))
+    }
+
+    /// Whether `path`, relative to the data directory, or a directory it is in is pinned
+    pub(crate) fn contains(&self, path: &Utf8Path) -> bool {
+        !self.0.is_empty()
+            && path
+                .ancestors()
+                .any(|p| !p.as_str().is_empty() && self.0.is_match(p))
+    }
+}
+
+/// Pin the paths matching every pattern in `patterns`, which are paths or globs relative to the
+/// data directory
+pub fn pin(data_path: &Utf8Path, config: &Config, patterns: &[String]) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    for pattern in patterns {
+        let pattern = relative_path(data_path, Utf8Path::new(pattern))?;
+        let glob = Glob::new(pattern.as_str())
+            .wrap_err_with(|| format!("Invalid pattern {pattern}"))?
+            .compile_matcher();
+        let matching = files
+            .iter()
+            .filter(|(p, _)| Utf8Path::new(p).ancestors().any(|p| glob.is_match(p)))
+            .count();
+        if db::insert_pin(&conn, pattern.as_str(), Utc::now().timestamp())
+            .wrap_err("Failed saving pin")?
+        {
+            info!("Pinned \"{pattern}\", matching {matching} indexed files");
+        } else {
+            info!("\"{pattern}\" was already pinned, matching {matching} indexed files");
+        }
+        if matching == 0 {
+            warn!("\"{pattern}\" does not match any indexed file");
+        }
+    }
+    Ok(())
+}
+
+/// Unpin every pattern in `patterns`, as they were given to [`pin`]
+pub fn unpin(data_path: &Utf8Path, config: &Config, patterns: &[String]) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    for pattern in patterns {
+        let pattern = relative_path(data_path, Utf8Path::new(pattern))?;
+        if !db::remove_pin(&conn, pattern.as_str()).wrap_err("Failed removing pin")? {
+            bail!("\"{pattern}\" is not pinned, see `cstfs pin` for the pinned patterns");
+        }
+        info!("Unpinned \"{pattern}\"");
+    }
+    Ok(())
+}
+
+/// Print every pinned pattern
+pub fn list(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let pins = db::pins(&conn).wrap_err("Failed fetching pins from db")?;
+    if pins.is_empty() {
+        println!("There are no pinned paths");
+    }
+    for pattern in pins {
+        println!("{pattern}");
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::fmt::Write as _;
+
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::{info, warn};
+
+use crate::cat::locate;
+use crate::config::{Config, MediaKind};
+use crate::db::{self, IndexedFile};
+use crate::search;
+use crate::utils::media_kind;
+
+/// Comment before every entry of a playlist with the hash of its file, which players skip over and
+/// [`update`] finds the files by
+const HASH_COMMENT: &str = "#CSTFS-HASH:";
+
+/// Path relative to the data directory of the copy of `file` that is still there, if any
+fn entry(conn: &rusqlite::Connection, data_path: &Utf8Path, file: &IndexedFile) -> Option<String> {
+    match locate(conn, data_path, file) {
+        Ok(full_path) => Some(
+            full_path
+                .strip_prefix(data_path)
+                .map_or_else(|_| full_path.to_string(), ToString::to_string),
+        ),
+        Err(e) => {
+            warn!("Leaving \"{}\" out of the playlist: {e}", file.path);
+            None
+        }
+    }
+}
+
+/// Write an extended M3U playlist of `entries`, pairs of hashes and paths, to `output`, or to
+/// stdout without it
+fn write(entries: &[(String, String)], output: Option<&Utf8Path>) -> Result<()> {
+    let mut playlist = String::from("#EXTM3U\n");
+    for (hash, path) in entries {
+        let title = Utf8Path::new(path).file_stem().unwrap_or(path);
+        let _ = writeln!(playlist, "{HASH_COMMENT}{hash}");
+        let _ = writeln!(playlist, "#EXTINF:-1,{title}");
+        let _ = writeln!(playlist, "{path}");
+    }
+    match output {
+        Some(output) => {
+            std::fs::write(output, playlist)
+                .wrap_err_with(|| format!("Failed writing playlist \"{output}\""))?;
+            info!("Wrote {} entries to \"{output}\"", entries.len());
+        }
+        None => print!("{playlist}"),
+    }
+    Ok(())
+}
+
+/// Make a playlist of the indexed audio files matching the search `query`, or of every one of
+/// them without it, to `output` or stdout.
+///
+/// The paths in it are relative to the data directory, so players find them when the playlist is
+/// kept there. The hashes of the files are kept in it too, so [`update`] can point it to where the
+/// files were moved to later.
+pub fn create(
+    data_path: &Utf8Path,
+    config: &Config,
+    query: Option<&str>,
+    output: Option<&Utf8Path>,
+) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = if let Some(query) = query {
+        search::matching(&conn, query)?
+    } else {
+        let mut files = db::listing(&conn).wrap_err("Failed fetching files from db")?;
+        files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        files
+    };
+
+    let mut entries = vec![];
+    for file in &files {
+        let Some(path) = entry(&conn, data_path, file) else {
+            continue;
+        };
+        let kind = media_kind(&data_path.join(&path), config)
+            .wrap_err_with(|| format!("Failed finding out the kind of \"{path}\""))?;
+        if kind == Some(MediaKind::Audio) {
+            entries.push((file.hash.clone(), path));
+        }
+    }
+    write(&entries, output)
+}
+
+/// Point the entries of the playlist made by [`create`] at `playlist` to the paths their files are
+/// at now. Entries of files that are no longer indexed are left out.
+pub fn update(data_path: &Utf8Path, config: &Config, playlist: &Utf8Path) -> Result<()> {
+    let contents = std::fs::read_to_string(playlist)
+        .wrap_err_with(|| format!("Failed reading playlist \"{playlist}\""))?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+
+    let mut entries = vec![];
+    for hash in contents
+        .lines()
+        .filter_map(|l| l.strip_prefix(HASH_COMMENT))
+    {
+        let copies = db::files_with_hash(&conn, hash).wrap_err("Failed fetching files")?;
+        let Some(file) = copies.first() else {
+            warn!("No indexed file has hash {hash}, leaving it out of the playlist");
+            continue;
+        };
+        if let Some(path) = entry(&conn, data_path, file) {
+            entries.push((hash.to_owned(), path));
+        }
+    }
+    write(&entries, Some(playlist))
+}
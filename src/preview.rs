@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use camino::Utf8Path;
+use color_eyre::{eyre::WrapErr, Result};
+use cstfs::config::Preview;
+use image::{ImageFormat, RgbImage};
+
+/// Side length in pixels of the box images are scaled down to fit in
+const SIZE: u32 = 256;
+
+/// Width in terminal cells of the previews, with the protocols that let it be chosen
+const COLUMNS: u32 = 30;
+
+/// Size of the chunks images are sent to kitty in, as the protocol requires
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Terminal graphics protocol used to show previews
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Kitty,
+    Iterm,
+    Sixel,
+}
+
+impl Protocol {
+    /// Protocol to show previews with as `preview` says, if any
+    pub fn new(preview: Preview) -> Option<Self> {
+        match preview {
+            Preview::Off => None,
+            Preview::Auto => Self::detect(),
+            Preview::Kitty => Some(Self::Kitty),
+            Preview::Iterm => Some(Self::Iterm),
+            Preview::Sixel => Some(Self::Sixel),
+        }
+    }
+
+    /// Find out from the environment which protocol the terminal supports
+    fn detect() -> Option<Self> {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        if var("TERM") == "xterm-kitty" || std::env::var_os("KITTY_WINDOW_ID").is_some() {
+            return Some(Self::Kitty);
+        }
+        if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm") {
+            return Some(Self::Iterm);
+        }
+        None
+    }
+}
+
+/// Read the image at `path`, scaled down to be previewed if it is large
+pub fn load(path: &Utf8Path) -> Result<RgbImage> {
+    let img = image::open(path).wrap_err("Failed decoding image")?;
+    if img.width() <= SIZE && img.height() <= SIZE {
+        return Ok(img.into_rgb8());
+    }
+    Ok(img.thumbnail(SIZE, SIZE).into_rgb8())
+}
+
+/// Print `img` on the terminal with `protocol`
+pub fn print(img: &RgbImage, protocol: Protocol) -> Result<()> {
+    let mut out = io::stdout().lock();
+    match protocol {
+        Protocol::Kitty => kitty(&mut out, &png(img)?),
+        Protocol::Iterm => iterm(&mut out, &png(img)?),
+        Protocol::Sixel => sixel(&mut out, img),
+    }
+    .and_then(|()| writeln!(out))
+    .and_then(|()| out.flush())
+    .wrap_err("Failed printing preview")
+}
+
+fn png(img: &RgbImage) -> Result<Vec<u8>> {
+    let mut png = io::Cursor::new(vec![]);
+    img.write_to(&mut png, ImageFormat::Png)
+        .wrap_err("Failed encoding preview")?;
+    Ok(png.into_inner())
+}
+
+/// Send `png` to kitty, in as many chunks as needed
+fn kitty(out: &mut impl Write, png: &[u8]) -> io::Result<()> {
+    let data = STANDARD.encode(png);
+    let mut chunks = data.as_bytes().chunks(KITTY_CHUNK_SIZE).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let more = u8::from(chunks.peek().is_some());
+        if first {
+            write!(out, "\x1b_Ga=T,f=100,q=2,c={COLUMNS},m={more};")?;
+            first = false;
+        } else {
+            write!(out, "\x1b_Gm={more};")?;
+        }
+        out.write_all(chunk)?;
+        write!(out, "\x1b\\")?;
+    }
+    Ok(())
+}
+
+fn iterm(out: &mut impl Write, png: &[u8]) -> io::Result<()> {
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={};width={COLUMNS};preserveAspectRatio=1:{}\x07",
+        png.len(),
+        STANDARD.encode(png)
+    )
+}
+
+/// Print `img` as sixels, with its colors rounded to a palette of 6 levels of red, green and blue
+fn sixel(out: &mut impl Write, img: &RgbImage) -> io::Result<()> {
+    let (width, height) = img.dimensions();
+    write!(out, "\x1bPq\"1;1;{width};{height}")?;
+    for i in 0..216 {
+        let (r, g, b) = (i / 36, i / 6 % 6, i % 6);
+        write!(out, "#{i};2;{};{};{}", r * 20, g * 20, b * 20)?;
+    }
+
+    let level = |c: u8| (u32::from(c) * 5 + 127) / 255;
+    for top in (0..height).step_by(6) {
+        // Which of the 6 rows of the band every pixel column has in each color
+        let mut colors: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        for y in top..height.min(top + 6) {
+            for x in 0..width {
+                let [r, g, b] = img.get_pixel(x, y).0;
+                let color = level(r) * 36 + level(g) * 6 + level(b);
+                colors
+                    .entry(color)
+                    .or_insert_with(|| vec![0; width as usize])[x as usize] |= 1 << (y - top);
+            }
+        }
+        for (i, (color, columns)) in colors.iter().enumerate() {
+            if i > 0 {
+                // Back to the start of the band for the next color
+                write!(out, "$")?;
+            }
+            write!(out, "#{color}")?;
+            let mut rest = &columns[..];
+            while let Some(&rows) = rest.first() {
+                let run = rest.iter().take_while(|r| **r == rows).count();
+                let c = char::from(63 + rows);
+                match run {
+                    1 => write!(out, "{c}")?,
+                    n => write!(out, "!{n}{c}")?,
+                }
+                rest = &rest[run..];
+            }
+        }
+        write!(out, "-")?;
+    }
+    write!(out, "\x1b\\")
+}
@@ -0,0 +1,42 @@
+use color_eyre::Result;
+
+/// Run cstfs with the lowest CPU priority, and the lowest I/O priority where it can be set. Only
+/// the threads started after this is called are affected, so it is called before any other one.
+#[cfg(unix)]
+pub fn lower() -> Result<()> {
+    use color_eyre::eyre::WrapErr;
+
+    // SAFETY: setpriority only changes the priority of this process, and takes no pointers
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(std::io::Error::last_os_error()).wrap_err("Failed lowering CPU priority");
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // See ioprio_set(2), the idle class only gets disk time when nothing else wants it
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        // SAFETY: ioprio_set only changes the priority of this thread, and takes no pointers
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error()).wrap_err("Failed lowering I/O priority");
+        }
+    }
+    tracing::debug!("Lowered priority");
+    Ok(())
+}
+
+// Fallible like the unix version
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+pub fn lower() -> Result<()> {
+    tracing::warn!("Lowering the priority of cstfs is not supported on this platform");
+    Ok(())
+}
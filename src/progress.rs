@@ -0,0 +1,28 @@
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// How the progress of long running operations is shown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Progress {
+    /// As progress bars on stderr, when it is a terminal
+    #[default]
+    Bar,
+    /// As a line of JSON on stderr for every event and log message, for programs wrapping cstfs
+    Json,
+}
+
+/// Template of the hashing progress bar, in indicatif's syntax
+const HASHING_TEMPLATE: &str =
+    "{spinner} [{bar:20}] {prefix} {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {wide_msg}";
+
+/// Progress bar for hashing `files` files adding up to `bytes` bytes, showing the throughput, the
+/// time left and the file currently being hashed. It is hidden when stderr is not a terminal.
+pub fn hashing(files: usize, bytes: u64) -> ProgressBar {
+    let bar = ProgressBar::new(bytes);
+    bar.set_style(
+        ProgressStyle::with_template(HASHING_TEMPLATE)
+            .expect("Progress template is valid")
+            .progress_chars("=> "),
+    );
+    bar.set_prefix(format!("0/{files} files"));
+    bar
+}
@@ -0,0 +1,53 @@
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::info;
+
+use crate::backup;
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+
+/// Remove the indexed files that are no longer in the data directory from the index.
+///
+/// Nothing is hashed: this applies only the removals a refresh would, for after files were deleted
+/// on purpose outside of cstfs.
+pub fn prune(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    backup::before(data_path, config, "prune")?;
+    let now = Instant::now();
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating prune transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "prune").wrap_err("Failed recording operation")?;
+
+    let files = db::files(&transaction).wrap_err("Failed fetching files from db")?;
+    let mut removed = 0;
+    for (path, hash) in files {
+        let path = Utf8PathBuf::from(path);
+        if data_path
+            .join(&path)
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{path}\""))?
+        {
+            continue;
+        }
+
+        db::remove(&transaction, &path, &hash)
+            .wrap_err_with(|| format!("Failed removing {path} from the index"))?;
+        info!("Removed: {path}");
+        let action = JournalAction::Remove { path, hash };
+        db::record(&transaction, operation, &action).wrap_err("Failed recording removal")?;
+        removed += 1;
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+
+    let elapsed = now.elapsed();
+    info!("Removed {removed} missing files from the index. Took {elapsed:.2?}");
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+
+use crate::cat::locate;
+use crate::config::Config;
+use crate::db;
+use crate::open::launch;
+use crate::search;
+
+/// Pick `count` different indexed files at random and print their full paths.
+///
+/// Only the files matching the search `query` are picked from if it is given, and fewer are picked
+/// when fewer match. With `open` they are opened with the viewer one after the other instead.
+pub fn random(
+    data_path: &Utf8Path,
+    config: &Config,
+    query: Option<&str>,
+    count: usize,
+    open: bool,
+) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let mut files = match query {
+        Some(query) => search::matching(&conn, query)?,
+        None => db::listing(&conn).wrap_err("Failed fetching files from db")?,
+    };
+    if files.is_empty() {
+        match query {
+            Some(query) => bail!("No indexed file matches \"{query}\""),
+            None => bail!("There are no indexed files to pick from"),
+        }
+    }
+    fastrand::shuffle(&mut files);
+    files.truncate(count);
+
+    for file in &files {
+        let full_path = locate(&conn, data_path, file)?;
+        if open {
+            launch(config, &full_path)?;
+        } else {
+            println!("{full_path}");
+        }
+    }
+    Ok(())
+}
@@ -1,30 +1,51 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
 use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
 use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{Connection, Transaction};
 use std::time::Instant;
+use tracing::{debug, info, warn};
 
-use crate::db;
-use crate::utils::{hash_file, recursive_directory_read};
+use crate::backup;
+use crate::config::{ChangePolicy, Config, CopyPolicy};
+use crate::db::{self, JournalAction};
+use crate::dir_times::DirTimes;
+use crate::duplicate::handle_duplicate;
+use crate::hooks;
+use crate::lock::Lock;
+use crate::notify;
+use crate::report::Reporter;
+use crate::skipped::Skipped;
+use crate::utils::{self, hash_stream, normalize, path_key, relative_path, walk_recording};
 
 /// Represents a change in the filesystem, containing metadata for what exactly happened.
-#[derive(Debug)]
-struct Diff {
-    /// Path to the file that this diff refers to
-    path: Utf8PathBuf,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff {
+    /// Path to the file that this diff refers to, relative to the data directory
+    pub path: Utf8PathBuf,
     /// Hash of the file that this diff refers to
-    hash: String,
+    pub hash: String,
     /// Type of the diff
-    ty: DiffType,
+    pub ty: DiffType,
 }
 
 /// Represents exactly what operation a diff encodes, and some other information if necessary for
 /// the specific operation
-#[derive(Debug)]
-enum DiffType {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffType {
     /// A new path was found, whose hash is not recorded in the db
     New,
+    /// A new path was found, whose hash is not recorded in the db, but is the same as the one of
+    /// another new path
+    Duplicate {
+        /// Path to the other new file, which is indexed instead
+        orig_path: Utf8PathBuf,
+    },
     /// A new path was found, whose hash was already found in the db, while the original path still
     /// exists
-    Duplicate {
+    Copied {
         /// Path to the file that was in the index before
         orig_path: Utf8PathBuf,
     },
@@ -42,144 +63,343 @@ enum DiffType {
     Removed,
 }
 
-fn remove_indeces<T>(v: &mut Vec<T>, indices: &[usize]) {
-    // Sort indices in descending order to avoid invalidating subsequent indices
-    let mut sorted_indices = indices.to_vec();
-    // If we remove indeces in decreasing order, then no index can shift
-    sorted_indices.sort_by(|a, b| b.cmp(a));
+/// A directory that was renamed or moved with every indexed file in it, found from the [`Moved`]
+/// diffs of its files
+///
+/// [`Moved`]: DiffType::Moved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirMove {
+    /// Path the directory was at before, relative to the data directory
+    pub from: Utf8PathBuf,
+    /// Path the directory is at now, relative to the data directory
+    pub to: Utf8PathBuf,
+    /// Amount of files moved along with it
+    pub files: usize,
+}
 
-    for index in sorted_indices {
-        if index < v.len() {
-            v.remove(index);
+impl DirMove {
+    /// Check if the file at `path`, before the move, was in the directory
+    #[must_use]
+    pub fn contains(&self, path: &Utf8Path) -> bool {
+        path.starts_with(&self.from)
+    }
+}
+
+/// Find the directories that were moved with every file in them that is in `indexed`, from the
+/// moves among `diffs`, so they can be shown as a single move instead of one for every file
+#[must_use]
+pub fn dir_moves(diffs: &[Diff], indexed: &[(String, String)]) -> Vec<DirMove> {
+    let mut moved: BTreeMap<(Utf8PathBuf, Utf8PathBuf), usize> = BTreeMap::new();
+    for diff in diffs {
+        let DiffType::Moved { orig_path } = &diff.ty else {
+            continue;
+        };
+        let from: Vec<_> = orig_path.components().collect();
+        let to: Vec<_> = diff.path.components().collect();
+        // The file keeps its name and its path inside the moved directory
+        let kept = from
+            .iter()
+            .rev()
+            .zip(to.iter().rev())
+            .take_while(|(f, t)| f == t)
+            .count();
+        if kept == 0 || kept >= from.len() || kept >= to.len() {
+            continue;
         }
+        let from_dir = from[..from.len() - kept].iter().collect();
+        let to_dir = to[..to.len() - kept].iter().collect();
+        *moved.entry((from_dir, to_dir)).or_default() += 1;
     }
+
+    let candidates: Vec<DirMove> = moved
+        .into_iter()
+        .filter(|(_, files)| *files > 1)
+        .map(|((from, to), files)| DirMove { from, to, files })
+        .collect();
+    // Otherwise only some of the files in it were moved
+    let mut indexed_in: HashMap<&Utf8Path, usize> =
+        candidates.iter().map(|m| (m.from.as_path(), 0)).collect();
+    for (path, _) in indexed {
+        for dir in Utf8Path::new(path).ancestors().skip(1) {
+            if let Some(count) = indexed_in.get_mut(dir) {
+                *count += 1;
+            }
+        }
+    }
+    let every_file: Vec<bool> = candidates
+        .iter()
+        .map(|m| indexed_in.get(m.from.as_path()) == Some(&m.files))
+        .collect();
+    candidates
+        .into_iter()
+        .zip(every_file)
+        .filter_map(|(m, every_file)| every_file.then_some(m))
+        .collect()
 }
 
+/// Turn every new file whose hash is indexed into a move of a removed file with the hash, or into a
+/// copy of the indexed file if there are no removed files left with it
 fn coalesce_diffs(diffs: &mut Vec<Diff>, db_paths_and_hashes: &[(String, String)]) {
-    'outer: loop {
-        // List of indexes to remove
-        // If this is empty, then the loop can stop, because there is no coalescing to be done
-        let mut indeces_to_remove = vec![];
-        let mut to_push = vec![];
-        'inner: for (i, diff) in diffs.iter().enumerate() {
-            match diff.ty {
-                DiffType::New => {
-                    // If the file is not in the index, we can continue, because the diff can
-                    // remain as a New, as there cannot exist a file it should be related to
-                    let Some((db_path, _)) =
-                        db_paths_and_hashes.iter().find(|(_, h)| *h == diff.hash)
-                    else {
-                        continue 'inner;
-                    };
-                    // Since we're going to replace this with some other diff, this should always
-                    // be removed
-                    indeces_to_remove.push(i);
-                    // Can we find another diff where the file removed has the same hash as this
-                    // one?
-                    if let Some((
-                        i,
-                        Diff {
-                            path: removed_path, ..
-                        },
-                    )) = diffs
-                        .iter()
-                        .enumerate()
-                        .find(|(_, d)| matches!(d.ty, DiffType::Removed) && d.hash == diff.hash)
-                    {
-                        // If so, then it means that we moved the previous file to be this one, and
-                        // we can remove the removed entry as well, and coalesce the new file and
-                        // the removed file into a move operation
-                        indeces_to_remove.push(i);
-                        to_push.push(Diff {
-                            path: diff.path.clone(),
-                            hash: diff.hash.clone(),
-                            ty: DiffType::Moved {
-                                orig_path: removed_path.clone(),
-                            },
-                        });
-                    } else {
-                        // If not, then there is a file in the index with the same hash as the file
-                        // that was added, which means there's a duplicate hash
-                        to_push.push(Diff {
-                            path: diff.path.clone(),
-                            hash: diff.hash.clone(),
-                            ty: DiffType::Duplicate {
-                                orig_path: db_path.into(),
-                            },
-                        });
-                    }
-                    // We always break, so as to not duplicate the coalescing upon finding a
-                    // Removed
-                    break 'inner;
+    // New files are copies of the first file indexed with their hash
+    let mut indexed: HashMap<&str, &str> = HashMap::new();
+    for (path, hash) in db_paths_and_hashes {
+        indexed.entry(hash).or_insert(path);
+    }
+    // Every removed file is moved to at most one new file, in the order they were found
+    let mut removed: HashMap<&str, VecDeque<usize>> = HashMap::new();
+    for (i, diff) in diffs.iter().enumerate() {
+        if diff.ty == DiffType::Removed {
+            removed.entry(&diff.hash).or_default().push_back(i);
+        }
+    }
+
+    let mut coalesced = vec![];
+    let mut replaced = vec![false; diffs.len()];
+    // Copies found once every removed file with their hash is moved are copies of where it went
+    let mut moved_to: HashMap<&Utf8Path, &Utf8Path> = HashMap::new();
+    for (i, diff) in diffs.iter().enumerate() {
+        if diff.ty != DiffType::New {
+            continue;
+        }
+        // Files whose hash is not indexed stay new
+        let Some(db_path) = indexed.get(diff.hash.as_str()) else {
+            continue;
+        };
+        replaced[i] = true;
+        let moved = removed
+            .get_mut(diff.hash.as_str())
+            .and_then(VecDeque::pop_front)
+            .map(|j| {
+                replaced[j] = true;
+                moved_to.insert(&diffs[j].path, &diff.path);
+                DiffType::Moved {
+                    orig_path: diffs[j].path.clone(),
                 }
-                // Duplicate: There is no way to coalesce a duplicate into another operation, as
-                // the only way that could happen is if two duplicate files were added, at the same
-                // time there was a file in the index with the exact same hash, so three
-                // files. However, it is easier to have this as two duplicate diffs, rather than a
-                // single one
-                //
-                // Moved: There is also no way to coalesce a move, as two files cannot move to the
-                // same location at the same time, nor can there be a file that is moved to two
-                // locations, as one would be a copy, while the other is a move
-                //
-                // Removed: Removals can only be coalesced with a new, to make a moved, otherwise they
-                // remain as removeds. Since this is already done above, there is no need to handle
-                // a removed specifically
-                //
-                // Changed: There is no way to coalesce a removal
-                DiffType::Duplicate { .. }
-                | DiffType::Moved { .. }
-                | DiffType::Removed
-                | DiffType::Changed { .. } => {}
+            });
+        let ty = moved.unwrap_or_else(|| {
+            let db_path = Utf8Path::new(db_path);
+            DiffType::Copied {
+                orig_path: moved_to.get(db_path).unwrap_or(&db_path).to_path_buf(),
             }
+        });
+        coalesced.push(Diff {
+            path: diff.path.clone(),
+            hash: diff.hash.clone(),
+            ty,
+        });
+    }
+
+    let mut replaced = replaced.into_iter();
+    diffs.retain(|_| !replaced.next().unwrap_or(false));
+    diffs.extend(coalesced);
+}
+
+/// Turn every new file with the same hash as a new file found before it into a duplicate of it,
+/// so only the first one is indexed
+fn find_duplicates(diffs: &mut [Diff]) {
+    let mut first_paths: HashMap<String, Utf8PathBuf> = HashMap::new();
+    for diff in diffs {
+        if diff.ty != DiffType::New {
+            continue;
         }
-        if to_push.is_empty() && indeces_to_remove.is_empty() {
-            break 'outer;
+        if let Some(orig_path) = first_paths.get(&diff.hash) {
+            diff.ty = DiffType::Duplicate {
+                orig_path: orig_path.clone(),
+            };
+        } else {
+            first_paths.insert(diff.hash.clone(), diff.path.clone());
         }
-        remove_indeces(diffs, &indeces_to_remove);
-        diffs.extend(to_push);
     }
 }
 
-fn generate_diffs(data_path: &Utf8Path) -> Result<Vec<Diff>> {
-    let conn = db::open(data_path).wrap_err("Failed to open db")?;
+/// Hash every file in the data directory, or only the ones in `scope` if it is not empty,
+/// returning their paths and hashes sorted by path. Files of `indexed` that were moved without
+/// changing are found by their inode instead of hashed again, and the ones in directories that did
+/// not change since they were recorded in `times` are not hashed. If `sizes` is given, files that
+/// are not indexed are only hashed if they have one of them.
+#[allow(clippy::too_many_arguments)]
+fn hash_contents(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    conn: &Connection,
+    indexed: &[(String, String)],
+    case_sensitive: bool,
+    scope: &[Utf8PathBuf],
+    times: &Arc<DirTimes>,
+    skipped: &Skipped,
+    sizes: Option<&HashSet<u64>>,
+) -> Result<Vec<(Utf8PathBuf, String)>> {
+    let indexed_paths: HashSet<String> = indexed
+        .iter()
+        .map(|(p, _)| path_key(p, case_sensitive))
+        .collect();
+    let is_indexed = |path: &Utf8Path| {
+        path.strip_prefix(data_path).is_ok_and(|relative| {
+            indexed_paths.contains(&path_key(normalize(relative).as_str(), case_sensitive))
+        })
+    };
+    let inodes = db::inodes(conn).wrap_err("Failed fetching inodes from db")?;
+    let by_inode: HashMap<_, _> = inodes
+        .iter()
+        .filter_map(|(path, hash, stat)| Some(((stat.device?, stat.inode?), (path, hash, stat))))
+        .collect();
+    // Whether the file at `path` is an indexed file that was moved there without changing, in
+    // which case its hash is known without hashing it again
+    let moved_hash = |path: &Utf8Path| -> Option<String> {
+        let relative = normalize(path.strip_prefix(data_path).ok()?);
+        if is_indexed(path) {
+            return None;
+        }
+        let stat = utils::stat(path).ok()?;
+        let (old_path, hash, old_stat) = by_inode.get(&(stat.device?, stat.inode?))?;
+        if **old_stat != stat || utils::full_path(data_path, Utf8Path::new(old_path)).exists() {
+            return None;
+        }
+        debug!("Found \"{relative}\" by its inode, moved from \"{old_path}\"");
+        Some((*hash).clone())
+    };
+
+    // Files are hashed as they are found, in no particular order, so they are sorted afterwards
+    // for the diffs to come in the same order every time
+    let mut data_path_contents = vec![];
+    let mut moved = vec![];
+    let walk = walk_recording(data_path, config, scope, Arc::clone(times));
+    let paths = skipped
+        .walk(config, walk.wrap_err("Failed reading directory contents")?)
+        .filter(|p| {
+            let Ok(p) = p else {
+                return true;
+            };
+            if let Some(sizes) = sizes {
+                let size = p.metadata().map_or(0, |m| m.len());
+                if !sizes.contains(&size) && !is_indexed(p) {
+                    return false;
+                }
+            }
+            let Some(hash) = moved_hash(p) else {
+                return true;
+            };
+            moved.push((p.clone(), hash));
+            false
+        });
+    hash_stream(paths, config, reporter, |path, hash| {
+        if let Some(hash) = skipped.hash(config, &path, hash)? {
+            data_path_contents.push((path, hash));
+        }
+        Ok(())
+    })?;
+    data_path_contents.extend(moved);
+    data_path_contents.sort_unstable();
+    Ok(data_path_contents)
+}
+
+/// Compare the files in the data directory with the index, returning every change between them
+/// without applying any. The progress and every change found are sent to `reporter`.
+pub fn generate_diffs(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+) -> Result<Vec<Diff>> {
+    let times = Arc::new(DirTimes::default());
+    diffs(
+        data_path,
+        config,
+        reporter,
+        &[],
+        &times,
+        &Skipped::default(),
+        false,
+    )
+}
+
+/// Diffs of [`generate_diffs`] that may be copies of indexed files, without hashing the new files
+/// with a size no indexed file has, which cannot be. Every file is hashed if the size of an indexed
+/// one is not known yet.
+pub(crate) fn copy_diffs(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+) -> Result<Vec<Diff>> {
+    let times = Arc::new(DirTimes::default());
+    diffs(
+        data_path,
+        config,
+        reporter,
+        &[],
+        &times,
+        &Skipped::default(),
+        true,
+    )
+}
+
+/// Diffs of [`generate_diffs`], carrying on past the files that cannot be read and keeping them in
+/// `skipped`. Those are not taken as removed, as they may still be there.
+///
+/// If `scope` is not empty, only the files in the paths in it are compared with the index. Indexed
+/// files outside of it are only taken as removed if they were moved into it. The directories that
+/// did not change since they were recorded in `times` are not read, and the indexed files in them
+/// are taken as unchanged. If `only_copies` is set, only the new files with the size of an indexed
+/// file are hashed, and the others are left out.
+fn diffs(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    scope: &[Utf8PathBuf],
+    times: &Arc<DirTimes>,
+    skipped: &Skipped,
+    only_copies: bool,
+) -> Result<Vec<Diff>> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    db::check_hash(&conn, config.hash)?;
     let mut diffs = vec![];
+    let sizes = if only_copies {
+        db::sizes(&conn).wrap_err("Failed fetching sizes from db")?
+    } else {
+        None
+    };
 
-    let mut query = conn
-        .prepare("SELECT path, hash FROM files")
-        .wrap_err("Failed preparing path and hash query")?;
-    let db_paths_and_hashes: Result<Vec<(String, String)>> = query
-        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
-        .wrap_err("Failed executing path and hash query")?
-        .map(|v| v.wrap_err("Failed getting column from db"))
-        .collect();
     let db_paths_and_hashes =
-        db_paths_and_hashes.wrap_err("Failed fetching paths and hashes from db")?;
+        db::files(&conn).wrap_err("Failed fetching paths and hashes from db")?;
+    // On case insensitive filesystems a file whose name changed case on disk is the same file
+    let case_sensitive = utils::case_sensitive(data_path, config);
+    let db_hashes: HashMap<String, &str> = db_paths_and_hashes
+        .iter()
+        .map(|(p, h)| (path_key(p, case_sensitive), h.as_str()))
+        .collect();
 
-    let data_path_contents =
-        recursive_directory_read(data_path).wrap_err("Failed reading directory contents")?;
-    for path in &data_path_contents {
-        if path.file_name().expect("File has file name") == "cstfs.db" {
+    let data_path_contents = hash_contents(
+        data_path,
+        config,
+        reporter,
+        &conn,
+        &db_paths_and_hashes,
+        case_sensitive,
+        scope,
+        times,
+        skipped,
+        sizes.as_ref(),
+    )?;
+    for (path, hash) in &data_path_contents {
+        if path.file_name().is_some_and(db::is_db_file) {
             continue;
         }
-        let hash = hash_file(path).wrap_err_with(|| format!("Could not hash file {path}"))?;
-        let path = path
-            .strip_prefix(data_path)
-            .wrap_err_with(|| format!("Path \"{path}\" was not a base of \"{data_path}\""))?;
+        let hash = hash.clone();
+        let path = normalize(
+            path.strip_prefix(data_path)
+                .wrap_err_with(|| format!("Path \"{path}\" was not a base of \"{data_path}\""))?,
+        );
+        let path = path.as_path();
 
         // If the file is in the db...
-        if let Some(db_hash_for_path) = db_paths_and_hashes
-            .iter()
-            .find(|(db_path, _)| *db_path == path)
-            .map(|(_, h)| h)
-        {
+        if let Some(db_hash_for_path) = db_hashes.get(&path_key(path.as_str(), case_sensitive)) {
             // ...and the hash in the db is different, then the file changed.
             if *db_hash_for_path != hash {
                 diffs.push(Diff {
                     path: path.to_path_buf(),
                     hash,
                     ty: DiffType::Changed {
-                        prev_hash: db_hash_for_path.clone(),
+                        prev_hash: (*db_hash_for_path).to_owned(),
                     },
                 });
             }
@@ -193,16 +413,31 @@ fn generate_diffs(data_path: &Utf8Path) -> Result<Vec<Diff>> {
         }
     }
 
+    let found: HashSet<String> = data_path_contents
+        .iter()
+        .filter_map(|(p, _)| p.strip_prefix(data_path).ok())
+        .map(|p| path_key(normalize(p).as_str(), case_sensitive))
+        .collect();
+    let in_scope = |path: &Utf8Path| scope.is_empty() || scope.iter().any(|s| path.starts_with(s));
+    // Indexed files outside of the scope with the contents of a new file in it, which are only
+    // removed if they were moved into it
+    let new_hashes: HashSet<String> = diffs
+        .iter()
+        .filter(|d| d.ty == DiffType::New)
+        .map(|d| d.hash.clone())
+        .collect();
     for (path, hash) in &db_paths_and_hashes {
-        let path = Utf8Path::new(path);
         // If a path in the directory is not in the cache...
-        if !data_path_contents
-            .iter()
-            .map(|p| {
-                p.strip_prefix(data_path)
-                    .expect("Path is subdir of base directory")
-            })
-            .any(|db_path| db_path == path)
+        let path = Utf8Path::new(path);
+        let looked_at = in_scope(path)
+            || (new_hashes.contains(hash)
+                && !utils::full_path(data_path, path)
+                    .try_exists()
+                    .unwrap_or(true));
+        if looked_at
+            && !found.contains(&path_key(path.as_str(), case_sensitive))
+            && !skipped.covers(&data_path.join(path))
+            && !times.covers(path)
         {
             // ...it was removed
             diffs.push(Diff {
@@ -213,20 +448,544 @@ fn generate_diffs(data_path: &Utf8Path) -> Result<Vec<Diff>> {
         }
     }
     coalesce_diffs(&mut diffs, &db_paths_and_hashes);
+    find_duplicates(&mut diffs);
+    // Hardlinks of another file have its contents, but are not duplicates taking more space
+    diffs.retain(|d| match &d.ty {
+        DiffType::Duplicate { orig_path } | DiffType::Copied { orig_path } => {
+            let orig_path = utils::full_path(data_path, orig_path);
+            let path = utils::full_path(data_path, &d.path);
+            // Symlinks have the inode of their target, but are indexed when they are copies
+            utils::is_symlink_copy(config, &orig_path, &path)
+                || !utils::is_hardlink(&orig_path, &path)
+        }
+        _ => true,
+    });
+    for diff in &diffs {
+        reporter.diff_found(diff);
+    }
 
     Ok(diffs)
 }
 
-pub fn refresh(data_path: &Utf8Path) -> Result<()> {
-    println!("Starting refresh of \"{data_path}\"");
+impl DiffType {
+    /// Name of the diff type, as stored in the history
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Duplicate { .. } => "duplicate",
+            Self::Copied { .. } => "copied",
+            Self::Changed { .. } => "changed",
+            Self::Moved { .. } => "moved",
+            Self::Removed => "removed",
+        }
+    }
+
+    /// Order in which diffs are applied. Removals go first so the hashes of removed files are no
+    /// longer in the index by the time the other diffs are applied, and duplicates go after the
+    /// new files they duplicate.
+    #[must_use]
+    pub const fn apply_order(&self) -> u8 {
+        match self {
+            Self::Removed => 0,
+            Self::Moved { .. } => 1,
+            Self::Changed { .. } => 2,
+            Self::New => 3,
+            Self::Duplicate { .. } | Self::Copied { .. } => 4,
+        }
+    }
+}
+
+impl From<&Diff> for db::HistoryDiff {
+    fn from(diff: &Diff) -> Self {
+        let (orig_path, prev_hash) = match &diff.ty {
+            DiffType::Duplicate { orig_path }
+            | DiffType::Copied { orig_path }
+            | DiffType::Moved { orig_path } => (Some(orig_path.clone()), None),
+            DiffType::Changed { prev_hash } => (None, Some(prev_hash.clone())),
+            DiffType::New | DiffType::Removed => (None, None),
+        };
+        Self {
+            kind: diff.ty.name().to_owned(),
+            path: diff.path.clone(),
+            hash: diff.hash.clone(),
+            orig_path,
+            prev_hash,
+        }
+    }
+}
+
+/// Apply `diff` to the index, recording every change in the journal as part of `operation`. Moves
+/// of files in one of `dir_moves` are not logged, the directory move is instead.
+fn apply_diff(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    dir_moves: &[DirMove],
+    diff: &Diff,
+) -> Result<()> {
+    let Diff { path, hash, ty } = diff;
+    // Deal with the file as a duplicate of the one at `orig_path`
+    let handle = |orig_path: &Utf8Path| {
+        handle_duplicate(
+            transaction,
+            operation,
+            data_path,
+            config,
+            reporter,
+            orig_path,
+            path,
+            hash,
+        )
+        .wrap_err_with(|| format!("Could not handle duplicate file {path}"))
+    };
+    let action = match ty {
+        DiffType::New => {
+            match db::insert_into(transaction, path, hash) {
+                Ok(()) => {}
+                // Two new files with the same contents
+                Err(db::Error::DuplicateInsertion { path_old, .. }) => {
+                    return handle(&path_old);
+                }
+                Err(e) => {
+                    return Err(e)
+                        .wrap_err_with(|| format!("Failed inserting {path} into the index"))
+                }
+            }
+            info!("New: {path}");
+            JournalAction::Insert {
+                path: path.clone(),
+                hash: hash.clone(),
+            }
+        }
+        DiffType::Copied { orig_path }
+            if matches!(config.on_copy, CopyPolicy::Record)
+                || (config.index_symlinks && utils::full_path(data_path, path).is_symlink()) =>
+        {
+            db::insert_copy(transaction, path, hash)
+                .wrap_err_with(|| format!("Failed inserting {path} into the index"))?;
+            info!("Copied: {orig_path} -> {path}");
+            JournalAction::Insert {
+                path: path.clone(),
+                hash: hash.clone(),
+            }
+        }
+        DiffType::Copied { orig_path } if matches!(config.on_copy, CopyPolicy::Keep) => {
+            info!("Kept copy: {path} of {orig_path}");
+            return Ok(());
+        }
+        DiffType::Duplicate { orig_path } | DiffType::Copied { orig_path } => {
+            return handle(orig_path);
+        }
+        DiffType::Changed { .. } => {
+            let Some(action) = update_hash(transaction, config, reporter, path, hash)? else {
+                return Ok(());
+            };
+            action
+        }
+        DiffType::Moved { orig_path } => {
+            db::update_path(transaction, orig_path, path, hash)
+                .wrap_err_with(|| format!("Failed updating path of {orig_path}"))?;
+            if dir_moves.iter().any(|m| m.contains(orig_path)) {
+                debug!("Moved: {orig_path} -> {path}");
+            } else {
+                info!("Moved: {orig_path} -> {path}");
+            }
+            JournalAction::UpdatePath {
+                path: path.clone(),
+                prev_path: orig_path.clone(),
+                hash: hash.clone(),
+            }
+        }
+        DiffType::Removed => {
+            db::remove(transaction, path, hash)
+                .wrap_err_with(|| format!("Failed removing {path} from the index"))?;
+            info!("Removed: {path}");
+            JournalAction::Remove {
+                path: path.clone(),
+                hash: hash.clone(),
+            }
+        }
+    };
+    db::record(transaction, operation, &action).wrap_err("Failed recording change")?;
+
+    Ok(())
+}
+
+/// Record `hash` as the new hash of the changed file at `path`, if the change policy in `config`
+/// says so, asking `reporter` if needed. If another file has the hash, it is only recorded if
+/// copies are. Returns the change to record in the journal, if the index was changed.
+fn update_hash(
+    transaction: &Transaction<'_>,
+    config: &Config,
+    reporter: &dyn Reporter,
+    path: &Utf8Path,
+    hash: &str,
+) -> Result<Option<JournalAction>> {
+    let update = match config.on_change {
+        ChangePolicy::Update => true,
+        ChangePolicy::Flag => false,
+        ChangePolicy::Ask => reporter.changed(path)?,
+    };
+    if !update {
+        warn!("Changed: {path}, which may be corrupted or overwritten, leaving the index as is");
+        return Ok(None);
+    }
+
+    let copies = matches!(config.on_copy, CopyPolicy::Record);
+    let prev_hash = match db::update_hash(transaction, path, hash, copies) {
+        Ok(prev_hash) => prev_hash,
+        Err(db::Error::DuplicateInsertion { path_old, .. }) => {
+            warn!("Changed: {path}, but it is now a duplicate of {path_old}, leaving it as is, set on-copy to record to index it as a copy");
+            return Ok(None);
+        }
+        Err(e) => return Err(e).wrap_err_with(|| format!("Failed updating hash of {path}")),
+    };
+    info!("Changed: {path}");
+    Ok(Some(JournalAction::UpdateHash {
+        path: path.to_path_buf(),
+        hash: hash.to_owned(),
+        prev_hash,
+    }))
+}
+
+/// Record the sizes and inodes of the indexed files whose [`db::Stat`] is not known yet, like the
+/// ones that were just added or changed. The ones that cannot be read, like files missing outside
+/// of the scope of the refresh, are left for a later one.
+fn record_stats(
+    transaction: &Transaction<'_>,
+    data_path: &Utf8Path,
+    config: &Config,
+) -> Result<()> {
+    let files = db::unstatted_files(transaction).wrap_err("Failed fetching files from db")?;
+    let mut recorded = 0;
+    for (path, _) in &files {
+        let full_path = utils::full_path(data_path, Utf8Path::new(path));
+        let stat = match utils::retry(config, &full_path, || utils::stat(&full_path)) {
+            Ok(stat) => stat,
+            Err(e) => {
+                debug!("Not recording the size of \"{path}\": {e:#}");
+                continue;
+            }
+        };
+        db::set_stat(transaction, Utf8Path::new(path), &stat).wrap_err("Failed recording size")?;
+        recorded += 1;
+    }
+    debug!("Recorded the sizes of {recorded} files");
+    Ok(())
+}
+
+/// The `paths` a refresh looks at relative to the data directory, leaving out the ones inside of
+/// others, or none if it looks at the whole of it
+fn scope(data_path: &Utf8Path, paths: &[Utf8PathBuf]) -> Result<Vec<Utf8PathBuf>> {
+    let mut scope = paths
+        .iter()
+        .map(|p| relative_path(data_path, p))
+        .collect::<Result<Vec<_>>>()?;
+    scope.sort_unstable();
+    // Paths inside of others would be read twice
+    scope.dedup_by(|inner, outer| inner.starts_with(&*outer));
+    if scope.iter().any(|p| p.as_str().is_empty()) {
+        scope.clear();
+    }
+    Ok(scope)
+}
+
+/// Modification times of the directories recorded by the last refreshes, if `config.incremental`
+/// says to skip the ones that did not change, unless a `full` refresh was asked for or the last one
+/// was at least `config.full_refresh_days` before `now`, a unix timestamp
+fn dir_times(data_path: &Utf8Path, config: &Config, full: bool, now: i64) -> Result<DirTimes> {
+    if full || !config.incremental {
+        return Ok(DirTimes::default());
+    }
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let last_full = db::last_full_refresh(&conn).wrap_err("Failed fetching history")?;
+    let interval = i64::from(config.full_refresh_days) * 24 * 60 * 60;
+    if last_full.map_or(true, |t| now - t >= interval) {
+        info!(
+            "Reading every directory, the last full refresh was over {} days ago",
+            config.full_refresh_days
+        );
+        return Ok(DirTimes::default());
+    }
+    let known = db::dir_times(&conn).wrap_err("Failed fetching the times of the directories")?;
+    Ok(DirTimes::new(known))
+}
+
+/// Apply every change in the data directory to the index, recording them in the journal and the
+/// history, and return how many there were.
+///
+/// If `paths` is not empty, only the files and directories at them are looked at, and only the
+/// indexed files in them can be removed, but for the ones moved into them. Unless `full` is set,
+/// the directories that did not change since the last refresh are not read if `config.incremental`
+/// says so. If it is interrupted while hashing, the index is left as it was, as the files not
+/// hashed yet would be taken as removed. Once the changes are being applied, they are all applied.
+pub fn refresh(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    paths: &[Utf8PathBuf],
+    full: bool,
+) -> Result<usize> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let scope = scope(data_path, paths)?;
+    if scope.is_empty() {
+        info!("Starting refresh of \"{data_path}\"");
+    } else {
+        let scope: Vec<_> = scope.iter().map(|p| format!("\"{p}\"")).collect();
+        info!(
+            "Starting refresh of {} in \"{data_path}\"",
+            scope.join(", ")
+        );
+    }
+    let started_at = Utc::now();
     let now = Instant::now();
 
-    println!("Generating diff from index db");
-    let _diffs = generate_diffs(data_path).wrap_err("Failed generating diffs")?;
-    println!("Cannot apply diffs on index yet");
+    let times = Arc::new(dir_times(data_path, config, full, started_at.timestamp())?);
+    // Every directory is read when none was recorded, the index is not known to match them
+    let incremental = times.has_known();
+    info!("Generating diff from index db");
+    let skipped = Skipped::default();
+    let mut diffs = match diffs(data_path, config, reporter, &scope, &times, &skipped, false) {
+        Ok(diffs) => diffs,
+        Err(e) if utils::interrupted() => {
+            let cached = if config.xattr_cache {
+                ", reusing the hashes cached so far"
+            } else {
+                ""
+            };
+            info!("Refresh interrupted, leaving the index as it was. Run `cstfs refresh` again to redo it{cached}");
+            return Err(e);
+        }
+        Err(e) => return Err(e).wrap_err("Failed generating diffs"),
+    };
+    diffs.sort_by_key(|d| d.ty.apply_order());
+    let removed = diffs
+        .iter()
+        .filter(|d| matches!(d.ty, DiffType::Removed))
+        .count();
+    if removed >= config.backup_removals {
+        backup::before(
+            data_path,
+            config,
+            &format!("refresh removes {removed} files"),
+        )?;
+    }
+
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating refresh transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "refresh").wrap_err("Failed recording operation")?;
+    let indexed = db::files(&transaction).wrap_err("Failed fetching files from db")?;
+    let dir_moves = dir_moves(&diffs, &indexed);
+    for m in &dir_moves {
+        info!(
+            "Moved directory: {} -> {} ({} files)",
+            m.from, m.to, m.files
+        );
+    }
+    for diff in &diffs {
+        apply_diff(
+            &transaction,
+            operation,
+            data_path,
+            config,
+            reporter,
+            &dir_moves,
+            diff,
+        )
+        .wrap_err_with(|| format!("Failed applying diff for {}", diff.path))?;
+    }
+
+    record_stats(&transaction, data_path, config)?;
+    // The directories below the ones that could not be read would not be read the next time
+    if skipped.is_empty() {
+        db::record_dir_times(&transaction, &scope, &times.take())
+            .wrap_err("Failed recording the times of the directories")?;
+    }
 
     let elapsed = now.elapsed();
-    println!("Done generating database at \"{data_path}\". Took {elapsed:.2?}");
+    let history: Vec<db::HistoryDiff> = diffs.iter().map(Into::into).collect();
+    db::record_history(
+        &transaction,
+        started_at.timestamp(),
+        i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX),
+        scope.is_empty() && !incremental,
+        &history,
+    )
+    .wrap_err("Failed recording refresh in the history")?;
+    // Having compared every file with the index, every hash in it is one of the configured algorithm
+    if scope.is_empty() && !incremental && skipped.is_empty() {
+        db::record_hash(&transaction, config.hash.name()).wrap_err("Failed recording hash")?;
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
 
-    Ok(())
+    info!(
+        "Done refreshing \"{data_path}\", applied {} changes. Took {elapsed:.2?}",
+        diffs.len()
+    );
+    hooks::diffs(data_path, config, &diffs);
+    hooks::post_refresh(data_path, config, &diffs, elapsed);
+    notify::refresh(data_path, config, &diffs);
+
+    skipped.finish()?;
+    Ok(diffs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(path: &str, hash: &str, ty: DiffType) -> Diff {
+        Diff {
+            path: path.into(),
+            hash: hash.to_owned(),
+            ty,
+        }
+    }
+
+    fn indexed(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(p, h)| ((*p).to_owned(), (*h).to_owned()))
+            .collect()
+    }
+
+    fn moved(from: &str) -> DiffType {
+        DiffType::Moved {
+            orig_path: from.into(),
+        }
+    }
+
+    fn copied(from: &str) -> DiffType {
+        DiffType::Copied {
+            orig_path: from.into(),
+        }
+    }
+
+    #[test]
+    fn coalesces_removed_and_new_file_into_move() {
+        let mut diffs = vec![
+            diff("a.jpg", "1", DiffType::Removed),
+            diff("b.jpg", "1", DiffType::New),
+        ];
+        coalesce_diffs(&mut diffs, &indexed(&[("a.jpg", "1")]));
+        assert_eq!(diffs, [diff("b.jpg", "1", moved("a.jpg"))]);
+    }
+
+    #[test]
+    fn coalesces_new_file_with_indexed_hash_into_copy() {
+        let mut diffs = vec![
+            diff("b.jpg", "1", DiffType::New),
+            diff("c.jpg", "2", DiffType::New),
+        ];
+        coalesce_diffs(&mut diffs, &indexed(&[("a.jpg", "1")]));
+        assert_eq!(
+            diffs,
+            [
+                diff("c.jpg", "2", DiffType::New),
+                diff("b.jpg", "1", copied("a.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_of_moved_file_are_copies_of_where_it_went() {
+        let mut diffs = vec![
+            diff("a.jpg", "1", DiffType::Removed),
+            diff("b.jpg", "1", DiffType::New),
+            diff("c.jpg", "1", DiffType::New),
+        ];
+        coalesce_diffs(&mut diffs, &indexed(&[("a.jpg", "1")]));
+        assert_eq!(
+            diffs,
+            [
+                diff("b.jpg", "1", moved("a.jpg")),
+                diff("c.jpg", "1", copied("b.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn pairs_every_removed_copy_with_a_single_new_file() {
+        let mut diffs = vec![
+            diff("a.jpg", "1", DiffType::Removed),
+            diff("b.jpg", "1", DiffType::Removed),
+            diff("d/a.jpg", "1", DiffType::New),
+            diff("d/b.jpg", "1", DiffType::New),
+        ];
+        coalesce_diffs(&mut diffs, &indexed(&[("a.jpg", "1"), ("b.jpg", "1")]));
+        assert_eq!(
+            diffs,
+            [
+                diff("d/a.jpg", "1", moved("a.jpg")),
+                diff("d/b.jpg", "1", moved("b.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_other_diffs_as_they_are() {
+        let changed = DiffType::Changed {
+            prev_hash: "3".to_owned(),
+        };
+        let mut diffs = vec![
+            diff("a.jpg", "1", DiffType::Removed),
+            diff("b.jpg", "2", DiffType::New),
+            diff("c.jpg", "4", changed.clone()),
+        ];
+        coalesce_diffs(&mut diffs, &indexed(&[("a.jpg", "1"), ("c.jpg", "3")]));
+        assert_eq!(
+            diffs,
+            [
+                diff("a.jpg", "1", DiffType::Removed),
+                diff("b.jpg", "2", DiffType::New),
+                diff("c.jpg", "4", changed),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_directory_moved_with_every_file_in_it() {
+        let diffs = [
+            diff("new/sub/a.jpg", "1", moved("old/sub/a.jpg")),
+            diff("new/b.jpg", "2", moved("old/b.jpg")),
+        ];
+        let indexed = indexed(&[("old/sub/a.jpg", "1"), ("old/b.jpg", "2"), ("c.jpg", "3")]);
+        assert_eq!(
+            dir_moves(&diffs, &indexed),
+            [DirMove {
+                from: "old".into(),
+                to: "new".into(),
+                files: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn does_not_find_directory_when_only_some_files_moved() {
+        let diffs = [
+            diff("new/a.jpg", "1", moved("old/a.jpg")),
+            diff("new/b.jpg", "2", moved("old/b.jpg")),
+        ];
+        let indexed = indexed(&[("old/a.jpg", "1"), ("old/b.jpg", "2"), ("old/c.jpg", "3")]);
+        assert!(dir_moves(&diffs, &indexed).is_empty());
+    }
+
+    #[test]
+    fn does_not_find_directory_for_single_file_or_renames() {
+        let diffs = [
+            diff("new/a.jpg", "1", moved("old/a.jpg")),
+            diff("b2.jpg", "2", moved("b.jpg")),
+            diff("c2.jpg", "3", moved("c.jpg")),
+        ];
+        let indexed = indexed(&[("old/a.jpg", "1"), ("b.jpg", "2"), ("c.jpg", "3")]);
+        assert!(dir_moves(&diffs, &indexed).is_empty());
+    }
 }
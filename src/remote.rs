@@ -0,0 +1,383 @@
+//! Access to stores on other machines, by running `cstfs serve` on them over ssh and talking to it
+//! through its stdin and stdout.
+//!
+//! The protocol is line based: every request is a single line, answered by a line starting with
+//! either `ok` (followed by the response, if any) or `err` (followed by the error message). Paths
+//! are relative to the data directory, and the ones leading out of it are answered with an error.
+//!
+//! - `hash`: answered with `ok <algorithm>`, the name of the algorithm the files are hashed with
+//! - `list`: answered with `ok <count>`, followed by `count` lines of `<hash>\t<path>`
+//! - `exists <path>`: answered with `ok true` or `ok false`
+//! - `get <path>`: answered with `ok <size>`, followed by the `size` bytes of the file
+//! - `put <hash> <size> <path>`: followed by the `size` bytes of the file, answered with `ok`
+//! - `quit`: closes the connection
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+
+use crate::config::{Config, HashAlgorithm};
+use crate::sync::{LocalStore, Store};
+use crate::utils;
+
+/// Split `spec` into the host and path of a remote store if it looks like `[user@]host:path`.
+///
+/// It follows the same rules as scp: anything with a `/` before the first `:` is a local path, and
+/// so are Windows paths starting with a drive like `C:\`.
+#[must_use]
+pub fn parse_spec(spec: &str) -> Option<(&str, &str)> {
+    let (host, path) = spec.split_once(':')?;
+    let is_drive = host.len() == 1
+        && host.starts_with(|c: char| c.is_ascii_alphabetic())
+        && path.starts_with(['\\', '/']);
+    if host.is_empty() || host.contains(['/', '\\']) || is_drive {
+        return None;
+    }
+    Some((host, path))
+}
+
+/// Quote `s` so it is passed verbatim as a single argument through a posix shell
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Check that `path` can be sent as part of a single protocol line, and stays inside of the store
+fn check_path(path: &Utf8Path) -> Result<()> {
+    utils::check_relative(path)?;
+    if path.as_str().contains('\n') {
+        bail!("Path \"{path}\" contains a newline, which is not supported over ssh");
+    }
+    Ok(())
+}
+
+/// Copy `size` bytes from `contents` to `out`, the amount the other end was told it would read.
+/// Files that turn out to be shorter are padded with zeros so the next line is still where it is
+/// looked for, and fail the hash check of the receiving end. Returns the amount read from
+/// `contents`.
+fn send_exact(contents: &mut dyn Read, size: u64, out: &mut dyn Write) -> std::io::Result<u64> {
+    let sent = std::io::copy(&mut contents.take(size), out)?;
+    std::io::copy(&mut std::io::repeat(0).take(size - sent), out)?;
+    Ok(sent)
+}
+
+/// A store on another machine, accessed through ssh
+pub struct RemoteStore {
+    name: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl RemoteStore {
+    /// Run `remote_cstfs serve` on `host` for the store at `path`, and connect to it
+    pub fn connect(host: &str, path: &str, remote_cstfs: &str) -> Result<Self> {
+        let mut child = Command::new("ssh")
+            .arg(host)
+            .arg(format!(
+                "{} -d {} serve",
+                shell_quote(remote_cstfs),
+                shell_quote(path)
+            ))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .wrap_err("Failed running ssh")?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| eyre!("ssh has no stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| eyre!("ssh has no stdout"))?;
+
+        Ok(Self {
+            name: format!("{host}:{path}"),
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        })
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        let n = self
+            .stdout
+            .read_line(&mut line)
+            .wrap_err("Failed reading from remote")?;
+        if n == 0 {
+            bail!("Remote closed the connection");
+        }
+        Ok(line.trim_end_matches('\n').to_owned())
+    }
+
+    fn send(&mut self, request: &str) -> Result<()> {
+        writeln!(self.stdin, "{request}").wrap_err("Failed writing to remote")?;
+        self.stdin.flush().wrap_err("Failed writing to remote")
+    }
+
+    /// Read a response, returning what follows the `ok`
+    fn response(&mut self) -> Result<String> {
+        let line = self.read_line()?;
+        if let Some(msg) = line.strip_prefix("err ") {
+            bail!("Remote error: {msg}");
+        }
+        match line.strip_prefix("ok") {
+            Some(rest) => Ok(rest.trim_start().to_owned()),
+            None => bail!("Unexpected response from remote: {line}"),
+        }
+    }
+
+    fn request(&mut self, request: &str) -> Result<String> {
+        self.send(request)?;
+        self.response()
+    }
+}
+
+impl Drop for RemoteStore {
+    fn drop(&mut self) {
+        // The remote may have already gone away, there is nothing to be done about it
+        let _ = self.send("quit");
+        let _ = self.child.wait();
+    }
+}
+
+impl Store for RemoteStore {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn hash(&mut self) -> Result<HashAlgorithm> {
+        let name = self.request("hash")?;
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|a| a.name() == name)
+            .ok_or_else(|| eyre!("Unknown hash algorithm from remote: {name}"))
+    }
+
+    fn files(&mut self) -> Result<Vec<(String, String)>> {
+        let count: usize = self
+            .request("list")?
+            .parse()
+            .wrap_err("Invalid file count from remote")?;
+        let mut files = Vec::with_capacity(count);
+        for _ in 0..count {
+            let line = self.read_line()?;
+            let (hash, path) = line
+                .split_once('\t')
+                .ok_or_else(|| eyre!("Invalid file entry from remote: {line}"))?;
+            files.push((path.to_owned(), hash.to_owned()));
+        }
+        Ok(files)
+    }
+
+    fn exists(&mut self, path: &Utf8Path) -> Result<bool> {
+        check_path(path)?;
+        match self.request(&format!("exists {path}"))?.as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            r => bail!("Unexpected response from remote: {r}"),
+        }
+    }
+
+    fn open(&mut self, path: &Utf8Path) -> Result<(u64, Box<dyn Read + '_>)> {
+        check_path(path)?;
+        let size = self
+            .request(&format!("get {path}"))?
+            .parse()
+            .wrap_err("Invalid file size from remote")?;
+        Ok((size, Box::new((&mut self.stdout).take(size))))
+    }
+
+    fn put(
+        &mut self,
+        path: &Utf8Path,
+        hash: &str,
+        size: u64,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        check_path(path)?;
+        self.send(&format!("put {hash} {size} {path}"))?;
+        let written = send_exact(contents, size, &mut self.stdin)
+            .wrap_err("Failed sending file to remote")?;
+        self.stdin.flush().wrap_err("Failed writing to remote")?;
+        let res = self.response();
+        if written != size {
+            bail!("File changed size while sending it, expected {size} bytes but sent {written}");
+        }
+        res.map(drop)
+    }
+}
+
+/// Path of a file in a request, failing if it leads out of the store
+fn request_path(path: &str) -> Result<&Utf8Path> {
+    let path = Utf8Path::new(path);
+    utils::check_relative(path)?;
+    Ok(path)
+}
+
+/// Handle a single request from `stdin`, writing the response to `stdout`. Returns false once the
+/// connection should be closed.
+fn handle_request(
+    store: &mut LocalStore,
+    stdin: &mut impl BufRead,
+    stdout: &mut impl Write,
+) -> Result<bool> {
+    let mut line = String::new();
+    if stdin
+        .read_line(&mut line)
+        .wrap_err("Failed reading request")?
+        == 0
+    {
+        return Ok(false);
+    }
+    let line = line.trim_end_matches('\n');
+    let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+    let res = match command {
+        "hash" => store.hash().map(|hash| format!("ok {}\n", hash.name())),
+        "list" => store.files().map(|files| {
+            let mut response = format!("ok {}\n", files.len());
+            for (path, hash) in files {
+                let _ = writeln!(response, "{hash}\t{path}");
+            }
+            response
+        }),
+        "exists" => request_path(args)
+            .and_then(|path| store.exists(path))
+            .map(|exists| format!("ok {exists}\n")),
+        "get" => match request_path(args).and_then(|path| store.open(path)) {
+            Ok((size, mut contents)) => {
+                writeln!(stdout, "ok {size}").wrap_err("Failed writing response")?;
+                send_exact(&mut contents, size, stdout).wrap_err("Failed sending file")?;
+                stdout.flush().wrap_err("Failed writing response")?;
+                return Ok(true);
+            }
+            Err(e) => Err(e),
+        },
+        "put" => {
+            let mut args = args.splitn(3, ' ');
+            let (Some(hash), Some(size), Some(path)) = (args.next(), args.next(), args.next())
+            else {
+                bail!("Invalid put request: {line}");
+            };
+            let size = size.parse().wrap_err("Invalid file size in put request")?;
+            let mut contents = stdin.take(size);
+            let res =
+                request_path(path).and_then(|path| store.put(path, hash, size, &mut contents));
+            // Whatever happened, the rest of the file has to be skipped to read the next request
+            std::io::copy(&mut contents, &mut std::io::sink())
+                .wrap_err("Failed reading file contents")?;
+            res.map(|()| "ok\n".to_owned())
+        }
+        "quit" => return Ok(false),
+        _ => bail!("Unknown request: {line}"),
+    };
+    let response = match res {
+        Ok(response) => response,
+        Err(e) => format!("err {}\n", format!("{e:#}").replace('\n', " ")),
+    };
+    stdout
+        .write_all(response.as_bytes())
+        .wrap_err("Failed writing response")?;
+    stdout.flush().wrap_err("Failed writing response")?;
+
+    Ok(true)
+}
+
+/// Serve the store at `data_path` over stdin and stdout, for a `RemoteStore` on the other end
+pub fn serve(data_path: &Utf8Path, config: Config) -> Result<()> {
+    let mut store = LocalStore::open(data_path, config).wrap_err("Failed opening store")?;
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    while handle_request(&mut store, &mut stdin, &mut stdout)? {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::db;
+    use crate::utils::{hash_file, test_dir};
+
+    #[test]
+    fn parses_remote_specs() {
+        assert_eq!(parse_spec("host:photos"), Some(("host", "photos")));
+        assert_eq!(
+            parse_spec("user@host:/srv/photos"),
+            Some(("user@host", "/srv/photos"))
+        );
+        assert_eq!(parse_spec("host:"), Some(("host", "")));
+    }
+
+    #[test]
+    fn takes_local_paths_as_local() {
+        assert_eq!(parse_spec("photos"), None);
+        assert_eq!(parse_spec("/srv/photos"), None);
+        assert_eq!(parse_spec("./host:photos"), None);
+        assert_eq!(parse_spec(":photos"), None);
+        assert_eq!(parse_spec(r"C:\photos"), None);
+        assert_eq!(parse_spec("C:/photos"), None);
+    }
+
+    /// Answer every request in `requests` with the store at `data_path`, returning the responses
+    fn serve_requests(data_path: &Utf8Path, requests: &[u8]) -> Result<String> {
+        let mut store = LocalStore::open(data_path, Config::default())?;
+        let mut stdin = Cursor::new(requests);
+        let mut stdout = vec![];
+        while handle_request(&mut store, &mut stdin, &mut stdout)? {}
+        Ok(String::from_utf8(stdout)?)
+    }
+
+    #[test]
+    fn serves_files_over_the_line_protocol() -> Result<()> {
+        let dir = test_dir("serve");
+        let data_path = dir.join("store");
+        std::fs::create_dir(&data_path)?;
+        db::open(&data_path, &Config::default())?;
+        let source = dir.join("a.jpg");
+        std::fs::write(&source, "contents")?;
+        let hash = hash_file(&source, &Config::default())?;
+
+        let mut requests = format!("hash\nexists a.jpg\nput {hash} 8 a.jpg\ncontents").into_bytes();
+        requests.extend_from_slice(b"exists a.jpg\nlist\nget a.jpg\nquit\n");
+        let responses = serve_requests(&data_path, &requests)?;
+        assert_eq!(
+            responses,
+            format!("ok seahash\nok false\nok\nok true\nok 1\n{hash}\ta.jpg\nok 8\ncontents")
+        );
+        assert_eq!(
+            std::fs::read_to_string(data_path.join("a.jpg"))?,
+            "contents"
+        );
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_paths_leading_out_of_the_store() -> Result<()> {
+        let dir = test_dir("serve-outside");
+        let data_path = dir.join("store");
+        std::fs::create_dir(&data_path)?;
+        db::open(&data_path, &Config::default())?;
+
+        let requests = b"exists ../a.jpg\nget /etc/hostname\nput 0 3 ../b.jpg\nabcquit\n";
+        let responses = serve_requests(&data_path, requests)?;
+        let lines: Vec<_> = responses.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.starts_with("err ")), "{responses}");
+        assert!(!dir.join("b.jpg").exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use rusqlite::Transaction;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::exit::Failures;
+use crate::lock::Lock;
+use crate::{backup, sidecar, trash, utils};
+
+/// Remove the file at `path` with hash `hash` from the disk together with its sidecars, moving
+/// them to the trash if `use_trash` is set and recording that in the journal as part of
+/// `operation`
+pub fn delete(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    config: &Config,
+    use_trash: bool,
+    path: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    let sidecars = sidecar::find(data_path, config, path)?;
+    delete_file(transaction, operation, data_path, use_trash, path, hash)?;
+    // Sidecars are not indexed, they are trashed under the hash of the file they belong to
+    for sidecar in sidecars {
+        delete_file(transaction, operation, data_path, use_trash, &sidecar, hash)?;
+    }
+    Ok(())
+}
+
+fn delete_file(
+    transaction: &Transaction<'_>,
+    operation: i64,
+    data_path: &Utf8Path,
+    use_trash: bool,
+    path: &Utf8Path,
+    hash: &str,
+) -> Result<()> {
+    if !use_trash {
+        let full_path = utils::full_path(data_path, path);
+        utils::remove_file(&full_path).wrap_err_with(|| format!("Could not remove {path}"))?;
+        let action = JournalAction::Delete {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+        };
+        db::record(transaction, operation, &action).wrap_err("Failed recording removal")?;
+        info!("Removed file {path}");
+        return Ok(());
+    }
+
+    let trash_name = trash::trash_file(data_path, path, hash)
+        .wrap_err_with(|| format!("Could not move {path} to the trash"))?;
+    let action = JournalAction::Trash {
+        path: path.to_path_buf(),
+        hash: hash.to_owned(),
+        trash_name,
+    };
+    db::record(transaction, operation, &action).wrap_err("Failed recording removal")?;
+    info!("Moved file {path} to the trash");
+    Ok(())
+}
+
+/// Remove the files at `paths` from the disk and from the index at the same time. They are moved
+/// to the trash, unless `force` is set or `config` does not use it.
+///
+/// Files moved to the trash are moved back if the index cannot be changed, and the ones deleted
+/// for good are only deleted once it is.
+pub fn remove(
+    data_path: &Utf8Path,
+    config: &Config,
+    paths: &[impl AsRef<Utf8Path>],
+    force: bool,
+) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    backup::before(data_path, config, "rm")?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating remove transaction")?;
+    let operation =
+        db::begin_operation(&transaction, "rm").wrap_err("Failed recording operation")?;
+
+    // Every path is checked before removing any, to not stop halfway through
+    let mut files: Vec<(Utf8PathBuf, String)> = vec![];
+    for path in paths {
+        let path = utils::relative_path(data_path, path.as_ref())?;
+        if files.iter().any(|(p, _)| *p == path) {
+            continue;
+        }
+        let Some(hash) = db::hash(&transaction, &path).wrap_err("Failed fetching hash from db")?
+        else {
+            bail!("\"{path}\" is not in the index");
+        };
+        files.push((path, hash));
+    }
+
+    let mut removal = Removal::new(operation, data_path, config.use_trash && !force);
+    let res = files
+        .iter()
+        .try_for_each(|(path, hash)| removal.remove(&transaction, config, path, hash))
+        .and_then(|()| {
+            transaction
+                .commit()
+                .wrap_err("Could not commit transaction")
+        });
+    removal.finish(res)
+}
+
+/// Removal of files from the disk as part of `operation`, which is only final once the index is
+/// committed. Files moved to the trash are moved back if it is not, and the ones deleted for good
+/// are only deleted once it is.
+pub(crate) struct Removal<'a> {
+    operation: i64,
+    data_path: &'a Utf8Path,
+    use_trash: bool,
+    /// Names in the trash of the files moved to it
+    trashed: Vec<String>,
+    /// Files to delete for good once the index is committed
+    deleted: Vec<Utf8PathBuf>,
+}
+
+impl<'a> Removal<'a> {
+    pub(crate) const fn new(operation: i64, data_path: &'a Utf8Path, use_trash: bool) -> Self {
+        Self {
+            operation,
+            data_path,
+            use_trash,
+            trashed: vec![],
+            deleted: vec![],
+        }
+    }
+
+    /// Remove the file at `path` with hash `hash` from the index, and from the disk with its
+    /// sidecars
+    pub(crate) fn remove(
+        &mut self,
+        transaction: &Transaction<'_>,
+        config: &Config,
+        path: &Utf8Path,
+        hash: &str,
+    ) -> Result<()> {
+        db::remove(transaction, path, hash)
+            .wrap_err_with(|| format!("Failed removing {path} from the index"))?;
+        let action = JournalAction::Remove {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+        };
+        db::record(transaction, self.operation, &action).wrap_err("Failed recording removal")?;
+        self.delete(transaction, config, path, hash)
+    }
+
+    /// Remove the file at `path` with hash `hash` from the disk together with its sidecars,
+    /// recording that in the journal
+    pub(crate) fn delete(
+        &mut self,
+        transaction: &Transaction<'_>,
+        config: &Config,
+        path: &Utf8Path,
+        hash: &str,
+    ) -> Result<()> {
+        let sidecars = sidecar::find(self.data_path, config, path)?;
+        // Sidecars are not indexed, they are removed under the hash of the file they belong to
+        for path in std::iter::once(path).chain(sidecars.iter().map(Utf8PathBuf::as_path)) {
+            let action = if self.use_trash {
+                let trash_name = trash::trash_file(self.data_path, path, hash)
+                    .wrap_err_with(|| format!("Could not move {path} to the trash"))?;
+                self.trashed.push(trash_name.clone());
+                info!("Moved file {path} to the trash");
+                JournalAction::Trash {
+                    path: path.to_path_buf(),
+                    hash: hash.to_owned(),
+                    trash_name,
+                }
+            } else {
+                self.deleted.push(path.to_path_buf());
+                JournalAction::Delete {
+                    path: path.to_path_buf(),
+                    hash: hash.to_owned(),
+                }
+            };
+            db::record(transaction, self.operation, &action)
+                .wrap_err("Failed recording removal")?;
+        }
+        Ok(())
+    }
+
+    /// Finish the removal once committing the index ended with `res`, moving the files back from
+    /// the trash if it failed and deleting the rest for good if it did not
+    pub(crate) fn finish(self, res: Result<()>) -> Result<()> {
+        if let Err(e) = res {
+            // The index is left as it was, so the files go back where it says they are
+            for name in self.trashed.iter().rev() {
+                if let Err(e) = trash::restore_file(self.data_path, name) {
+                    warn!("Could not restore \"{name}\" from the trash: {e:#}");
+                }
+            }
+            return Err(e);
+        }
+
+        let mut failed = 0;
+        for path in self.deleted {
+            let full_path = utils::full_path(self.data_path, &path);
+            match utils::remove_file(&full_path) {
+                Ok(()) => info!("Removed file {path}"),
+                Err(e) => {
+                    warn!("Could not remove {path}: {e:#}");
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!(Failures(format!(
+                "{failed} files could not be removed, the next refresh will find them again"
+            )));
+        }
+        Ok(())
+    }
+}
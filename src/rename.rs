@@ -0,0 +1,92 @@
+use camino::Utf8Path;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::info;
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::{sidecar, utils};
+
+/// Move the file at `from` to `to`, updating its path in the index in the same transaction.
+///
+/// The move does not have to be found by the next refresh then. If `to` is a directory, the file
+/// is moved inside of it.
+pub fn rename(data_path: &Utf8Path, config: &Config, from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let from = utils::relative_path(data_path, from)?;
+    let mut to = utils::relative_path(data_path, to)?;
+    let full_from = utils::full_path(data_path, &from);
+    if !full_from.is_file() {
+        bail!("\"{from}\" is not a file");
+    }
+    if data_path.join(&to).is_dir() {
+        let file_name = from
+            .file_name()
+            .ok_or_else(|| eyre!("Path \"{from}\" has no file name"))?;
+        to.push(file_name);
+    }
+    let full_to = data_path.join(&to);
+    if full_to
+        .try_exists()
+        .wrap_err_with(|| format!("Could not check existence of \"{to}\""))?
+    {
+        bail!("Cannot move \"{from}\" to \"{to}\", which already exists");
+    }
+
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating move transaction")?;
+    let Some(hash) = db::hash(&transaction, &from).wrap_err("Failed fetching hash from db")? else {
+        bail!("\"{from}\" is not in the index");
+    };
+    let operation =
+        db::begin_operation(&transaction, "mv").wrap_err("Failed recording operation")?;
+    db::update_path(&transaction, &from, &to, &hash)
+        .wrap_err_with(|| format!("Failed updating path of {from}"))?;
+    let action = JournalAction::Rename {
+        path: to.clone(),
+        prev_path: from.clone(),
+        hash,
+    };
+    db::record(&transaction, operation, &action).wrap_err("Failed recording move")?;
+
+    std::fs::rename(&full_from, &full_to)
+        .wrap_err_with(|| format!("Failed moving \"{from}\" to \"{to}\""))?;
+    if let Err(e) = transaction.commit() {
+        // Put the file back, so it is still where the index says it is
+        std::fs::rename(&full_to, &full_from)
+            .wrap_err_with(|| format!("Failed moving \"{to}\" back to \"{from}\""))?;
+        return Err(e).wrap_err("Could not commit transaction");
+    }
+    info!("Moved: {from} -> {to}");
+    sidecar::move_along(data_path, config, &from, &to)?;
+
+    Ok(())
+}
+
+/// Move the file at `from` to `to`, both relative to the data directory, creating the
+/// directories `to` is in if needed. Its sidecars are moved along with it.
+pub fn move_file(
+    data_path: &Utf8Path,
+    config: &Config,
+    from: &Utf8Path,
+    to: &Utf8Path,
+) -> Result<()> {
+    let full_to = data_path.join(to);
+    if full_to
+        .try_exists()
+        .wrap_err_with(|| format!("Could not check existence of \"{full_to}\""))?
+    {
+        bail!("\"{to}\" already exists");
+    }
+    if let Some(parent) = full_to.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+    }
+    std::fs::rename(utils::full_path(data_path, from), &full_to).wrap_err("Failed moving file")?;
+    sidecar::move_along(data_path, config, from, to)
+}
@@ -0,0 +1,70 @@
+use camino::Utf8Path;
+use color_eyre::Result;
+
+use crate::refresh::Diff;
+
+/// How a duplicate file is dealt with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Remove the new file
+    RemoveNew,
+    /// Remove the file in the index and index the new one instead
+    RemoveOld,
+    /// Leave both files in place, without indexing the new one
+    Skip,
+}
+
+/// Receives the events of long running operations, to show their progress, and decides what to do
+/// with duplicate and changed files when the policy is to ask.
+///
+/// Every method does nothing by default, duplicates are skipped and changes are not recorded. Files are hashed in parallel,
+/// so the hashing events may come from several threads at once.
+pub trait Reporter: Sync {
+    /// Hashing of `files` files, adding up to `bytes` bytes, started
+    fn hashing_started(&self, files: usize, bytes: u64) {
+        let _ = (files, bytes);
+    }
+
+    /// The file at `path`, which is `bytes` bytes long, was found and will be hashed, when the
+    /// files are hashed while they are being found and their amount is not known at the start
+    fn file_found(&self, path: &Utf8Path, bytes: u64) {
+        let _ = (path, bytes);
+    }
+
+    /// The file at `path` started being hashed
+    fn file_started(&self, path: &Utf8Path) {
+        let _ = path;
+    }
+
+    /// The file at `path`, which is `bytes` bytes long, was hashed
+    fn file_hashed(&self, path: &Utf8Path, bytes: u64) {
+        let _ = (path, bytes);
+    }
+
+    /// Every file was hashed
+    fn hashing_finished(&self) {}
+
+    /// A change between the data directory and the index was found
+    fn diff_found(&self, diff: &Diff) {
+        let _ = diff;
+    }
+
+    /// Decide what to do with `path_new`, which has the same contents as `path_old`. Returning an
+    /// error stops the operation without changing the index.
+    fn duplicate(&self, path_old: &Utf8Path, path_new: &Utf8Path) -> Result<Resolution> {
+        let _ = (path_old, path_new);
+        Ok(Resolution::Skip)
+    }
+
+    /// Decide whether the new contents of the indexed file at `path`, whose hash changed, are
+    /// recorded in the index. Returning an error stops the operation without changing the index.
+    fn changed(&self, path: &Utf8Path) -> Result<bool> {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+/// Reporter that ignores every event and skips every duplicate
+pub struct Silent;
+
+impl Reporter for Silent {}
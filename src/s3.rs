@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::lock::Lock;
+use crate::utils::{full_path, hash_file};
+
+/// Payload hash used when the body is not signed, to avoid reading every file twice
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Where the remote copies of the indexed files live
+pub struct Bucket {
+    /// Url of the S3 compatible service, like `https://s3.us-east-1.amazonaws.com`
+    pub endpoint: String,
+    /// Name of the bucket
+    pub name: String,
+    /// Region of the bucket, used for signing requests
+    pub region: String,
+    /// Prefix prepended to every object key
+    pub prefix: String,
+}
+
+impl Bucket {
+    /// Identifier of this bucket in the `remote_objects` table
+    fn id(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.endpoint.trim_end_matches('/'),
+            self.name,
+            self.prefix
+        )
+    }
+
+    fn key(&self, hash: &str) -> String {
+        format!("{}{hash}", self.prefix)
+    }
+}
+
+/// Credentials used to sign requests, read from the usual AWS environment variables
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self> {
+        let var = |name| std::env::var(name).wrap_err_with(|| format!("{name} is not set"));
+        Ok(Self {
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent encode `s` as required by `SigV4`, leaving `/` untouched
+fn uri_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~' | b'/') {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{b:02X}");
+        }
+    }
+    out
+}
+
+/// Format `time` as an ISO 8601 basic timestamp (`YYYYMMDDTHHMMSSZ`), as used by `SigV4`
+fn amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .expect("System time is after the epoch")
+        .as_secs();
+    let days = i64::try_from(secs / 86400).expect("Date fits in an i64");
+    let rem = secs % 86400;
+    // Days since the epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// A client for the handful of S3 operations needed to mirror the index
+struct Client<'a> {
+    bucket: &'a Bucket,
+    credentials: Credentials,
+    agent: ureq::Agent,
+}
+
+impl<'a> Client<'a> {
+    fn new(bucket: &'a Bucket) -> Result<Self> {
+        let credentials = Credentials::from_env().wrap_err("Failed reading S3 credentials")?;
+        let agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .new_agent();
+        Ok(Self {
+            bucket,
+            credentials,
+            agent,
+        })
+    }
+
+    /// Url and signed headers of a request for the object at `key`
+    fn sign(&self, method: &str, key: &str) -> Result<(String, Vec<(&'static str, String)>)> {
+        let endpoint = self.bucket.endpoint.trim_end_matches('/');
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint, |(_, host)| host)
+            .to_owned();
+        if host.is_empty() || host.contains('/') {
+            bail!("Invalid S3 endpoint \"{endpoint}\"");
+        }
+        let uri = uri_encode(&format!("/{}/{key}", self.bucket.name));
+
+        let date_time = amz_date(SystemTime::now());
+        let date = &date_time[..8];
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_owned()),
+            ("x-amz-date", date_time.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers = headers.iter().fold(String::new(), |mut out, (k, v)| {
+            let _ = writeln!(out, "{k}:{}", v.trim());
+            out
+        });
+        let canonical_request =
+            format!("{method}\n{uri}\n\n{canonical_headers}\n{signed_headers}\n{UNSIGNED_PAYLOAD}");
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.bucket.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{date_time}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = format!("AWS4{}", self.credentials.secret_key).into_bytes();
+        for part in [date, &self.bucket.region, "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part);
+        }
+        let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+        // The host header is set by the http client from the url
+        headers.remove(0);
+        headers.push((
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                self.credentials.access_key
+            ),
+        ));
+
+        Ok((format!("{endpoint}{uri}"), headers))
+    }
+
+    /// Whether there is an object for `hash` in the bucket
+    fn exists(&self, hash: &str) -> Result<bool> {
+        let (url, headers) = self.sign("HEAD", &self.bucket.key(hash))?;
+        let mut req = self.agent.head(&url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        let res = req.call().wrap_err("HEAD request failed")?;
+        match res.status().as_u16() {
+            200 => Ok(true),
+            404 => Ok(false),
+            status => bail!("HEAD request failed with status {status}"),
+        }
+    }
+
+    /// Upload the file at `path` as the object for `hash`
+    fn put(&self, hash: &str, path: &Utf8Path) -> Result<()> {
+        let file = File::open(path).wrap_err_with(|| format!("Failed opening \"{path}\""))?;
+        let (url, headers) = self.sign("PUT", &self.bucket.key(hash))?;
+        let mut req = self.agent.put(&url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        let mut res = req.send(&file).wrap_err("PUT request failed")?;
+        if !res.status().is_success() {
+            let body = res.body_mut().read_to_string().unwrap_or_default();
+            bail!("PUT request failed with status {}: {body}", res.status());
+        }
+        Ok(())
+    }
+
+    /// Download the object for `hash` into the file at `path`, replacing it if it exists
+    fn get(&self, hash: &str, path: &Utf8Path) -> Result<()> {
+        let (url, headers) = self.sign("GET", &self.bucket.key(hash))?;
+        let mut req = self.agent.get(&url);
+        for (k, v) in headers {
+            req = req.header(k, v);
+        }
+        let mut res = req.call().wrap_err("GET request failed")?;
+        if !res.status().is_success() {
+            let body = res.body_mut().read_to_string().unwrap_or_default();
+            bail!("GET request failed with status {}: {body}", res.status());
+        }
+        let mut file =
+            File::create(path).wrap_err_with(|| format!("Failed creating \"{path}\""))?;
+        std::io::copy(&mut res.body_mut().as_reader(), &mut file)
+            .wrap_err("Failed writing downloaded object")?;
+        Ok(())
+    }
+}
+
+/// Upload every indexed file whose hash is not yet known to be in `bucket`, recording each one in
+/// the `remote_objects` table
+pub fn push(data_path: &Utf8Path, config: &Config, bucket: &Bucket) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    let remote = bucket.id();
+    let remote_hashes: HashSet<String> = db::remote_hashes(&conn, &remote)
+        .wrap_err("Failed fetching remote hashes from db")?
+        .into_iter()
+        .collect();
+    let client = Client::new(bucket)?;
+
+    info!("Pushing \"{data_path}\" to \"{remote}\"");
+    let now = Instant::now();
+    let (mut uploaded, mut present) = (0, 0);
+    for (path, hash) in &files {
+        if remote_hashes.contains(hash) {
+            continue;
+        }
+        if client
+            .exists(hash)
+            .wrap_err_with(|| format!("Could not check remote existence of {hash}"))?
+        {
+            present += 1;
+        } else {
+            info!("Uploading \"{path}\"");
+            client
+                .put(hash, &full_path(data_path, Utf8Path::new(path)))
+                .wrap_err_with(|| format!("Failed uploading \"{path}\""))?;
+            uploaded += 1;
+        }
+        // Recorded right away, so an interrupted push does not start over
+        let transaction = conn
+            .transaction()
+            .wrap_err("Failed creating insert transaction")?;
+        db::insert_remote_object(&transaction, &remote, hash)
+            .wrap_err_with(|| format!("Failed recording remote object {hash}"))?;
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+    }
+
+    let elapsed = now.elapsed();
+    info!("Uploaded {uploaded} files ({present} were already present). Took {elapsed:.2?}");
+
+    Ok(())
+}
+
+/// Path a file at `path` is downloaded to before it is checked, hidden so it is not indexed
+fn partial_path(path: &Utf8Path) -> Utf8PathBuf {
+    path.with_file_name(format!(".{}.partial", path.file_name().unwrap_or_default()))
+}
+
+/// Download every indexed file that is missing from the data directory but is in `bucket`
+pub fn fetch(data_path: &Utf8Path, config: &Config, bucket: &Bucket) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+    let remote = bucket.id();
+    let remote_hashes: HashSet<String> = db::remote_hashes(&conn, &remote)
+        .wrap_err("Failed fetching remote hashes from db")?
+        .into_iter()
+        .collect();
+    let client = Client::new(bucket)?;
+
+    info!("Fetching missing files of \"{data_path}\" from \"{remote}\"");
+    let now = Instant::now();
+    let (mut fetched, mut unavailable) = (0, 0);
+    for (path, hash) in &files {
+        let full_path = full_path(data_path, Utf8Path::new(path));
+        if full_path
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{full_path}\""))?
+        {
+            continue;
+        }
+        if !remote_hashes.contains(hash) {
+            warn!("\"{path}\" is missing, but was never pushed to \"{remote}\"");
+            unavailable += 1;
+            continue;
+        }
+
+        info!("Downloading \"{path}\"");
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+        }
+        // Downloaded next to it first, so an interrupted download is not taken for the file
+        let partial = partial_path(&full_path);
+        client
+            .get(hash, &partial)
+            .wrap_err_with(|| format!("Failed downloading \"{path}\""))?;
+        let downloaded_hash = hash_file(&partial, config)
+            .wrap_err_with(|| format!("Could not hash downloaded file {partial}"))?;
+        if downloaded_hash != *hash {
+            crate::utils::remove_file(&partial)
+                .wrap_err_with(|| format!("Failed removing corrupt download {partial}"))?;
+            return Err(eyre!(
+                "Downloaded \"{path}\" has hash {downloaded_hash}, expected {hash}"
+            ));
+        }
+        std::fs::rename(&partial, &full_path)
+            .wrap_err_with(|| format!("Failed moving download to \"{full_path}\""))?;
+        fetched += 1;
+    }
+
+    let elapsed = now.elapsed();
+    info!("Downloaded {fetched} files ({unavailable} unavailable). Took {elapsed:.2?}");
+
+    Ok(())
+}
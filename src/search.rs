@@ -0,0 +1,227 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{Datelike, Local, TimeZone};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::info;
+
+use crate::config::Config;
+use crate::db::{self, IndexedFile};
+use crate::list;
+use crate::utils::Abbrev;
+
+/// A condition on one of the things known about an indexed file
+#[derive(Debug)]
+enum Term {
+    /// Its extension is this one, in any case
+    Ext(String),
+    /// It is inside of this directory, relative to the data directory, at any depth
+    Under(Utf8PathBuf),
+    /// It takes at least this many bytes
+    MinSize(u64),
+    /// It takes at most this many bytes
+    MaxSize(u64),
+    /// It was modified after this time, in nanoseconds since the unix epoch
+    Since(i64),
+    /// It was modified in this year, in the local timezone
+    Year(i32),
+}
+
+impl Term {
+    fn parse(term: &str) -> Result<Self> {
+        let Some((key, value)) = term.split_once(':') else {
+            bail!("Expected a term like `ext:jpg`, found `{term}`");
+        };
+        let invalid = |what: &str| format!("Invalid {what} in `{term}`");
+        Ok(match key {
+            "ext" => Self::Ext(value.trim_start_matches('.').to_owned()),
+            "under" => Self::Under(value.trim_end_matches('/').into()),
+            "min-size" => {
+                Self::MinSize(parse_size::parse_size(value).wrap_err_with(|| invalid("size"))?)
+            }
+            "max-size" => {
+                Self::MaxSize(parse_size::parse_size(value).wrap_err_with(|| invalid("size"))?)
+            }
+            "since" => {
+                let since =
+                    list::parse_since(value).map_err(|e| eyre!("{}: {e}", invalid("time")))?;
+                Self::Since(since.timestamp_nanos_opt().unwrap_or(i64::MIN))
+            }
+            "year" => Self::Year(value.parse().wrap_err_with(|| invalid("year"))?),
+            "tag" => bail!("Files have no tags in the index, `{term}` cannot match any"),
+            _ => bail!(
+                "Unknown key `{key}` in `{term}`, expected ext, under, min-size, max-size, since or year"
+            ),
+        })
+    }
+
+    /// Whether `file` meets the condition. Files whose size or modification time are not known
+    /// yet never meet the conditions on them.
+    fn matches(&self, file: &IndexedFile) -> bool {
+        let path = Utf8Path::new(&file.path);
+        match self {
+            Self::Ext(ext) => path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case(ext)),
+            Self::Under(dir) => path.starts_with(dir),
+            Self::MinSize(min) => matches!(file.size, Some(s) if s >= *min),
+            Self::MaxSize(max) => matches!(file.size, Some(s) if s <= *max),
+            Self::Since(since) => matches!(file.mtime, Some(m) if m >= *since),
+            Self::Year(year) => file
+                .mtime
+                .is_some_and(|m| Local.timestamp_nanos(m).year() == *year),
+        }
+    }
+}
+
+/// A search query, made of terms like `ext:jpg` combined with `AND`, `OR` and `NOT`, and grouped
+/// with parentheses. Terms next to each other without an operator between them must all match.
+#[derive(Debug)]
+enum Query {
+    Term(Term),
+    Not(Box<Self>),
+    And(Box<Self>, Box<Self>),
+    Or(Box<Self>, Box<Self>),
+}
+
+impl Query {
+    fn parse(query: &str) -> Result<Self> {
+        let spaced = query.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut parser = Parser { tokens, next: 0 };
+        let parsed = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected `{token}` in search query");
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, file: &IndexedFile) -> bool {
+        match self {
+            Self::Term(term) => term.matches(file),
+            Self::Not(q) => !q.matches(file),
+            Self::And(a, b) => a.matches(file) && b.matches(file),
+            Self::Or(a, b) => a.matches(file) || b.matches(file),
+        }
+    }
+}
+
+/// Recursive descent parser of a [`Query`], with `OR` binding the loosest and `NOT` the tightest
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).copied()
+    }
+
+    /// Take the next token if it is the operator `op`, in any case
+    fn eat(&mut self, op: &str) -> bool {
+        let found = self.peek().is_some_and(|t| t.eq_ignore_ascii_case(op));
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut query = self.and()?;
+        while self.eat("OR") {
+            query = Query::Or(Box::new(query), Box::new(self.and()?));
+        }
+        Ok(query)
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut query = self.not()?;
+        loop {
+            if !self.eat("AND") {
+                match self.peek() {
+                    Some(t) if t != ")" && !t.eq_ignore_ascii_case("OR") => {}
+                    _ => return Ok(query),
+                }
+            }
+            query = Query::And(Box::new(query), Box::new(self.not()?));
+        }
+    }
+
+    fn not(&mut self) -> Result<Query> {
+        if self.eat("NOT") {
+            return Ok(Query::Not(Box::new(self.not()?)));
+        }
+        let Some(token) = self.peek() else {
+            bail!("Search query ended where a term was expected");
+        };
+        self.next += 1;
+        if token == "(" {
+            let query = self.or()?;
+            if !self.eat(")") {
+                bail!("Missing `)` in search query");
+            }
+            return Ok(query);
+        }
+        Ok(Query::Term(Term::parse(token)?))
+    }
+}
+
+/// Indexed files matching `query`, by path
+pub(crate) fn matching(conn: &rusqlite::Connection, query: &str) -> Result<Vec<IndexedFile>> {
+    let parsed = Query::parse(query).wrap_err("Invalid search query")?;
+    let mut files = db::listing(conn).wrap_err("Failed fetching files from db")?;
+    files.retain(|f| parsed.matches(f));
+    files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Print the hash, size and path of every indexed file matching `query`, by path
+pub fn query(data_path: &Utf8Path, config: &Config, query: &str) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = matching(&conn, query)?;
+    list::print(&files, &Abbrev::new(&conn, config)?);
+    Ok(())
+}
+
+/// Save `query` as `name`, to run it later with [`run`]. It is checked to be valid first.
+pub fn save(data_path: &Utf8Path, config: &Config, name: &str, query: &str) -> Result<()> {
+    Query::parse(query).wrap_err("Invalid search query")?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    if db::save_search(&conn, name, query).wrap_err("Failed saving search")? {
+        info!("Replaced saved search \"{name}\"");
+    } else {
+        info!("Saved search \"{name}\"");
+    }
+    Ok(())
+}
+
+/// Print the files matching the search saved as `name`, like [`query`]
+pub fn run(data_path: &Utf8Path, config: &Config, name: &str) -> Result<()> {
+    let saved = {
+        let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+        db::search(&conn, name).wrap_err("Failed fetching saved search")?
+    };
+    query(data_path, config, &saved)
+}
+
+/// Print every saved search, with its query
+pub fn list(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let searches = db::searches(&conn).wrap_err("Failed fetching saved searches")?;
+    if searches.is_empty() {
+        println!("There are no saved searches");
+    }
+    for (name, query) in searches {
+        println!("{name}: {query}");
+    }
+    Ok(())
+}
+
+/// Remove the search saved as `name`
+pub fn remove(data_path: &Utf8Path, config: &Config, name: &str) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    db::remove_search(&conn, name).wrap_err("Failed removing saved search")?;
+    info!("Removed saved search \"{name}\"");
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Sidecars are the files photo editors keep next to a RAW file with the edits made to it, like
+//! `.xmp` and `.pp3` files. They are named after the file they belong to, either after its whole
+//! name (`IMG_1.CR2.xmp`, as darktable does) or after its stem (`IMG_1.xmp`, as Lightroom does).
+//!
+//! They are not indexed on their own, since they change every time the photo is edited, but they
+//! are moved and removed together with the file they belong to.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::utils::full_path;
+
+/// Check if the file at `path` is a sidecar, by its extension
+#[must_use]
+pub fn is_sidecar(path: &Utf8Path, config: &Config) -> bool {
+    path.extension()
+        .is_some_and(|ext| config.extensions.is_sidecar(ext))
+}
+
+/// Check if another file than the one at `full_path` has the same stem, apart from sidecars, in
+/// which case a sidecar named after the stem cannot be told to belong to either of them
+fn shares_stem(full_path: &Utf8Path, config: &Config) -> Result<bool> {
+    let (Some(parent), Some(file_name), Some(stem)) = (
+        full_path.parent(),
+        full_path.file_name(),
+        full_path.file_stem(),
+    ) else {
+        return Ok(false);
+    };
+    for entry in parent
+        .read_dir_utf8()
+        .wrap_err_with(|| format!("Failed reading directory contents of {parent}"))?
+    {
+        let entry = entry.wrap_err_with(|| format!("Failed reading an entry of {parent}"))?;
+        let path = entry.path();
+        if entry.file_name() != file_name
+            && path.file_stem() == Some(stem)
+            && !is_sidecar(path, config)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Sidecars of the file at `path` that exist, relative to the data directory like it
+pub fn find(data_path: &Utf8Path, config: &Config, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let (Some(file_name), Some(stem)) = (path.file_name(), path.file_stem()) else {
+        return Ok(vec![]);
+    };
+    let parent = path.parent().unwrap_or_else(|| Utf8Path::new(""));
+    let exists = |p: &Utf8Path| {
+        data_path
+            .join(p)
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{p}\""))
+    };
+
+    let mut found = vec![];
+    let mut by_stem = vec![];
+    for ext in &config.extensions.sidecar {
+        let sidecar = parent.join(format!("{file_name}.{ext}"));
+        if exists(&sidecar)? {
+            found.push(sidecar);
+        }
+        if stem != file_name {
+            let sidecar = parent.join(format!("{stem}.{ext}"));
+            if exists(&sidecar)? {
+                by_stem.push(sidecar);
+            }
+        }
+    }
+    if !by_stem.is_empty() && !shares_stem(&full_path(data_path, path), config)? {
+        found.extend(by_stem);
+    }
+    Ok(found)
+}
+
+/// Path the sidecar at `sidecar` of the file at `from` goes to when the file is moved to `to`,
+/// keeping the way it is named
+fn moved_path(sidecar: &Utf8Path, from: &Utf8Path, to: &Utf8Path) -> Option<Utf8PathBuf> {
+    let sidecar_name = sidecar.file_name()?;
+    let (from_name, to_name) = (from.file_name()?, to.file_name()?);
+    let (from_stem, to_stem) = (from.file_stem()?, to.file_stem()?);
+    let name = match sidecar_name.strip_prefix(from_name) {
+        Some(ext) => format!("{to_name}{ext}"),
+        None => format!("{to_stem}{}", sidecar_name.strip_prefix(from_stem)?),
+    };
+    Some(to.parent().unwrap_or_else(|| Utf8Path::new("")).join(name))
+}
+
+/// Move the sidecars of the file at `from` next to it after it was moved to `to`, both relative to
+/// the data directory. Sidecars that would overwrite another file are left where they are.
+pub fn move_along(
+    data_path: &Utf8Path,
+    config: &Config,
+    from: &Utf8Path,
+    to: &Utf8Path,
+) -> Result<()> {
+    for sidecar in find(data_path, config, from)? {
+        let Some(dst) = moved_path(&sidecar, from, to) else {
+            continue;
+        };
+        let full_dst = data_path.join(&dst);
+        if full_dst
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{dst}\""))?
+        {
+            warn!("Leaving sidecar \"{sidecar}\" in place, \"{dst}\" already exists");
+            continue;
+        }
+        std::fs::rename(data_path.join(&sidecar), &full_dst)
+            .wrap_err_with(|| format!("Failed moving sidecar \"{sidecar}\" to \"{dst}\""))?;
+        info!("Moved: {sidecar} -> {dst}");
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Signatures of the files cstfs exports, like dumps of the index and checksum manifests, to prove
+//! they were not tampered with when they are restored or checked later.
+//!
+//! Keys are ed25519 key pairs. Public keys and signatures are written like minisign does, so
+//! minisign can check the signatures too. Signatures are of the whole file, as the legacy
+//! minisign ones, and are kept next to the file they sign, named like it with `.minisig` added.
+//! Secret keys are kept in a format of their own, without a password, so they must only be
+//! readable by their owner. Only with the `sign` feature.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::info;
+
+use crate::config::Config;
+
+/// Algorithm of the signatures, in minisign's terms: ed25519 of the whole file
+const ALGORITHM: &[u8; 2] = b"Ed";
+
+/// Algorithm of minisign signatures of the blake2b hash of the file, which are not supported
+const PREHASHED_ALGORITHM: &[u8; 2] = b"ED";
+
+/// Start of the first line of keys and signatures, which says what they are
+const UNTRUSTED_COMMENT: &str = "untrusted comment: ";
+
+/// Start of the line of signatures after the signature itself, which is signed too
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+
+/// Random number telling keys apart, so a signature is only checked against the key it was made
+/// with
+type KeyId = [u8; 8];
+
+/// How minisign shows key ids, as a little endian number in uppercase hex
+fn show_id(id: KeyId) -> String {
+    format!("{:016X}", u64::from_le_bytes(id))
+}
+
+/// Path of the signature of the file at `path`
+#[must_use]
+pub fn signature_path(path: &Utf8Path) -> Utf8PathBuf {
+    format!("{path}.minisig").into()
+}
+
+/// Decode the base64 line after the comment in the file at `path`, which is a `what`
+fn read_encoded(path: &Utf8Path, what: &str) -> Result<Vec<u8>> {
+    let contents =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+    let mut lines = contents.lines();
+    if !lines
+        .next()
+        .is_some_and(|l| l.starts_with(UNTRUSTED_COMMENT))
+    {
+        bail!("\"{path}\" is not a {what}");
+    }
+    lines
+        .next()
+        .and_then(|l| STANDARD.decode(l.trim()).ok())
+        .ok_or_else(|| eyre!("\"{path}\" is not a {what}"))
+}
+
+/// Split `bytes`, made of an algorithm, a key id and `N` bytes more, into the last two, checking
+/// the algorithm
+fn split_key<const N: usize>(bytes: &[u8]) -> Option<(KeyId, [u8; N])> {
+    if bytes.get(..2)? != ALGORITHM {
+        return None;
+    }
+    let id = bytes.get(2..10)?.try_into().ok()?;
+    Some((id, bytes[10..].try_into().ok()?))
+}
+
+/// Make a new key pair, writing the secret key to `path` and the public key next to it, named
+/// like it with `.pub` added. Existing files are not overwritten.
+pub fn generate(path: &Utf8Path) -> Result<()> {
+    let secret = crypto::generate()?;
+    let public = crypto::public_key(&secret)?;
+    let id: KeyId = fastrand::u64(..).to_le_bytes();
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .wrap_err_with(|| format!("Failed creating \"{path}\""))?;
+    writeln!(
+        file,
+        "{UNTRUSTED_COMMENT}cstfs secret key {}, keep it private\n{}",
+        show_id(id),
+        STANDARD.encode([&id[..], &secret].concat())
+    )
+    .wrap_err_with(|| format!("Failed writing \"{path}\""))?;
+
+    let public_path = Utf8PathBuf::from(format!("{path}.pub"));
+    let public_key = format!(
+        "{UNTRUSTED_COMMENT}minisign public key {}\n{}\n",
+        show_id(id),
+        STANDARD.encode([&ALGORITHM[..], &id, &public].concat())
+    );
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&public_path)
+        .wrap_err_with(|| format!("Failed creating \"{public_path}\""))?;
+    file.write_all(public_key.as_bytes())
+        .wrap_err_with(|| format!("Failed writing \"{public_path}\""))?;
+    info!(
+        "Made key {} at \"{path}\", and its public key at \"{public_path}\"",
+        show_id(id)
+    );
+    Ok(())
+}
+
+/// Sign the file at `path` with the signing key of `config`, if it has one, writing the signature
+/// to [`signature_path`]. The key is relative to the data directory.
+pub fn sign(data_path: &Utf8Path, config: &Config, path: &Utf8Path) -> Result<()> {
+    let Some(key_path) = &config.signing_key else {
+        return Ok(());
+    };
+    let key_path = data_path.join(key_path);
+    let key = read_encoded(&key_path, "cstfs secret key")?;
+    let id: KeyId = key
+        .get(..8)
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(|| eyre!("\"{key_path}\" is not a cstfs secret key"))?;
+    let secret = &key[8..];
+
+    let contents = std::fs::read(path).wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+    let signature = crypto::sign(secret, &contents)
+        .wrap_err_with(|| format!("\"{key_path}\" is not a cstfs secret key"))?;
+    let trusted_comment = format!(
+        "timestamp:{}\tfile:{}",
+        Utc::now().timestamp(),
+        path.file_name().unwrap_or(path.as_str())
+    );
+    let global_signature =
+        crypto::sign(secret, &[&signature, trusted_comment.as_bytes()].concat())?;
+
+    let signature_path = signature_path(path);
+    let signature = format!(
+        "{UNTRUSTED_COMMENT}signature from cstfs secret key {}\n{}\n{TRUSTED_COMMENT}{trusted_comment}\n{}\n",
+        show_id(id),
+        STANDARD.encode([&ALGORITHM[..], &id, &signature].concat()),
+        STANDARD.encode(global_signature)
+    );
+    std::fs::write(&signature_path, signature)
+        .wrap_err_with(|| format!("Failed writing \"{signature_path}\""))?;
+    info!("Signed \"{path}\" with key {}", show_id(id));
+    Ok(())
+}
+
+/// Check that the file at `path` was signed by the key whose public key is at `public_key`, and
+/// was not changed since, returning the trusted comment of the signature, with when it was made
+pub fn verify(path: &Utf8Path, public_key: &Utf8Path) -> Result<String> {
+    let (key_id, public) = split_key::<32>(&read_encoded(public_key, "minisign public key")?)
+        .ok_or_else(|| eyre!("\"{public_key}\" is not an ed25519 minisign public key"))?;
+
+    let signature_path = signature_path(path);
+    let signature_file = std::fs::read_to_string(&signature_path)
+        .wrap_err_with(|| format!("Failed reading signature \"{signature_path}\""))?;
+    let lines: Vec<&str> = signature_file.lines().collect();
+    let [_, signature, trusted_comment, global_signature] = lines[..] else {
+        bail!("\"{signature_path}\" is not a minisign signature");
+    };
+    let signature = STANDARD
+        .decode(signature.trim())
+        .wrap_err_with(|| format!("\"{signature_path}\" is not a minisign signature"))?;
+    if signature.starts_with(PREHASHED_ALGORITHM) {
+        bail!("\"{signature_path}\" is a signature of the hash of the file, which cstfs cannot check, sign it with `minisign -l`");
+    }
+    let (id, signature) = split_key::<64>(&signature)
+        .ok_or_else(|| eyre!("\"{signature_path}\" is not a minisign signature"))?;
+    if id != key_id {
+        bail!(
+            "\"{path}\" was signed with key {}, not with key {} at \"{public_key}\"",
+            show_id(id),
+            show_id(key_id)
+        );
+    }
+    let trusted_comment = trusted_comment
+        .strip_prefix(TRUSTED_COMMENT)
+        .ok_or_else(|| eyre!("\"{signature_path}\" is not a minisign signature"))?;
+    let global_signature = STANDARD
+        .decode(global_signature.trim())
+        .wrap_err_with(|| format!("\"{signature_path}\" is not a minisign signature"))?;
+
+    let contents = std::fs::read(path).wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+    crypto::verify(&public, &contents, &signature).wrap_err_with(|| {
+        format!("The signature of \"{path}\" does not match it, it was changed since it was signed")
+    })?;
+    crypto::verify(
+        &public,
+        &[&signature, trusted_comment.as_bytes()].concat(),
+        &global_signature,
+    )
+    .wrap_err_with(|| format!("The trusted comment of \"{signature_path}\" was changed"))?;
+    info!(
+        "\"{path}\" was signed with key {}: {trusted_comment}",
+        show_id(id)
+    );
+    Ok(trusted_comment.to_owned())
+}
+
+#[cfg(feature = "sign")]
+mod crypto {
+    use color_eyre::{eyre::eyre, Result};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+    /// New secret key, as a PKCS#8 document
+    pub fn generate() -> Result<Vec<u8>> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map(|doc| doc.as_ref().to_vec())
+            .map_err(|_| eyre!("Failed generating key"))
+    }
+
+    fn key_pair(secret: &[u8]) -> Result<Ed25519KeyPair> {
+        Ed25519KeyPair::from_pkcs8(secret).map_err(|e| eyre!("Invalid secret key: {e}"))
+    }
+
+    pub fn public_key(secret: &[u8]) -> Result<Vec<u8>> {
+        Ok(key_pair(secret)?.public_key().as_ref().to_vec())
+    }
+
+    pub fn sign(secret: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+        Ok(key_pair(secret)?.sign(message).as_ref().to_vec())
+    }
+
+    pub fn verify(public: &[u8], message: &[u8], signature: &[u8]) -> Result<()> {
+        UnparsedPublicKey::new(&ED25519, public)
+            .verify(message, signature)
+            .map_err(|_| eyre!("Invalid signature"))
+    }
+}
+
+#[cfg(not(feature = "sign"))]
+mod crypto {
+    use color_eyre::{eyre::bail, Result};
+
+    const UNSUPPORTED: &str = "cstfs was built without the `sign` feature to sign files";
+
+    pub fn generate() -> Result<Vec<u8>> {
+        bail!(UNSUPPORTED)
+    }
+
+    pub fn public_key(_: &[u8]) -> Result<Vec<u8>> {
+        bail!(UNSUPPORTED)
+    }
+
+    pub fn sign(_: &[u8], _: &[u8]) -> Result<Vec<u8>> {
+        bail!(UNSUPPORTED)
+    }
+
+    pub fn verify(_: &[u8], _: &[u8], _: &[u8]) -> Result<()> {
+        bail!(UNSUPPORTED)
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::Mutex;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::bail, Report, Result};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::exit::Failures;
+use crate::utils::{normalize, Unreadable};
+
+/// Files and directories a command could not read, which it carries on past when `keep-going` is
+/// set, to report all of them once it is done
+#[derive(Default)]
+pub struct Skipped {
+    /// Paths that could not be read, `None` when it is not known which one it was
+    paths: Mutex<Vec<Option<Utf8PathBuf>>>,
+}
+
+impl Skipped {
+    /// Keep the `error` reading `path` to carry on past it, or return it if `config` says to stop
+    fn keep(&self, config: &Config, path: Option<&Utf8Path>, error: Report) -> Result<()> {
+        if !config.keep_going {
+            return Err(error);
+        }
+        warn!("Skipping, {error:#}");
+        self.paths
+            .lock()
+            .expect("Hashing thread panicked")
+            .push(path.map(normalize));
+        Ok(())
+    }
+
+    /// Pass on the paths found by a walk, keeping the errors reading the files and directories
+    /// along the way
+    pub fn walk<'a>(
+        &'a self,
+        config: &'a Config,
+        paths: impl Iterator<Item = Result<Utf8PathBuf>> + 'a,
+    ) -> impl Iterator<Item = Result<Utf8PathBuf>> + 'a {
+        paths.filter_map(move |p| match p {
+            Ok(p) => Some(Ok(p)),
+            Err(e) => {
+                let path = e.downcast_ref::<Unreadable>().map(|u| u.0.clone());
+                self.keep(config, path.as_deref(), e).err().map(Err)
+            }
+        })
+    }
+
+    /// The `hash` of the file at `path`, or `None` if it could not be hashed and is skipped
+    pub fn hash(
+        &self,
+        config: &Config,
+        path: &Utf8Path,
+        hash: Result<String>,
+    ) -> Result<Option<String>> {
+        match hash {
+            Ok(hash) => Ok(Some(hash)),
+            Err(e) => self.keep(config, Some(path), e).map(|()| None),
+        }
+    }
+
+    /// Whether the file at `path` may be one that could not be read, being at or below a path that
+    /// was skipped, or anywhere when it is not known which one was
+    pub fn covers(&self, path: &Utf8Path) -> bool {
+        let path = normalize(path);
+        self.paths
+            .lock()
+            .expect("Hashing thread panicked")
+            .iter()
+            .any(|p| p.as_ref().map_or(true, |p| path.starts_with(p)))
+    }
+
+    /// Whether nothing was skipped
+    pub fn is_empty(&self) -> bool {
+        self.paths
+            .lock()
+            .expect("Hashing thread panicked")
+            .is_empty()
+    }
+
+    /// Fail with the list of what was skipped, if anything was
+    pub fn finish(self) -> Result<()> {
+        let paths = self.paths.into_inner().expect("Hashing thread panicked");
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let listed: Vec<String> = paths.iter().flatten().map(|p| format!("\"{p}\"")).collect();
+        bail!(Failures(format!(
+            "{} files or directories could not be read and were skipped: {}",
+            paths.len(),
+            listed.join(", ")
+        )));
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use camino::Utf8Path;
+use chrono::{Local, TimeZone};
+use color_eyre::{eyre::WrapErr, Result};
+use tracing::info;
+
+use crate::config::Config;
+use crate::db;
+use crate::lock::Lock;
+use crate::style;
+
+/// Record the current contents of the index as a snapshot named `name`
+pub fn create(data_path: &Utf8Path, config: &Config, name: &str) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating snapshot transaction")?;
+    db::create_snapshot(&transaction, name).wrap_err("Failed creating snapshot")?;
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+    info!("Created snapshot \"{name}\"");
+    Ok(())
+}
+
+/// Print every snapshot of the index
+pub fn list(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let snapshots = db::snapshots(&conn).wrap_err("Failed fetching snapshots")?;
+    if snapshots.is_empty() {
+        println!("There are no snapshots");
+    }
+    for (name, created_at, files) in snapshots {
+        let created_at = Local.timestamp_opt(created_at, 0).single().map_or_else(
+            || created_at.to_string(),
+            |t| t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        );
+        println!("{created_at}  {name} ({files} files)");
+    }
+    Ok(())
+}
+
+/// Print the files added, removed, moved and changed between the snapshot named `from` and the
+/// one named `to`, or the current index if `to` is `None`, returning how many differences there are
+pub fn diff(data_path: &Utf8Path, config: &Config, from: &str, to: Option<&str>) -> Result<usize> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let from_files = db::snapshot_files(&conn, from)
+        .wrap_err_with(|| format!("Failed fetching snapshot \"{from}\""))?;
+    let to_files = match to {
+        Some(to) => db::snapshot_files(&conn, to)
+            .wrap_err_with(|| format!("Failed fetching snapshot \"{to}\""))?,
+        None => db::files(&conn).wrap_err("Failed fetching index")?,
+    };
+
+    let from_by_hash: HashMap<&str, &str> = from_files
+        .iter()
+        .map(|(p, h)| (h.as_str(), p.as_str()))
+        .collect();
+    let to_by_hash: HashMap<&str, &str> = to_files
+        .iter()
+        .map(|(p, h)| (h.as_str(), p.as_str()))
+        .collect();
+
+    let mut moved = vec![];
+    let mut added = vec![];
+    for (hash, path) in &to_by_hash {
+        match from_by_hash.get(hash) {
+            Some(orig_path) if orig_path != path => moved.push((*orig_path, *path)),
+            Some(_) => {}
+            None => added.push(*path),
+        }
+    }
+    let mut removed: Vec<&str> = from_by_hash
+        .iter()
+        .filter(|(hash, _)| !to_by_hash.contains_key(*hash))
+        .map(|(_, path)| *path)
+        .collect();
+    // A path that lost its hash and gained another one was changed in place
+    let mut changed: Vec<&str> = added
+        .iter()
+        .filter(|p| removed.contains(p))
+        .copied()
+        .collect();
+    added.retain(|p| !changed.contains(p));
+    removed.retain(|p| !changed.contains(p));
+
+    added.sort_unstable();
+    removed.sort_unstable();
+    changed.sort_unstable();
+    moved.sort_unstable();
+    for p in &added {
+        println!("{} {p}", style::label("New:"));
+    }
+    for p in &changed {
+        println!("{} {p}", style::label("Changed:"));
+    }
+    for (orig_path, path) in &moved {
+        println!("{} {orig_path} -> {path}", style::label("Moved:"));
+    }
+    for p in &removed {
+        println!("{} {p}", style::label("Removed:"));
+    }
+    println!(
+        "{} new, {} changed, {} moved, {} removed",
+        added.len(),
+        changed.len(),
+        moved.len(),
+        removed.len()
+    );
+
+    Ok(added.len() + changed.len() + moved.len() + removed.len())
+}
@@ -0,0 +1,202 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::backup;
+use crate::config::{self, Config};
+use crate::db;
+use crate::lock::Lock;
+use crate::sidecar;
+use crate::sync::{LocalStore, Store};
+use crate::utils::{self, relative_path};
+
+/// Make a new store at `dest` out of the indexed files in `subdir` of this one, with their paths
+/// relative to `subdir`.
+///
+/// The files are copied together with their sidecars, and checked against their hashes once
+/// copied. The configuration of this store is copied along with them, without the paths of its
+/// database and keys. If anything fails, what was made in the new store is removed again. With
+/// `move_files` the files are removed from this store and its index afterwards, which cannot be
+/// undone.
+pub fn split(
+    data_path: &Utf8Path,
+    config: &Config,
+    subdir: &Utf8Path,
+    dest: &Utf8Path,
+    move_files: bool,
+) -> Result<()> {
+    let subdir = relative_path(data_path, subdir)?;
+    let canonical =
+        |p: &Utf8Path| utils::canonicalize(p).wrap_err_with(|| format!("Failed resolving \"{p}\""));
+    // The new data directory may not exist yet, but its parent must
+    let parent = match dest.parent() {
+        Some(p) if !p.as_str().is_empty() => p,
+        _ => Utf8Path::new("."),
+    };
+    let dest_full = match dest.file_name() {
+        Some(name) if !dest.exists() => canonical(parent)?.join(name),
+        _ => canonical(dest)?,
+    };
+    if dest_full.starts_with(canonical(data_path)?) {
+        bail!("\"{dest}\" is inside of \"{data_path}\", the new store must be outside of it");
+    }
+    let created = !dest.exists();
+    std::fs::create_dir_all(dest).wrap_err_with(|| format!("Failed creating \"{dest}\""))?;
+
+    // The database and keys stay with this store, the new one gets its own database inside of it
+    let dest_config = Config {
+        db_path: None,
+        keyfile: None,
+        signing_key: None,
+        passphrase: None,
+        ..config.clone()
+    };
+    if db::path(dest, &dest_config)
+        .try_exists()
+        .wrap_err("Could not check database existence")?
+    {
+        bail!("\"{dest}\" already has a database, split into a new directory");
+    }
+
+    let _lock = Lock::acquire(data_path, config)?;
+    if move_files {
+        backup::before(data_path, config, "split")?;
+    }
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+
+    info!("Splitting \"{subdir}\" out of \"{data_path}\" into \"{dest}\"");
+    let now = Instant::now();
+    let mut files: Vec<(Utf8PathBuf, String)> = db::files(&conn)
+        .wrap_err("Failed fetching files from db")?
+        .into_iter()
+        .map(|(path, hash)| (Utf8PathBuf::from(path), hash))
+        .filter(|(path, _)| path.starts_with(&subdir))
+        .collect();
+    files.sort_unstable();
+
+    let mut sidecars = vec![];
+    // Paths of what was made in the new store, removed if the split fails so it can be tried again
+    let mut made = vec![];
+    let res = copy(
+        data_path,
+        config,
+        &subdir,
+        dest,
+        dest_config,
+        &files,
+        &mut sidecars,
+        &mut made,
+    );
+    if let Err(e) = res {
+        let cleaned = if created {
+            std::fs::remove_dir_all(dest).map_err(|e| (dest.to_path_buf(), e))
+        } else {
+            made.iter()
+                .rev()
+                .try_for_each(|path| utils::remove_file(path).map_err(|e| (path.clone(), e)))
+        };
+        if let Err((path, cleanup_err)) = cleaned {
+            warn!("Failed removing \"{path}\" after the split failed: {cleanup_err}");
+        }
+        return Err(e);
+    }
+    info!("Copied {} files into \"{dest}\"", files.len());
+
+    if move_files {
+        // Only once every file is safely in the new store
+        let transaction = conn
+            .transaction()
+            .wrap_err("Failed creating remove transaction")?;
+        for (path, hash) in &files {
+            db::remove(&transaction, path, hash)
+                .wrap_err_with(|| format!("Failed removing {path} from db"))?;
+        }
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+        for path in files.iter().map(|(p, _)| p).chain(&sidecars) {
+            utils::remove_file(&utils::full_path(data_path, path))
+                .wrap_err_with(|| format!("Could not remove {path}"))?;
+        }
+        info!("Removed them from \"{data_path}\"");
+    }
+
+    let elapsed = now.elapsed();
+    info!("Done splitting \"{subdir}\" into \"{dest}\". Took {elapsed:.2?}");
+    Ok(())
+}
+
+/// Write the configuration of the store at `data_path` to the new store at `dest`, without the
+/// paths of its database and keys, which belong to it. Returns the path written to if there was no
+/// configuration there before.
+fn write_config(data_path: &Utf8Path, dest: &Utf8Path) -> Result<Option<Utf8PathBuf>> {
+    let config_path = config::path(data_path);
+    let contents = match std::fs::read_to_string(&config_path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).wrap_err_with(|| format!("Failed reading \"{config_path}\"")),
+    };
+    let mut table: toml::Table = toml::from_str(&contents)
+        .wrap_err_with(|| format!("Invalid configuration in \"{config_path}\""))?;
+    for key in ["db-path", "keyfile", "signing-key"] {
+        if table.remove(key).is_some() {
+            info!("Leaving {key} out of the configuration of \"{dest}\"");
+        }
+    }
+    let dest_path = config::path(dest);
+    let existed = dest_path.exists();
+    std::fs::write(&dest_path, toml::to_string(&table)?)
+        .wrap_err_with(|| format!("Failed writing \"{dest_path}\""))?;
+    Ok((!existed).then_some(dest_path))
+}
+
+/// Copy `files`, indexed in this store, into the new one at `dest` along with their sidecars and
+/// the configuration, recording what was made in `made` and the sidecars copied in `sidecars`
+#[allow(clippy::too_many_arguments)]
+fn copy(
+    data_path: &Utf8Path,
+    config: &Config,
+    subdir: &Utf8Path,
+    dest: &Utf8Path,
+    dest_config: Config,
+    files: &[(Utf8PathBuf, String)],
+    sidecars: &mut Vec<Utf8PathBuf>,
+    made: &mut Vec<Utf8PathBuf>,
+) -> Result<()> {
+    made.extend(write_config(data_path, dest)?);
+    made.extend(db::paths(dest, &dest_config));
+    db::open(dest, &dest_config).wrap_err("Failed creating database")?;
+    let mut new_store = LocalStore::open(dest, dest_config)
+        .wrap_err("Failed opening new store")?
+        .recorded_as("split");
+
+    for (path, hash) in files {
+        let full_path = utils::full_path(data_path, path);
+        let relative = path.strip_prefix(subdir).unwrap_or(path);
+        let mut file =
+            File::open(&full_path).wrap_err_with(|| format!("Failed opening \"{full_path}\""))?;
+        let size = file
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata for \"{full_path}\""))?
+            .len();
+        new_store
+            .put(relative, hash, size, &mut file)
+            .wrap_err_with(|| format!("Failed copying \"{path}\""))?;
+        made.push(dest.join(relative));
+        for sidecar in sidecar::find(data_path, config, path)? {
+            let to = dest.join(sidecar.strip_prefix(subdir).unwrap_or(&sidecar));
+            std::fs::copy(data_path.join(&sidecar), &to)
+                .wrap_err_with(|| format!("Failed copying sidecar \"{sidecar}\""))?;
+            made.push(to);
+            sidecars.push(sidecar);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{eyre::WrapErr, Result};
+use indicatif::HumanBytes;
+
+use crate::config::{Config, MediaKind};
+use crate::db;
+use crate::history::format_timestamp;
+
+/// Amount of files and the bytes they take
+#[derive(Default)]
+struct Total {
+    files: usize,
+    bytes: u64,
+}
+
+impl Total {
+    const fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.bytes += size;
+    }
+}
+
+/// Print the totals of `totals` from the one taking the most space, under the heading `title`
+fn print_totals(title: &str, totals: HashMap<&str, Total>) {
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_unstable_by_key(|(name, t)| (Reverse(t.bytes), Reverse(t.files), *name));
+    println!("{title}:");
+    for (name, t) in totals {
+        println!(
+            "  {name:<10} {:>8} files  {:>10}",
+            t.files,
+            HumanBytes(t.bytes).to_string()
+        );
+    }
+}
+
+/// Print a summary of the index, read from the database alone.
+///
+/// It has how many files are indexed and how much space they take, split by media type and by
+/// extension, the `largest` largest of them, and when the last refresh happened and the
+/// duplicates it found.
+pub fn stats(data_path: &Utf8Path, config: &Config, largest: usize) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::sized_files(&conn).wrap_err("Failed fetching index")?;
+
+    let mut total = Total::default();
+    let mut unknown = 0;
+    let mut by_kind: HashMap<&str, Total> = HashMap::new();
+    let mut by_extension: HashMap<&str, Total> = HashMap::new();
+    for (path, _, size) in &files {
+        let Some(size) = *size else {
+            unknown += 1;
+            continue;
+        };
+        total.add(size);
+        let ext = Utf8Path::new(path).extension();
+        let kind = match ext.and_then(|ext| config.extensions.kind(ext)) {
+            Some(MediaKind::Image) => "image",
+            Some(MediaKind::Audio) => "audio",
+            Some(MediaKind::Video) => "video",
+            None => "other",
+        };
+        by_kind.entry(kind).or_default().add(size);
+        by_extension
+            .entry(ext.unwrap_or("(none)"))
+            .or_default()
+            .add(size);
+    }
+
+    println!(
+        "{} files, {} in total",
+        files.len(),
+        HumanBytes(total.bytes)
+    );
+    if unknown > 0 {
+        println!(
+            "The size of {unknown} of them is not known yet and is not counted, run `cstfs refresh` to record it"
+        );
+    }
+    if total.files > 0 {
+        print_totals("By media type", by_kind);
+        print_totals("By extension", by_extension);
+
+        let mut sized: Vec<_> = files
+            .iter()
+            .filter_map(|(path, _, size)| Some((path, (*size)?)))
+            .collect();
+        sized.sort_unstable_by_key(|(path, size)| (Reverse(*size), *path));
+        println!("Largest files:");
+        for (path, size) in sized.into_iter().take(largest) {
+            println!("  {:>10}  \"{path}\"", HumanBytes(size).to_string());
+        }
+    }
+
+    let history = db::history(&conn).wrap_err("Failed fetching history")?;
+    let Some((id, started_at, duration_ms)) = history.first() else {
+        println!("The data directory was never refreshed");
+        return Ok(());
+    };
+    let diffs = db::history_diffs(&conn, *id).wrap_err("Failed fetching history diffs")?;
+    // Duplicates are not indexed, unless they were recorded as copies, so they are only known
+    // from the refreshes that found them
+    let sizes: HashMap<&str, Option<u64>> = files
+        .iter()
+        .map(|(_, hash, size)| (hash.as_str(), *size))
+        .collect();
+    let duplicates: Vec<_> = diffs
+        .iter()
+        .filter(|d| matches!(d.kind.as_str(), "duplicate" | "copied"))
+        .collect();
+    let hashes: HashSet<&str> = duplicates.iter().map(|d| d.hash.as_str()).collect();
+    let wasted: u64 = duplicates
+        .iter()
+        .filter_map(|d| sizes.get(d.hash.as_str()).copied().flatten())
+        .sum();
+    println!(
+        "Last refresh: #{id} at {} (took {duration_ms}ms)",
+        format_timestamp(*started_at)
+    );
+    println!(
+        "It found {} duplicates of {} indexed files, wasting {}",
+        duplicates.len(),
+        hashes.len(),
+        HumanBytes(wasted)
+    );
+    Ok(())
+}
+
+/// Print a gauge named `cstfs_{name}` with value `value` in the Prometheus text format
+fn gauge(name: &str, help: &str, value: impl std::fmt::Display) {
+    println!("# HELP cstfs_{name} {help}");
+    println!("# TYPE cstfs_{name} gauge");
+    println!("cstfs_{name} {value}");
+}
+
+/// Print metrics of the index in the Prometheus text format, to be scraped from a file by the
+/// textfile collector of the node exporter.
+///
+/// They are read from the database alone, like the summary of [`stats`].
+pub fn prometheus(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::sized_files(&conn).wrap_err("Failed fetching index")?;
+    let bytes: u64 = files.iter().filter_map(|(_, _, size)| *size).sum();
+    let unknown = files.iter().filter(|(_, _, size)| size.is_none()).count();
+    gauge("indexed_files", "Files in the index", files.len());
+    gauge("indexed_bytes", "Bytes taken by the indexed files", bytes);
+    gauge(
+        "unknown_size_files",
+        "Indexed files whose size is not known yet",
+        unknown,
+    );
+
+    let (never_verified, oldest) =
+        db::verification(&conn).wrap_err("Failed fetching verification times")?;
+    gauge(
+        "never_verified_files",
+        "Indexed files that were never verified",
+        never_verified,
+    );
+    if let Some(oldest) = oldest {
+        gauge(
+            "oldest_verification_timestamp_seconds",
+            "When the least recently verified file was verified",
+            oldest,
+        );
+    }
+
+    let history = db::history(&conn).wrap_err("Failed fetching history")?;
+    let Some((id, started_at, duration_ms)) = history.first() else {
+        return Ok(());
+    };
+    gauge(
+        "last_refresh_timestamp_seconds",
+        "When the last refresh started",
+        started_at,
+    );
+    #[allow(clippy::cast_precision_loss)]
+    let duration = *duration_ms as f64 / 1000.0;
+    gauge(
+        "last_refresh_duration_seconds",
+        "How long the last refresh took",
+        duration,
+    );
+    let diffs = db::history_diffs(&conn, *id).wrap_err("Failed fetching history diffs")?;
+    let mut kinds: BTreeMap<&str, usize> =
+        ["new", "duplicate", "copied", "changed", "moved", "removed"]
+            .into_iter()
+            .map(|kind| (kind, 0))
+            .collect();
+    for d in &diffs {
+        *kinds.entry(d.kind.as_str()).or_default() += 1;
+    }
+    println!("# HELP cstfs_last_refresh_changes Changes found by the last refresh, by kind");
+    println!("# TYPE cstfs_last_refresh_changes gauge");
+    for (kind, n) in kinds {
+        println!("cstfs_last_refresh_changes{{kind=\"{kind}\"}} {n}");
+    }
+    Ok(())
+}
+
+/// Print how much space the indexed files in every directory take, like `du`.
+///
+/// The files in subdirectories are counted too. Only directories at most `depth` levels below the
+/// data directory are printed, if it is given.
+pub fn by_dir(data_path: &Utf8Path, config: &Config, depth: Option<usize>) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::sized_files(&conn).wrap_err("Failed fetching index")?;
+
+    let mut unknown = 0;
+    let mut dirs: BTreeMap<Utf8PathBuf, Total> = BTreeMap::new();
+    for (path, _, size) in &files {
+        let Some(size) = *size else {
+            unknown += 1;
+            continue;
+        };
+        let Some(parent) = Utf8Path::new(path).parent() else {
+            continue;
+        };
+        for dir in parent.ancestors() {
+            if depth.is_some_and(|depth| dir.components().count() > depth) {
+                continue;
+            }
+            dirs.entry(dir.to_path_buf()).or_default().add(size);
+        }
+    }
+
+    for (dir, t) in &dirs {
+        let dir = if dir.as_str().is_empty() {
+            "."
+        } else {
+            dir.as_str()
+        };
+        println!(
+            "{:>10}  {:>8} files  {dir}",
+            HumanBytes(t.bytes).to_string(),
+            t.files
+        );
+    }
+    if unknown > 0 {
+        println!(
+            "The size of {unknown} files is not known yet and is not counted, run `cstfs refresh` to record it"
+        );
+    }
+    Ok(())
+}
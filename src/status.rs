@@ -0,0 +1,117 @@
+use camino::Utf8Path;
+use chrono::Utc;
+use color_eyre::{eyre::WrapErr, Result};
+use serde_json::json;
+
+use crate::config::Config;
+use crate::db::{self, Meta};
+use crate::history::format_timestamp;
+
+/// Print a field of the store, aligned with the others
+fn field(label: &str, value: impl std::fmt::Display) {
+    println!("{:<15} {value}", format!("{label}:"));
+}
+
+/// The unix timestamp `t` as a local date and time and how long ago it was, or `none` if it is
+/// not known
+fn format_time(t: Option<i64>, none: &str) -> String {
+    let Some(t) = t else {
+        return none.to_owned();
+    };
+    let days = (Utc::now().timestamp() - t).div_euclid(24 * 60 * 60);
+    let ago = match days {
+        ..=0 => "today".to_owned(),
+        1 => "yesterday".to_owned(),
+        days => format!("{days} days ago"),
+    };
+    format!("{} ({ago})", format_timestamp(t))
+}
+
+/// Print what is recorded about the store, or a JSON object with it if `json` is set, for other
+/// tools to check.
+///
+/// It has when the store was made, when it was last refreshed and changed, which algorithm its
+/// files are hashed with and which versions of cstfs and of the schema its database is at, pointing
+/// out the ones this version of cstfs and its configuration disagree with.
+pub fn status(data_path: &Utf8Path, config: &Config, json: bool) -> Result<()> {
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let meta = db::meta(&conn).wrap_err("Failed fetching store metadata")?;
+    let schema = db::schema_version(&conn).wrap_err("Failed fetching schema")?;
+    let files = db::files(&conn).wrap_err("Failed fetching index")?.len();
+
+    let Meta {
+        created_at,
+        hash,
+        version,
+        last_operation,
+        last_operation_at,
+        last_refresh,
+    } = meta;
+    if json {
+        let status = json!({
+            "data_path": data_path,
+            "database": db::path(data_path, config),
+            "files": files,
+            "created_at": created_at,
+            "hash": hash,
+            "configured_hash": config.hash.name(),
+            "version": version,
+            "cstfs_version": env!("CARGO_PKG_VERSION"),
+            "schema_version": schema,
+            "supported_schema_version": db::SCHEMA_VERSION,
+            "last_refresh": last_refresh,
+            "last_operation": last_operation,
+            "last_operation_at": last_operation_at,
+        });
+        let json = serde_json::to_string_pretty(&status).wrap_err("Failed writing status")?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    field("Data directory", data_path);
+    field("Database", db::path(data_path, config));
+    field("Files", files);
+    field("Created", format_time(created_at, "unknown"));
+    field("Last refresh", format_time(last_refresh, "never"));
+    match (last_operation, last_operation_at) {
+        (Some(command), at) => field(
+            "Last change",
+            format!("{command} at {}", format_time(at, "an unknown time")),
+        ),
+        (None, _) => field("Last change", "unknown"),
+    }
+    match hash {
+        Some(hash) if hash != config.hash.name() => field(
+            "Hash",
+            format!(
+                "{hash}, but cstfs.toml says {}, every file shows up as changed on the next refresh",
+                config.hash.name()
+            ),
+        ),
+        Some(hash) => field("Hash", hash),
+        None => field("Hash", "unknown"),
+    }
+    match version {
+        Some(version) if version != env!("CARGO_PKG_VERSION") => field(
+            "Written by",
+            format!(
+                "cstfs {version}, this is cstfs {}",
+                env!("CARGO_PKG_VERSION")
+            ),
+        ),
+        Some(version) => field("Written by", format!("cstfs {version}")),
+        None => field("Written by", "unknown"),
+    }
+    if schema > db::SCHEMA_VERSION {
+        field(
+            "Schema",
+            format!(
+                "{schema}, newer than the {} this version of cstfs knows",
+                db::SCHEMA_VERSION
+            ),
+        );
+    } else {
+        field("Schema", schema);
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Colors and alignment of what is printed on the terminal.
+//!
+//! Changes are printed after a label saying what kind of change they are, like `New:`, padded so
+//! the paths after them line up, and colored when the output is a terminal: new files in green,
+//! removed ones in red and moved ones in yellow.
+
+use std::borrow::Cow;
+
+use console::{Color, Style};
+
+/// When output is colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// When printing to a terminal, and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    /// Always, even when printing to a file or a pipe
+    Always,
+    /// Never
+    Never,
+}
+
+/// Labels of the kinds of changes, with the color they are printed in
+const LABELS: [(&str, Color); 8] = [
+    ("New:", Color::Green),
+    ("Removed:", Color::Red),
+    ("Moved:", Color::Yellow),
+    ("Moved directory:", Color::Yellow),
+    ("Changed:", Color::Magenta),
+    ("Copied:", Color::Cyan),
+    ("Kept copy:", Color::Cyan),
+    ("Duplicate:", Color::Cyan),
+];
+
+/// Width labels are padded to, that of the longest one but `Moved directory:`, which is rare
+/// enough to not push every path to the right
+const LABEL_WIDTH: usize = 10;
+
+/// Color the output as `choice` says, on both stdout and stderr
+pub fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // Only when set to something, see https://no-color.org
+        ColorChoice::Auto if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) => false,
+        ColorChoice::Auto => return,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// `label`, padded, in the color of the kind of change it is the label of, for stdout
+#[must_use]
+pub fn label(label: &str) -> String {
+    styled(label, false)
+}
+
+/// `line` with the label of a change it starts with, if any, padded and colored, for stderr,
+/// where the changes applied by refresh are logged
+#[must_use]
+pub fn with_label(line: &str) -> Cow<'_, str> {
+    LABELS
+        .iter()
+        .find_map(|(label, _)| Some((label, line.strip_prefix(label)?.strip_prefix(' ')?)))
+        .map_or(Cow::Borrowed(line), |(label, rest)| {
+            Cow::Owned(format!("{} {rest}", styled(label, true)))
+        })
+}
+
+fn styled(label: &str, stderr: bool) -> String {
+    let padded = format!("{label:<LABEL_WIDTH$}");
+    let Some((_, color)) = LABELS.iter().find(|(l, _)| *l == label) else {
+        return padded;
+    };
+    let style = Style::new().fg(*color);
+    let style = if stderr { style.for_stderr() } else { style };
+    style.apply_to(padded).to_string()
+}
@@ -0,0 +1,298 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use crate::config::{self, Config, HashAlgorithm};
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::remote::{self, RemoteStore};
+use crate::utils::{self, hash_file};
+
+/// Which way files are copied between the two stores
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Only copy files from this store into the other one
+    Push,
+    /// Only copy files from the other store into this one
+    Pull,
+    /// Copy missing files in both directions
+    Both,
+}
+
+/// A store files can be synced from and to, wherever it lives
+pub trait Store {
+    /// Human readable location of the store
+    fn name(&self) -> String;
+
+    /// Algorithm the files of the store are hashed with
+    fn hash(&mut self) -> Result<HashAlgorithm>;
+
+    /// Path and hash of every file in the index of the store
+    fn files(&mut self) -> Result<Vec<(String, String)>>;
+
+    /// Whether there is a file at `path`, relative to the data directory
+    fn exists(&mut self, path: &Utf8Path) -> Result<bool>;
+
+    /// Open the file at `path` for reading, returning its size and a reader over its contents.
+    /// The reader must be read to the end before the store is used again.
+    fn open(&mut self, path: &Utf8Path) -> Result<(u64, Box<dyn Read + '_>)>;
+
+    /// Write the `size` bytes from `contents` to a new file at `path`, checking that they hash to
+    /// `hash`, and add it to the index
+    fn put(
+        &mut self,
+        path: &Utf8Path,
+        hash: &str,
+        size: u64,
+        contents: &mut dyn Read,
+    ) -> Result<()>;
+}
+
+/// A store on the local filesystem
+pub struct LocalStore {
+    data_path: Utf8PathBuf,
+    config: Config,
+    conn: Connection,
+    /// Operation in the journal the files put into this store are recorded under, started when
+    /// the first one is put
+    operation: Option<i64>,
+    /// Command the operation is recorded as, `sync` unless set with [`LocalStore::recorded_as`]
+    command: &'static str,
+    /// Held for as long as the store is open, since files can be put into it
+    _lock: Lock,
+}
+
+impl LocalStore {
+    /// Open the store at `data_path` with configuration `config`, failing if it was never
+    /// initialized
+    pub fn open(data_path: &Utf8Path, config: Config) -> Result<Self> {
+        let exists = db::path(data_path, &config)
+            .try_exists()
+            .wrap_err("Could not check database existence")?;
+        if !exists {
+            bail!("No database found at \"{data_path}\", initialize it first");
+        }
+        let lock = Lock::acquire(data_path, &config)?;
+        let conn = db::open(data_path, &config).wrap_err("Failed to open db")?;
+        db::check_hash(&conn, config.hash)?;
+        Ok(Self {
+            data_path: data_path.to_path_buf(),
+            config,
+            conn,
+            operation: None,
+            command: "sync",
+            _lock: lock,
+        })
+    }
+
+    /// Record the files put into this store in the journal as done by `command`
+    #[must_use]
+    pub fn recorded_as(self, command: &'static str) -> Self {
+        Self { command, ..self }
+    }
+
+    /// Full path of the file at `path`, failing if it leads out of the data directory, as paths
+    /// given by the other store cannot be trusted
+    fn full_path(&self, path: &Utf8Path) -> Result<Utf8PathBuf> {
+        utils::check_relative(path)?;
+        Ok(utils::full_path(&self.data_path, path))
+    }
+}
+
+impl Store for LocalStore {
+    fn name(&self) -> String {
+        self.data_path.to_string()
+    }
+
+    fn hash(&mut self) -> Result<HashAlgorithm> {
+        // Opening the store checked the index is hashed with it
+        Ok(self.config.hash)
+    }
+
+    fn files(&mut self) -> Result<Vec<(String, String)>> {
+        db::files(&self.conn).wrap_err("Failed fetching files from db")
+    }
+
+    fn exists(&mut self, path: &Utf8Path) -> Result<bool> {
+        let full_path = self.full_path(path)?;
+        full_path
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{full_path}\""))
+    }
+
+    fn open(&mut self, path: &Utf8Path) -> Result<(u64, Box<dyn Read + '_>)> {
+        let full_path = self.full_path(path)?;
+        let file = File::open(&full_path).wrap_err_with(|| {
+            format!("Failed opening \"{full_path}\", is the index out of date?")
+        })?;
+        let size = file
+            .metadata()
+            .wrap_err_with(|| format!("Failed reading metadata for {full_path}"))?
+            .len();
+        Ok((size, Box::new(file.take(size))))
+    }
+
+    fn put(
+        &mut self,
+        path: &Utf8Path,
+        hash: &str,
+        size: u64,
+        contents: &mut dyn Read,
+    ) -> Result<()> {
+        let dst = self.full_path(path)?;
+        if self.exists(path)? {
+            bail!("A different file already exists at \"{dst}\"");
+        }
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+        }
+        let mut file =
+            File::create(&dst).wrap_err_with(|| format!("Failed creating file \"{dst}\""))?;
+        let written = std::io::copy(&mut contents.take(size), &mut file)
+            .wrap_err_with(|| format!("Failed writing file \"{dst}\""))?;
+        drop(file);
+
+        let copied_hash = hash_file(&dst, &self.config)
+            .wrap_err_with(|| format!("Could not hash copied file {dst}"))?;
+        if written != size || copied_hash != hash {
+            utils::remove_file(&dst)
+                .wrap_err_with(|| format!("Failed removing corrupt copy {dst}"))?;
+            bail!("Copy of \"{path}\" has hash {copied_hash} and size {written}, expected {hash} and size {size}");
+        }
+
+        let transaction = self
+            .conn
+            .transaction()
+            .wrap_err("Failed creating insert transaction")?;
+        db::insert_into(&transaction, path, hash)
+            .wrap_err_with(|| format!("Failed inserting {path} into db"))?;
+        let stat = utils::stat(&dst)?;
+        db::set_stat(&transaction, path, &stat).wrap_err("Failed recording size")?;
+        let operation = match self.operation {
+            Some(operation) => operation,
+            None => db::begin_operation(&transaction, self.command)
+                .wrap_err("Failed recording operation")?,
+        };
+        let action = JournalAction::Insert {
+            path: path.to_path_buf(),
+            hash: hash.to_owned(),
+        };
+        db::record(&transaction, operation, &action).wrap_err("Failed recording insertion")?;
+        transaction
+            .commit()
+            .wrap_err("Could not commit transaction")?;
+        self.operation = Some(operation);
+
+        Ok(())
+    }
+}
+
+/// Copy every file from `from` whose hash is not in `to`, keeping its relative path. Returns the
+/// amount of files copied.
+fn copy_missing(from: &mut dyn Store, to: &mut dyn Store, dry_run: bool) -> Result<usize> {
+    let from_files = from.files().wrap_err("Failed fetching files from source")?;
+    let to_files = to
+        .files()
+        .wrap_err("Failed fetching files from destination")?;
+    let to_hashes: HashSet<&str> = to_files.iter().map(|(_, h)| h.as_str()).collect();
+
+    let (from_name, to_name) = (from.name(), to.name());
+    let mut copied = 0;
+    for (path, hash) in &from_files {
+        if to_hashes.contains(hash.as_str()) {
+            continue;
+        }
+        let path = Utf8Path::new(path);
+        if let Err(e) = utils::check_relative(path) {
+            warn!("Skipping a file of \"{from_name}\": {e}");
+            continue;
+        }
+        if to.exists(path)? {
+            warn!("Skipping \"{path}\", a different file already exists in \"{to_name}\"");
+            continue;
+        }
+        if dry_run {
+            info!("Would copy \"{path}\" from \"{from_name}\" to \"{to_name}\"");
+            copied += 1;
+            continue;
+        }
+
+        info!("Copying \"{path}\" from \"{from_name}\" to \"{to_name}\"");
+        let (size, mut contents) = from
+            .open(path)
+            .wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+        let res = to.put(path, hash, size, &mut contents);
+        // Whatever happened, the rest of the file has to be read for the next request to be answered
+        std::io::copy(&mut contents, &mut std::io::sink())
+            .wrap_err_with(|| format!("Failed reading \"{path}\""))?;
+        res.wrap_err_with(|| format!("Failed copying \"{path}\""))?;
+        copied += 1;
+    }
+
+    Ok(copied)
+}
+
+/// Compare the indexes of the stores at `data_path` and `other`, and copy the files missing from
+/// either one into the other, as specified by `direction`.
+///
+/// `other` may be a local path or a `[user@]host:path` reached over ssh. If `dry_run` is set, only print what would be copied.
+pub fn sync(
+    data_path: &Utf8Path,
+    config: &Config,
+    other: &str,
+    remote_cstfs: &str,
+    direction: Direction,
+    dry_run: bool,
+) -> Result<()> {
+    let mut local =
+        LocalStore::open(data_path, config.clone()).wrap_err("Failed opening local store")?;
+    let mut other: Box<dyn Store> = if let Some((host, path)) = remote::parse_spec(other) {
+        Box::new(
+            RemoteStore::connect(host, path, remote_cstfs)
+                .wrap_err("Failed connecting to remote store")?,
+        )
+    } else {
+        let other = Utf8Path::new(other);
+        let other_config = config::load(other).wrap_err("Failed loading other store config")?;
+        Box::new(LocalStore::open(other, other_config).wrap_err("Failed opening other store")?)
+    };
+    let other_name = other.name();
+    let (hash, other_hash) = (local.hash()?, other.hash()?);
+    if hash != other_hash {
+        bail!(
+            "\"{other_name}\" is indexed with {} and this store with {}, rehash one of them first",
+            other_hash.name(),
+            hash.name()
+        );
+    }
+
+    info!("Starting sync of \"{data_path}\" with \"{other_name}\"");
+    let now = Instant::now();
+
+    let verb = if dry_run { "Would copy" } else { "Copied" };
+    if matches!(direction, Direction::Push | Direction::Both) {
+        let pushed =
+            copy_missing(&mut local, other.as_mut(), dry_run).wrap_err("Failed pushing files")?;
+        info!("{verb} {pushed} files to \"{other_name}\"");
+    }
+    if matches!(direction, Direction::Pull | Direction::Both) {
+        let pulled =
+            copy_missing(other.as_mut(), &mut local, dry_run).wrap_err("Failed pulling files")?;
+        info!("{verb} {pulled} files to \"{data_path}\"");
+    }
+
+    let elapsed = now.elapsed();
+    info!("Done syncing \"{data_path}\" with \"{other_name}\". Took {elapsed:.2?}");
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Templates of the paths files are put at inside of the data directory, like
+//! `imports/{dir}/{filename}`. Every `{field}` in them is replaced by the value of that field for
+//! the file:
+//!
+//! - `{path}`: path of the file, relative to the directory it comes from
+//! - `{dir}`: directory of the file, relative to the directory it comes from
+//! - `{filename}`: name of the file
+//! - `{stem}`: name of the file without its extension
+//! - `{ext}`: extension of the file, without the leading dot
+//! - `{hash}`: hash of the contents of the file
+//! - `{yyyy}`, `{mm}` and `{dd}`: year, month and day the file was taken at, read from its EXIF
+//!   metadata, or the day it was last modified if it has none
+//! - `{date}`: the same date as `{yyyy}-{mm}-{dd}`
+
+use std::cell::OnceCell;
+use std::fs::File;
+use std::io::BufReader;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, NaiveDate};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+
+use crate::utils;
+
+/// Template used when none is configured, keeping files at the same path they come from
+pub const DEFAULT: &str = "{path}";
+
+/// Day the file at `path` was taken at according to its EXIF metadata, if it has any
+fn exif_date(path: &Utf8Path) -> Option<NaiveDate> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    [exif::Tag::DateTimeOriginal, exif::Tag::DateTime]
+        .into_iter()
+        .find_map(|tag| {
+            let field = exif.get_field(tag, exif::In::PRIMARY)?;
+            let exif::Value::Ascii(ref values) = field.value else {
+                return None;
+            };
+            let t = exif::DateTime::from_ascii(values.first()?).ok()?;
+            NaiveDate::from_ymd_opt(t.year.into(), t.month.into(), t.day.into())
+        })
+}
+
+/// Day the file at `path` was taken at, or last modified at if that is not known
+fn date(path: &Utf8Path) -> Result<NaiveDate> {
+    if let Some(date) = exif_date(path) {
+        return Ok(date);
+    }
+    let modified = path
+        .metadata()
+        .and_then(|m| m.modified())
+        .wrap_err_with(|| format!("Failed reading modification time of \"{path}\""))?;
+    Ok(DateTime::<Local>::from(modified).date_naive())
+}
+
+/// File a template is rendered for
+pub struct Fields<'a> {
+    /// Path of the file, relative to the directory it comes from
+    path: &'a Utf8Path,
+    /// Path the file can be read at
+    full_path: &'a Utf8Path,
+    hash: &'a str,
+    /// Date of the file, only read once a template needs it
+    date: OnceCell<NaiveDate>,
+}
+
+impl<'a> Fields<'a> {
+    /// Fields of the file at `path` inside of `dir`, with hash `hash`
+    #[must_use]
+    pub fn new(dir: &Utf8Path, path: &'a Utf8Path, hash: &'a str) -> Self {
+        Self {
+            path: path.strip_prefix(dir).unwrap_or(path),
+            full_path: path,
+            hash,
+            date: OnceCell::new(),
+        }
+    }
+
+    fn date(&self) -> Result<NaiveDate> {
+        if let Some(date) = self.date.get() {
+            return Ok(*date);
+        }
+        let date = date(self.full_path)?;
+        Ok(*self.date.get_or_init(|| date))
+    }
+
+    /// Value of the field named `name`
+    fn get(&self, name: &str) -> Result<String> {
+        match name {
+            "yyyy" => return Ok(self.date()?.format("%Y").to_string()),
+            "mm" => return Ok(self.date()?.format("%m").to_string()),
+            "dd" => return Ok(self.date()?.format("%d").to_string()),
+            "date" => return Ok(self.date()?.format("%Y-%m-%d").to_string()),
+            _ => {}
+        }
+        let value = match name {
+            "path" => self.path.as_str(),
+            "dir" => self.path.parent().map_or("", Utf8Path::as_str),
+            "filename" => self.path.file_name().unwrap_or_default(),
+            "stem" => self.path.file_stem().unwrap_or_default(),
+            "ext" => self.path.extension().unwrap_or_default(),
+            "hash" => self.hash,
+            _ => bail!("Unknown field {{{name}}}"),
+        };
+        Ok(value.to_owned())
+    }
+}
+
+/// Check that `template` is well formed and only has known fields, before rendering it for any file
+pub fn check(template: &str) -> Result<()> {
+    let fields = Fields {
+        path: Utf8Path::new("dir/file.ext"),
+        full_path: Utf8Path::new("dir/file.ext"),
+        hash: "0",
+        date: OnceCell::from(NaiveDate::default()),
+    };
+    render(template, &fields).map(|_| ())
+}
+
+/// Path, relative to the data directory, that `template` gives for the file described by `fields`
+pub fn render(template: &str, fields: &Fields<'_>) -> Result<Utf8PathBuf> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| eyre!("Unclosed {{ in template \"{template}\""))?;
+        rendered.push_str(&fields.get(&rest[start + 1..start + end])?);
+        rest = &rest[start + end + 1..];
+    }
+    rendered.push_str(rest);
+
+    let path = Utf8PathBuf::from(rendered);
+    if path.is_absolute() {
+        bail!("Template \"{template}\" gave the absolute path \"{path}\"");
+    }
+    let path = utils::clean_relative(&path)
+        .wrap_err_with(|| format!("Template \"{template}\" gave an invalid path"))?;
+    if path.as_str().is_empty() {
+        bail!("Template \"{template}\" gave an empty path");
+    }
+    Ok(path)
+}
@@ -0,0 +1,322 @@
+use std::io::Write;
+use std::process::Command;
+use std::sync::Mutex;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Local;
+use color_eyre::{eyre::WrapErr, Result};
+use cstfs::dedupe::{File, Group};
+use cstfs::{full_path, Config, Diff, Reporter, Resolution};
+use indicatif::{HumanBytes, ProgressBar};
+use tracing::{debug, warn};
+
+use crate::preview::{self, Protocol};
+use crate::progress;
+
+/// Progress of the files being hashed
+struct Hashing {
+    bar: ProgressBar,
+    files: usize,
+    done: usize,
+}
+
+/// Reports events on the terminal: progress bars on stderr, and duplicates are asked about on
+/// stdin
+pub struct Terminal {
+    hashing: Mutex<Option<Hashing>>,
+    /// Data directory the paths of duplicates are relative to
+    data_path: Utf8PathBuf,
+    /// How duplicates are previewed, if they are
+    preview: Option<Protocol>,
+    /// Command duplicates are opened with, arguments separated by whitespace
+    viewer: String,
+}
+
+impl Terminal {
+    pub fn new(data_path: &Utf8Path, config: &Config) -> Self {
+        Self {
+            hashing: Mutex::new(None),
+            data_path: data_path.to_path_buf(),
+            preview: Protocol::new(config.preview),
+            viewer: config.viewer(),
+        }
+    }
+
+    /// Open the files at `paths` in the viewer one after the other, waiting for it to exit
+    fn view(&self, paths: &[&Utf8Path]) {
+        let mut args = self.viewer.split_whitespace();
+        let Some(program) = args.next() else {
+            warn!("No viewer configured");
+            return;
+        };
+        for path in paths {
+            match Command::new(program)
+                .args(args.clone())
+                .arg(full_path(&self.data_path, path))
+                .status()
+            {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("Viewer {} failed opening \"{path}\": {status}", self.viewer),
+                Err(e) => warn!("Failed running viewer {}: {e}", self.viewer),
+            }
+        }
+    }
+
+    /// Ask the user what to do about `path_new`, which is a duplicate of `path_old`
+    fn ask_duplicate(&self, path_old: &Utf8Path, path_new: &Utf8Path) -> Result<Resolution> {
+        const VALID_COMMANDS: &str = "Y/n/s/o/v/e/?";
+        let flush =
+            || -> Result<()> { std::io::stdout().flush().wrap_err("Failed flushing stdout") };
+
+        print!("Found path \"{path_new}\", duplicate of \"{path_old}\", would you like to remove it? ({VALID_COMMANDS}): ");
+        flush()?;
+
+        let stdin = std::io::stdin();
+        loop {
+            let mut input = String::new();
+            stdin
+                .read_line(&mut input)
+                .wrap_err("Failed reading line from stdin")?;
+            println!();
+            flush()?;
+            match input.trim().to_lowercase().as_str() {
+                "" | "y" => return Ok(Resolution::RemoveNew),
+                "n" => {
+                    println!("Quitting...");
+                    std::process::exit(1);
+                }
+                "s" => return Ok(Resolution::Skip),
+                "o" => return Ok(Resolution::RemoveOld),
+                "v" => self.view(&[path_old, path_new]),
+                "e" => self.view(&[path_new]),
+                "?" => {
+                    println!("y(Yes)  - Remove the new file");
+                    println!("n(No)   - Do not remove the file and quit the program");
+                    println!("s(Skip) - Leave the file in place without indexing it");
+                    println!("o(Old)  - Remove the old file and keep the new one");
+                    println!("v(View) - Open both files in the viewer");
+                    println!("e(New)  - Open the new file in the viewer");
+                    println!("?(Help) - Print this message");
+                }
+                _ => println!("Invalid command, valid ones are ({VALID_COMMANDS})"),
+            }
+            flush()?;
+        }
+    }
+
+    /// Ask the user whether the new contents of `path`, whose hash changed, are recorded
+    fn ask_changed(&self, path: &Utf8Path) -> Result<bool> {
+        const VALID_COMMANDS: &str = "Y/n/f/v/?";
+        let flush =
+            || -> Result<()> { std::io::stdout().flush().wrap_err("Failed flushing stdout") };
+
+        print!("File \"{path}\" changed, would you like to record its new contents? ({VALID_COMMANDS}): ");
+        flush()?;
+
+        let stdin = std::io::stdin();
+        loop {
+            let mut input = String::new();
+            stdin
+                .read_line(&mut input)
+                .wrap_err("Failed reading line from stdin")?;
+            println!();
+            flush()?;
+            match input.trim().to_lowercase().as_str() {
+                "" | "y" => return Ok(true),
+                "n" => {
+                    println!("Quitting...");
+                    std::process::exit(1);
+                }
+                "f" => return Ok(false),
+                "v" => self.view(&[path]),
+                "?" => {
+                    println!("y(Yes)  - Record the new contents in the index");
+                    println!("n(No)   - Do not record them and quit the program");
+                    println!("f(Flag) - Leave the index as is, to look into the file later");
+                    println!("v(View) - Open the file in the viewer");
+                    println!("?(Help) - Print this message");
+                }
+                _ => println!("Invalid command, valid ones are ({VALID_COMMANDS})"),
+            }
+            flush()?;
+        }
+    }
+
+    /// Print previews of the files at `paths` that are images, if previews are on
+    fn preview(&self, paths: [&Utf8Path; 2]) {
+        let Some(protocol) = self.preview else {
+            return;
+        };
+        for path in paths {
+            match preview::load(&full_path(&self.data_path, path)) {
+                Ok(img) => {
+                    println!("\"{path}\":");
+                    if let Err(e) = preview::print(&img, protocol) {
+                        debug!("Failed previewing \"{path}\": {e}");
+                    }
+                }
+                Err(e) => debug!("Not previewing \"{path}\": {e}"),
+            }
+        }
+    }
+
+    fn with_hashing(&self, f: impl FnOnce(&mut Hashing)) {
+        if let Some(hashing) = self.hashing.lock().expect("Reporter panicked").as_mut() {
+            f(hashing);
+        }
+    }
+}
+
+impl Reporter for Terminal {
+    fn hashing_started(&self, files: usize, bytes: u64) {
+        *self.hashing.lock().expect("Reporter panicked") = Some(Hashing {
+            bar: progress::hashing(files, bytes),
+            files,
+            done: 0,
+        });
+    }
+
+    fn file_found(&self, _path: &Utf8Path, bytes: u64) {
+        self.with_hashing(|h| {
+            h.files += 1;
+            h.bar.set_prefix(format!("{}/{} files", h.done, h.files));
+            h.bar.inc_length(bytes);
+        });
+    }
+
+    fn file_started(&self, path: &Utf8Path) {
+        self.with_hashing(|h| h.bar.set_message(path.to_string()));
+    }
+
+    fn file_hashed(&self, _path: &Utf8Path, bytes: u64) {
+        self.with_hashing(|h| {
+            h.done += 1;
+            h.bar.set_prefix(format!("{}/{} files", h.done, h.files));
+            h.bar.inc(bytes);
+        });
+    }
+
+    fn hashing_finished(&self) {
+        let hashing = self.hashing.lock().expect("Reporter panicked").take();
+        if let Some(h) = hashing {
+            h.bar.finish_and_clear();
+        }
+    }
+
+    fn diff_found(&self, diff: &Diff) {
+        debug!("Found {} file \"{}\"", diff.ty.name(), diff.path);
+    }
+
+    /// Ask the user what to do about `path_new`, which is a duplicate of `path_old`, after
+    /// previewing them. Files are hashed while asking when they are indexed as they are hashed, so
+    /// hashing waits for the answer and its progress bar is hidden until then.
+    fn duplicate(&self, path_old: &Utf8Path, path_new: &Utf8Path) -> Result<Resolution> {
+        let ask = || {
+            self.preview([path_old, path_new]);
+            self.ask_duplicate(path_old, path_new)
+        };
+        let hashing = self.hashing.lock().expect("Reporter panicked");
+        let res = hashing.as_ref().map_or_else(ask, |h| h.bar.suspend(ask));
+        drop(hashing);
+        res
+    }
+
+    fn changed(&self, path: &Utf8Path) -> Result<bool> {
+        let ask = || self.ask_changed(path);
+        let hashing = self.hashing.lock().expect("Reporter panicked");
+        let res = hashing.as_ref().map_or_else(ask, |h| h.bar.suspend(ask));
+        drop(hashing);
+        res
+    }
+}
+
+/// Size, modification time and dimensions of `file`, as shown next to its path
+pub fn describe(file: &File) -> String {
+    let mut description = vec![HumanBytes(file.size).to_string()];
+    if let Some(modified) = file.modified {
+        let modified = modified.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S");
+        description.push(format!("modified {modified}"));
+    }
+    if let Some((width, height)) = file.dimensions {
+        description.push(format!("{width}x{height}"));
+    }
+    description.join(", ")
+}
+
+/// Answer to which file of a group of duplicates to keep
+enum Kept {
+    File(usize),
+    Leave,
+    /// Keep the indexed file of this group and every one after it
+    IndexedEverywhere,
+    Quit,
+}
+
+/// Ask the user which file of every group of duplicates to keep, one group at a time
+pub fn choose_kept(groups: &[Group]) -> Result<Vec<Option<usize>>> {
+    let mut kept = Vec::with_capacity(groups.len());
+    for (i, group) in groups.iter().enumerate() {
+        println!("Duplicates {}/{} ({}):", i + 1, groups.len(), group.hash);
+        for (n, file) in group.files.iter().enumerate() {
+            let indexed = if n == 0 { " (indexed)" } else { "" };
+            let pinned = if file.pinned { " (pinned)" } else { "" };
+            println!(
+                "  {}) \"{}\"{indexed}{pinned}: {}",
+                n + 1,
+                file.path,
+                describe(file)
+            );
+        }
+        match ask_kept(group.files.len())? {
+            Kept::File(n) => kept.push(Some(n)),
+            Kept::Leave => kept.push(None),
+            Kept::IndexedEverywhere => {
+                kept.resize(groups.len(), Some(0));
+                break;
+            }
+            Kept::Quit => break,
+        }
+    }
+    kept.resize(groups.len(), None);
+    Ok(kept)
+}
+
+/// Ask which of `files` duplicates to keep
+fn ask_kept(files: usize) -> Result<Kept> {
+    let valid_commands = format!("1-{files}/s/i/q/?");
+    let flush = || -> Result<()> { std::io::stdout().flush().wrap_err("Failed flushing stdout") };
+
+    print!("Which file would you like to keep? ({valid_commands}): ");
+    flush()?;
+
+    let stdin = std::io::stdin();
+    loop {
+        let mut input = String::new();
+        stdin
+            .read_line(&mut input)
+            .wrap_err("Failed reading line from stdin")?;
+        println!();
+        flush()?;
+        match input.trim().to_lowercase().as_str() {
+            "" => return Ok(Kept::File(0)),
+            "s" => return Ok(Kept::Leave),
+            "i" => return Ok(Kept::IndexedEverywhere),
+            "q" => return Ok(Kept::Quit),
+            "?" => {
+                let range = format!("1-{files}");
+                println!(
+                    "{range:<9}- Keep that file and remove the others, the indexed one by default"
+                );
+                println!("s(Skip)  - Leave every file in place");
+                println!("i(Index) - Keep the indexed file in this group and every one after it");
+                println!("q(Quit)  - Leave this group and every one after it in place");
+                println!("?(Help)  - Print this message");
+            }
+            n => match n.parse::<usize>() {
+                Ok(n) if (1..=files).contains(&n) => return Ok(Kept::File(n - 1)),
+                _ => println!("Invalid command, valid ones are ({valid_commands})"),
+            },
+        }
+        flush()?;
+    }
+}
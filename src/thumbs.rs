@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::process::Command;
+use std::time::Instant;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use image::ImageFormat;
+use tracing::{info, warn};
+
+use crate::config::{Config, MediaKind};
+use crate::db;
+use crate::lock::Lock;
+use crate::utils;
+
+/// Side length of the box thumbnails are scaled down to fit in, if none is specified
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// Directory where thumbnails are cached
+#[must_use]
+pub fn dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    utils::cstfs_dir(data_path).join("thumbs")
+}
+
+/// Path to the cached thumbnail for the file with hash `hash`. Since thumbnails are keyed by the
+/// content hash, a file whose contents change will never be matched with a stale thumbnail.
+#[must_use]
+pub fn path(data_path: &Utf8Path, hash: &str) -> Utf8PathBuf {
+    dir(data_path).join(format!("{hash}.jpg"))
+}
+
+/// Check if the image at `path` is in a format cstfs is built to decode, which AVIF and RAW photos
+/// are not
+fn is_decodable(path: &Utf8Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|f| f.reading_enabled())
+}
+
+/// Generate a thumbnail for the image at `path`, and save it as a jpeg at `out`
+fn generate_image(path: &Utf8Path, out: &Utf8Path, size: u32) -> Result<()> {
+    let img = image::open(path).wrap_err("Failed decoding image")?;
+    img.thumbnail(size, size)
+        .into_rgb8()
+        .save_with_format(out, ImageFormat::Jpeg)
+        .wrap_err("Failed saving thumbnail")?;
+    Ok(())
+}
+
+/// Generate a thumbnail from a keyframe of the video at `path`, and save it as a jpeg at `out`.
+/// This shells out to ffmpeg, which must be in the `PATH`.
+fn generate_video(path: &Utf8Path, out: &Utf8Path, size: u32) -> Result<()> {
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-skip_frame", "nokey", "-i"])
+        .arg(path)
+        .args([
+            "-vf",
+            &format!("thumbnail,scale={size}:{size}:force_original_aspect_ratio=decrease"),
+            "-frames:v",
+            "1",
+        ])
+        .arg(out)
+        .output()
+        .wrap_err("Failed running ffmpeg")?;
+    if !output.status.success() {
+        bail!(
+            "ffmpeg exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Remove every cached thumbnail whose hash is not in `hashes`, returning how many were removed
+fn remove_stale(data_path: &Utf8Path, hashes: &HashSet<&str>) -> Result<usize> {
+    let mut removed = 0;
+    for entry in dir(data_path)
+        .read_dir_utf8()
+        .wrap_err("Failed reading thumbnail directory")?
+    {
+        let entry = entry.wrap_err("Failed reading thumbnail directory entry")?;
+        let p = entry.path();
+        if p.file_stem().is_some_and(|h| hashes.contains(h)) {
+            continue;
+        }
+        utils::remove_file(p).wrap_err_with(|| format!("Failed removing thumbnail {p}"))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Generate thumbnails for every image and video in the index, skipping the ones that are already
+/// cached unless `force` is set, and remove the thumbnails of hashes no longer in the index
+pub fn generate(data_path: &Utf8Path, config: &Config, size: u32, force: bool) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let files = db::files(&conn).wrap_err("Failed fetching files from db")?;
+
+    let thumbs_dir = dir(data_path);
+    std::fs::create_dir_all(&thumbs_dir)
+        .wrap_err_with(|| format!("Failed creating thumbnail directory \"{thumbs_dir}\""))?;
+
+    info!("Generating thumbnails at \"{thumbs_dir}\"");
+    let now = Instant::now();
+    let mut generated = 0;
+    let mut cached = 0;
+    let mut failed = 0;
+    let mut unsupported = 0;
+    for (p, hash) in &files {
+        let p = utils::full_path(data_path, Utf8Path::new(p));
+        let out = path(data_path, hash);
+        if out.exists() && !force {
+            cached += 1;
+            continue;
+        }
+        let kind = utils::media_kind(&p, config)
+            .wrap_err_with(|| format!("Failed finding out the type of {p}"))?;
+        let res = match kind {
+            Some(MediaKind::Image) if !is_decodable(&p) => {
+                unsupported += 1;
+                continue;
+            }
+            Some(MediaKind::Image) => generate_image(&p, &out, size),
+            Some(MediaKind::Video) => generate_video(&p, &out, size),
+            _ => continue,
+        };
+        match res {
+            Ok(()) => generated += 1,
+            Err(e) => {
+                warn!("Could not generate thumbnail for \"{p}\": {e:#}");
+                utils::remove_file(&out)
+                    .wrap_err_with(|| format!("Failed removing partial thumbnail {out}"))?;
+                failed += 1;
+            }
+        }
+    }
+
+    let hashes = files.iter().map(|(_, h)| h.as_str()).collect();
+    let removed = remove_stale(data_path, &hashes).wrap_err("Failed removing stale thumbnails")?;
+
+    let elapsed = now.elapsed();
+    info!(
+        "Generated {generated} thumbnails ({cached} cached, {failed} failed, {unsupported} in formats that cannot be decoded), removed {removed} stale. Took {elapsed:.2?}"
+    );
+
+    Ok(())
+}
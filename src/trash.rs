@@ -0,0 +1,258 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use tracing::info;
+
+use crate::config::Config;
+use crate::lock::Lock;
+use crate::utils;
+
+/// Directory where removed files are kept until the trash is emptied.
+///
+/// It follows the layout of the freedesktop.org trash: the files themselves are in `files/`, and the information needed to
+/// restore each one is in `info/<name>.trashinfo`, so it stays usable even if the database is lost.
+#[must_use]
+pub fn dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    utils::cstfs_dir(data_path).join("trash")
+}
+
+fn files_dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    dir(data_path).join("files")
+}
+
+fn info_dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    dir(data_path).join("info")
+}
+
+fn info_path(data_path: &Utf8Path, name: &str) -> Utf8PathBuf {
+    info_dir(data_path).join(format!("{name}.trashinfo"))
+}
+
+/// A file in the trash
+#[derive(Debug)]
+pub struct Entry {
+    /// Name of the file inside the trash
+    pub name: String,
+    /// Path the file was at before being trashed, relative to the data directory
+    pub path: Utf8PathBuf,
+    /// Hash of the file
+    pub hash: String,
+    /// When the file was trashed
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// Escape the characters that would break the line based trashinfo format
+fn escape(s: &str) -> String {
+    s.replace('%', "%25").replace('\n', "%0A")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("%0A", "\n").replace("%25", "%")
+}
+
+impl Entry {
+    fn to_info(&self) -> String {
+        format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\nHash={}\n",
+            escape(self.path.as_str()),
+            self.deleted_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.hash
+        )
+    }
+
+    fn from_info(name: &str, info: &str) -> Result<Self> {
+        let (mut path, mut deleted_at, mut hash) = (None, None, None);
+        for line in info.lines() {
+            if let Some(p) = line.strip_prefix("Path=") {
+                path = Some(Utf8PathBuf::from(unescape(p)));
+            } else if let Some(d) = line.strip_prefix("DeletionDate=") {
+                deleted_at = Some(
+                    DateTime::parse_from_rfc3339(d)
+                        .wrap_err_with(|| format!("Invalid deletion date {d}"))?
+                        .with_timezone(&Utc),
+                );
+            } else if let Some(h) = line.strip_prefix("Hash=") {
+                hash = Some(h.to_owned());
+            }
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            path: path.ok_or_else(|| eyre!("Missing Path"))?,
+            hash: hash.ok_or_else(|| eyre!("Missing Hash"))?,
+            deleted_at: deleted_at.ok_or_else(|| eyre!("Missing DeletionDate"))?,
+        })
+    }
+}
+
+/// Move `from` to `to`, copying it and removing the original if they are on different filesystems
+fn move_file(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to).wrap_err("Failed copying file")?;
+        std::fs::remove_file(from).wrap_err("Failed removing original file")?;
+    }
+    Ok(())
+}
+
+/// Move the file at `path`, relative to the data directory, with hash `hash` into the trash,
+/// returning the name it was given inside the trash
+pub fn trash_file(data_path: &Utf8Path, path: &Utf8Path, hash: &str) -> Result<String> {
+    let files_dir = files_dir(data_path);
+    let info_dir = info_dir(data_path);
+    for d in [&files_dir, &info_dir] {
+        std::fs::create_dir_all(d)
+            .wrap_err_with(|| format!("Failed creating trash directory \"{d}\""))?;
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| eyre!("Path \"{path}\" has no file name"))?;
+    let entry = Entry {
+        name: String::new(),
+        path: path.to_path_buf(),
+        hash: hash.to_owned(),
+        deleted_at: Utc::now(),
+    };
+    // The info file is created exclusively, to claim the name in the trash
+    for n in 0.. {
+        let name = if n == 0 {
+            file_name.to_owned()
+        } else {
+            format!("{n}_{file_name}")
+        };
+        let info_path = info_path(data_path, &name);
+        let mut info = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Failed creating \"{info_path}\""));
+            }
+        };
+        info.write_all(entry.to_info().as_bytes())
+            .wrap_err_with(|| format!("Failed writing \"{info_path}\""))?;
+
+        let full_path = utils::full_path(data_path, path);
+        if let Err(e) = move_file(&full_path, &files_dir.join(&name)) {
+            utils::remove_file(&info_path)
+                .wrap_err_with(|| format!("Failed removing \"{info_path}\""))?;
+            return Err(e).wrap_err_with(|| format!("Failed moving \"{full_path}\" to the trash"));
+        }
+        return Ok(name);
+    }
+    unreachable!("There is always an unused name in the trash")
+}
+
+/// Every file currently in the trash, oldest first
+pub fn entries(data_path: &Utf8Path) -> Result<Vec<Entry>> {
+    let info_dir = info_dir(data_path);
+    if !info_dir
+        .try_exists()
+        .wrap_err("Could not check trash existence")?
+    {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+    for e in info_dir
+        .read_dir_utf8()
+        .wrap_err("Failed reading trash directory")?
+    {
+        let e = e.wrap_err("Failed reading trash directory entry")?;
+        let Some(name) = e.file_name().strip_suffix(".trashinfo") else {
+            continue;
+        };
+        let info = std::fs::read_to_string(e.path())
+            .wrap_err_with(|| format!("Failed reading \"{}\"", e.path()))?;
+        let entry = Entry::from_info(name, &info)
+            .wrap_err_with(|| format!("Invalid trash info \"{}\"", e.path()))?;
+        entries.push(entry);
+    }
+    entries.sort_by_key(|e| e.deleted_at);
+
+    Ok(entries)
+}
+
+/// Print every file in the trash
+pub fn list(data_path: &Utf8Path) -> Result<()> {
+    let entries = entries(data_path).wrap_err("Failed reading trash")?;
+    if entries.is_empty() {
+        println!("The trash is empty");
+    }
+    for e in entries {
+        let deleted_at = e
+            .deleted_at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M:%S");
+        println!(
+            "{deleted_at}  {}  \"{}\" (from \"{}\")",
+            e.hash, e.name, e.path
+        );
+    }
+    Ok(())
+}
+
+/// Move the file in the trash named `name` back to where it was before being trashed, returning
+/// that path
+pub fn restore_file(data_path: &Utf8Path, name: &str) -> Result<Utf8PathBuf> {
+    let info = info_path(data_path, name);
+    let info_contents = match std::fs::read_to_string(&info) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("There is no file named \"{name}\" in the trash")
+        }
+        Err(e) => return Err(e).wrap_err_with(|| format!("Failed reading \"{info}\"")),
+    };
+    let entry = Entry::from_info(name, &info_contents)
+        .wrap_err_with(|| format!("Invalid trash info \"{info}\""))?;
+
+    let to = data_path.join(&entry.path);
+    if to
+        .try_exists()
+        .wrap_err_with(|| format!("Could not check existence of \"{to}\""))?
+    {
+        bail!("Cannot restore \"{name}\", \"{to}\" already exists");
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed creating directory \"{parent}\""))?;
+    }
+    move_file(&files_dir(data_path).join(name), &to)
+        .wrap_err_with(|| format!("Failed restoring \"{name}\""))?;
+    utils::remove_file(&info).wrap_err_with(|| format!("Failed removing \"{info}\""))?;
+
+    Ok(entry.path)
+}
+
+/// Move the files in the trash named `names` back to where they were before being trashed
+pub fn restore(data_path: &Utf8Path, config: &Config, names: &[String]) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    for name in names {
+        let path = restore_file(data_path, name)?;
+        info!("Restored \"{path}\"");
+    }
+    info!("Restored files are indexed on the next refresh");
+    Ok(())
+}
+
+/// Permanently remove every file in the trash
+pub fn empty(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    let entries = entries(data_path).wrap_err("Failed reading trash")?;
+    for e in &entries {
+        let file = files_dir(data_path).join(&e.name);
+        utils::remove_file(&file).wrap_err_with(|| format!("Failed removing \"{file}\""))?;
+        utils::remove_file(&info_path(data_path, &e.name))
+            .wrap_err_with(|| format!("Failed removing trash info of \"{}\"", e.name))?;
+    }
+    info!("Permanently removed {} files", entries.len());
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use std::io::{stdout, Stdout};
+
+use chrono::Local;
+use color_eyre::{eyre::WrapErr, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use cstfs::dedupe::Group;
+use indicatif::HumanBytes;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, List, ListState, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+
+const HELP: &str = "↑↓ file  ←→ group  enter keep file  s leave group  i keep indexed files  w remove duplicates  q quit";
+
+/// Which file of every group of duplicates is kept, as chosen so far
+struct App<'a> {
+    groups: &'a [Group],
+    kept: Vec<Option<usize>>,
+    group: ListState,
+    file: TableState,
+}
+
+impl App<'_> {
+    fn group(&self) -> usize {
+        self.group.selected().unwrap_or(0)
+    }
+
+    fn select_group(&mut self, i: usize) {
+        let i = i.min(self.groups.len() - 1);
+        self.group.select(Some(i));
+        self.file.select(Some(self.kept[i].unwrap_or(0)));
+    }
+
+    fn select_file(&mut self, i: usize) {
+        let files = self.groups[self.group()].files.len();
+        self.file.select(Some(i.min(files - 1)));
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.size());
+        let [groups, files] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Min(0)]).areas(main);
+
+        let items = self.groups.iter().zip(&self.kept).map(|(g, kept)| {
+            let hash = g.hash.get(..12).unwrap_or(&g.hash);
+            let choice = kept.map_or_else(String::new, |k| format!(", keep {}", k + 1));
+            format!("{hash} ({} files{choice})", g.files.len())
+        });
+        let list = List::new(items)
+            .block(Block::bordered().title("Duplicates"))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, groups, &mut self.group);
+
+        let group = self.group();
+        let rows = self.groups[group].files.iter().enumerate().map(|(n, f)| {
+            let choice = match self.kept[group] {
+                Some(k) if k == n => "keep",
+                // Never removed
+                Some(_) if f.pinned => "pinned",
+                Some(_) => "remove",
+                None => "",
+            };
+            let indexed = if n == 0 { " (indexed)" } else { "" };
+            let modified = f.modified.map_or_else(String::new, |m| {
+                m.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            });
+            let dimensions = f
+                .dimensions
+                .map_or_else(String::new, |(w, h)| format!("{w}x{h}"));
+            Row::new([
+                choice.to_owned(),
+                format!("{}{indexed}", f.path),
+                HumanBytes(f.size).to_string(),
+                modified,
+                dimensions,
+            ])
+        });
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Min(20),
+            Constraint::Length(10),
+            Constraint::Length(19),
+            Constraint::Length(11),
+        ];
+        let table = Table::new(rows, widths)
+            .header(
+                Row::new(["", "Path", "Size", "Modified", "Dimensions"])
+                    .style(Style::new().add_modifier(Modifier::BOLD)),
+            )
+            .block(Block::bordered().title(self.groups[group].hash.as_str()))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, files, &mut self.file);
+
+        frame.render_widget(Paragraph::new(HELP), help);
+    }
+
+    /// Handle the key `code`, returning whether the user is done choosing and wants the
+    /// duplicates removed, if they are done
+    fn key(&mut self, code: KeyCode) -> Option<bool> {
+        let group = self.group();
+        let file = self.file.selected().unwrap_or(0);
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.select_file(file.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => self.select_file(file + 1),
+            KeyCode::Left | KeyCode::Char('h') | KeyCode::BackTab => {
+                self.select_group(group.saturating_sub(1));
+            }
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Tab => self.select_group(group + 1),
+            KeyCode::Enter | KeyCode::Char(' ') => {
+                self.kept[group] = Some(file);
+                self.select_group(group + 1);
+            }
+            KeyCode::Char('s') => {
+                self.kept[group] = None;
+                self.select_group(group + 1);
+            }
+            KeyCode::Char('i') => {
+                for kept in self.kept.iter_mut().filter(|k| k.is_none()) {
+                    *kept = Some(0);
+                }
+                self.select_group(group);
+            }
+            KeyCode::Char('w') => return Some(true),
+            KeyCode::Char('q') | KeyCode::Esc => return Some(false),
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Let the user go through every group of duplicates in a full screen interface, choosing which
+/// file of each to keep. Nothing is kept if they quit without removing the duplicates.
+pub fn choose_kept(groups: &[Group]) -> Result<Vec<Option<usize>>> {
+    let mut app = App {
+        groups,
+        kept: vec![None; groups.len()],
+        group: ListState::default(),
+        file: TableState::default(),
+    };
+    app.select_group(0);
+
+    enable_raw_mode().wrap_err("Failed setting up terminal")?;
+    let res = stdout()
+        .execute(EnterAlternateScreen)
+        .wrap_err("Failed setting up terminal")
+        .and_then(|_| {
+            let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))
+                .wrap_err("Failed setting up terminal")?;
+            run(&mut terminal, &mut app)
+        });
+    // The terminal is restored even if drawing failed, to not leave it unusable
+    stdout()
+        .execute(LeaveAlternateScreen)
+        .wrap_err("Failed restoring terminal")?;
+    disable_raw_mode().wrap_err("Failed restoring terminal")?;
+
+    if res? {
+        Ok(app.kept)
+    } else {
+        Ok(vec![None; groups.len()])
+    }
+}
+
+/// Draw `app` and handle key presses until the user is done, returning whether they want the
+/// duplicates removed
+fn run(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) -> Result<bool> {
+    loop {
+        terminal
+            .draw(|frame| app.draw(frame))
+            .wrap_err("Failed drawing interface")?;
+        let Event::Key(key) = event::read().wrap_err("Failed reading key")? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        if let Some(remove) = app.key(key.code) {
+            return Ok(remove);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use camino::Utf8Path;
+use chrono::{Local, TimeZone};
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db::{self, JournalAction};
+use crate::lock::Lock;
+use crate::{backup, rename, trash, utils};
+
+/// Roll back the last operation recorded in the journal, reverting its changes to the index and
+/// moving back the files it moved or put in the trash, latest change first
+pub fn undo(data_path: &Utf8Path, config: &Config) -> Result<()> {
+    let _lock = Lock::acquire(data_path, config)?;
+    backup::before(data_path, config, "undo")?;
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating undo transaction")?;
+
+    let Some((operation, command, started_at)) =
+        db::last_operation(&transaction).wrap_err("Failed fetching last operation")?
+    else {
+        info!("There is nothing to undo");
+        return Ok(());
+    };
+    let started_at = Local.timestamp_opt(started_at, 0).single().map_or_else(
+        || started_at.to_string(),
+        |t| t.format("%Y-%m-%d %H:%M:%S").to_string(),
+    );
+    info!("Undoing {command} from {started_at}");
+
+    let actions = db::operation_actions(&transaction, operation)
+        .wrap_err("Failed fetching operation actions")?;
+    // Files are only moved once the index is reverted, so they are never where it does not say
+    let mut moves = vec![];
+    let mut removed = vec![];
+    let mut deleted = vec![];
+    for action in &actions {
+        match action {
+            JournalAction::Insert { path, hash } => {
+                db::remove(&transaction, path, hash)
+                    .wrap_err_with(|| format!("Could not remove {path} from the index"))?;
+                info!("Removed {path} from the index");
+            }
+            JournalAction::UpdatePath {
+                path,
+                prev_path,
+                hash,
+            } => {
+                db::update_path(&transaction, path, prev_path, hash)
+                    .wrap_err_with(|| format!("Could not update path {prev_path} at {hash}"))?;
+                info!("Updated index with {prev_path} (was {path})");
+            }
+            // Actions come latest first, so a file deleted for good is known before its removal
+            JournalAction::Remove { path, .. } if deleted.contains(&path) => {
+                info!("Left {path} out of the index, it is no longer on disk");
+            }
+            JournalAction::Remove { path, hash } => {
+                // It may have been a copy of another indexed file
+                db::insert_copy(&transaction, path, hash)
+                    .wrap_err_with(|| format!("Could not add {path} back to the index"))?;
+                info!("Added {path} back to the index");
+                removed.push(path.as_path());
+            }
+            JournalAction::UpdateHash {
+                path, prev_hash, ..
+            } => {
+                // Other files may have been given the hash since, it was indexed before them
+                db::update_hash(&transaction, path, prev_hash, true)
+                    .wrap_err_with(|| format!("Could not update hash of {path}"))?;
+                info!("Updated index with previous hash of {path}");
+            }
+            JournalAction::Trash { .. } => moves.push(action),
+            JournalAction::Delete { path, .. } => {
+                warn!("Could not restore {path}, it was deleted without going to the trash");
+                deleted.push(path);
+            }
+            JournalAction::Rename {
+                path,
+                prev_path,
+                hash,
+            } => {
+                db::update_path(&transaction, path, prev_path, hash)
+                    .wrap_err_with(|| format!("Could not update path {prev_path} at {hash}"))?;
+                moves.push(action);
+            }
+        }
+    }
+
+    db::delete_operation(&transaction, operation)
+        .wrap_err("Failed removing operation from the journal")?;
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+
+    for action in moves {
+        match action {
+            JournalAction::Trash {
+                path, trash_name, ..
+            } => match trash::restore_file(data_path, trash_name) {
+                Ok(_) => info!("Restored {path} from the trash"),
+                // The index is still reverted, the file may have been restored by hand
+                Err(e) => warn!("Could not restore {path} from the trash: {e:#}"),
+            },
+            JournalAction::Rename {
+                path, prev_path, ..
+            } => match rename::move_file(data_path, config, path, prev_path) {
+                Ok(()) => info!("Moved {path} back to {prev_path}"),
+                // The index is still reverted, the file may have been moved back by hand
+                Err(e) => warn!("Could not move {path} back to {prev_path}: {e:#}"),
+            },
+            _ => {}
+        }
+    }
+    restore_stats(data_path, &mut conn, &removed)?;
+    info!("Undid {} changes", actions.len());
+
+    Ok(())
+}
+
+/// Record the size and inode of the files at `paths`, added back to the index, so they are known
+/// like the ones of every other file. The ones no longer on disk are left without.
+fn restore_stats(data_path: &Utf8Path, conn: &mut Connection, paths: &[&Utf8Path]) -> Result<()> {
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating stat transaction")?;
+    for path in paths {
+        let full_path = utils::full_path(data_path, path);
+        if !full_path.is_file() {
+            continue;
+        }
+        let stat = utils::stat(&full_path)?;
+        db::set_stat(&transaction, path, &stat)
+            .wrap_err_with(|| format!("Failed recording size of {path}"))?;
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")
+}
@@ -1,27 +1,304 @@
-use camino::{Utf8Path, Utf8PathBuf};
-use color_eyre::{eyre::WrapErr, Result};
-use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fs::OpenOptions;
+use std::hash::Hasher;
+use std::io::Read;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use camino::{ReadDirUtf8, Utf8Component, Utf8DirEntry, Utf8Path, Utf8PathBuf};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use memmap2::Mmap;
+use seahash::SeaHasher;
+use tracing::{debug, info, warn};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+use crate::config::{
+    self, Config, Detection, HashAlgorithm, MediaKind, ReadMethod, SmallFilePolicy,
+};
+use crate::db;
+use crate::dir_times::{self, DirTimes};
+use crate::report::Reporter;
+use crate::sidecar;
+
+/// How many found files can wait to be hashed when hashing while walking a directory
+const STREAM_QUEUE_SIZE: usize = 1024;
+
+/// Size of the chunks files are read in when they are not mmaped
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+/// How many times a file is hashed when it keeps changing while it is hashed, see [`hash_file`]
+const HASH_ATTEMPTS: usize = 3;
+
+/// How long to wait before trying again to read a file after the first transient error, see
+/// [`retry`]
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Shortest length hashes are abbreviated to when printed, like git does
+const MIN_ABBREV: usize = 7;
 
-pub fn is_image_extension(ext: &str) -> bool {
-    matches!(ext, "png" | "jpg" | "jpeg" | "avif" | "webp" | "gif")
+/// Extended attribute hashes are cached in, when the configuration says so
+#[cfg(unix)]
+const HASH_XATTR: &str = "user.cstfs.hash";
+
+/// Whether the work was asked to stop, see [`interrupt`]
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the work in progress to stop. Hashing stops taking new files, and the commands that can be
+/// resumed keep what was done before it, while the others fail without changing the index.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
 }
 
-pub fn is_audio_extension(ext: &str) -> bool {
-    matches!(ext, "mp3" | "opus" | "flac")
+/// Whether [`interrupt`] was called
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
 }
 
-pub fn is_video_extension(ext: &str) -> bool {
-    matches!(ext, "mkv" | "mp4" | "mov" | "avi" | "webm")
+/// Shortens hashes when they are printed, to the shortest prefix that tells every indexed hash
+/// apart, which [`db::resolve_hash`] finds them by
+pub struct Abbrev(Option<usize>);
+
+impl Abbrev {
+    /// Abbreviation of the hashes in the index `conn` is the connection to, or none if `config`
+    /// asks for the full hashes
+    pub fn new(conn: &rusqlite::Connection, config: &Config) -> Result<Self> {
+        if config.full_hash {
+            return Ok(Self(None));
+        }
+        let hashes = db::hashes(conn).wrap_err("Failed fetching hashes from db")?;
+        // Sorted, the hashes sharing the longest prefix are next to each other
+        let len = hashes
+            .windows(2)
+            .map(|w| {
+                w[0].bytes()
+                    .zip(w[1].bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+                    + 1
+            })
+            .max()
+            .unwrap_or(0)
+            .max(MIN_ABBREV);
+        Ok(Self(Some(len)))
+    }
+
+    pub fn apply<'a>(&self, hash: &'a str) -> &'a str {
+        self.0.and_then(|len| hash.get(..len)).unwrap_or(hash)
+    }
 }
 
-/// Check if ext is an extension corresponding to a media file (video, audio or image)
-pub fn is_media_extension(ext: &str) -> bool {
-    is_image_extension(ext) || is_audio_extension(ext) || is_video_extension(ext)
+/// Name of [`cstfs_dir`] in the data directory
+const CSTFS_DIR: &str = ".cstfs";
+
+/// Directory inside the data directory where cstfs keeps its own state (thumbnails, etc.)
+pub fn cstfs_dir(data_path: &Utf8Path) -> Utf8PathBuf {
+    data_path.join(CSTFS_DIR)
+}
+
+/// Check if `relative`, a path relative to the data directory, is [`cstfs_dir`] or in it. Only the
+/// one at the root of the data directory is cstfs', others are the user's.
+fn is_in_cstfs_dir(relative: &Utf8Path) -> bool {
+    relative
+        .components()
+        .find(|c| *c != Utf8Component::CurDir)
+        .is_some_and(|c| c.as_str() == CSTFS_DIR)
+}
+
+/// Path of `path` relative to the data directory, as it is stored in the index. Relative paths are
+/// taken as relative to the current directory, like the shell completes them, and both must lead
+/// inside of the data directory once the symlinks of the directories they go through are resolved.
+pub fn relative_path(data_path: &Utf8Path, path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let canonical_data_path =
+        canonicalize(data_path).wrap_err_with(|| format!("Failed canonicalizing {data_path}"))?;
+    let resolved = resolve(path)?;
+    let relative = resolved
+        .strip_prefix(&canonical_data_path)
+        .map_err(|_| eyre!("\"{path}\" is not inside of \"{data_path}\""))?;
+    Ok(normalize(relative))
+}
+
+/// Absolute path of `path`, with the symlinks of the directories it goes through that exist
+/// resolved. The file itself is left as it is, as it may be a symlink indexed under its own path.
+fn resolve(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let cwd = std::env::current_dir().wrap_err("Failed reading current directory")?;
+    let cwd = Utf8PathBuf::try_from(cwd).wrap_err("Current directory is not UTF-8")?;
+    let path = cwd.join(path);
+    let mut missing = vec![];
+    let mut existing = path.as_path();
+    if let Some(Utf8Component::Normal(name)) = existing.components().next_back() {
+        missing.push(name);
+        existing = existing.parent().unwrap_or(existing);
+    }
+    loop {
+        let e = match canonicalize(existing) {
+            Ok(canonical) => return Ok(missing.iter().rev().fold(canonical, |p, n| p.join(n))),
+            Err(e) => e,
+        };
+        // Only directories that do not exist yet are taken as they are
+        match (existing.components().next_back(), existing.parent()) {
+            (Some(Utf8Component::Normal(name)), Some(parent)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return Err(e).wrap_err_with(|| format!("Failed canonicalizing {path}")),
+        }
+    }
+}
+
+/// `path`, relative to some directory, without its `.` components, failing if it is absolute or
+/// leads out of the directory
+pub fn clean_relative(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let mut cleaned = Utf8PathBuf::new();
+    for component in path.components() {
+        match component {
+            Utf8Component::Normal(c) => cleaned.push(c),
+            Utf8Component::CurDir => {}
+            _ => bail!("\"{path}\" leads out of the directory it is relative to"),
+        }
+    }
+    Ok(normalize(&cleaned))
+}
+
+/// Fail unless `path` is relative and made only of names, so it cannot lead out of the directory it
+/// is relative to. Paths given by other stores are checked with it before they are used.
+pub fn check_relative(path: &Utf8Path) -> Result<()> {
+    let only_names = path
+        .components()
+        .all(|c| matches!(c, Utf8Component::Normal(_)));
+    if path.as_str().is_empty() || !only_names {
+        bail!("\"{path}\" is not a path inside of the store");
+    }
+    Ok(())
 }
 
-/// Hash the file at `path` using seahash
-pub fn hash_file(path: &Utf8Path) -> Result<String> {
+/// `path` in Unicode normalization form C, which paths are indexed and compared in. macOS and Linux
+/// disagree on which form file names are in, so the same name may come in either. On Windows the
+/// components are separated by `/` too, so an index reads the same on every platform.
+pub fn normalize(path: &Utf8Path) -> Utf8PathBuf {
+    let path: Cow<str> = if cfg!(windows) {
+        path.as_str().replace('\\', "/").into()
+    } else {
+        path.as_str().into()
+    };
+    if is_nfc_quick(path.chars()) == IsNormalized::Yes {
+        return path.into_owned().into();
+    }
+    path.nfc().collect::<String>().into()
+}
+
+/// Absolute path of `path` with every symlink resolved. On Windows it is not given as a verbatim
+/// `\\?\C:\` path when it can be helped, so it still starts with the paths users give.
+pub fn canonicalize(path: &Utf8Path) -> std::io::Result<Utf8PathBuf> {
+    Utf8PathBuf::from_path_buf(dunce::canonicalize(path)?).map_err(|p| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("\"{}\" is not valid UTF-8", p.display()),
+        )
+    })
+}
+
+/// Whether the filesystem of the data directory tells apart names that only differ in case, as
+/// `config.case_sensitive` says or else found out by creating a file in [`cstfs_dir`]
+pub fn case_sensitive(data_path: &Utf8Path, config: &Config) -> bool {
+    if let Some(case_sensitive) = config.case_sensitive {
+        return case_sensitive;
+    }
+    if config.read_only {
+        debug!("Not trying whether read-only \"{data_path}\" is case sensitive, assuming it is");
+        return true;
+    }
+    let dir = cstfs_dir(data_path);
+    let probe = dir.join("CASE-PROBE");
+    let created = std::fs::create_dir_all(&dir).and_then(|()| std::fs::File::create(&probe));
+    if let Err(e) = created {
+        debug!("Could not find out whether \"{data_path}\" is case sensitive, assuming it is: {e}");
+        return true;
+    }
+    let sensitive = !dir.join("case-probe").exists();
+    let _ = std::fs::remove_file(&probe);
+    debug!(
+        "\"{data_path}\" is case {}sensitive",
+        if sensitive { "" } else { "in" }
+    );
+    sensitive
+}
+
+/// What `path` is compared by, lowercased when the filesystem is not [`case_sensitive`]
+#[must_use]
+pub fn path_key(path: &str, case_sensitive: bool) -> String {
+    if case_sensitive {
+        path.to_owned()
+    } else {
+        path.to_lowercase()
+    }
+}
+
+/// Full path of the file indexed at `path`, inside of the data directory.
+///
+/// Indexed paths are [`normalize`]d, so on filesystems that keep names as they are given, like
+/// Linux ones, a file whose name on disk is in another form is looked up by the normalized name of
+/// every entry.
+#[must_use]
+pub fn full_path(data_path: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    let joined = data_path.join(path);
+    if path.as_str().is_ascii() || joined.symlink_metadata().is_ok() {
+        return joined;
+    }
+    let mut full = data_path.to_path_buf();
+    for component in path.components() {
+        let name = component.as_str();
+        if full.join(name).symlink_metadata().is_ok() {
+            full.push(name);
+            continue;
+        }
+        let on_disk = full.read_dir_utf8().ok().and_then(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .find(|e| normalize(Utf8Path::new(e.file_name())) == Utf8Path::new(name))
+        });
+        match on_disk {
+            Some(entry) => full.push(entry.file_name()),
+            None => full.push(name),
+        }
+    }
+    full
+}
+
+/// Hash the file at `path` with the algorithm in `config`, reading it as `config` says.
+///
+/// A file written to while it is hashed would get the hash of a mix of its old and new contents,
+/// so it is hashed again if it changed meanwhile, up to [`HASH_ATTEMPTS`] times.
+pub fn hash_file(path: &Utf8Path, config: &Config) -> Result<String> {
+    hash_unchanged(path, config).map(|(hash, _)| hash)
+}
+
+/// Hash the file at `path` like [`hash_file`], returning the stat it had while it was hashed
+fn hash_unchanged(path: &Utf8Path, config: &Config) -> Result<(String, db::Stat)> {
+    retry(config, path, || hash_unchanged_once(path, config))
+}
+
+fn hash_unchanged_once(path: &Utf8Path, config: &Config) -> Result<(String, db::Stat)> {
+    for _ in 0..HASH_ATTEMPTS {
+        let before = stat(path)?;
+        let hash = hash_once(path, config)?;
+        let after = stat(path)?;
+        if before == after {
+            return Ok((hash, after));
+        }
+        debug!("{path} changed while it was hashed, hashing it again");
+    }
+    bail!("File kept changing while it was hashed, it may still be being written to")
+}
+
+/// Hash the file at `path` as it is being read
+fn hash_once(path: &Utf8Path, config: &Config) -> Result<String> {
     let file = OpenOptions::new()
         .read(true)
         .write(false)
@@ -29,61 +306,1066 @@ pub fn hash_file(path: &Utf8Path) -> Result<String> {
         .open(path)
         .wrap_err("Failed to open file")?;
 
-    let mmap = unsafe { Mmap::map(&file).wrap_err("Failed mmaping file")? };
+    // A mmaped file is read as fast as it can be, so throttled files are read in chunks instead.
+    // Reading the mapping of a file another program locked crashes on Windows instead of failing,
+    // so there files are only mapped if they can be locked.
+    if matches!(config.read, ReadMethod::Mmap)
+        && config.throttle.is_none()
+        && (!cfg!(windows) || fs2::FileExt::try_lock_shared(&file).is_ok())
+    {
+        match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => return Ok(hash_bytes(&mmap, config.hash)),
+            Err(e) => debug!("Failed mmaping {path}, reading it instead: {e}"),
+        }
+    }
+    let reader = Throttled {
+        reader: file,
+        throttle: config.throttle,
+    };
+    hash_reader(reader, config.hash).wrap_err("Failed reading file")
+}
+
+/// When the next read of a throttled file may end, shared by every thread hashing files
+static NEXT_READ: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Reader that waits after every read for as long as the bytes read take at `throttle` megabytes
+/// per second, counting the reads of every other throttled reader
+struct Throttled<R> {
+    reader: R,
+    throttle: Option<NonZeroU64>,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        let Some(throttle) = self.throttle else {
+            return Ok(n);
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let duration = Duration::from_secs_f64(n as f64 / (throttle.get() as f64 * 1e6));
+        let now = Instant::now();
+        let mut next_read = NEXT_READ.lock().expect("Hashing thread panicked");
+        let end = next_read.map_or(now, |t| t.max(now)) + duration;
+        *next_read = Some(end);
+        drop(next_read);
+        std::thread::sleep(end - now);
+        Ok(n)
+    }
+}
+
+/// Hash the file at `path` like [`hash_file`], reusing the hash cached in its extended attributes
+/// if `config` enables the cache and the file was not modified since, and caching it otherwise
+fn cached_hash_file(path: &Utf8Path, config: &Config) -> Result<String> {
+    if !config.xattr_cache {
+        return hash_file(path, config);
+    }
+    let metadata = path.metadata().wrap_err("Failed reading metadata")?;
+    let Some(modified) = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+    else {
+        return hash_file(path, config);
+    };
+    // The hash is only valid for the algorithm it was made with and the exact same file
+    let key = format!(
+        "{}:{}:{}",
+        config.hash.name(),
+        metadata.len(),
+        modified.as_nanos()
+    );
+
+    if let Some(value) = read_cached_hash(path) {
+        let cached = std::str::from_utf8(&value)
+            .ok()
+            .and_then(|v| v.strip_prefix(key.as_str()))
+            .and_then(|v| v.strip_prefix(':'))
+            .filter(|h| config.hash.is_valid_hash(h));
+        if let Some(hash) = cached {
+            return Ok(hash.to_owned());
+        }
+    }
+
+    let (hash, stat) = hash_unchanged(path, config)?;
+    // The file may have changed since the key was made, the hash is of its contents once hashed
+    let Some(mtime) = stat.mtime else {
+        return Ok(hash);
+    };
+    if config.read_only {
+        return Ok(hash);
+    }
+    let key = format!("{}:{}:{mtime}", config.hash.name(), stat.size);
+    write_cached_hash(path, &format!("{key}:{hash}"));
+    Ok(hash)
+}
+
+/// Value of the hash cached in the extended attributes of the file at `path`, if it has one
+#[cfg(unix)]
+fn read_cached_hash(path: &Utf8Path) -> Option<Vec<u8>> {
+    xattr::get(path, HASH_XATTR).unwrap_or_else(|e| {
+        debug!("Failed reading cached hash of {path}: {e}");
+        None
+    })
+}
 
-    let h = seahash::hash(&mmap);
-    Ok(format!("{h:016x}"))
+/// Windows has no extended attributes, so no hash is ever cached
+#[cfg(not(unix))]
+const fn read_cached_hash(_path: &Utf8Path) -> Option<Vec<u8>> {
+    None
 }
 
-/// Return an vector that contains the paths for all files within the directory, recursively, or an
-/// error upon any io failure
-pub fn recursive_directory_read(path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
-    let v: Result<Vec<_>> = path
-        .read_dir_utf8()
-        .wrap_err("Failed reading directory contents")?
-        .map(|e| e.wrap_err("Failed reading file"))
+/// Cache `value` in the extended attributes of the file at `path`, ignoring failures as it is
+/// only a cache
+#[cfg(unix)]
+fn write_cached_hash(path: &Utf8Path, value: &str) {
+    if let Err(e) = xattr::set(path, HASH_XATTR, value.as_bytes()) {
+        debug!("Failed caching hash of {path}: {e}");
+    }
+}
+
+#[cfg(not(unix))]
+const fn write_cached_hash(_path: &Utf8Path, _value: &str) {}
+
+fn hash_bytes(bytes: &[u8], algorithm: HashAlgorithm) -> String {
+    match algorithm {
+        HashAlgorithm::Seahash => {
+            let h = seahash::hash(bytes);
+            format!("{h:016x}")
+        }
+        HashAlgorithm::Blake3 => blake3::hash(bytes).to_hex().to_string(),
+    }
+}
+
+/// Hash everything read from `reader` in chunks, giving the same hash as [`hash_bytes`] would
+fn hash_reader(mut reader: impl Read, algorithm: HashAlgorithm) -> std::io::Result<String> {
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    let mut seahasher = SeaHasher::new();
+    let mut blake3_hasher = blake3::Hasher::new();
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        match algorithm {
+            HashAlgorithm::Seahash => seahasher.write(&buf[..n]),
+            HashAlgorithm::Blake3 => {
+                blake3_hasher.update(&buf[..n]);
+            }
+        }
+    }
+    Ok(match algorithm {
+        HashAlgorithm::Seahash => format!("{:016x}", seahasher.finish()),
+        HashAlgorithm::Blake3 => blake3_hasher.finalize().to_hex().to_string(),
+    })
+}
+
+/// Hash every file in `paths` with the algorithm in `config`, using as many threads as it allows,
+/// returning the hashes in the same order as the paths. The progress is sent to `reporter`.
+///
+/// Hashes cached in the extended attributes of the files are used if `config` enables the cache.
+///
+/// # Panics
+///
+/// If any of the hashing threads panics
+#[must_use]
+pub fn hash_files(
+    paths: &[Utf8PathBuf],
+    config: &Config,
+    reporter: &dyn Reporter,
+) -> Vec<Result<String>> {
+    let sizes: Vec<u64> = paths
+        .iter()
+        .map(|p| p.metadata().map_or(0, |m| m.len()))
         .collect();
-    let entries = v?;
-    let mut paths = vec![];
-    for entry in entries {
+    reporter.hashing_started(paths.len(), sizes.iter().sum());
+
+    let next = AtomicUsize::new(0);
+    let hashes = Mutex::new((0..paths.len()).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|s| {
+        for _ in 0..config.jobs().min(paths.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(p) = paths.get(i) else {
+                    break;
+                };
+                reporter.file_started(p);
+                let h = cached_hash_file(p, config)
+                    .wrap_err_with(|| format!("Could not hash file {p}"));
+                hashes.lock().expect("Hashing thread panicked")[i] = Some(h);
+                reporter.file_hashed(p, sizes[i]);
+            });
+        }
+    });
+    reporter.hashing_finished();
+    hashes
+        .into_inner()
+        .expect("Hashing thread panicked")
+        .into_iter()
+        .map(|h| h.expect("Every file was hashed"))
+        .collect()
+}
+
+/// Hash the files yielded by `paths` with the algorithm in `config` as they come, using as many
+/// threads as it allows, calling `f` with every path and its hash in the order they are hashed.
+///
+/// Unlike [`hash_files`], the amount of files does not need to be known beforehand, and it is
+/// sent to `reporter` as the files are found. Stops at the first error from `paths` or `f`. Once
+/// [`interrupt`] is called, the files being hashed are still passed to `f` before failing.
+///
+/// # Panics
+///
+/// If any of the hashing threads panics
+pub fn hash_stream(
+    paths: impl Iterator<Item = Result<Utf8PathBuf>> + Send,
+    config: &Config,
+    reporter: &dyn Reporter,
+    mut f: impl FnMut(Utf8PathBuf, Result<String>) -> Result<()>,
+) -> Result<()> {
+    reporter.hashing_started(0, 0);
+    let (path_tx, path_rx) = mpsc::sync_channel::<(Utf8PathBuf, u64)>(STREAM_QUEUE_SIZE);
+    // Shared by the hashing threads, so the walk stops once all of them are gone
+    let path_rx = Arc::new(Mutex::new(path_rx));
+    let (hash_tx, hash_rx) = mpsc::channel();
+
+    let res = std::thread::scope(|s| {
+        let walker = s.spawn(move || -> Result<()> {
+            for p in paths {
+                if interrupted() {
+                    break;
+                }
+                let p = p?;
+                let size = p.metadata().map_or(0, |m| m.len());
+                reporter.file_found(&p, size);
+                if path_tx.send((p, size)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        for _ in 0..config.jobs() {
+            let path_rx = Arc::clone(&path_rx);
+            let hash_tx = hash_tx.clone();
+            s.spawn(move || loop {
+                if interrupted() {
+                    break;
+                }
+                let next = path_rx.lock().expect("Hashing thread panicked").recv();
+                let Ok((p, size)) = next else {
+                    break;
+                };
+                reporter.file_started(&p);
+                let h = cached_hash_file(&p, config)
+                    .wrap_err_with(|| format!("Could not hash file {p}"));
+                reporter.file_hashed(&p, size);
+                if hash_tx.send((p, h)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop((path_rx, hash_tx));
+
+        let res = hash_rx.into_iter().try_for_each(|(p, h)| f(p, h));
+        res.and_then(|()| walker.join().expect("Walking thread panicked"))
+    });
+    reporter.hashing_finished();
+    if res.is_ok() && interrupted() {
+        bail!("Interrupted before every file was hashed");
+    }
+    res
+}
+
+/// Kind of media of the file at `path`, found out from its extension or its contents as `config`
+/// says. When they disagree, the mismatch is reported.
+pub fn media_kind(path: &Utf8Path, config: &Config) -> Result<Option<MediaKind>> {
+    let by_extension = path.extension().and_then(|ext| config.extensions.kind(ext));
+    if matches!(config.detect, Detection::Extension) {
+        return Ok(by_extension);
+    }
+
+    let Some(t) = infer::get_from_path(path).wrap_err("Failed reading file header")? else {
+        return Ok(by_extension);
+    };
+    let by_content = match t.matcher_type() {
+        infer::MatcherType::Image => Some(MediaKind::Image),
+        infer::MatcherType::Audio => Some(MediaKind::Audio),
+        infer::MatcherType::Video => Some(MediaKind::Video),
+        _ => None,
+    };
+    if let Some(ext) = path.extension() {
+        if by_content != by_extension {
+            warn!(
+                "\"{path}\" has extension {ext}, but its contents look like {}",
+                t.mime_type()
+            );
+        }
+    }
+    Ok(by_content)
+}
+
+/// Set of the globs in `patterns`, ignoring trailing slashes
+pub fn glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern.trim_end_matches('/'))
+            .wrap_err_with(|| format!("Invalid pattern {pattern}"))?;
+        builder.add(glob);
+    }
+    builder.build().wrap_err("Failed building patterns")
+}
+
+/// Return an vector that contains the paths for all the media files within the data directory.
+///
+/// Directories are read recursively, skipping the files ignored by `config`, and every file is
+/// returned if `config.all_files` is set. Fails upon any io failure.
+pub fn recursive_directory_read(data_path: &Utf8Path, config: &Config) -> Result<Vec<Utf8PathBuf>> {
+    walk(data_path, config)?.collect()
+}
+
+/// Like [`recursive_directory_read`], but yielding the paths as the directories are read instead
+/// of collecting them, so the memory used does not grow with the amount of files.
+///
+/// If `config.walk_jobs` is more than one, that many directories are read in parallel, and the
+/// files are yielded in no particular order.
+pub fn walk(data_path: &Utf8Path, config: &Config) -> Result<Walk> {
+    let rules = Rules::new(data_path, config)?;
+    Walk::start(rules, vec![(data_path.to_path_buf(), 0)], vec![])
+}
+
+/// Like [`walk`], but only through the files and directories at `paths`, relative to the data
+/// directory, if it is not empty. The modification time of every directory gone through is
+/// recorded in `times`, and the ones whose time is still the one recorded there before are not
+/// read: their files are not yielded, and the directories recorded below them are read instead.
+///
+/// The paths that do not exist are skipped, as are the ones a walk of the whole data directory
+/// would not reach, because they are ignored or too deep.
+pub fn walk_recording(
+    data_path: &Utf8Path,
+    config: &Config,
+    paths: &[Utf8PathBuf],
+    times: Arc<DirTimes>,
+) -> Result<Walk> {
+    let mut rules = Rules::new(data_path, config)?;
+    rules.dir_times = Some(times);
+    if paths.is_empty() {
+        return Walk::start(rules, vec![(data_path.to_path_buf(), 0)], vec![]);
+    }
+    start_walk(rules, paths)
+}
+
+/// Start walking through the files and directories at `paths`, relative to the data directory, as
+/// [`walk_recording`] does
+fn start_walk(rules: Rules, paths: &[Utf8PathBuf]) -> Result<Walk> {
+    let (data_path, config) = (&rules.data_path, &rules.config);
+    let (mut dirs, mut files) = (vec![], vec![]);
+    for path in paths {
+        let full_path = data_path.join(path);
+        if !full_path
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{path}\""))?
+        {
+            continue;
+        }
+        let is_dir = full_path.is_dir();
+        let depth = path.components().count();
+        let too_deep = config
+            .max_depth
+            .is_some_and(|max| depth > max + usize::from(!is_dir));
+        if too_deep || rules.is_ignored(path) {
+            info!("Skipping \"{path}\", which is not indexed");
+        } else if is_dir {
+            dirs.push((full_path, depth));
+        } else if rules.is_indexed(&full_path, path)? {
+            files.push(full_path);
+        }
+    }
+    Walk::start(rules, dirs, files)
+}
+
+/// Like [`recursive_directory_read`], but only reading the files and directories at `paths`.
+///
+/// The paths are relative to the data directory, and the ones a read of the whole data directory
+/// would not reach, because they are ignored or too deep, are skipped.
+pub fn read_paths(
+    data_path: &Utf8Path,
+    config: &Config,
+    paths: &[Utf8PathBuf],
+) -> Result<Vec<Utf8PathBuf>> {
+    let mut walk = SerialWalk::new(Rules::new(data_path, config)?);
+    let mut files = vec![];
+    for path in paths {
+        let full_path = data_path.join(path);
+        if !full_path
+            .try_exists()
+            .wrap_err_with(|| format!("Could not check existence of \"{path}\""))?
+        {
+            bail!("\"{path}\" does not exist");
+        }
+        let is_dir = full_path.is_dir();
+        let depth = path.components().count();
+        let too_deep = config
+            .max_depth
+            .is_some_and(|max| depth > max + usize::from(!is_dir));
+        if too_deep || walk.rules.is_ignored(path) {
+            info!("Skipping \"{path}\", which is not indexed");
+            continue;
+        }
+
+        if is_dir {
+            walk.open_dir(&full_path, depth)?;
+            for p in walk.by_ref() {
+                files.push(p?);
+            }
+        } else if walk.rules.is_indexed(&full_path, path)? {
+            files.push(full_path);
+        }
+    }
+    Ok(files)
+}
+
+/// Walk through the data directory, yielding the path of every file that is indexed as its
+/// directory is read
+pub struct Walk {
+    /// Files given to the walk, yielded before the ones found in the directories
+    files: std::vec::IntoIter<Utf8PathBuf>,
+    inner: Inner,
+}
+
+enum Inner {
+    Serial(Box<SerialWalk>),
+    Parallel(mpsc::IntoIter<Result<Utf8PathBuf>>),
+}
+
+impl Walk {
+    /// Start walking the directories in `dirs`, with how many directories below the data directory
+    /// they are, after yielding `files`
+    fn start(
+        rules: Rules,
+        dirs: Vec<(Utf8PathBuf, usize)>,
+        files: Vec<Utf8PathBuf>,
+    ) -> Result<Self> {
+        let inner = if rules.config.walk_jobs.get() > 1 {
+            let jobs = rules.config.walk_jobs.get();
+            Inner::Parallel(ParallelWalk::spawn(rules, dirs, jobs).into_iter())
+        } else {
+            let mut walk = SerialWalk::new(rules);
+            // The last one opened is read first
+            for (dir, depth) in dirs.iter().rev() {
+                walk.open_dir(dir, *depth)?;
+            }
+            Inner::Serial(Box::new(walk))
+        };
+        Ok(Self {
+            files: files.into_iter(),
+            inner,
+        })
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<Utf8PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(file) = self.files.next() {
+            return Some(Ok(file));
+        }
+        match &mut self.inner {
+            Inner::Serial(walk) => walk.next(),
+            Inner::Parallel(found) => found.next(),
+        }
+    }
+}
+
+/// What an entry of a directory is to a walk
+enum Entry {
+    /// A file that is indexed
+    File(Utf8PathBuf),
+    /// A directory to read
+    Dir(Utf8PathBuf),
+    Skipped,
+}
+
+/// Entries of a directory a walk goes through
+enum Listing {
+    /// Read from the directory, boxed as it is large on Windows
+    Read(Box<ReadDirUtf8>),
+    /// Directories recorded below a directory that did not change, which is not read
+    Unchanged(std::vec::IntoIter<Utf8PathBuf>),
+}
+
+/// Which files and directories of the data directory a walk goes through
+struct Rules {
+    data_path: Utf8PathBuf,
+    /// Canonical path of the data directory, to tell if symlinks point inside of it
+    canonical_data_path: Utf8PathBuf,
+    config: Config,
+    /// Matched against paths relative to the data directory
+    ignore: GlobSet,
+    /// Matched against file names
+    exclude: GlobSet,
+    /// Canonical paths of the directories already read, so symlinks can't make the walk loop
+    visited: Mutex<HashSet<Utf8PathBuf>>,
+    /// Where the modification times of the directories are recorded, if they are, see
+    /// [`walk_recording`]
+    dir_times: Option<Arc<DirTimes>>,
+}
+
+impl Rules {
+    fn new(data_path: &Utf8Path, config: &Config) -> Result<Self> {
+        Ok(Self {
+            data_path: data_path.to_path_buf(),
+            canonical_data_path: canonicalize(data_path)
+                .wrap_err_with(|| format!("Failed canonicalizing {data_path}"))?,
+            config: config.clone(),
+            ignore: glob_set(&config.ignore).wrap_err("Invalid ignore patterns")?,
+            exclude: glob_set(&config.exclude).wrap_err("Invalid exclude patterns")?,
+            visited: Mutex::new(HashSet::new()),
+            dir_times: None,
+        })
+    }
+
+    /// Check if `relative`, a path relative to the data directory, or any of the directories it is
+    /// in are skipped when reading the data directory
+    fn is_ignored(&self, relative: &Utf8Path) -> bool {
+        is_in_cstfs_dir(relative)
+            || relative.ancestors().any(|p| {
+                p.file_name()
+                    .is_some_and(|name| self.ignore.is_match(p) || self.is_excluded(name))
+            })
+    }
+
+    /// Check if files and directories named `name` are skipped wherever they are
+    fn is_excluded(&self, name: &str) -> bool {
+        self.exclude.is_match(name) || (!self.config.include_hidden && name.starts_with('.'))
+    }
+
+    /// Start reading the directory at `path`, unless it was already read through another path.
+    /// Errors say it could not be read with [`Unreadable`].
+    fn open_dir(&self, path: &Utf8Path) -> Result<Option<Listing>> {
+        self.try_open_dir(path)
+            .wrap_err_with(|| Unreadable(path.to_path_buf()))
+    }
+
+    fn try_open_dir(&self, path: &Utf8Path) -> Result<Option<Listing>> {
+        let canonical =
+            canonicalize(path).wrap_err_with(|| format!("Failed canonicalizing {path}"))?;
+        if !self
+            .visited
+            .lock()
+            .expect("Walking thread panicked")
+            .insert(canonical)
+        {
+            info!("Skipping \"{path}\", which was already read through another path");
+            return Ok(None);
+        }
+
+        let Some(times) = &self.dir_times else {
+            return path
+                .read_dir_utf8()
+                .map(|entries| Some(Listing::Read(Box::new(entries))))
+                .wrap_err_with(|| format!("Failed reading directory contents of {path}"));
+        };
+        let relative = path.strip_prefix(&self.data_path).unwrap_or(path);
+        if let Some(dirs) = times.unchanged(relative, path) {
+            debug!("Not reading \"{path}\", which did not change");
+            let dirs: Vec<_> = dirs
+                .into_iter()
+                .filter(|d| !self.is_ignored(d))
+                .map(|d| self.data_path.join(d))
+                .collect();
+            return Ok(Some(Listing::Unchanged(dirs.into_iter())));
+        }
+        // Taken before reading it, so changes made while it is read are found the next time
+        let mtime = dir_times::mtime(path);
+        let entries = path
+            .read_dir_utf8()
+            .wrap_err_with(|| format!("Failed reading directory contents of {path}"))?;
+        times.read(relative, mtime);
+        Ok(Some(Listing::Read(Box::new(entries))))
+    }
+
+    /// Find out what the next entry of `listing`, the one of the directory at `dir` which is
+    /// `depth` directories below the data directory, is to the walk
+    fn next_entry(
+        &self,
+        dir: &Utf8Path,
+        listing: &mut Listing,
+        depth: usize,
+    ) -> Option<Result<Entry>> {
+        match listing {
+            Listing::Read(entries) => Some(self.read_entry(dir, entries.next()?, depth)),
+            Listing::Unchanged(dirs) => {
+                let dir = dirs.next()?;
+                Some(
+                    if matches!(self.config.max_depth, Some(max) if depth >= max) {
+                        Ok(Entry::Skipped)
+                    } else {
+                        Ok(Entry::Dir(dir))
+                    },
+                )
+            }
+        }
+    }
+
+    /// Find out what `entry`, read from the directory at `dir` which is `depth` directories below
+    /// the data directory, is to the walk. Errors say which path could not be read with
+    /// [`Unreadable`].
+    fn read_entry(
+        &self,
+        dir: &Utf8Path,
+        entry: std::io::Result<Utf8DirEntry>,
+        depth: usize,
+    ) -> Result<Entry> {
+        let entry = entry
+            .wrap_err_with(|| format!("Failed reading file in {dir}"))
+            .wrap_err_with(|| Unreadable(dir.to_path_buf()))?;
+        self.classify(&entry, depth)
+            .wrap_err_with(|| Unreadable(entry.path().to_path_buf()))
+    }
+
+    /// Find out what `entry`, which is `depth` directories below the data directory, is to the walk
+    fn classify(&self, entry: &Utf8DirEntry, depth: usize) -> Result<Entry> {
         let p = entry.path();
-        if entry
-            .metadata()
-            .wrap_err_with(|| format!("Failed reading metadata for {p}"))?
-            .is_dir()
+        let relative = p.strip_prefix(&self.data_path).unwrap_or(p);
+        if self.ignore.is_match(relative)
+            || self.is_excluded(entry.file_name())
+            || (!self.config.include_hidden && has_hidden_attribute(entry))
         {
-            let v = recursive_directory_read(p)
-                .wrap_err_with(|| format!("Failed reading directory contents of {p}"))?;
-            paths.extend(v);
+            return Ok(Entry::Skipped);
+        }
+        let file_type = entry
+            .file_type()
+            .wrap_err_with(|| format!("Failed reading file type of {p}"))?;
+        // Symlinks indexed as copies go on like files, their type saying they are not directories
+        let is_dir = if file_type.is_symlink() && !self.is_indexed_symlink(p) {
+            match self.symlink_target(p) {
+                Some(target) => target.is_dir(),
+                None => return Ok(Entry::Skipped),
+            }
         } else {
-            if p.file_name().expect("Path is a file") == "cstfs.db" {
-                continue;
+            file_type.is_dir()
+        };
+
+        if is_dir {
+            if is_in_cstfs_dir(relative)
+                || matches!(self.config.max_depth, Some(max) if depth >= max)
+            {
+                return Ok(Entry::Skipped);
             }
-            match p.extension().map(is_media_extension) {
-                Some(true) => {}
-                Some(false) => {
-                    println!("Cowardly refusing to index file \"{p}\" which is not a media file");
-                    continue;
+            return Ok(Entry::Dir(p.to_path_buf()));
+        }
+        Ok(if self.is_indexed(p, relative)? {
+            Entry::File(p.to_path_buf())
+        } else {
+            Entry::Skipped
+        })
+    }
+
+    /// Check if the file at `path`, which is at `relative` inside the data directory, should be
+    /// indexed
+    fn is_indexed(&self, path: &Utf8Path, relative: &Utf8Path) -> Result<bool> {
+        if path.file_name().is_some_and(crate::db::is_db_file) || relative == config::FILE_NAME {
+            return Ok(false);
+        }
+        if sidecar::is_sidecar(path, &self.config) {
+            debug!("Not indexing sidecar \"{path}\", it goes along with its photo");
+            return Ok(false);
+        }
+        if matches!(self.config.small_files, SmallFilePolicy::Ignore) {
+            let size = path
+                .metadata()
+                .wrap_err_with(|| format!("Failed reading metadata of {path}"))?
+                .len();
+            if size <= self.config.small_file_size {
+                debug!("Not indexing small file \"{path}\"");
+                return Ok(false);
+            }
+        }
+        if !self.config.all_files {
+            let kind = media_kind(path, &self.config)
+                .wrap_err_with(|| format!("Failed finding out the type of {path}"))?;
+            match (kind, path.extension(), self.config.detect) {
+                (Some(_), ..) => {}
+                (None, None, Detection::Extension) => {
+                    info!("Cowardly refusing to index file \"{path}\" which has no extension");
+                    return Ok(false);
+                }
+                (None, ..) => {
+                    info!("Cowardly refusing to index file \"{path}\" which is not a media file");
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Check if the symlink at `path` is indexed as a copy of the file it points to, which it is if
+    /// `config.index_symlinks` is set and that file is inside the data directory
+    fn is_indexed_symlink(&self, path: &Utf8Path) -> bool {
+        self.config.index_symlinks
+            && canonicalize(path)
+                .is_ok_and(|t| t.starts_with(&self.canonical_data_path) && t.is_file())
+    }
+
+    /// Canonical path of the target of the symlink at `path`, if it should be followed. Symlinks
+    /// are only followed if `config.follow_symlinks` is set, and never when they point inside the
+    /// data directory, since their target is read through its own path already.
+    fn symlink_target(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        if !self.config.follow_symlinks {
+            info!("Not following symlink \"{path}\"");
+            return None;
+        }
+        match canonicalize(path) {
+            Ok(target) if target.starts_with(&self.canonical_data_path) => None,
+            Ok(target) => Some(target),
+            Err(e) => {
+                warn!("Not following broken symlink \"{path}\": {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Check if `entry` has the hidden attribute, which hides files on Windows instead of a leading dot
+#[cfg(windows)]
+fn has_hidden_attribute(entry: &Utf8DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    entry
+        .metadata()
+        .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+const fn has_hidden_attribute(_: &Utf8DirEntry) -> bool {
+    false
+}
+
+/// Context of the errors of a walk, saying which file or directory could not be read, so the ones
+/// below it are known to be unread
+#[derive(Debug)]
+pub struct Unreadable(pub Utf8PathBuf);
+
+impl std::fmt::Display for Unreadable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not read \"{}\"", self.0)
+    }
+}
+
+/// Walk reading one directory at a time, depth first
+struct SerialWalk {
+    rules: Rules,
+    /// Directories being read, with how many directories below the data directory they are, the
+    /// innermost last
+    open: Vec<(Utf8PathBuf, Listing, usize)>,
+}
+
+impl SerialWalk {
+    const fn new(rules: Rules) -> Self {
+        Self {
+            rules,
+            open: vec![],
+        }
+    }
+
+    /// Start reading the directory at `path`, which is `depth` directories below the data
+    /// directory
+    fn open_dir(&mut self, path: &Utf8Path, depth: usize) -> Result<()> {
+        if let Some(entries) = self.rules.open_dir(path)? {
+            self.open.push((path.to_path_buf(), entries, depth));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SerialWalk {
+    type Item = Result<Utf8PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (dir, entries, depth) = self.open.last_mut()?;
+            let depth = *depth;
+            let Some(entry) = self.rules.next_entry(dir, entries, depth) else {
+                self.open.pop();
+                continue;
+            };
+            match entry {
+                Ok(Entry::File(p)) => return Some(Ok(p)),
+                Ok(Entry::Dir(p)) => {
+                    if let Err(e) = self.open_dir(&p, depth + 1) {
+                        return Some(Err(e));
+                    }
                 }
-                None => {
-                    println!("Cowardly refusing to index file \"{p}\" which has no extension");
+                Ok(Entry::Skipped) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Directories waiting to be read by the threads of a parallel walk
+struct Queue {
+    /// Directories, with how many directories below the data directory they are
+    dirs: Vec<(Utf8PathBuf, usize)>,
+    /// Amount of directories queued or being read, the walk is done once there are none
+    pending: usize,
+    /// Set once the files found are no longer wanted
+    stopped: bool,
+}
+
+/// Walk reading several directories at once, each in its own thread, which keeps slow disks and
+/// network shares busy
+struct ParallelWalk {
+    rules: Rules,
+    queue: Mutex<Queue>,
+    /// Notified when directories are queued or the walk is done
+    changed: Condvar,
+}
+
+impl ParallelWalk {
+    /// Start walking the directories in `dirs`, with how many directories below the data directory
+    /// they are, with `jobs` threads, which send the files they find through the returned channel
+    /// and stop once it is dropped
+    fn spawn(
+        rules: Rules,
+        dirs: Vec<(Utf8PathBuf, usize)>,
+        jobs: usize,
+    ) -> mpsc::Receiver<Result<Utf8PathBuf>> {
+        let (found_tx, found_rx) = mpsc::sync_channel(STREAM_QUEUE_SIZE);
+        let walk = Arc::new(Self {
+            queue: Mutex::new(Queue {
+                pending: dirs.len(),
+                dirs,
+                stopped: false,
+            }),
+            rules,
+            changed: Condvar::new(),
+        });
+        for _ in 0..jobs {
+            let walk = Arc::clone(&walk);
+            let found_tx = found_tx.clone();
+            std::thread::spawn(move || walk.work(&found_tx));
+        }
+        found_rx
+    }
+
+    fn work(&self, found: &mpsc::SyncSender<Result<Utf8PathBuf>>) {
+        while let Some((dir, depth)) = self.next_dir() {
+            let wanted = self.read_dir(&dir, depth, found);
+            let mut queue = self.queue.lock().expect("Walking thread panicked");
+            queue.pending -= 1;
+            queue.stopped |= !wanted;
+            if queue.pending == 0 || queue.stopped {
+                self.changed.notify_all();
+            }
+        }
+    }
+
+    /// Wait for a directory to read, returning `None` once the walk is done
+    fn next_dir(&self) -> Option<(Utf8PathBuf, usize)> {
+        let mut queue = self.queue.lock().expect("Walking thread panicked");
+        loop {
+            if queue.stopped {
+                return None;
+            }
+            if let Some(dir) = queue.dirs.pop() {
+                return Some(dir);
+            }
+            if queue.pending == 0 {
+                return None;
+            }
+            queue = self.changed.wait(queue).expect("Walking thread panicked");
+        }
+    }
+
+    /// Read the directory at `dir`, which is `depth` directories below the data directory,
+    /// sending the files in it to `found` and queueing the directories in it. Returns whether the
+    /// files found are still wanted.
+    fn read_dir(
+        &self,
+        dir: &Utf8Path,
+        depth: usize,
+        found: &mpsc::SyncSender<Result<Utf8PathBuf>>,
+    ) -> bool {
+        let mut entries = match self.rules.open_dir(dir) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => return true,
+            Err(e) => return found.send(Err(e)).is_ok(),
+        };
+        while let Some(entry) = self.rules.next_entry(dir, &mut entries, depth) {
+            let res = match entry {
+                Ok(Entry::File(p)) => Ok(p),
+                Ok(Entry::Dir(p)) => {
+                    let mut queue = self.queue.lock().expect("Walking thread panicked");
+                    queue.dirs.push((p, depth + 1));
+                    queue.pending += 1;
+                    drop(queue);
+                    self.changed.notify_one();
                     continue;
                 }
+                Ok(Entry::Skipped) => continue,
+                Err(e) => Err(e),
+            };
+            if found.send(res).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Check if the directory at `path` can be written to, which it cannot on read-only media or mounts
+#[cfg(unix)]
+pub fn is_writable(path: &Utf8Path) -> bool {
+    let Ok(path) = std::ffi::CString::new(path.as_str()) else {
+        return true;
+    };
+    // SAFETY: access only reads the path, which is a valid C string until it returns
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_writable(path: &Utf8Path) -> bool {
+    path.metadata().is_ok_and(|m| !m.permissions().readonly())
+}
+
+/// Check if `e` may go away when trying again, like the I/O errors and timeouts network filesystems
+/// have now and then
+fn is_transient(e: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EIO, libc::ETIMEDOUT, libc::EAGAIN];
+    // ERROR_UNEXP_NET_ERR, ERROR_NETNAME_DELETED and ERROR_SEM_TIMEOUT
+    #[cfg(not(unix))]
+    let codes = [59, 64, 121];
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted
+    ) || e.raw_os_error().is_some_and(|c| codes.contains(&c))
+}
+
+/// Run `f`, reading the file at `path`, until it does not fail with a [transient](is_transient)
+/// error, up to `config.retries` times more, waiting twice as long before every one of them
+pub fn retry<T>(config: &Config, path: &Utf8Path, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = RETRY_DELAY;
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(e)
+                if retries < config.retries
+                    && !interrupted()
+                    && e.chain()
+                        .filter_map(|e| e.downcast_ref::<std::io::Error>())
+                        .any(is_transient) =>
+            {
+                warn!("Reading \"{path}\" again in {delay:.1?}, {e:#}");
+                std::thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
             }
-            paths.push(p.into());
+            res => return res,
         }
     }
+}
 
-    Ok(paths)
+/// Size of the file at `path` and what identifies it on disk, as recorded in the index
+pub fn stat(path: &Utf8Path) -> Result<db::Stat> {
+    let metadata = path
+        .metadata()
+        .wrap_err_with(|| format!("Failed reading metadata of {path}"))?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .and_then(|m| i64::try_from(m.as_nanos()).ok());
+    #[cfg(unix)]
+    let (device, inode) = {
+        use std::os::unix::fs::MetadataExt;
+        // sqlite only has signed integers, the bits are kept as they are
+        (
+            Some(i64::from_ne_bytes(metadata.dev().to_ne_bytes())),
+            Some(i64::from_ne_bytes(metadata.ino().to_ne_bytes())),
+        )
+    };
+    #[cfg(not(unix))]
+    let (device, inode) = (None, None);
+    Ok(db::Stat {
+        size: metadata.len(),
+        device,
+        inode,
+        mtime,
+    })
 }
 
-/// Remove a file, ignoring the case where the file is not found (like rm -f <file>)
+/// Check if the files at `a` and `b` are hardlinks of each other, which are the same file on disk
+pub fn is_hardlink(a: &Utf8Path, b: &Utf8Path) -> bool {
+    let (Ok(a), Ok(b)) = (stat(a), stat(b)) else {
+        return false;
+    };
+    a.inode.is_some() && (a.device, a.inode) == (b.device, b.inode)
+}
+
+/// Check if either of `a` and `b`, files with the same contents, is a symlink indexed as a copy of
+/// the file it points to, as `config.index_symlinks` says
+pub fn is_symlink_copy(config: &Config, a: &Utf8Path, b: &Utf8Path) -> bool {
+    config.index_symlinks && (a.is_symlink() || b.is_symlink())
+}
+
+/// How many times removing a file is tried again on Windows, where files open in another program
+/// cannot be removed until it closes them
+const REMOVE_RETRIES: u32 = 5;
+
+/// Remove a file, ignoring the case where the file is not found (like rm -f <file>).
+///
+/// On Windows read-only files are made writable to be removed, and files open in another program,
+/// like a viewer, are tried again for a little while before giving up.
 pub fn remove_file(path: &Utf8Path) -> std::io::Result<()> {
-    match std::fs::remove_file(path) {
-        Ok(()) => {}
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
-        e @ Err(_) => e?,
+    let mut retries = 0;
+    loop {
+        match std::fs::remove_file(path) {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            // ERROR_ACCESS_DENIED and ERROR_SHARING_VIOLATION
+            Err(e) if cfg!(windows) && matches!(e.raw_os_error(), Some(5 | 32)) => {
+                if retries == REMOVE_RETRIES {
+                    return Err(std::io::Error::new(
+                        e.kind(),
+                        format!("{e}, it may be open in another program"),
+                    ));
+                }
+                clear_readonly(path);
+                retries += 1;
+                debug!("Could not remove \"{path}\" yet, trying again: {e}");
+                std::thread::sleep(Duration::from_millis(100 << retries));
+            }
+            Err(e) => return Err(e),
+        }
     }
+}
 
-    Ok(())
+/// Make the file at `path` writable if it is read-only, which Windows does not remove
+#[cfg(windows)]
+#[allow(clippy::permissions_set_readonly_false)] // Only world writable on unix
+fn clear_readonly(path: &Utf8Path) {
+    if let Ok(metadata) = path.symlink_metadata() {
+        let mut permissions = metadata.permissions();
+        if permissions.readonly() {
+            permissions.set_readonly(false);
+            let _ = std::fs::set_permissions(path, permissions);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+const fn clear_readonly(_: &Utf8Path) {}
+
+/// New empty directory named after `name` in the temporary directory of the system, for tests to
+/// make stores in
+#[cfg(test)]
+pub fn test_dir(name: &str) -> Utf8PathBuf {
+    let dir = std::env::temp_dir().join(format!("cstfs-test-{name}-{}", std::process::id()));
+    let dir = Utf8PathBuf::from_path_buf(dir).expect("temporary directory is not UTF-8");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("failed creating test directory");
+    dir
 }
@@ -0,0 +1,155 @@
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::Utc;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::db;
+use crate::exit::Failures;
+use crate::parity;
+use crate::report::Reporter;
+use crate::utils::{full_path, hash_files};
+
+/// Random subset of the files to verify
+#[derive(Debug, Clone, Copy)]
+pub enum Sample {
+    /// This fraction of them, between 0 and 1
+    Fraction(f64),
+    /// This many of them
+    Count(usize),
+}
+
+impl Sample {
+    /// How many files out of `total` are in the sample
+    // The fraction is at most 1, so the amount fits back in a usize
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    fn size(self, total: usize) -> usize {
+        match self {
+            Self::Fraction(f) => ((total as f64 * f).ceil() as usize).min(total),
+            Self::Count(n) => n.min(total),
+        }
+    }
+}
+
+/// Which of the indexed files a verification reads
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Selection {
+    /// Only the files that were not verified in this long, or every file if `None`
+    pub older_than: Option<Duration>,
+    /// Only a random sample of the files, picked before the budget is applied
+    pub sample: Option<Sample>,
+    /// Stop once the files selected take this many bytes, at least one file is always verified
+    pub budget: Option<u64>,
+}
+
+/// Hash the indexed files `selection` picks, the least recently verified first, and check that
+/// their contents still match the index, recording when the ones that do were verified.
+///
+/// Running it regularly with a budget spreads the scrubbing of a large store across many runs.
+/// Fails with [`Failures`] if any file is corrupted, missing or unreadable.
+pub fn verify(
+    data_path: &Utf8Path,
+    config: &Config,
+    reporter: &dyn Reporter,
+    selection: Selection,
+) -> Result<()> {
+    let now = Instant::now();
+    let started_at = Utc::now().timestamp();
+    let before = selection.older_than.map_or(i64::MAX, |older_than| {
+        started_at.saturating_sub(i64::try_from(older_than.as_secs()).unwrap_or(i64::MAX))
+    });
+
+    // The index is only read until the results are recorded, so this does not lock the store
+    // and a long scrub does not keep other commands waiting
+    let mut conn = db::open(data_path, config).wrap_err("Failed to open db")?;
+    db::check_hash(&conn, config.hash)?;
+    let read_only = db::read_only(data_path, config);
+    let mut candidates =
+        db::unverified_files(&conn, before).wrap_err("Failed fetching files from db")?;
+    if let Some(sample) = selection.sample {
+        fastrand::shuffle(&mut candidates);
+        candidates.truncate(sample.size(candidates.len()));
+    }
+    let mut total: u64 = 0;
+    let mut files = vec![];
+    for (path, hash, size) in candidates {
+        let size = size.unwrap_or_else(|| {
+            full_path(data_path, Utf8Path::new(&path))
+                .metadata()
+                .map_or(0, |m| m.len())
+        });
+        if let Some(budget) = selection.budget {
+            if !files.is_empty() && total.saturating_add(size) > budget {
+                break;
+            }
+        }
+        total = total.saturating_add(size);
+        files.push((Utf8PathBuf::from(path), hash));
+    }
+    info!("Verifying {} files in \"{data_path}\"", files.len());
+
+    let full_paths: Vec<_> = files
+        .iter()
+        .map(|(p, _)| full_path(data_path, Utf8Path::new(p)))
+        .collect();
+    // Cached hashes would hide the corruption verifying is meant to find
+    let uncached = Config {
+        xattr_cache: false,
+        ..config.clone()
+    };
+    let hashes = hash_files(&full_paths, &uncached, reporter);
+
+    let transaction = conn
+        .transaction()
+        .wrap_err("Failed creating verify transaction")?;
+    let mut failed = 0;
+    for ((path, hash), current) in files.iter().zip(hashes) {
+        match current {
+            // Nothing is recorded in a read-only store, so it is verified again next time
+            Ok(current) if current == *hash && read_only => {}
+            Ok(current) if current == *hash => {
+                db::set_verified(&transaction, path, hash, started_at)
+                    .wrap_err("Failed recording verification")?;
+            }
+            Ok(current) => {
+                let hint = if parity::exists(data_path, hash) {
+                    ", run `cstfs repair` to repair it"
+                } else {
+                    ""
+                };
+                warn!("\"{path}\" is corrupted, its hash is {current} instead of {hash}{hint}");
+                failed += 1;
+            }
+            Err(_) if !full_path(data_path, Utf8Path::new(path)).exists() => {
+                warn!("\"{path}\" is missing");
+                failed += 1;
+            }
+            Err(e) => {
+                warn!("{e:#}");
+                failed += 1;
+            }
+        }
+    }
+    transaction
+        .commit()
+        .wrap_err("Could not commit transaction")?;
+
+    let elapsed = now.elapsed();
+    info!(
+        "Verified {} files, {failed} of them failed. Took {elapsed:.2?}",
+        files.len()
+    );
+    if failed > 0 {
+        bail!(Failures(format!("{failed} files failed verification")));
+    }
+    Ok(())
+}